@@ -0,0 +1,7 @@
+pub use crate::lift::{Lifting, Reference, lift_pointers};
+pub use crate::rust::{emit_rust, emit_rust_mapped};
+
+mod lift;
+mod rust;
+#[cfg(test)]
+mod test_util;
@@ -0,0 +1,299 @@
+// Finds pointer parameters that can be references. A parameter qualifies if it is
+// only ever dereferenced at constant, non-negative offsets, and the largest offset is
+// accessed on every path through the function. C then guarantees that the pointer is
+// non-null, aligned and valid for the whole range, which is what a reference needs.
+//
+// The function is lifted as a whole or not at all: if any memory is accessed through
+// something other than such a parameter, a write through it could change what a
+// reference points to.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use my_cfg::{BasicBlock, CType, Cfg, Idx, Local, Place, Stmt, Terminator, Value};
+
+/// The reference that replaces a pointer parameter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reference {
+    /// Whether anything is written through it.
+    pub mutable: bool,
+    /// `Some(n)` for `&[T; n]`, `None` for `&T` when only offset 0 is accessed.
+    pub len: Option<usize>,
+}
+
+impl Reference {
+    /// The number of elements the reference covers.
+    pub fn elements(&self) -> usize {
+        self.len.unwrap_or(1)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Lifting {
+    /// Pointer parameters that are not accessed at all are left out and stay pointers.
+    pub params: BTreeMap<Idx<Local>, Reference>,
+}
+
+#[derive(Default)]
+struct Accesses {
+    written: bool,
+    max_offset: usize,
+    // Whether an access at `max_offset` happens on every path.
+    max_is_certain: bool,
+    only_first: bool,
+}
+
+/// Returns `None` if no parameter can be lifted.
+pub fn lift_pointers(cfg: &Cfg) -> Option<Lifting> {
+    let candidates: BTreeSet<_> = (1..=cfg.arg_count)
+        .map(Idx::from_usize)
+        .filter(|idx| match &cfg.locals[*idx].ty {
+            CType::Ptr(ty) => !matches!(**ty, CType::Void),
+            _ => false,
+        })
+        .collect();
+    if candidates.is_empty() {
+        return None;
+    }
+    let certain = certain_blocks(cfg)?;
+    let mut finder = AccessFinder {
+        candidates: &candidates,
+        accesses: BTreeMap::new(),
+        certain: false,
+    };
+    for (idx, bb) in cfg.bb.iter() {
+        finder.certain = certain.contains(&idx);
+        for stmt in &bb.stmts {
+            match stmt {
+                Stmt::Assign { place, value, .. } => {
+                    finder.place(place, true)?;
+                    finder.value(value)?;
+                }
+            }
+        }
+        if let Some(Terminator::If { cond, .. }) = &bb.terminator {
+            finder.value(cond)?;
+        }
+    }
+
+    let mut params = BTreeMap::new();
+    for (idx, accesses) in finder.accesses {
+        if !accesses.max_is_certain {
+            return None;
+        }
+        let len = (!accesses.only_first).then_some(accesses.max_offset + 1);
+        params.insert(
+            idx,
+            Reference {
+                mutable: accesses.written,
+                len,
+            },
+        );
+    }
+    (!params.is_empty()).then_some(Lifting { params })
+}
+
+struct AccessFinder<'a> {
+    candidates: &'a BTreeSet<Idx<Local>>,
+    accesses: BTreeMap<Idx<Local>, Accesses>,
+    // Whether the block being looked at runs on every path.
+    certain: bool,
+}
+
+impl AccessFinder<'_> {
+    // `None` if the function can't be lifted.
+    fn value(&mut self, value: &Value) -> Option<()> {
+        match value {
+            Value::Place(place) => self.place(place, false),
+            Value::Literal(_) => Some(()),
+            Value::Binop(l, _, r) => {
+                self.value(l)?;
+                self.value(r)
+            }
+            Value::Select(cond, then, else_) => {
+                self.value(cond)?;
+                self.value(then)?;
+                self.value(else_)
+            }
+        }
+    }
+
+    fn place(&mut self, place: &Place, written: bool) -> Option<()> {
+        match place {
+            // A parameter used as a pointer value might escape or be compared.
+            Place::Local(idx) if self.candidates.contains(idx) => None,
+            Place::Local(_) => Some(()),
+            Place::Deref(inner) => match &**inner {
+                Place::Local(idx) if self.candidates.contains(idx) => {
+                    self.access(*idx, 0, true, written);
+                    Some(())
+                }
+                Place::Offset(base, offset) => match (&**base, &**offset) {
+                    (Place::Local(idx), Value::Literal(i))
+                        if self.candidates.contains(idx) && *i >= 0 =>
+                    {
+                        self.access(*idx, *i as usize, *i == 0, written);
+                        Some(())
+                    }
+                    _ => None,
+                },
+                _ => None,
+            },
+            Place::Offset(base, offset) => {
+                self.place(base, false)?;
+                self.value(offset)
+            }
+        }
+    }
+
+    fn access(&mut self, idx: Idx<Local>, offset: usize, first: bool, written: bool) {
+        let accesses = self.accesses.entry(idx).or_insert(Accesses {
+            only_first: true,
+            ..Default::default()
+        });
+        accesses.written |= written;
+        accesses.only_first &= first;
+        if offset > accesses.max_offset {
+            accesses.max_offset = offset;
+            accesses.max_is_certain = false;
+        }
+        if offset == accesses.max_offset {
+            accesses.max_is_certain |= self.certain;
+        }
+    }
+}
+
+// Blocks that every path from the entry to a return goes through. `None` if no
+// return is reachable.
+fn certain_blocks(cfg: &Cfg) -> Option<BTreeSet<Idx<BasicBlock>>> {
+    let entry = Idx::from_usize(0);
+    let returns = |avoid: Option<Idx<BasicBlock>>| {
+        if avoid == Some(entry) {
+            return false;
+        }
+        let mut seen = BTreeSet::from([entry]);
+        let mut stack = vec![entry];
+        while let Some(idx) = stack.pop() {
+            let successors = match cfg.bb[idx].terminator() {
+                Terminator::Return => return true,
+                Terminator::Goto { bb } => vec![*bb],
+                Terminator::If { then, else_, .. } => vec![*then, *else_],
+            };
+            for succ in successors {
+                if Some(succ) != avoid && seen.insert(succ) {
+                    stack.push(succ);
+                }
+            }
+        }
+        false
+    };
+    if !returns(None) {
+        return None;
+    }
+    Some(
+        cfg.bb
+            .iter()
+            .map(|(idx, _)| idx)
+            .filter(|idx| !returns(Some(*idx)))
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use my_cfg::Binop;
+    use my_cfg::test_util::*;
+
+    use super::*;
+    use crate::emit_rust;
+    use crate::test_util::run;
+
+    // `_1[i]`
+    fn element(i: usize, offset: i32) -> Place {
+        let base = Place::Offset(Box::new(place(i)), Box::new(Value::Literal(offset)));
+        Place::Deref(Box::new(base))
+    }
+
+    // bb0: *_1 = _2[0] + _2[2]; _0 = *_1 + _2[2]; return
+    fn sum() -> Cfg {
+        let ptr = CType::Ptr(Box::new(CType::Int(4)));
+        let mut cfg = with_locals([CType::Int(4), ptr.clone(), ptr]);
+        cfg.arg_count = 2;
+        let first = Place::Deref(Box::new(place(1)));
+        let stmts = vec![
+            Stmt::Assign {
+                place: first.clone(),
+                value: binop(
+                    Value::Place(element(2, 0)),
+                    Binop::Add,
+                    Value::Place(element(2, 2)),
+                ),
+                origin: None,
+            },
+            assign(
+                0,
+                binop(Value::Place(first), Binop::Add, Value::Place(element(2, 2))),
+            ),
+        ];
+        cfg.bb.alloc(block(stmts, Terminator::Return));
+        cfg
+    }
+
+    #[test]
+    fn test_lift_pointers() {
+        let cfg = sum();
+        let lifting = lift_pointers(&cfg).unwrap();
+        let params: Vec<_> = lifting
+            .params
+            .iter()
+            .map(|(idx, reference)| (idx.to_usize(), *reference))
+            .collect();
+        let written = Reference {
+            mutable: true,
+            len: None,
+        };
+        let read = Reference {
+            mutable: false,
+            len: Some(3),
+        };
+        assert_eq!(params, [(1, written), (2, read)]);
+
+        // _2[2] is only read on one path, so _2 may be shorter.
+        let mut cfg = sum();
+        let stmts = std::mem::take(&mut cfg.bb[Idx::from_usize(0)].stmts);
+        cfg.bb[Idx::from_usize(0)] = block(vec![], branch(local(0), 1, 2));
+        cfg.bb.alloc(block(stmts, Terminator::Return));
+        cfg.bb.alloc(block(vec![], Terminator::Return));
+        assert!(lift_pointers(&cfg).is_none());
+
+        // A pointer that is compared might be null.
+        let mut cfg = sum();
+        let compare = assign(0, binop(local(1), Binop::Eq, local(2)));
+        cfg.bb[Idx::from_usize(0)].stmts.push(compare);
+        assert!(lift_pointers(&cfg).is_none());
+    }
+
+    #[test]
+    fn test_shim() {
+        // Through the references, and through the raw pointers when the written one
+        // points into the other array: then the second read of _2[2] sees the write.
+        let cfg = sum();
+        let main = r#"
+fn main() {
+    let mut q = [1, 5, 10];
+    let mut p = 0;
+    assert_eq!(unsafe { sum(&mut p, q.as_mut_ptr()) }, 21);
+    assert_eq!(p, 11);
+    let q = q.as_mut_ptr();
+    assert_eq!(unsafe { sum(q.wrapping_add(2), q) }, 22);
+}
+"#;
+        let source = emit_rust(&cfg, &cfg.loopify(), "sum");
+        assert!(
+            source.contains("fn sum_safe(mut _1: &mut i32, mut _2: &[i32; 3])"),
+            "{source}"
+        );
+        if let Err(errors) = run(&(source.clone() + main), "shim") {
+            panic!("{source}\n{errors}");
+        }
+    }
+}
@@ -1,3 +1,20 @@
+use converter::emit_rust_mapped;
+use my_cfg::{Cfg, IrreducibleStrategy, LoopifyConfig, Structurer};
+
 fn main() {
-    println!("Hello, world!");
+    let mut args = std::env::args().skip(1);
+    let path = args
+        .next()
+        .expect("usage: converter <cfg.json> [<source map.json>]");
+    let cfg = Cfg::from_json(&std::fs::read_to_string(path).unwrap());
+    // Rust has no `goto`, so irreducible regions are always split.
+    let config = LoopifyConfig {
+        structurer: Structurer::Dominators,
+        irreducible: IrreducibleStrategy::NodeSplitting { budget: usize::MAX },
+    };
+    let (code, source_map) = emit_rust_mapped(&cfg, &cfg.loopify_with(&config), "sub");
+    println!("{code}");
+    if let Some(path) = args.next() {
+        std::fs::write(path, source_map.to_json()).unwrap();
+    }
 }
@@ -0,0 +1,1164 @@
+// Rust backend. Walks a `StructuredNode` together with the `Cfg` it was made from
+// and prints an `unsafe extern "C"` function. Integer arithmetic wraps, like it does
+// in the IR, and jumps become labeled `break`s and `continue`s. Rust has no `goto`,
+// so the node must not need one: irreducible regions have to be structured with
+// `IrreducibleStrategy::Dispatch`, or `IrreducibleStrategy::NodeSplitting` with a
+// budget that covers the copies.
+
+use std::collections::BTreeSet;
+
+use my_cfg::expr::{Expr, Syntax, literal_expr};
+use my_cfg::jumps::{Jump, Next, Scope, ScopeKind, next_of, resolve};
+use my_cfg::ranges::Range;
+use my_cfg::{
+    BasicBlock, Binop, CType, Cfg, Idx, Local, Place, SourceMap, Stmt, StructuredNode, Terminator,
+    Value,
+};
+
+use crate::lift::{Lifting, Reference, lift_pointers};
+
+/// Emits an `unsafe extern "C" fn` called `name` that runs `cfg`, with control flow
+/// shaped like `node`. If `lift_pointers` allows it, that function is a shim and the
+/// code is in a safe function that takes references instead of pointers.
+pub fn emit_rust(cfg: &Cfg, node: &StructuredNode, name: &str) -> String {
+    emit_rust_mapped(cfg, node, name).0
+}
+
+/// Like `emit_rust`, and also returns where each line comes from.
+pub fn emit_rust_mapped(cfg: &Cfg, node: &StructuredNode, name: &str) -> (String, SourceMap) {
+    let Some(lifting) = lift_pointers(cfg) else {
+        return emit_function(cfg, node, None, name, Function::Extern);
+    };
+    let safe = format!("{name}_safe");
+    let (mut out, mut source_map) = emit_function(cfg, node, Some(&lifting), &safe, Function::Safe);
+    let overlap = overlap_check(cfg, &lifting);
+    let raw = format!("{name}_raw");
+    if overlap.is_some() {
+        out += "\n";
+        let (function, function_map) = emit_function(cfg, node, None, &raw, Function::Raw);
+        source_map.append(function_map, out.lines().count());
+        out += &function;
+    }
+    out += "\n";
+    out += &shim(
+        cfg,
+        &lifting,
+        name,
+        &safe,
+        overlap.map(|check| (check, raw)),
+    );
+    (out, source_map)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Function {
+    /// The exported function.
+    Extern,
+    /// The safe function behind a shim, with the lifted parameters as references.
+    Safe,
+    /// A copy of the exported function that a shim falls back to.
+    Raw,
+}
+
+fn emit_function(
+    cfg: &Cfg,
+    node: &StructuredNode,
+    lifting: Option<&Lifting>,
+    name: &str,
+    function: Function,
+) -> (String, SourceMap) {
+    let mut emitter = RustEmitter::new(cfg, lifting);
+    // Loops and blocks only get a label if a jump needs it, which the first walk
+    // finds out.
+    emitter.emit_body(node);
+    emitter.labels = std::mem::take(&mut emitter.used_labels);
+    emitter.emit_body(node);
+    emitter.finish(name, function)
+}
+
+const LABEL_VARIABLE: &str = "label";
+
+static EMPTY: StructuredNode = StructuredNode::Sequence(Vec::new());
+
+const RESERVED: &[&str] = &[
+    LABEL_VARIABLE,
+    "as",
+    "async",
+    "await",
+    "break",
+    "const",
+    "continue",
+    "crate",
+    "dyn",
+    "else",
+    "enum",
+    "extern",
+    "false",
+    "fn",
+    "for",
+    "gen",
+    "if",
+    "impl",
+    "in",
+    "let",
+    "loop",
+    "match",
+    "mod",
+    "move",
+    "mut",
+    "pub",
+    "ref",
+    "return",
+    "self",
+    "Self",
+    "static",
+    "struct",
+    "super",
+    "trait",
+    "true",
+    "type",
+    "unsafe",
+    "use",
+    "where",
+    "while",
+    "abstract",
+    "become",
+    "box",
+    "do",
+    "final",
+    "macro",
+    "override",
+    "priv",
+    "try",
+    "typeof",
+    "unsized",
+    "virtual",
+    "yield",
+    "core",
+    "std",
+];
+
+struct RustEmitter<'a> {
+    cfg: &'a Cfg,
+    lifting: Option<&'a Lifting>,
+    names: Vec<String>,
+    // Latches of `DoWhile` loops, whose branch is the loop condition.
+    latches: BTreeSet<Idx<BasicBlock>>,
+    out: String,
+    // Lines in `out`, and the source map of them.
+    lines: usize,
+    source_map: SourceMap,
+    indent: usize,
+    scopes: Vec<Scope<'a>>,
+    // Parallel to `scopes`, the number of each scope in walk order.
+    scope_ids: Vec<usize>,
+    scope_counter: usize,
+    used_labels: BTreeSet<String>,
+    // Labels used by the previous walk, which are the ones that get printed.
+    labels: BTreeSet<String>,
+    uses_label_variable: bool,
+}
+
+impl<'a> RustEmitter<'a> {
+    fn new(cfg: &'a Cfg, lifting: Option<&'a Lifting>) -> Self {
+        Self {
+            cfg,
+            lifting,
+            names: local_names(cfg),
+            latches: BTreeSet::new(),
+            out: String::new(),
+            lines: 0,
+            source_map: SourceMap::default(),
+            indent: 1,
+            scopes: vec![],
+            scope_ids: vec![],
+            scope_counter: 0,
+            used_labels: BTreeSet::new(),
+            labels: BTreeSet::new(),
+            uses_label_variable: false,
+        }
+    }
+
+    fn emit_body(&mut self, node: &'a StructuredNode) {
+        self.out.clear();
+        self.lines = 0;
+        self.source_map = SourceMap::default();
+        self.scope_counter = 0;
+        self.emit_node(node, Next::Nothing);
+    }
+
+    fn finish(self, name: &str, function: Function) -> (String, SourceMap) {
+        let allow = "unused_mut, unused_assignments, unused_variables, unused_labels, \
+                     unreachable_code, non_snake_case";
+        let mut out = match function {
+            Function::Extern => format!(
+                "#[allow({allow}, unsafe_op_in_unsafe_fn)]\n\
+                 #[unsafe(no_mangle)]\n\
+                 pub unsafe extern \"C\" fn"
+            ),
+            Function::Safe => format!("#[allow({allow})]\npub fn"),
+            Function::Raw => format!("#[allow({allow}, unsafe_op_in_unsafe_fn)]\nunsafe fn"),
+        };
+        let params: Vec<_> = (1..=self.cfg.arg_count)
+            .map(|i| {
+                let idx = Idx::from_usize(i);
+                let ty = &self.cfg.locals[idx].ty;
+                let ty = match self.lifting.and_then(|lifting| lifting.params.get(&idx)) {
+                    Some(reference) => reference_type(reference, ty),
+                    None => rust_type(ty),
+                };
+                format!("mut {}: {ty}", self.names[i])
+            })
+            .collect();
+        out += &format!(
+            " {name}({}){} {{\n",
+            params.join(", "),
+            return_type(self.cfg)
+        );
+
+        // Rust wants every local initialized before it is read, C doesn't.
+        for (idx, local) in self.cfg.locals.iter() {
+            let is_param = (1..=self.cfg.arg_count).contains(&idx.to_usize());
+            if !is_param && !matches!(local.ty, CType::Void) {
+                out += &format!(
+                    "    let mut {}: {} = {};\n",
+                    self.names[idx.to_usize()],
+                    rust_type(&local.ty),
+                    zero(&local.ty)
+                );
+            }
+        }
+        if self.uses_label_variable {
+            out += &format!("    let mut {LABEL_VARIABLE}: i32 = 0;\n");
+        }
+        let mut source_map = SourceMap::default();
+        source_map.append(self.source_map, out.lines().count());
+        out += &self.out;
+        out += "}\n";
+        (out, source_map)
+    }
+
+    fn line(&mut self, line: &str) {
+        for _ in 0..self.indent {
+            self.out += "    ";
+        }
+        self.out += line;
+        self.out += "\n";
+        self.lines += 1;
+    }
+
+    // Records that the next line prints position `index` of `bb`, where the index
+    // one past the last statement is the terminator.
+    fn map(&mut self, bb: Idx<BasicBlock>, index: usize) {
+        self.source_map.add(self.lines + 1, bb, index, self.cfg);
+    }
+
+    fn map_terminator(&mut self, bb: Idx<BasicBlock>) {
+        self.map(bb, self.cfg.bb[bb].stmts.len());
+    }
+
+    fn emit_node(&mut self, node: &'a StructuredNode, next: Next<'a>) {
+        match node {
+            StructuredNode::Basic(idx) | StructuredNode::Duplicate(idx) => {
+                self.emit_block(*idx, next)
+            }
+            StructuredNode::Sequence(nodes) => self.emit_sequence(nodes, next),
+            StructuredNode::If { .. } => {
+                panic!("`If` must follow the block whose branch it is")
+            }
+            StructuredNode::Loop(body) => {
+                let header = body.entry().expect("loop body is not empty");
+                let kind = ScopeKind::Loop { header };
+                self.emit_scope(kind, Some("loop".to_string()), next, |this| {
+                    this.emit_node(body, Next::Block(header))
+                });
+            }
+            // `init` and `step` of a `For` stay in their blocks, which run them at the
+            // right time, so it is printed like a `While`.
+            StructuredNode::While { header, cond, body }
+            | StructuredNode::For {
+                header, cond, body, ..
+            } => {
+                let head = format!("while {}", self.condition(cond).print(Syntax::Rust));
+                let kind = ScopeKind::Loop { header: *header };
+                self.map_terminator(*header);
+                self.emit_scope(kind, Some(head), next, |this| {
+                    this.emit_node(body, Next::Block(*header))
+                });
+            }
+            StructuredNode::DoWhile { body, latch, cond } => {
+                let header = body.entry().expect("loop body is not empty");
+                self.latches.insert(*latch);
+                let exit = self.condition(&cond.negated(self.cfg)).print(Syntax::Rust);
+                let kind = ScopeKind::Loop { header };
+                self.emit_scope(kind, Some("loop".to_string()), next, |this| {
+                    this.emit_node(body, Next::Block(header));
+                    this.map_terminator(*latch);
+                    this.line(&format!("if {exit} {{"));
+                    this.line("    break;");
+                    this.line("}");
+                });
+            }
+            StructuredNode::Block(body) => {
+                self.emit_scope(ScopeKind::Block, None, next, |this| {
+                    this.emit_node(body, next)
+                });
+            }
+            StructuredNode::Dispatch {
+                entry_map,
+                handlers,
+            } => {
+                self.uses_label_variable = true;
+                let kind = ScopeKind::Dispatch { entry_map };
+                self.emit_scope(kind, Some("loop".to_string()), next, |this| {
+                    for (i, (label, handler)) in handlers.iter().enumerate() {
+                        let keyword = if i == 0 { "if" } else { "} else if" };
+                        this.line(&format!("{keyword} {LABEL_VARIABLE} == {label} {{"));
+                        this.indent += 1;
+                        this.emit_node(handler, Next::Dispatch(entry_map));
+                        this.indent -= 1;
+                    }
+                    if !handlers.is_empty() {
+                        this.line("}");
+                    }
+                });
+            }
+            StructuredNode::Labeled(_) => {
+                panic!(
+                    "Rust has no `goto`, irreducible regions can't use `IrreducibleStrategy::Goto`"
+                )
+            }
+        }
+    }
+
+    // Prints the body of a scope. `head` starts the Rust loop around it, a `Block`
+    // has none and only gets braces if something breaks out of it.
+    fn emit_scope(
+        &mut self,
+        kind: ScopeKind<'a>,
+        head: Option<String>,
+        next: Next<'a>,
+        body: impl FnOnce(&mut Self),
+    ) {
+        let id = self.scope_counter;
+        self.scope_counter += 1;
+        self.scopes.push(Scope { kind, next });
+        self.scope_ids.push(id);
+        let label = scope_label(kind, id);
+        let open = match (head, self.labels.contains(&label)) {
+            (Some(head), true) => Some(format!("{label}: {head} {{")),
+            (Some(head), false) => Some(format!("{head} {{")),
+            (None, true) => Some(format!("{label}: {{")),
+            (None, false) => None,
+        };
+        if let Some(open) = &open {
+            self.line(open);
+            self.indent += 1;
+        }
+        body(self);
+        if open.is_some() {
+            self.indent -= 1;
+            self.line("}");
+        }
+        self.scopes.pop();
+        self.scope_ids.pop();
+    }
+
+    fn emit_sequence(&mut self, nodes: &'a [StructuredNode], next: Next<'a>) {
+        let nodes: Vec<_> = nodes.iter().filter(|node| !node.is_empty()).collect();
+        let mut i = 0;
+        while i < nodes.len() {
+            // A block followed by an `If` branches into it, and the `If` is followed
+            // by what comes after both.
+            let paired = matches!(nodes.get(i + 1), Some(StructuredNode::If { .. }));
+            let after = if paired { i + 2 } else { i + 1 };
+            let node_next = nodes[after..]
+                .iter()
+                .find_map(|node| next_of(node))
+                .unwrap_or(next);
+            match (nodes[i], nodes.get(i + 1)) {
+                (
+                    StructuredNode::Basic(idx) | StructuredNode::Duplicate(idx),
+                    Some(StructuredNode::If {
+                        cond,
+                        then_node,
+                        else_node,
+                    }),
+                ) => {
+                    self.emit_stmts(*idx);
+                    self.emit_branch(*idx, cond, then_node, else_node, node_next);
+                }
+                (node, _) => self.emit_node(node, node_next),
+            }
+            i = after;
+        }
+    }
+
+    fn emit_block(&mut self, idx: Idx<BasicBlock>, next: Next<'a>) {
+        self.emit_stmts(idx);
+        if self.latches.contains(&idx) {
+            return;
+        }
+        match self.cfg.bb[idx].terminator() {
+            Terminator::Return => {
+                self.map_terminator(idx);
+                if matches!(return_value(self.cfg), CType::Void) {
+                    self.line("return;");
+                } else {
+                    let line = format!("return {};", self.names[0]);
+                    self.line(&line);
+                }
+            }
+            Terminator::Goto { bb } => self.emit_jump(*bb, next),
+            Terminator::If { cond, .. } => {
+                self.emit_branch(idx, cond, &EMPTY, &EMPTY, next);
+            }
+        }
+    }
+
+    fn emit_stmts(&mut self, idx: Idx<BasicBlock>) {
+        for (index, stmt) in self.cfg.bb[idx].stmts.iter().enumerate() {
+            let line = format!("{};", self.stmt(stmt));
+            self.map(idx, index);
+            self.line(&line);
+        }
+    }
+
+    // The branch at the end of `idx`. An empty side is a jump to the target of the
+    // terminator, a non-empty one starts with it.
+    fn emit_branch(
+        &mut self,
+        idx: Idx<BasicBlock>,
+        cond: &Value,
+        then_node: &'a StructuredNode,
+        else_node: &'a StructuredNode,
+        next: Next<'a>,
+    ) {
+        let Terminator::If { then, else_, .. } = self.cfg.bb[idx].terminator() else {
+            panic!("`If` must follow a block that ends with a branch");
+        };
+        let is_empty = |node: &StructuredNode, target| {
+            node.is_empty() && resolve(&self.scopes, next, target) == Jump::Fallthrough
+        };
+        let then_empty = is_empty(then_node, *then);
+        let else_empty = is_empty(else_node, *else_);
+        let emit_side = |this: &mut Self, node: &'a StructuredNode, target| {
+            this.indent += 1;
+            if node.is_empty() {
+                this.emit_jump(target, next);
+            } else {
+                this.emit_node(node, next);
+            }
+            this.indent -= 1;
+        };
+        let print = |this: &Self, cond: &Value| this.condition(cond).print(Syntax::Rust);
+        match (then_empty, else_empty) {
+            (true, true) => {}
+            (false, true) => {
+                self.map_terminator(idx);
+                self.line(&format!("if {} {{", print(self, cond)));
+                emit_side(self, then_node, *then);
+                self.line("}");
+            }
+            (true, false) => {
+                self.map_terminator(idx);
+                self.line(&format!("if {} {{", print(self, &cond.negated(self.cfg))));
+                emit_side(self, else_node, *else_);
+                self.line("}");
+            }
+            (false, false) => {
+                self.map_terminator(idx);
+                self.line(&format!("if {} {{", print(self, cond)));
+                emit_side(self, then_node, *then);
+                self.line("} else {");
+                emit_side(self, else_node, *else_);
+                self.line("}");
+            }
+        }
+    }
+
+    fn emit_jump(&mut self, target: Idx<BasicBlock>, next: Next<'a>) {
+        let (scope, label, keyword) = match resolve(&self.scopes, next, target) {
+            Jump::Fallthrough => return,
+            Jump::SetLabel(label) => {
+                self.line(&format!("{LABEL_VARIABLE} = {label};"));
+                return;
+            }
+            Jump::Goto(target) => {
+                panic!("Rust has no `goto`, nothing around the jump reaches {target:?}")
+            }
+            Jump::Break { scope, label } => (scope, label, "break"),
+            Jump::Continue { scope, label } => (scope, label, "continue"),
+        };
+        if let Some(label) = label {
+            self.line(&format!("{LABEL_VARIABLE} = {label};"));
+        }
+        // An unlabeled `break` or `continue` goes to the innermost loop, and isn't
+        // allowed inside of a labeled block.
+        let kind = self.scopes[scope].kind;
+        let innermost = scope + 1 == self.scopes.len() && !matches!(kind, ScopeKind::Block);
+        if innermost {
+            self.line(&format!("{keyword};"));
+        } else {
+            let label = scope_label(kind, self.scope_ids[scope]);
+            self.line(&format!("{keyword} {label};"));
+            self.used_labels.insert(label);
+        }
+    }
+
+    fn stmt(&self, stmt: &Stmt) -> String {
+        match stmt {
+            Stmt::Assign { place, value, .. } => {
+                let value = self.value(value, &place.ty(self.cfg));
+                format!(
+                    "{} = {}",
+                    self.place(place).print(Syntax::Rust),
+                    value.print(Syntax::Rust)
+                )
+            }
+        }
+    }
+
+    // The type a value has in Rust. Comparisons are `bool`, and arithmetic is done
+    // in the type of its first operand that isn't a literal.
+    fn ty(&self, value: &Value) -> CType {
+        match value {
+            Value::Place(place) => place.ty(self.cfg),
+            Value::Literal(_) => CType::Int(4),
+            Value::Binop(_, binop, _) if is_condition(*binop) => CType::Bool,
+            Value::Binop(l, _, r) => match self.operand_ty(l, r) {
+                CType::Bool => CType::Int(4),
+                ty => ty,
+            },
+            Value::Select(_, then, else_) => self.operand_ty(then, else_),
+        }
+    }
+
+    fn operand_ty(&self, l: &Value, r: &Value) -> CType {
+        match l {
+            Value::Literal(_) => self.ty(r),
+            _ => self.ty(l),
+        }
+    }
+
+    /// `value` as an expression of type `ty`.
+    fn value(&self, value: &Value, ty: &CType) -> Expr {
+        match value {
+            Value::Literal(i) => literal(*i, ty, false),
+            Value::Place(place) => convert(self.place(place), &place.ty(self.cfg), ty),
+            Value::Binop(_, binop, _) if is_condition(*binop) => {
+                convert(self.condition(value), &CType::Bool, ty)
+            }
+            Value::Select(cond, then, else_) => {
+                let operand_ty = self.ty(value);
+                // Like a literal, an `if` between two of them would have no type yet.
+                let suffixed =
+                    matches!((&**then, &**else_), (Value::Literal(_), Value::Literal(_)));
+                let branch = |value: &Value| match value {
+                    Value::Literal(i) if suffixed => literal(*i, &operand_ty, true),
+                    _ => self.value(value, &operand_ty),
+                };
+                let expr = Expr::conditional(self.condition(cond), branch(then), branch(else_));
+                convert(expr, &operand_ty, ty)
+            }
+            Value::Binop(l, binop, r) => {
+                let operand_ty = self.ty(value);
+                let r_expr = self.value(r, &operand_ty);
+                let expr = match operand_ty {
+                    // Only the carries of arithmetic can overflow, not bitwise operations.
+                    CType::Int(_) | CType::UInt(_) if *binop == Binop::Xor => {
+                        Expr::binary(self.value(l, &operand_ty), *binop, r_expr)
+                    }
+                    CType::Int(bytes) | CType::UInt(bytes) => {
+                        // `Shr` shifts in zeros and `Sar` copies of the sign bit, which
+                        // Rust decides by the signedness of the left side.
+                        let shift_ty = match (binop, &operand_ty) {
+                            (Binop::Shr, _) => CType::UInt(bytes),
+                            (Binop::Sar, _) => CType::Int(bytes),
+                            _ => operand_ty.clone(),
+                        };
+                        // The receiver of a method call needs a type, an unsuffixed
+                        // literal doesn't have one yet.
+                        let l_expr = match **l {
+                            Value::Literal(i) => literal(i, &shift_ty, true),
+                            _ => convert(self.value(l, &operand_ty), &operand_ty, &shift_ty),
+                        };
+                        // A negative literal cast to `u32` wouldn't compile.
+                        let amount = match **r {
+                            Value::Literal(i) => {
+                                Expr::Atom((i & (bytes as i32 * 8 - 1)).to_string())
+                            }
+                            _ => Expr::cast(r_expr.clone(), "u32"),
+                        };
+                        let (method, r_expr) = match binop {
+                            Binop::Add => ("wrapping_add", r_expr),
+                            Binop::Sub => ("wrapping_sub", r_expr),
+                            Binop::Mul => ("wrapping_mul", r_expr),
+                            Binop::Div => ("wrapping_div", r_expr),
+                            Binop::Rem => ("wrapping_rem", r_expr),
+                            // The wrapping shifts mask the amount like the IR does.
+                            Binop::Shl => ("wrapping_shl", amount),
+                            Binop::Shr | Binop::Sar => ("wrapping_shr", amount),
+                            _ => unreachable!(),
+                        };
+                        let expr = convert(
+                            Expr::method(l_expr, method, vec![r_expr.clone()]),
+                            &shift_ty,
+                            &operand_ty,
+                        );
+                        match (binop, &**r) {
+                            (Binop::Div | Binop::Rem, Value::Literal(i))
+                                if Range::exact((*i).into())
+                                    .convert(&operand_ty)
+                                    .is_some_and(|range| range.min != 0) =>
+                            {
+                                expr
+                            }
+                            // `wrapping_div` panics on zero, the IR gives all ones.
+                            (Binop::Div, _) => Expr::conditional(
+                                Expr::binary(r_expr, Binop::Eq, literal(0, &operand_ty, false)),
+                                literal(-1, &operand_ty, false),
+                                expr,
+                            ),
+                            // So does `wrapping_rem`, the IR gives the left side.
+                            (Binop::Rem, _) => Expr::conditional(
+                                Expr::binary(r_expr, Binop::Eq, literal(0, &operand_ty, false)),
+                                self.value(l, &operand_ty),
+                                expr,
+                            ),
+                            _ => expr,
+                        }
+                    }
+                    CType::Float(_)
+                        if matches!(
+                            binop,
+                            Binop::Rem | Binop::Shl | Binop::Shr | Binop::Sar | Binop::Xor
+                        ) =>
+                    {
+                        panic!("no {binop} for {operand_ty}")
+                    }
+                    CType::Float(_) => Expr::binary(self.value(l, &operand_ty), *binop, r_expr),
+                    _ => {
+                        panic!("no {binop} for {operand_ty}, pointer arithmetic is `Place::Offset`")
+                    }
+                };
+                convert(expr, &operand_ty, ty)
+            }
+        }
+    }
+
+    /// `value` as a `bool`.
+    fn condition(&self, value: &Value) -> Expr {
+        match value {
+            Value::Binop(l, binop @ (Binop::And | Binop::Or), r) => {
+                Expr::binary(self.condition(l), *binop, self.condition(r))
+            }
+            // Comparing a `bool` or a pointer to zero is testing it.
+            Value::Binop(l, binop @ (Binop::Eq | Binop::Ne), r)
+                if **r == Value::Literal(0)
+                    && matches!(self.ty(l), CType::Bool | CType::Ptr(_)) =>
+            {
+                match (self.ty(l), binop) {
+                    (ty @ CType::Ptr(_), Binop::Eq) => {
+                        Expr::method(self.value(l, &ty), "is_null", vec![])
+                    }
+                    (_, Binop::Eq) => Expr::prefix("!", self.condition(l)),
+                    _ => self.condition(l),
+                }
+            }
+            Value::Binop(l, binop, r) if is_condition(*binop) => {
+                let operand_ty = self.operand_ty(l, r);
+                Expr::binary(
+                    self.value(l, &operand_ty),
+                    *binop,
+                    self.value(r, &operand_ty),
+                )
+            }
+            _ => self.value(value, &CType::Bool),
+        }
+    }
+
+    fn place(&self, place: &Place) -> Expr {
+        match place {
+            Place::Local(idx) => Expr::Atom(self.names[idx.to_usize()].clone()),
+            Place::Deref(inner) => match self.lifted(inner) {
+                Some((idx, Reference { len: Some(_), .. }, offset)) => Expr::Index(
+                    Box::new(Expr::Atom(self.names[idx.to_usize()].clone())),
+                    Box::new(literal_expr(offset as i64, Syntax::Rust)),
+                ),
+                Some((idx, Reference { len: None, .. }, _)) => {
+                    Expr::prefix("*", Expr::Atom(self.names[idx.to_usize()].clone()))
+                }
+                None => Expr::prefix("*", self.place(inner)),
+            },
+            Place::Offset(base, offset) => {
+                let offset = match **offset {
+                    Value::Literal(i) => literal_expr(i as i64, Syntax::Rust),
+                    _ => Expr::cast(self.value(offset, &self.ty(offset)), "isize"),
+                };
+                Expr::method(self.place(base), "offset", vec![offset])
+            }
+        }
+    }
+
+    // The parameter, reference and offset if `place` is a lifted parameter, possibly
+    // offset by a literal.
+    fn lifted(&self, place: &Place) -> Option<(Idx<Local>, Reference, i32)> {
+        let (idx, offset) = match place {
+            Place::Local(idx) => (*idx, 0),
+            Place::Offset(base, offset) => match (&**base, &**offset) {
+                (Place::Local(idx), Value::Literal(offset)) => (*idx, *offset),
+                _ => return None,
+            },
+            Place::Deref(_) => return None,
+        };
+        let reference = self.lifting?.params.get(&idx)?;
+        Some((idx, *reference, offset))
+    }
+}
+
+// The `unsafe extern "C" fn` that turns the lifted parameters into references and
+// calls `safe`. With a `fallback`, it calls the raw function instead if the check
+// finds that the references would overlap.
+fn shim(
+    cfg: &Cfg,
+    lifting: &Lifting,
+    name: &str,
+    safe: &str,
+    fallback: Option<(Expr, String)>,
+) -> String {
+    let names = local_names(cfg);
+    let params: Vec<_> = (1..=cfg.arg_count)
+        .map(|i| {
+            let ty = rust_type(&cfg.locals[Idx::from_usize(i)].ty);
+            format!("{}: {ty}", names[i])
+        })
+        .collect();
+    let raw_args: Vec<_> = (1..=cfg.arg_count).map(|i| names[i].clone()).collect();
+    let args: Vec<_> = (1..=cfg.arg_count)
+        .map(|i| {
+            let idx = Idx::from_usize(i);
+            let Some(reference) = lifting.params.get(&idx) else {
+                return names[i].clone();
+            };
+            let borrow = if reference.mutable { "&mut " } else { "&" };
+            match (reference.len, &cfg.locals[idx].ty) {
+                (Some(len), CType::Ptr(ty)) => {
+                    format!("{borrow}*{}.cast::<[{}; {len}]>()", names[i], rust_type(ty))
+                }
+                _ => format!("{borrow}*{}", names[i]),
+            }
+        })
+        .collect();
+
+    let mut out = format!(
+        "#[unsafe(no_mangle)]\npub unsafe extern \"C\" fn {name}({}){} {{\n    unsafe {{\n",
+        params.join(", "),
+        return_type(cfg)
+    );
+    if let Some((check, raw)) = fallback {
+        out += &format!("        if {} {{\n", check.print(Syntax::Rust));
+        out += &format!("            return {raw}({});\n", raw_args.join(", "));
+        out += "        }\n";
+    }
+    out += &format!("        {safe}({})\n    }}\n}}\n", args.join(", "));
+    out
+}
+
+// A check for whether a `&mut` reference would overlap another reference, `None` if
+// that can't happen.
+fn overlap_check(cfg: &Cfg, lifting: &Lifting) -> Option<Expr> {
+    let names = local_names(cfg);
+    let bounds = |idx: Idx<Local>, reference: &Reference| {
+        let name = || Expr::Atom(names[idx.to_usize()].clone());
+        let end = Expr::method(
+            name(),
+            "wrapping_add",
+            vec![Expr::Atom(reference.elements().to_string())],
+        );
+        (Expr::cast(name(), "usize"), Expr::cast(end, "usize"))
+    };
+    let mut check: Option<Expr> = None;
+    for (a, reference_a) in &lifting.params {
+        for (b, reference_b) in lifting.params.range(*a..).skip(1) {
+            if !reference_a.mutable && !reference_b.mutable {
+                continue;
+            }
+            let (start_a, end_a) = bounds(*a, reference_a);
+            let (start_b, end_b) = bounds(*b, reference_b);
+            let overlap = Expr::binary(
+                Expr::binary(start_a, Binop::Lt, end_b),
+                Binop::And,
+                Expr::binary(start_b, Binop::Lt, end_a),
+            );
+            check = Some(match check {
+                Some(check) => Expr::binary(check, Binop::Or, overlap),
+                None => overlap,
+            });
+        }
+    }
+    check
+}
+
+fn return_value(cfg: &Cfg) -> CType {
+    cfg.locals
+        .iter()
+        .next()
+        .map_or(CType::Void, |(_, local)| local.ty.clone())
+}
+
+fn return_type(cfg: &Cfg) -> String {
+    match return_value(cfg) {
+        CType::Void => String::new(),
+        ty => format!(" -> {}", rust_type(&ty)),
+    }
+}
+
+fn is_condition(binop: Binop) -> bool {
+    matches!(
+        binop,
+        Binop::Lt | Binop::Le | Binop::Eq | Binop::Ne | Binop::And | Binop::Or
+    )
+}
+
+// Converts like a C cast or an assignment would.
+fn convert(expr: Expr, from: &CType, to: &CType) -> Expr {
+    if from == to {
+        return expr;
+    }
+    match (from, to) {
+        (CType::Float(_), CType::Bool) => Expr::binary(expr, Binop::Ne, Expr::Atom("0.0".into())),
+        (CType::Ptr(_), CType::Bool) => Expr::prefix("!", Expr::method(expr, "is_null", vec![])),
+        (_, CType::Bool) => Expr::binary(expr, Binop::Ne, Expr::Atom("0".into())),
+        // Rust only casts between these through an integer.
+        (CType::Bool, CType::Float(_)) => Expr::cast(Expr::cast(expr, "i32"), rust_type(to)),
+        (CType::Bool | CType::Float(_), CType::Ptr(_)) | (CType::Ptr(_), CType::Float(_)) => {
+            Expr::cast(Expr::cast(expr, "usize"), rust_type(to))
+        }
+        _ => Expr::cast(expr, rust_type(to)),
+    }
+}
+
+// `i` as a literal of type `ty`, with a type suffix if `suffixed`.
+fn literal(i: i32, ty: &CType, suffixed: bool) -> Expr {
+    let suffix = if suffixed {
+        rust_type(ty)
+    } else {
+        String::new()
+    };
+    let signed = |magnitude: String, negative: bool| {
+        let atom = Expr::Atom(format!("{magnitude}{suffix}"));
+        if negative {
+            Expr::prefix("-", atom)
+        } else {
+            atom
+        }
+    };
+    match ty {
+        CType::Int(bytes) => {
+            let i = match bytes {
+                1 => i as i8 as i64,
+                2 => i as i16 as i64,
+                _ => i as i64,
+            };
+            signed(i.unsigned_abs().to_string(), i < 0)
+        }
+        CType::UInt(bytes) => {
+            let i = i as i128 as u128;
+            let i = if *bytes < 16 {
+                i & ((1 << (bytes * 8)) - 1)
+            } else {
+                i
+            };
+            signed(i.to_string(), false)
+        }
+        CType::Float(_) => signed(format!("{}.0", i.unsigned_abs()), i < 0),
+        CType::Bool => Expr::Atom((i != 0).to_string()),
+        CType::Ptr(_) if i == 0 => Expr::Atom("core::ptr::null_mut()".into()),
+        CType::Ptr(_) => Expr::cast(
+            Expr::Atom(format!("{}usize", i as isize as usize)),
+            rust_type(ty),
+        ),
+        CType::Void => panic!("void has no values"),
+    }
+}
+
+fn scope_label(kind: ScopeKind<'_>, id: usize) -> String {
+    match kind {
+        ScopeKind::Block => format!("'block_{id}"),
+        ScopeKind::Loop { .. } | ScopeKind::Dispatch { .. } => format!("'loop_{id}"),
+    }
+}
+
+// The name of every local: its own name if it is a usable and unique Rust
+// identifier, `_N` otherwise.
+fn local_names(cfg: &Cfg) -> Vec<String> {
+    let is_identifier = |name: &str| {
+        let mut chars = name.chars();
+        chars
+            .next()
+            .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+            && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+            && name != "_"
+    };
+    let is_generated = |name: &str| {
+        name.strip_prefix('_')
+            .is_some_and(|rest| !rest.is_empty() && rest.chars().all(|c| c.is_ascii_digit()))
+    };
+    let mut taken = BTreeSet::new();
+    cfg.locals
+        .iter()
+        .map(|(idx, local)| match &local.name {
+            Some(name)
+                if is_identifier(name)
+                    && !is_generated(name)
+                    && !RESERVED.contains(&name.as_str())
+                    && taken.insert(name.clone()) =>
+            {
+                name.clone()
+            }
+            _ => format!("_{}", idx.to_usize()),
+        })
+        .collect()
+}
+
+fn rust_type(ty: &CType) -> String {
+    match ty {
+        CType::Void => "core::ffi::c_void".to_string(),
+        CType::Float(4) => "f32".to_string(),
+        CType::Float(8) => "f64".to_string(),
+        CType::Int(bytes @ (1 | 2 | 4 | 8 | 16)) => format!("i{}", bytes * 8),
+        CType::UInt(bytes @ (1 | 2 | 4 | 8 | 16)) => format!("u{}", bytes * 8),
+        CType::Bool => "bool".to_string(),
+        CType::Ptr(ty) => format!("*mut {}", rust_type(ty)),
+        CType::Float(_) | CType::Int(_) | CType::UInt(_) => {
+            panic!("{ty} has no Rust equivalent")
+        }
+    }
+}
+
+fn reference_type(reference: &Reference, ty: &CType) -> String {
+    let CType::Ptr(ty) = ty else {
+        panic!("only pointers are lifted");
+    };
+    let borrow = if reference.mutable { "&mut " } else { "&" };
+    match reference.len {
+        Some(len) => format!("{borrow}[{}; {len}]", rust_type(ty)),
+        None => format!("{borrow}{}", rust_type(ty)),
+    }
+}
+
+fn zero(ty: &CType) -> &'static str {
+    match ty {
+        CType::Float(_) => "0.0",
+        CType::Bool => "false",
+        CType::Ptr(_) => "core::ptr::null_mut()",
+        _ => "0",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use my_cfg::test_util::*;
+    use my_cfg::{IrreducibleStrategy, LoopifyConfig, Structurer};
+
+    use super::*;
+    use crate::test_util::{check, run};
+
+    // Both branches of bb0 run into bb3 and bb4, with bb3 also falling into bb4.
+    // Nothing jumps to bb1.
+    //
+    // bb0: _0 = _1; if _1 < _2 { goto bb3 } else { goto bb2 }
+    // bb1: return
+    // bb2: _0 = _0 + 1; if _2 < 3 { goto bb4 } else { goto bb3 }
+    // bb3: _0 = _0 * 2; goto bb4
+    // bb4: return
+    fn shared_successors() -> Cfg {
+        let mut cfg = with_locals(vec![CType::Int(4); 3]);
+        cfg.arg_count = 2;
+        cfg.bb.alloc(block(
+            vec![assign(0, local(1))],
+            branch(binop(local(1), Binop::Lt, local(2)), 3, 2),
+        ));
+        cfg.bb.alloc(block(vec![], Terminator::Return));
+        cfg.bb.alloc(block(
+            vec![assign(0, binop(local(0), Binop::Add, Value::Literal(1)))],
+            branch(binop(local(2), Binop::Lt, Value::Literal(3)), 4, 3),
+        ));
+        cfg.bb.alloc(block(
+            vec![assign(0, binop(local(0), Binop::Mul, Value::Literal(2)))],
+            goto(4),
+        ));
+        cfg.bb.alloc(block(vec![], Terminator::Return));
+        cfg
+    }
+
+    // A cycle between bb1 and bb2, entered through both of them.
+    //
+    // bb0: if _1 < _2 { goto bb1 } else { goto bb2 }
+    // bb1: _0 = _0 + 1; if _0 < _2 { goto bb2 } else { goto bb3 }
+    // bb2: _0 = _0 * 2; if _0 < _1 { goto bb1 } else { goto bb3 }
+    // bb3: return
+    fn irreducible() -> Cfg {
+        let mut cfg = with_locals(vec![CType::Int(4); 3]);
+        cfg.arg_count = 2;
+        cfg.bb.alloc(block(
+            vec![],
+            branch(binop(local(1), Binop::Lt, local(2)), 1, 2),
+        ));
+        cfg.bb.alloc(block(
+            vec![assign(0, binop(local(0), Binop::Add, Value::Literal(1)))],
+            branch(binop(local(0), Binop::Lt, local(2)), 2, 3),
+        ));
+        cfg.bb.alloc(block(
+            vec![assign(0, binop(local(0), Binop::Mul, Value::Literal(2)))],
+            branch(binop(local(0), Binop::Lt, local(1)), 1, 3),
+        ));
+        cfg.bb.alloc(block(vec![], Terminator::Return));
+        cfg
+    }
+
+    // Nested loops that add up `_1` with a multiplication that wraps, until it turns
+    // negative. `_2` is unsigned, so the outer loop compares it as such. Going on with
+    // the outer loop from the inner one, past bb7, takes a labeled `continue` or a
+    // labeled block, and leaving both takes a labeled `break`.
+    //
+    // bb0: _0 = 1; goto bb1
+    // bb1: if _3 < _2 { goto bb2 } else { goto bb5 }
+    // bb2: _3 = _3 + 1; _4 = 0; goto bb3
+    // bb3: if _4 < 3 { goto bb4 } else { goto bb7 }
+    // bb4: _0 = _0 * 65537 + _1; _4 = _4 + 1; if _0 < 0 { goto bb5 } else { goto bb6 }
+    // bb5: return
+    // bb6: if _4 < _3 { goto bb3 } else { goto bb1 }
+    // bb7: _0 = _0 + 2; goto bb1
+    fn wrapping() -> Cfg {
+        let mut cfg = with_locals([
+            CType::Int(4),
+            CType::Int(4),
+            CType::UInt(4),
+            CType::UInt(4),
+            CType::Int(4),
+        ]);
+        cfg.arg_count = 2;
+        cfg.bb
+            .alloc(block(vec![assign(0, Value::Literal(1))], goto(1)));
+        cfg.bb.alloc(block(
+            vec![],
+            branch(binop(local(3), Binop::Lt, local(2)), 2, 5),
+        ));
+        cfg.bb.alloc(block(
+            vec![
+                assign(3, binop(local(3), Binop::Add, Value::Literal(1))),
+                assign(4, Value::Literal(0)),
+            ],
+            goto(3),
+        ));
+        cfg.bb.alloc(block(
+            vec![],
+            branch(binop(local(4), Binop::Lt, Value::Literal(3)), 4, 7),
+        ));
+        let product = binop(local(0), Binop::Mul, Value::Literal(65537));
+        cfg.bb.alloc(block(
+            vec![
+                assign(0, binop(product, Binop::Add, local(1))),
+                assign(4, binop(local(4), Binop::Add, Value::Literal(1))),
+            ],
+            branch(binop(local(0), Binop::Lt, Value::Literal(0)), 5, 6),
+        ));
+        cfg.bb.alloc(block(vec![], Terminator::Return));
+        cfg.bb.alloc(block(
+            vec![],
+            branch(binop(local(4), Binop::Lt, local(3)), 3, 1),
+        ));
+        cfg.bb.alloc(block(
+            vec![assign(0, binop(local(0), Binop::Add, Value::Literal(2)))],
+            goto(1),
+        ));
+        cfg
+    }
+
+    // A `main` that asserts `name` returns what the interpreter makes of `cfg` for
+    // each of `args`.
+    fn asserting_main(cfg: &Cfg, name: &str, args: &[[i128; 2]]) -> String {
+        let mut main = String::from("\nfn main() {\n");
+        for args in args {
+            let expected = my_cfg::interpreter::run(cfg, args, 1000).unwrap();
+            let args: Vec<_> = (1..=cfg.arg_count)
+                .map(|i| {
+                    format!(
+                        "{}i128 as {}",
+                        args[i - 1],
+                        cfg.locals[Idx::from_usize(i)].ty
+                    )
+                })
+                .collect();
+            main += &format!(
+                "    assert_eq!(unsafe {{ {name}({}) }} as i128, {expected});\n",
+                args.join(", ")
+            );
+        }
+        main + "}\n"
+    }
+
+    #[test]
+    fn test_runs_for_every_config() {
+        let strategies = [
+            IrreducibleStrategy::Dispatch,
+            IrreducibleStrategy::NodeSplitting { budget: 100 },
+        ];
+        let cfgs = [
+            ("shared", shared_successors()),
+            ("irreducible", irreducible()),
+            ("wrapping", wrapping()),
+        ];
+        let args = [[1, 3], [-7, 2], [3, 0], [5, -1], [100000, -1]];
+        let mut continued = false;
+        for (name, cfg) in cfgs {
+            let main = asserting_main(&cfg, name, &args);
+            for structurer in [Structurer::Relooper, Structurer::Dominators] {
+                for irreducible in strategies {
+                    let config = LoopifyConfig {
+                        structurer,
+                        irreducible,
+                    };
+                    let source = emit_rust(&cfg, &cfg.loopify_with(&config), name);
+                    if name == "wrapping" {
+                        assert!(source.contains("break '"), "{config:?}\n{source}");
+                        continued |= source.contains("continue '");
+                    }
+                    if let Err(errors) = run(&(source.clone() + &main), name) {
+                        panic!("{config:?}\n{source}{main}\n{errors}");
+                    }
+                }
+            }
+        }
+        // The relooper leaves the inner loop through a labeled block instead.
+        assert!(continued);
+    }
+
+    #[test]
+    fn test_compiles_for_every_config() {
+        let strategies = [
+            IrreducibleStrategy::Dispatch,
+            IrreducibleStrategy::NodeSplitting { budget: 100 },
+        ];
+        let cfgs = [
+            ("shared", shared_successors()),
+            ("irreducible", irreducible()),
+        ];
+        for (name, cfg) in cfgs {
+            for structurer in [Structurer::Relooper, Structurer::Dominators] {
+                for irreducible in strategies {
+                    let config = LoopifyConfig {
+                        structurer,
+                        irreducible,
+                    };
+                    let source = emit_rust(&cfg, &cfg.loopify_with(&config), name);
+                    if let Err(errors) = check(&source, name) {
+                        panic!("{config:?}\n{source}\n{errors}");
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,55 @@
+// Runs rustc on what the tests emit.
+
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+/// Type checks `source` as a library crate, returns rustc's errors if it fails.
+pub fn check(source: &str, name: &str) -> Result<(), String> {
+    let out = output(&format!("lib{name}.rmeta"));
+    let result = rustc(source, &["--crate-type=lib", "--emit=metadata"], &out);
+    let _ = std::fs::remove_file(&out);
+    result
+}
+
+/// Compiles `source` as a program and runs it, returns rustc's errors or what the
+/// program printed if either fails.
+pub fn run(source: &str, name: &str) -> Result<(), String> {
+    let binary = output(name);
+    rustc(source, &[], &binary)?;
+    let output = Command::new(&binary).output().unwrap();
+    let _ = std::fs::remove_file(&binary);
+    match output.status.success() {
+        true => Ok(()),
+        false => Err(String::from_utf8_lossy(&output.stderr).into_owned()),
+    }
+}
+
+// A file in the temporary directory that no other test process uses.
+fn output(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("{}_{name}", std::process::id()))
+}
+
+fn rustc(source: &str, args: &[&str], out: &PathBuf) -> Result<(), String> {
+    let mut child = Command::new("rustc")
+        .arg("--edition=2024")
+        .args(args)
+        .arg("-o")
+        .arg(out)
+        .arg("-")
+        .stdin(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("rustc runs");
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(source.as_bytes())
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    match output.status.success() {
+        true => Ok(()),
+        false => Err(String::from_utf8_lossy(&output.stderr).into_owned()),
+    }
+}
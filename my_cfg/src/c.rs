@@ -0,0 +1,1944 @@
+// C backend. Walks a `StructuredNode` together with the `Cfg` it was made from and
+// prints a C translation unit with a single function.
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::eval::wrap;
+use crate::expr::{Expr, Operator, Syntax, literal_expr, place_expr_with, value_expr};
+use crate::jumps::{Jump, Next, Scope, ScopeKind, next_of, resolve};
+use crate::liveness::{Liveness, place_reads, terminator_reads, value_reads};
+use crate::loopified::{Relooper, StructuredNode};
+use crate::ranges::{Range, Ranges, State, binop_range, operand_ty, value_ty};
+use crate::*;
+
+/// How the C backend prints integer arithmetic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CArithmetic {
+    /// With C's own operators. Where the IR wraps around, C has undefined behavior:
+    /// signed overflow, dividing by zero and shifting by too much. So does
+    /// converting a float that is out of the range of an integer type.
+    Plain,
+    /// Exactly like the IR: wrapping arithmetic is done in unsigned types and cast
+    /// back, shift amounts are masked, and right shifts say whether they are
+    /// arithmetic or logical. Floats are converted to integers by helper functions
+    /// that saturate. Casts are left out where the types alone show there is
+    /// nothing to wrap.
+    Exact,
+    /// Like `Exact`, but also leaves out the casts where value range analysis shows
+    /// there is nothing to wrap.
+    #[default]
+    Readable,
+}
+
+/// Options for `emit_c_with`.
+#[derive(Debug, Clone, Default)]
+pub struct CConfig {
+    pub arithmetic: CArithmetic,
+}
+
+/// Emits a C file defining `cfg` as a function called `name`, with control flow
+/// shaped like `node`.
+pub fn emit_c(cfg: &Cfg, node: &StructuredNode, name: &str) -> String {
+    emit_c_with(cfg, node, name, &CConfig::default())
+}
+
+/// Like `emit_c`, with options.
+pub fn emit_c_with(cfg: &Cfg, node: &StructuredNode, name: &str, config: &CConfig) -> String {
+    emit_c_mapped(cfg, node, name, config).0
+}
+
+/// Like `emit_c_with`, and also returns where each line comes from.
+pub fn emit_c_mapped(
+    cfg: &Cfg,
+    node: &StructuredNode,
+    name: &str,
+    config: &CConfig,
+) -> (String, SourceMap) {
+    let mut emitter = CEmitter::new(cfg, node, config);
+    // Which blocks become `do { } while (0)` depends on every jump that crosses
+    // them, and gotos can go backwards, so the body is walked three times: to see
+    // the jumps, to see which labels are used, and to print.
+    emitter.emit_body(node);
+    emitter.loop_blocks = &emitter.break_targets - &emitter.crossed_blocks;
+    emitter.emit_body(node);
+    emitter.labels = std::mem::take(&mut emitter.used_labels);
+    emitter.declarations = emitter.place_declarations();
+    emitter.emit_body(node);
+    emitter.finish(name)
+}
+
+/// Emits a `main` that calls the function `name` that `emit_c` made from `cfg` on the
+/// inputs of `test`, to be appended to it. It exits with 1 if the function returns
+/// something else or leaves other bytes in memory than `test` expects, and with 0
+/// otherwise. Each chunk of memory is an array of its own, so pointers stored in
+/// memory don't point into them.
+pub fn emit_c_harness(cfg: &Cfg, name: &str, test: &TestCase) -> String {
+    let mut out = String::from("\n#include <string.h>\n\nint main(void)\n{\n");
+    // The arrays start at the alignment the target has at most, with padding for
+    // the chunks that don't, so that their addresses are aligned like in `test`.
+    let align = u64::from(cfg.layout.max_align);
+    for (i, chunk) in test.memory.iter().enumerate() {
+        let padding = vec![0; (chunk.address % align) as usize];
+        out += &format!(
+            "    static uint8_t memory{i}[] __attribute__((aligned({align}))) = {{{}}};\n",
+            bytes(&[padding, chunk.bytes.clone()].concat())
+        );
+    }
+    let value = |value: &TestValue, ty: &CType| test_value(value, ty, &test.memory, align);
+    let args: Vec<_> = test
+        .args
+        .iter()
+        .zip(cfg.locals.iter().skip(1))
+        .map(|(arg, (_, local))| value(arg, &local.ty))
+        .collect();
+    let call = format!("{name}({})", args.join(", "));
+    let (_, returned) = cfg.locals.iter().next().unwrap();
+    match &test.expected {
+        // Floats are compared by their bits, any NaN matches any other.
+        Some(expected @ TestValue::Float(_)) => {
+            let ty = c_type(&returned.ty);
+            let expected = value(expected, &returned.ty);
+            out += &format!("    {ty} returned = {call}, expected = {expected};\n");
+            out += "    if (memcmp(&returned, &expected, sizeof returned) != 0\n";
+            out += "        && !(returned != returned && expected != expected)) {\n";
+            out += "        return 1;\n    }\n";
+        }
+        Some(expected) if returned.ty != CType::Void => {
+            out += &format!("    if ({call} != {}) {{\n", value(expected, &returned.ty));
+            out += "        return 1;\n    }\n";
+        }
+        _ => out += &format!("    {call};\n"),
+    }
+    for expected in &test.expected_memory {
+        let Some(at) = pointer_into(&test.memory, expected.region, expected.address, align) else {
+            continue;
+        };
+        out += &format!(
+            "    if (memcmp({at}, (uint8_t[]){{{}}}, {}) != 0) {{\n",
+            bytes(&expected.bytes),
+            expected.bytes.len()
+        );
+        out += "        return 1;\n    }\n";
+    }
+    out += "    return 0;\n}\n";
+    out
+}
+
+fn bytes(bytes: &[u8]) -> String {
+    let bytes: Vec<_> = bytes.iter().map(|byte| byte.to_string()).collect();
+    bytes.join(", ")
+}
+
+// `value` as a C expression of type `ty`. Pointers point into the array of the chunk
+// of `memory` that they point into, if there is one.
+fn test_value(value: &TestValue, ty: &CType, memory: &[MemoryChunk], align: u64) -> String {
+    let c_ty = c_type(ty);
+    match *value {
+        TestValue::Int(i) if i32::try_from(i).is_ok() => {
+            format!(
+                "({c_ty}){}",
+                literal_expr(i as i64, Syntax::C).print(Syntax::C)
+            )
+        }
+        TestValue::Int(i) if i64::try_from(i).is_ok() => format!("({c_ty})INT64_C({i})"),
+        TestValue::Int(i) => format!("({c_ty}){}", uint_literal(i as u128)),
+        // Type punning through a union is fine in C.
+        TestValue::Float(bits) => {
+            let CType::Float(bytes) = ty else {
+                panic!("float test value for {ty}");
+            };
+            let int_ty = c_type(&CType::UInt(*bytes));
+            let bits = uint_literal(bits);
+            format!("((union {{ {int_ty} i; {c_ty} f; }}){{ .i = {bits} }}).f")
+        }
+        TestValue::Pointer { region, address } => {
+            match pointer_into(memory, region, address, align) {
+                Some(at) => format!("({c_ty})({at})"),
+                None => format!("({c_ty})(uintptr_t)UINT64_C({address})"),
+            }
+        }
+    }
+}
+
+fn uint_literal(i: u128) -> String {
+    match u64::try_from(i) {
+        Ok(i) => format!("UINT64_C({i})"),
+        Err(_) => format!(
+            "((unsigned __int128)UINT64_C({}) << 64 | UINT64_C({}))",
+            (i >> 64) as u64,
+            i as u64
+        ),
+    }
+}
+
+// The C pointer to `address` in `region`, if it is in one of the arrays of `memory`.
+fn pointer_into(memory: &[MemoryChunk], region: u64, address: u64, align: u64) -> Option<String> {
+    let i = memory
+        .iter()
+        .position(|chunk| chunk.contains(region, address))?;
+    let chunk = &memory[i];
+    Some(format!(
+        "memory{i} + {}",
+        chunk.address % align + (address - chunk.address)
+    ))
+}
+
+const LABEL_VARIABLE: &str = "label";
+
+// The longest `c ? a : b` that a diamond is printed as.
+const MAX_CONDITIONAL_WIDTH: usize = 60;
+
+static EMPTY: StructuredNode = StructuredNode::Sequence(Vec::new());
+
+const RESERVED: &[&str] = &[
+    LABEL_VARIABLE,
+    "auto",
+    "break",
+    "case",
+    "char",
+    "const",
+    "continue",
+    "default",
+    "do",
+    "double",
+    "else",
+    "enum",
+    "extern",
+    "float",
+    "for",
+    "goto",
+    "if",
+    "inline",
+    "int",
+    "long",
+    "register",
+    "restrict",
+    "return",
+    "short",
+    "signed",
+    "sizeof",
+    "static",
+    "struct",
+    "switch",
+    "typedef",
+    "union",
+    "unsigned",
+    "void",
+    "volatile",
+    "while",
+    "bool",
+    "true",
+    "false",
+];
+
+struct CEmitter<'a> {
+    cfg: &'a Cfg,
+    arithmetic: CArithmetic,
+    // Computed in `CArithmetic::Readable` mode only.
+    ranges: Option<Ranges>,
+    names: Vec<String>,
+    // `init` and `step` statements of `For` loops, printed by the loop itself.
+    loop_stmts: BTreeSet<(Idx<BasicBlock>, usize)>,
+    // Latches of `DoWhile` loops, whose branch is the loop condition.
+    latches: BTreeSet<Idx<BasicBlock>>,
+    // The number of edges into each block.
+    preds: BTreeMap<Idx<BasicBlock>, usize>,
+    out: String,
+    // Lines in `out`, and the source map of them.
+    lines: usize,
+    source_map: SourceMap,
+    indent: usize,
+    scopes: Vec<Scope<'a>>,
+    // Parallel to `scopes`: the number of each scope in walk order, and whether it
+    // is printed as a C loop, i.e. `break` and `continue` apply to it.
+    scope_ids: Vec<usize>,
+    scope_is_loop: Vec<bool>,
+    scope_counter: usize,
+    // Blocks that something breaks out of, and blocks that a `break` or `continue`
+    // to an enclosing scope passes through. Collected by the first walk.
+    break_targets: BTreeSet<usize>,
+    crossed_blocks: BTreeSet<usize>,
+    // The `StructuredNode::Block`s that are printed as `do { } while (0)`.
+    loop_blocks: BTreeSet<usize>,
+    used_labels: BTreeSet<String>,
+    // Labels used by the previous walk, which are the ones that get printed.
+    labels: BTreeSet<String>,
+    uses_label_variable: bool,
+    // The helpers that convert floats to integers, by name, with their definitions.
+    conversions: RefCell<BTreeMap<String, String>>,
+    // The C blocks of the walk so far, the first one is the function body, and the
+    // ones around the current line, innermost last.
+    c_blocks: Vec<CBlock>,
+    open_blocks: Vec<usize>,
+    // Every position that the walk went through, in order.
+    visits: Vec<Visit>,
+    // Where locals are declared, from the visits of the previous walk.
+    declarations: Declarations,
+}
+
+// A pair of braces that locals can be declared in.
+struct CBlock {
+    parent: Option<usize>,
+    // Where control enters the block, besides jumps into it from outside: the
+    // header of a loop is entered again by every iteration.
+    entries: Vec<(Idx<BasicBlock>, usize)>,
+    // The scope of a `for` statement, which can only declare a local in `init`.
+    only_merged: bool,
+}
+
+// A statement or terminator that the walk went through, and the C block it was
+// printed in. It is `mergeable` if it is printed as an assignment that a
+// declaration can be merged into.
+struct Visit {
+    at: (Idx<BasicBlock>, usize),
+    c_block: usize,
+    mergeable: bool,
+}
+
+#[derive(Default)]
+struct Declarations {
+    // The locals declared at the start of each C block.
+    at_start: BTreeMap<usize, Vec<Idx<Local>>>,
+    // Visits whose assignment declares the local it assigns.
+    merged: BTreeSet<usize>,
+}
+
+impl<'a> CEmitter<'a> {
+    fn new(cfg: &'a Cfg, node: &StructuredNode, config: &CConfig) -> Self {
+        let mut loop_stmts = BTreeSet::new();
+        collect_loop_stmts(node, &mut loop_stmts);
+        let mut preds = BTreeMap::new();
+        for (_, block) in cfg.bb.iter() {
+            for succ in Relooper::get_successors_from_block(block) {
+                *preds.entry(succ).or_insert(0) += 1;
+            }
+        }
+        Self {
+            cfg,
+            arithmetic: config.arithmetic,
+            ranges: (config.arithmetic == CArithmetic::Readable).then(|| Ranges::new(cfg)),
+            names: local_names(cfg),
+            loop_stmts,
+            latches: BTreeSet::new(),
+            preds,
+            out: String::new(),
+            lines: 0,
+            source_map: SourceMap::default(),
+            indent: 1,
+            scopes: vec![],
+            scope_ids: vec![],
+            scope_is_loop: vec![],
+            scope_counter: 0,
+            break_targets: BTreeSet::new(),
+            crossed_blocks: BTreeSet::new(),
+            loop_blocks: BTreeSet::new(),
+            used_labels: BTreeSet::new(),
+            labels: BTreeSet::new(),
+            uses_label_variable: false,
+            conversions: RefCell::default(),
+            c_blocks: vec![],
+            open_blocks: vec![],
+            visits: vec![],
+            declarations: Declarations::default(),
+        }
+    }
+
+    fn emit_body(&mut self, node: &'a StructuredNode) {
+        self.out.clear();
+        self.lines = 0;
+        self.source_map = SourceMap::default();
+        self.scope_counter = 0;
+        self.c_blocks.clear();
+        self.open_blocks.clear();
+        self.visits.clear();
+        self.open_block(vec![], false);
+        self.emit_node(node, Next::Nothing);
+        self.close_block();
+    }
+
+    fn finish(self, name: &str) -> (String, SourceMap) {
+        let mut out = String::from("#include <stdint.h>\n");
+        let uses_bool = self
+            .cfg
+            .locals
+            .iter()
+            .any(|(_, local)| mentions_bool(&local.ty));
+        if uses_bool {
+            out += "#include <stdbool.h>\n";
+        }
+        out += "\n";
+        for definition in self.conversions.borrow().values() {
+            out += definition;
+            out += "\n";
+        }
+
+        let return_type = self.return_type();
+        let params: Vec<_> = (1..=self.cfg.arg_count)
+            .map(|i| self.declaration(Idx::from_usize(i)))
+            .collect();
+        let params = if params.is_empty() {
+            "void".to_string()
+        } else {
+            params.join(", ")
+        };
+        out += &format!("{} {name}({params})\n{{\n", c_type(&return_type));
+
+        for idx in self.declarations.at_start.get(&0).into_iter().flatten() {
+            out += &format!("    {};\n", self.declaration(*idx));
+        }
+        if self.uses_label_variable {
+            out += &format!("    int {LABEL_VARIABLE} = 0;\n");
+        }
+        let mut source_map = SourceMap::default();
+        source_map.append(self.source_map, out.lines().count());
+        out += &self.out;
+        out += "}\n";
+        (out, source_map)
+    }
+
+    // Declares every local in the innermost C block around all of its uses that it
+    // doesn't carry a value into. If the first of them is an assignment of the
+    // local in that block itself, the declaration is merged into it. Locals that
+    // aren't used aren't declared.
+    fn place_declarations(&self) -> Declarations {
+        let cfg = self.cfg;
+        let liveness = Liveness::new(cfg);
+        let inside = |mut inner: usize, outer: usize| loop {
+            if inner == outer {
+                return true;
+            }
+            match self.c_blocks[inner].parent {
+                Some(parent) => inner = parent,
+                None => return false,
+            }
+        };
+
+        // The C blocks that each block starts in and that each terminator is in.
+        let mut starts: BTreeMap<_, Vec<_>> = BTreeMap::new();
+        let mut ends: BTreeMap<_, Vec<_>> = BTreeMap::new();
+        let mut uses: BTreeMap<_, Vec<_>> = BTreeMap::new();
+        for (i, visit) in self.visits.iter().enumerate() {
+            let (bb, index) = visit.at;
+            let mut locals = BTreeSet::new();
+            match cfg.bb[bb].stmts.get(index) {
+                Some(Stmt::Assign { place, value, .. }) => {
+                    place_reads(place, true, &mut locals);
+                    value_reads(value, &mut locals);
+                }
+                None => {
+                    terminator_reads(cfg, cfg.bb[bb].terminator(), &mut locals);
+                    ends.entry(bb).or_default().push(visit.c_block);
+                }
+            }
+            if index == 0 {
+                starts.entry(bb).or_default().push(visit.c_block);
+            }
+            for local in locals {
+                uses.entry(local).or_default().push(i);
+            }
+        }
+
+        // Where control enters each C block: its own entries, and the blocks in it
+        // that something outside of it jumps to.
+        let mut preds: BTreeMap<_, Vec<_>> = BTreeMap::new();
+        for (idx, block) in cfg.bb.iter() {
+            for succ in Relooper::get_successors_from_block(block) {
+                preds.entry(succ).or_default().push(idx);
+            }
+        }
+        let entries: Vec<Vec<_>> = (0..self.c_blocks.len())
+            .map(|id| {
+                let mut entries = self.c_blocks[id].entries.clone();
+                for (bb, c_blocks) in &starts {
+                    let from_outside = bb.to_usize() == 0
+                        || preds.get(bb).into_iter().flatten().any(|pred| {
+                            let c_blocks = ends.get(pred).into_iter().flatten();
+                            c_blocks.into_iter().any(|c_block| !inside(*c_block, id))
+                        });
+                    if from_outside && c_blocks.iter().any(|c_block| inside(*c_block, id)) {
+                        entries.push((*bb, 0));
+                    }
+                }
+                entries
+            })
+            .collect();
+
+        let mut declarations = Declarations::default();
+        for (local, visits) in uses {
+            let is_param = (1..=cfg.arg_count).contains(&local.to_usize());
+            if is_param || matches!(cfg.locals[local].ty, CType::Void) {
+                continue;
+            }
+            let mut id = visits
+                .iter()
+                .map(|i| self.visits[*i].c_block)
+                .reduce(|a, b| {
+                    let mut common = a;
+                    while !inside(b, common) {
+                        common = self.c_blocks[common].parent.expect("all are in the body");
+                    }
+                    common
+                })
+                .expect("only used locals are declared");
+            let first = &self.visits[visits[0]];
+            let (bb, index) = first.at;
+            let assigns = match cfg.bb[bb].stmts.get(index) {
+                Some(Stmt::Assign { place, value, .. }) => {
+                    *place == Place::Local(local) && !value.has_local(local)
+                }
+                None => false,
+            };
+            loop {
+                // The function body starts with whatever the caller left in locals,
+                // which is nothing to be carried.
+                let carried = self.c_blocks[id].parent.is_some()
+                    && entries[id]
+                        .iter()
+                        .any(|(bb, index)| liveness.before(cfg, *bb, *index).contains(&local));
+                if !carried {
+                    if first.c_block == id && first.mergeable && assigns {
+                        declarations.merged.insert(visits[0]);
+                        break;
+                    }
+                    if !self.c_blocks[id].only_merged {
+                        declarations.at_start.entry(id).or_default().push(local);
+                        break;
+                    }
+                }
+                id = self.c_blocks[id]
+                    .parent
+                    .expect("nothing is carried into the function body");
+            }
+        }
+        declarations
+    }
+
+    fn return_type(&self) -> CType {
+        self.cfg
+            .locals
+            .iter()
+            .next()
+            .map_or(CType::Void, |(_, local)| local.ty.clone())
+    }
+
+    fn declaration(&self, local: Idx<Local>) -> String {
+        let ty = c_type(&self.cfg.locals[local].ty);
+        let name = &self.names[local.to_usize()];
+        if ty.ends_with('*') {
+            format!("{ty}{name}")
+        } else {
+            format!("{ty} {name}")
+        }
+    }
+
+    fn line(&mut self, line: &str) {
+        for _ in 0..self.indent {
+            self.out += "    ";
+        }
+        self.out += line;
+        self.out += "\n";
+        self.lines += 1;
+    }
+
+    // Records that the next line prints what is at `at`.
+    fn map(&mut self, at: (Idx<BasicBlock>, usize)) {
+        self.source_map.add(self.lines + 1, at.0, at.1, self.cfg);
+        self.visit(at, false);
+    }
+
+    // Like `map`, for an assignment. Whether it declares the local it assigns.
+    fn map_assignment(&mut self, at: (Idx<BasicBlock>, usize)) -> bool {
+        self.source_map.add(self.lines + 1, at.0, at.1, self.cfg);
+        self.visit(at, true)
+    }
+
+    // Records that the walk went through `at`, which may print nothing. Whether a
+    // declaration is merged into it.
+    fn visit(&mut self, at: (Idx<BasicBlock>, usize), mergeable: bool) -> bool {
+        let c_block = *self.open_blocks.last().expect("the function body is open");
+        self.visits.push(Visit {
+            at,
+            c_block,
+            mergeable,
+        });
+        self.declarations.merged.contains(&(self.visits.len() - 1))
+    }
+
+    // Starts a C block inside the current one, after its opening brace. Only the
+    // locals of the function body are declared by `finish`.
+    fn open_block(&mut self, entries: Vec<(Idx<BasicBlock>, usize)>, only_merged: bool) {
+        let id = self.c_blocks.len();
+        self.c_blocks.push(CBlock {
+            parent: self.open_blocks.last().copied(),
+            entries,
+            only_merged,
+        });
+        self.open_blocks.push(id);
+        if id != 0 {
+            let locals = self.declarations.at_start.get(&id).cloned();
+            for idx in locals.into_iter().flatten() {
+                self.line(&format!("{};", self.declaration(idx)));
+            }
+        }
+    }
+
+    fn close_block(&mut self) {
+        self.open_blocks.pop();
+    }
+
+    fn label(&mut self, label: &str) {
+        if self.labels.contains(label) {
+            self.indent -= 1;
+            self.line(&format!("{label}:;"));
+            self.indent += 1;
+        }
+    }
+
+    fn emit_node(&mut self, node: &'a StructuredNode, next: Next<'a>) {
+        match node {
+            StructuredNode::Basic(idx) => {
+                self.label(&block_label(*idx));
+                self.emit_block(*idx, next);
+            }
+            StructuredNode::Duplicate(idx) => self.emit_block(*idx, next),
+            StructuredNode::Sequence(nodes) => self.emit_sequence(nodes, next),
+            StructuredNode::If { .. } => {
+                panic!("`If` must follow the block whose branch it is")
+            }
+            StructuredNode::Loop(body) => {
+                let header = body.entry().expect("loop body is not empty");
+                let kind = ScopeKind::Loop { header };
+                let lines = Some(("while (1) {", "}".to_string()));
+                self.emit_scope(kind, lines, None, next, |this| {
+                    this.emit_node(body, Next::Block(header))
+                });
+            }
+            StructuredNode::While { header, cond, body } => {
+                self.label(&block_label(*header));
+                let open = format!("while ({}) {{", self.value(cond, self.end(*header)));
+                let kind = ScopeKind::Loop { header: *header };
+                self.map(self.end(*header));
+                self.emit_scope(kind, Some((&open, "}".to_string())), None, next, |this| {
+                    this.emit_node(body, Next::Block(*header))
+                });
+            }
+            StructuredNode::DoWhile { body, latch, cond } => {
+                let header = body.entry().expect("loop body is not empty");
+                self.latches.insert(*latch);
+                let close = format!("}} while ({});", self.value(cond, self.end(*latch)));
+                let kind = ScopeKind::Loop { header };
+                let close_at = Some(self.end(*latch));
+                self.emit_scope(kind, Some(("do {", close)), close_at, next, |this| {
+                    this.emit_node(body, Next::Block(header))
+                });
+            }
+            StructuredNode::For {
+                init,
+                header,
+                cond,
+                step,
+                body,
+            } => {
+                self.label(&block_label(*header));
+                let init_at = (init.bb, init.index);
+                let step_at = (step.bb, step.index);
+                self.open_block(vec![init_at], true);
+                let declares = self.map_assignment(init_at);
+                self.map(self.end(*header));
+                self.map(step_at);
+                let open = format!(
+                    "for ({}; {}; {}) {{",
+                    self.stmt(init.stmt(self.cfg), init_at, declares),
+                    self.value(cond, self.end(*header)),
+                    self.stmt(step.stmt(self.cfg), step_at, false),
+                );
+                let kind = ScopeKind::Loop { header: *header };
+                self.emit_scope(kind, Some((&open, "}".to_string())), None, next, |this| {
+                    this.emit_node(body, Next::Block(*header))
+                });
+                self.close_block();
+            }
+            StructuredNode::Block(body) => {
+                let is_loop = self.loop_blocks.contains(&self.scope_counter);
+                let lines = is_loop.then(|| ("do {", "} while (0);".to_string()));
+                self.emit_scope(ScopeKind::Block, lines, None, next, |this| {
+                    this.emit_node(body, next)
+                });
+            }
+            StructuredNode::Dispatch {
+                entry_map,
+                handlers,
+            } => {
+                self.uses_label_variable = true;
+                let kind = ScopeKind::Dispatch { entry_map };
+                let lines = Some(("while (1) {", "}".to_string()));
+                self.emit_scope(kind, lines, None, next, |this| {
+                    for (i, (label, handler)) in handlers.iter().enumerate() {
+                        let keyword = if i == 0 { "if" } else { "} else if" };
+                        this.line(&format!("{keyword} ({LABEL_VARIABLE} == {label}) {{"));
+                        this.indent += 1;
+                        // `continue` leaves the handler, and can come back to it.
+                        let entries = handler.entry().map(|bb| (bb, 0)).into_iter().collect();
+                        this.open_block(entries, false);
+                        this.emit_node(handler, Next::Dispatch(entry_map));
+                        this.close_block();
+                        this.indent -= 1;
+                    }
+                    if !handlers.is_empty() {
+                        this.line("}");
+                    }
+                });
+            }
+            StructuredNode::Labeled(blocks) => {
+                for (i, idx) in blocks.iter().enumerate() {
+                    let next = blocks.get(i + 1).map_or(next, |idx| Next::Block(*idx));
+                    self.label(&block_label(*idx));
+                    self.emit_block(*idx, next);
+                }
+            }
+        }
+    }
+
+    // Prints the body of a scope. `lines` are the opening and closing lines of the C
+    // loop around it, if it is printed as one, and `close_at` is what the closing
+    // line prints. Loops get a label at the end of their body and every scope gets
+    // one after it, in case a `continue` or `break` to it has to be a `goto`.
+    fn emit_scope(
+        &mut self,
+        kind: ScopeKind<'a>,
+        lines: Option<(&str, String)>,
+        close_at: Option<(Idx<BasicBlock>, usize)>,
+        next: Next<'a>,
+        body: impl FnOnce(&mut Self),
+    ) {
+        let id = self.scope_counter;
+        self.scope_counter += 1;
+        self.scopes.push(Scope { kind, next });
+        self.scope_ids.push(id);
+        self.scope_is_loop.push(lines.is_some());
+        if let Some((open, _)) = &lines {
+            self.line(open);
+            self.indent += 1;
+            let entries = match kind {
+                ScopeKind::Loop { header } => vec![(header, 0)],
+                ScopeKind::Block => vec![],
+                ScopeKind::Dispatch { entry_map } => entry_map.keys().map(|bb| (*bb, 0)).collect(),
+            };
+            self.open_block(entries, false);
+        }
+        body(self);
+        if !matches!(kind, ScopeKind::Block) {
+            self.label(&format!("continue_{id}"));
+        }
+        if let Some((_, close)) = &lines {
+            self.close_block();
+            self.indent -= 1;
+            if let Some(at) = close_at {
+                self.map(at);
+            }
+            self.line(close);
+        }
+        self.scopes.pop();
+        self.scope_ids.pop();
+        self.scope_is_loop.pop();
+        self.label(&format!("break_{id}"));
+    }
+
+    fn emit_sequence(&mut self, nodes: &'a [StructuredNode], next: Next<'a>) {
+        let nodes: Vec<_> = nodes.iter().filter(|node| !node.is_empty()).collect();
+        let mut i = 0;
+        while i < nodes.len() {
+            // A block followed by an `If` branches into it, and the `If` is followed
+            // by what comes after both.
+            let paired = matches!(nodes.get(i + 1), Some(StructuredNode::If { .. }));
+            let after = if paired { i + 2 } else { i + 1 };
+            let node_next = nodes[after..]
+                .iter()
+                .find_map(|node| next_of(node))
+                .unwrap_or(next);
+            match (nodes[i], nodes.get(i + 1)) {
+                (
+                    StructuredNode::Basic(idx) | StructuredNode::Duplicate(idx),
+                    Some(StructuredNode::If {
+                        cond,
+                        then_node,
+                        else_node,
+                    }),
+                ) => {
+                    if matches!(nodes[i], StructuredNode::Basic(_)) {
+                        self.label(&block_label(*idx));
+                    }
+                    self.emit_stmts(*idx);
+                    self.emit_branch(*idx, cond, then_node, else_node, node_next);
+                }
+                (node, _) => self.emit_node(node, node_next),
+            }
+            i = after;
+        }
+    }
+
+    fn emit_block(&mut self, idx: Idx<BasicBlock>, next: Next<'a>) {
+        self.emit_stmts(idx);
+        if self.latches.contains(&idx) {
+            return;
+        }
+        match self.cfg.bb[idx].terminator() {
+            Terminator::Return => {
+                self.map(self.end(idx));
+                if matches!(self.return_type(), CType::Void) {
+                    self.line("return;");
+                } else {
+                    let line = format!("return {};", self.names[0]);
+                    self.line(&line);
+                }
+            }
+            Terminator::Goto { bb } => {
+                self.visit(self.end(idx), false);
+                self.emit_jump(*bb, next);
+            }
+            Terminator::If { cond, .. } => {
+                self.emit_branch(idx, cond, &EMPTY, &EMPTY, next);
+            }
+        }
+    }
+
+    fn emit_stmts(&mut self, idx: Idx<BasicBlock>) {
+        for (index, stmt) in self.cfg.bb[idx].stmts.iter().enumerate() {
+            if !self.loop_stmts.contains(&(idx, index)) {
+                let declares = self.map_assignment((idx, index));
+                let line = format!("{};", self.stmt(stmt, (idx, index), declares));
+                self.line(&line);
+            }
+        }
+    }
+
+    // The branch at the end of `idx`. An empty side is a jump to the target of the
+    // terminator, a non-empty one starts with it.
+    fn emit_branch(
+        &mut self,
+        idx: Idx<BasicBlock>,
+        cond: &Value,
+        then_node: &'a StructuredNode,
+        else_node: &'a StructuredNode,
+        next: Next<'a>,
+    ) {
+        let Terminator::If { then, else_, .. } = self.cfg.bb[idx].terminator() else {
+            panic!("`If` must follow a block that ends with a branch");
+        };
+        let is_empty = |node: &StructuredNode, target| {
+            node.is_empty() && resolve(&self.scopes, next, target) == Jump::Fallthrough
+        };
+        let at = self.end(idx);
+        let then_empty = is_empty(then_node, *then);
+        let else_empty = is_empty(else_node, *else_);
+        let emit_side = |this: &mut Self, node: &'a StructuredNode, target| {
+            this.indent += 1;
+            this.open_block(vec![], false);
+            if node.is_empty() {
+                this.emit_jump(target, next);
+            } else {
+                this.emit_node(node, next);
+            }
+            this.close_block();
+            this.indent -= 1;
+        };
+        match (then_empty, else_empty) {
+            (true, true) => {
+                self.visit(at, false);
+            }
+            (false, true) => {
+                self.map(at);
+                self.line(&format!("if ({}) {{", self.value(cond, at)));
+                emit_side(self, then_node, *then);
+                self.line("}");
+            }
+            (true, false) => {
+                self.map(at);
+                self.line(&format!(
+                    "if ({}) {{",
+                    self.value(&cond.negated(self.cfg), at)
+                ));
+                emit_side(self, else_node, *else_);
+                self.line("}");
+            }
+            (false, false) if self.emit_conditional(idx, cond, then_node, else_node, next) => {}
+            (false, false) => {
+                self.map(at);
+                self.line(&format!("if ({}) {{", self.value(cond, at)));
+                emit_side(self, then_node, *then);
+                self.line("} else {");
+                emit_side(self, else_node, *else_);
+                self.line("}");
+            }
+        }
+    }
+
+    // Prints a diamond whose sides only assign the same local as one assignment of
+    // `cond ? a : b`, if that is short. Whether it did.
+    fn emit_conditional(
+        &mut self,
+        idx: Idx<BasicBlock>,
+        cond: &Value,
+        then_node: &StructuredNode,
+        else_node: &StructuredNode,
+        next: Next<'a>,
+    ) -> bool {
+        let side = |node: &StructuredNode| {
+            let bb = match node {
+                StructuredNode::Basic(bb) => bb,
+                StructuredNode::Sequence(nodes) => match nodes.as_slice() {
+                    [StructuredNode::Basic(bb)] => bb,
+                    _ => return None,
+                },
+                _ => return None,
+            };
+            let block = &self.cfg.bb[*bb];
+            let (
+                [
+                    Stmt::Assign {
+                        place: Place::Local(local),
+                        value,
+                        ..
+                    },
+                ],
+                Terminator::Goto { bb: join },
+            ) = (block.stmts.as_slice(), block.terminator())
+            else {
+                return None;
+            };
+            // `?:` converts both sides to a common type before the assignment does.
+            let ty = &self.cfg.locals[*local].ty;
+            let converts = match value {
+                Value::Literal(_) => matches!(ty, CType::Ptr(_)),
+                _ => value_ty(value, self.cfg) != *ty,
+            };
+            let only_from_branch = self.preds[bb] == 1 && !self.latches.contains(bb);
+            (only_from_branch && !converts && !self.loop_stmts.contains(&(*bb, 0)))
+                .then_some((*bb, *local, value, *join))
+        };
+        let (Some((then, local, a, join)), Some((else_, other, b, other_join))) =
+            (side(then_node), side(else_node))
+        else {
+            return false;
+        };
+        if local != other
+            || join != other_join
+            || resolve(&self.scopes, next, join) != Jump::Fallthrough
+        {
+            return false;
+        }
+        let ty = &self.cfg.locals[local].ty;
+        let at = self.end(idx);
+        let value = Expr::conditional(
+            self.expr(cond, &self.state(at)),
+            self.assigned(a, ty, &self.state((then, 0))),
+            self.assigned(b, ty, &self.state((else_, 0))),
+        )
+        .print(Syntax::C);
+        if value.len() > MAX_CONDITIONAL_WIDTH {
+            return false;
+        }
+        self.map(at);
+        let declares = self.map_assignment((then, 0));
+        self.visit(self.end(then), false);
+        self.map((else_, 0));
+        self.visit(self.end(else_), false);
+        let place = if declares {
+            self.declaration(local)
+        } else {
+            self.name(local)
+        };
+        self.line(&format!("{place} = {value};"));
+        true
+    }
+
+    fn emit_jump(&mut self, target: Idx<BasicBlock>, next: Next<'a>) {
+        let (scope, label, keyword) = match resolve(&self.scopes, next, target) {
+            Jump::Fallthrough => return,
+            Jump::SetLabel(label) => {
+                self.line(&format!("{LABEL_VARIABLE} = {label};"));
+                return;
+            }
+            Jump::Goto(target) => {
+                let label = block_label(target);
+                self.line(&format!("goto {label};"));
+                self.used_labels.insert(label);
+                return;
+            }
+            Jump::Break { scope, label } => (scope, label, "break"),
+            Jump::Continue { scope, label } => (scope, label, "continue"),
+        };
+        if let Some(label) = label {
+            self.line(&format!("{LABEL_VARIABLE} = {label};"));
+        }
+        let id = self.scope_ids[scope];
+        if keyword == "break" {
+            self.break_targets.insert(id);
+        }
+        // A `break` or `continue` that passes through a `do { } while (0)` would
+        // stop there, so blocks it crosses can't be printed as one.
+        for inner in scope + 1..self.scopes.len() {
+            if matches!(self.scopes[inner].kind, ScopeKind::Block) {
+                self.crossed_blocks.insert(self.scope_ids[inner]);
+            }
+        }
+        let innermost =
+            self.scope_is_loop[scope] && !self.scope_is_loop[scope + 1..].contains(&true);
+        if innermost {
+            self.line(&format!("{keyword};"));
+        } else {
+            let label = format!("{keyword}_{id}");
+            self.line(&format!("goto {label};"));
+            self.used_labels.insert(label);
+        }
+    }
+
+    // The position of the terminator of `bb`.
+    fn end(&self, bb: Idx<BasicBlock>) -> (Idx<BasicBlock>, usize) {
+        (bb, self.cfg.bb[bb].stmts.len())
+    }
+
+    // `stmt` and `value` print what is at position `at`, a statement or a terminator.
+    // Prints the assignment at `at`, which `declares` the local it assigns.
+    fn stmt(&self, stmt: &Stmt, at: (Idx<BasicBlock>, usize), declares: bool) -> String {
+        match stmt {
+            Stmt::Assign { place, value, .. } => {
+                let state = self.state(at);
+                let value = self.assigned(value, &place.ty(self.cfg), &state);
+                let place = match place {
+                    Place::Local(idx) if declares => self.declaration(*idx),
+                    _ => place_expr_with(place, Syntax::C, &|idx| self.name(idx), &|value| {
+                        self.expr(value, &state)
+                    })
+                    .print(Syntax::C),
+                };
+                format!("{place} = {}", value.print(Syntax::C))
+            }
+        }
+    }
+
+    // `value` when it is assigned to something of type `ty`.
+    fn assigned(&self, value: &Value, ty: &CType, state: &State) -> Expr {
+        match value {
+            // Assigning converts, and C compilers warn about literals that change.
+            Value::Literal(i)
+                if self.arithmetic != CArithmetic::Plain
+                    && matches!(ty, CType::Int(_) | CType::UInt(_)) =>
+            {
+                c_literal(*i, ty)
+            }
+            // Except to and from pointers, which takes a cast unless it is to `bool`.
+            Value::Literal(i) if matches!(ty, CType::Ptr(_)) => {
+                Expr::cast(literal_expr((*i).into(), Syntax::C), c_type(ty))
+            }
+            _ if *ty != CType::Bool
+                && matches!(
+                    (value_ty(value, self.cfg), ty),
+                    (CType::Ptr(_), _) | (_, CType::Ptr(_))
+                ) =>
+            {
+                self.operand(value, ty, state)
+            }
+            // And from floats to integers, unless that is left to C.
+            _ if self.saturates(&value_ty(value, self.cfg), ty) => self.operand(value, ty, state),
+            _ => self.expr(value, state),
+        }
+    }
+
+    fn value(&self, value: &Value, at: (Idx<BasicBlock>, usize)) -> String {
+        self.expr(value, &self.state(at)).print(Syntax::C)
+    }
+
+    fn name(&self, idx: Idx<Local>) -> String {
+        self.names[idx.to_usize()].clone()
+    }
+
+    // What is known about the locals at `at`. Without range analysis that is
+    // nothing, and only types limit values.
+    fn state(&self, (bb, index): (Idx<BasicBlock>, usize)) -> State {
+        self.ranges
+            .as_ref()
+            .map_or_else(State::default, |ranges| ranges.before(bb, index))
+    }
+
+    fn expr(&self, value: &Value, state: &State) -> Expr {
+        let cfg = self.cfg;
+        let (l, binop, r) = match value {
+            _ if self.arithmetic == CArithmetic::Plain => {
+                return value_expr(value, Syntax::C, &|idx| self.name(idx));
+            }
+            Value::Literal(i) => return literal_expr((*i).into(), Syntax::C),
+            Value::Place(place) => {
+                let name = |idx| self.name(idx);
+                return place_expr_with(place, Syntax::C, &name, &|value| self.expr(value, state));
+            }
+            Value::Binop(l, binop, r) => (&**l, *binop, &**r),
+            Value::Select(cond, then, else_) => {
+                let ty = value_ty(value, cfg);
+                return Expr::conditional(
+                    self.operand(cond, &CType::Bool, state),
+                    self.operand(then, &ty, state),
+                    self.operand(else_, &ty, state),
+                );
+            }
+        };
+        // Comparisons convert their operands to a common type, arithmetic also
+        // promotes `bool` to `int`.
+        let ty = if is_condition(binop) {
+            operand_ty(l, r, cfg)
+        } else {
+            value_ty(value, cfg)
+        };
+        let is_integer = matches!(ty, CType::Int(_) | CType::UInt(_) | CType::Bool);
+        if matches!(binop, Binop::And | Binop::Or) || !is_integer {
+            return Expr::binary(self.expr(l, state), binop, self.expr(r, state));
+        }
+        let operand = |value| self.operand(value, &ty, state);
+        if is_condition(binop) {
+            return Expr::binary(operand(l), binop, operand(r));
+        }
+
+        let bytes = ty.size(&cfg.layout);
+        let bits = i128::from(bytes) * 8;
+        let signed = matches!(ty, CType::Int(_));
+        let range = |value| state.range(value, cfg).and_then(|range| range.convert(&ty));
+        let (l_range, r_range) = (range(l), range(r));
+        let fits = |ty: &CType| match (l_range, r_range) {
+            (Some(l), Some(r)) => {
+                binop_range(l, binop, r, bits as u32).is_some_and(|range| range.fits(ty))
+            }
+            _ => false,
+        };
+        let may_be = |range: Option<Range>, i: i128| {
+            range.is_none_or(|range| range.min <= i && i <= range.max)
+        };
+        let non_negative = l_range.is_some_and(|range| range.min >= 0);
+        let valid_amount = r_range.is_some_and(|range| range.min >= 0 && range.max < bits);
+        // C does arithmetic in `int` or wider, so narrower types can wrap by a cast
+        // of the result, and wider unsigned ones wrap by themselves.
+        let narrow = bytes < 4;
+        let wraps = !signed && !narrow;
+        // Where the operation wraps around, it is done in `unsigned`.
+        let unsigned = CType::UInt(bytes.max(4));
+        let in_unsigned = |value| self.operand(value, &unsigned, state);
+        let c_ty = c_type(&ty);
+        // Shifts don't convert their left side to a common type, and a literal is an
+        // `int`.
+        let shifted = |value: &Value, to: &CType| match value {
+            Value::Literal(i) if !promotes_to_signed(to) || to.size(&cfg.layout) > 4 => {
+                let literal = c_literal(*i, to);
+                match literal {
+                    Expr::Cast(..) => literal,
+                    _ => Expr::cast(literal, c_type(to)),
+                }
+            }
+            _ => self.operand(value, to, state),
+        };
+
+        // The amount shifted by is masked like the IR does.
+        let amount = match r {
+            Value::Literal(i) => Expr::Atom((i128::from(*i) & (bits - 1)).to_string()),
+            _ if valid_amount => operand(r),
+            _ => Expr::binary(
+                self.expr(r, state),
+                Operator::BitAnd,
+                Expr::Atom((bits - 1).to_string()),
+            ),
+        };
+        match binop {
+            Binop::Add | Binop::Sub | Binop::Mul => {
+                if fits(&ty) || wraps {
+                    Expr::binary(operand(l), binop, operand(r))
+                } else if narrow && fits(&CType::Int(4)) {
+                    Expr::cast(Expr::binary(operand(l), binop, operand(r)), c_ty)
+                } else {
+                    Expr::cast(Expr::binary(in_unsigned(l), binop, in_unsigned(r)), c_ty)
+                }
+            }
+            Binop::Div | Binop::Rem => {
+                let mut expr = Expr::binary(operand(l), binop, operand(r));
+                // `MIN / -1` and `MIN % -1` overflow. In `int` they don't for narrower
+                // types, and the cast wraps the result.
+                let min = -(1 << (bits - 1));
+                if signed && may_be(r_range, -1) && may_be(l_range, min) {
+                    if narrow {
+                        expr = Expr::cast(expr, c_ty.clone());
+                    } else {
+                        let overflowed = match binop {
+                            Binop::Div => Expr::cast(
+                                Expr::binary(Expr::Atom("0".into()), Binop::Sub, in_unsigned(l)),
+                                c_ty.clone(),
+                            ),
+                            _ => Expr::Atom("0".into()),
+                        };
+                        expr = Expr::conditional(
+                            Expr::binary(operand(r), Binop::Eq, literal_expr(-1, Syntax::C)),
+                            overflowed,
+                            expr,
+                        );
+                    }
+                }
+                if may_be(r_range, 0) {
+                    let by_zero = match binop {
+                        Binop::Div => c_literal(-1, &ty),
+                        _ => operand(l),
+                    };
+                    expr = Expr::conditional(
+                        Expr::binary(operand(r), Binop::Eq, Expr::Atom("0".into())),
+                        by_zero,
+                        expr,
+                    );
+                }
+                expr
+            }
+            Binop::Shl => {
+                if fits(&ty) || wraps {
+                    Expr::binary(shifted(l, &ty), binop, amount)
+                } else {
+                    Expr::cast(Expr::binary(shifted(l, &unsigned), binop, amount), c_ty)
+                }
+            }
+            // Shifts in zeros: a shift of the unsigned type of the same size.
+            Binop::Shr => {
+                if non_negative || !signed {
+                    Expr::binary(shifted(l, &ty), binop, amount)
+                } else {
+                    let same_size = CType::UInt(bytes);
+                    Expr::cast(Expr::binary(shifted(l, &same_size), binop, amount), c_ty)
+                }
+            }
+            // Shifts in copies of the sign bit. Shifting a negative value right is
+            // implementation defined in C, so those are shifted as the complement.
+            Binop::Sar => {
+                let same_size = CType::Int(bytes);
+                let positive =
+                    l_range.is_some_and(|range| range.min >= 0 && range.fits(&same_size));
+                if positive {
+                    return Expr::binary(shifted(l, &ty), binop, amount);
+                }
+                let l = shifted(l, &same_size);
+                let complement = |e| Expr::prefix("~", e);
+                let shifted = Expr::conditional(
+                    Expr::binary(l.clone(), Binop::Lt, Expr::Atom("0".into())),
+                    complement(Expr::binary(complement(l.clone()), binop, amount.clone())),
+                    Expr::binary(l, binop, amount),
+                );
+                if signed {
+                    shifted
+                } else {
+                    Expr::cast(shifted, c_ty)
+                }
+            }
+            // Bits above the width of a narrower type all stay copies of its top bit,
+            // or zeros.
+            Binop::Xor => Expr::binary(operand(l), binop, operand(r)),
+            _ => unreachable!(),
+        }
+    }
+
+    // `value` converted to the integer type `ty`, which the IR does to operands.
+    fn operand(&self, value: &Value, ty: &CType, state: &State) -> Expr {
+        if let Value::Literal(i) = value {
+            return c_literal(*i, ty);
+        }
+        let from = value_ty(value, self.cfg);
+        let expr = self.expr(value, state);
+        // Mixing signed and unsigned types would convert values, but converting a
+        // value to a type that holds it does nothing.
+        let holds = |to: &CType| {
+            promotes_to_signed(&from)
+                && promotes_to_signed(to)
+                && state
+                    .range(value, self.cfg)
+                    .is_some_and(|range| range.fits(to))
+        };
+        if from == *ty || holds(ty) {
+            expr
+        } else if *ty == CType::Bool {
+            Expr::binary(expr, Binop::Ne, Expr::Atom("0".into()))
+        } else if self.resizes_pointer(&from, ty) {
+            // C only converts between pointers and integers as wide as them.
+            let intptr = self.cfg.layout.intptr();
+            Expr::cast(Expr::cast(expr, c_type(&intptr)), c_type(ty))
+        } else if self.saturates(&from, ty) {
+            let (name, definition) = float_to_int(&from, ty);
+            let call = format!("{name}({})", expr.print(Syntax::C));
+            self.conversions.borrow_mut().insert(name, definition);
+            Expr::Atom(call)
+        } else {
+            Expr::cast(expr, c_type(ty))
+        }
+    }
+
+    // Whether converting `from` to `to` goes through a helper, because C leaves
+    // converting floats out of the range of an integer type undefined.
+    fn saturates(&self, from: &CType, to: &CType) -> bool {
+        self.arithmetic != CArithmetic::Plain
+            && matches!(from, CType::Float(_))
+            && matches!(to, CType::Int(_) | CType::UInt(_))
+    }
+
+    // Whether converting `from` to `to` is between a pointer and an integer of
+    // another width.
+    fn resizes_pointer(&self, from: &CType, to: &CType) -> bool {
+        let integer = |ty: &CType| matches!(ty, CType::Int(_) | CType::UInt(_) | CType::Bool);
+        let pointer_size = self.cfg.layout.pointer_size;
+        match (from, to) {
+            (CType::Ptr(_), ty) | (ty, CType::Ptr(_)) if integer(ty) => {
+                ty.size(&self.cfg.layout) != pointer_size
+            }
+            _ => false,
+        }
+    }
+}
+
+// `i` converted to the integer type `ty`, as a C expression whose value is also
+// right in `int` arithmetic.
+fn c_literal(i: i32, ty: &CType) -> Expr {
+    let converted = wrap(i.into(), ty);
+    match ty {
+        CType::UInt(bytes @ (4 | 8)) if converted == (1 << (bytes * 8)) - 1 => {
+            Expr::Atom(format!("UINT{}_MAX", bytes * 8))
+        }
+        CType::UInt(_) if converted > i128::from(i32::MAX) || converted < 0 => {
+            Expr::cast(literal_expr(i.into(), Syntax::C), c_type(ty))
+        }
+        _ => literal_expr(converted as i64, Syntax::C),
+    }
+}
+
+// The name and definition of a function that converts the float type `from` to the
+// integer type `to` like the IR does: rounding towards zero and saturating, with NaN
+// giving 0.
+fn float_to_int(from: &CType, to: &CType) -> (String, String) {
+    let (c_from, c_to) = (c_type(from), c_type(to));
+    let float = match from {
+        CType::Float(16) => "float128",
+        _ => &c_from,
+    };
+    let (signed, bytes) = match to {
+        CType::Int(bytes) => (true, *bytes),
+        CType::UInt(bytes) => (false, *bytes),
+        _ => panic!("{to} is not an integer type"),
+    };
+    let bits = u32::from(bytes) * 8;
+    let int = format!("{}int{bits}", if signed { "" } else { "u" });
+    // The bounds are powers of two, which every float type holds exactly.
+    let power = |n: u32| format!("0x1p{n}");
+    let (below, above) = match signed {
+        true => (format!("-{}", power(bits - 1)), power(bits - 1)),
+        false => ("-1.0".to_string(), power(bits)),
+    };
+    let (min, max) = match (signed, bytes) {
+        (true, 16) => {
+            let max = "(__int128)((unsigned __int128)-1 >> 1)";
+            (format!("-{max} - 1"), max.to_string())
+        }
+        (true, _) => (format!("INT{bits}_MIN"), format!("INT{bits}_MAX")),
+        (false, 16) => ("0".to_string(), "(unsigned __int128)-1".to_string()),
+        (false, _) => ("0".to_string(), format!("UINT{bits}_MAX")),
+    };
+    let name = format!("{int}_of_{float}");
+    let mut definition = format!("static {c_to} {name}({c_from} x)\n{{\n");
+    definition += "    if (x != x) {\n        return 0;\n    }\n";
+    definition += &format!("    if (x <= {below}) {{\n        return {min};\n    }}\n");
+    definition += &format!("    if (x >= {above}) {{\n        return {max};\n    }}\n");
+    definition += &format!("    return ({c_to})x;\n}}\n");
+    (name, definition)
+}
+
+// Whether C arithmetic on a `ty` is done in a signed type.
+fn promotes_to_signed(ty: &CType) -> bool {
+    match ty {
+        CType::Bool | CType::Int(_) => true,
+        CType::UInt(bytes) => *bytes < 4,
+        _ => false,
+    }
+}
+
+fn is_condition(binop: Binop) -> bool {
+    matches!(
+        binop,
+        Binop::Lt | Binop::Le | Binop::Eq | Binop::Ne | Binop::And | Binop::Or
+    )
+}
+
+fn block_label(idx: Idx<BasicBlock>) -> String {
+    format!("bb{}", idx.to_usize())
+}
+
+fn collect_loop_stmts(node: &StructuredNode, stmts: &mut BTreeSet<(Idx<BasicBlock>, usize)>) {
+    match node {
+        StructuredNode::For {
+            init, step, body, ..
+        } => {
+            stmts.insert((init.bb, init.index));
+            stmts.insert((step.bb, step.index));
+            collect_loop_stmts(body, stmts);
+        }
+        StructuredNode::Sequence(nodes) => {
+            for node in nodes {
+                collect_loop_stmts(node, stmts);
+            }
+        }
+        StructuredNode::If {
+            then_node,
+            else_node,
+            ..
+        } => {
+            collect_loop_stmts(then_node, stmts);
+            collect_loop_stmts(else_node, stmts);
+        }
+        StructuredNode::Loop(body)
+        | StructuredNode::Block(body)
+        | StructuredNode::While { body, .. }
+        | StructuredNode::DoWhile { body, .. } => collect_loop_stmts(body, stmts),
+        StructuredNode::Dispatch { handlers, .. } => {
+            for (_, handler) in handlers {
+                collect_loop_stmts(handler, stmts);
+            }
+        }
+        StructuredNode::Basic(_) | StructuredNode::Duplicate(_) | StructuredNode::Labeled(_) => {}
+    }
+}
+
+// The name of every local: its own name if it is a usable and unique C identifier,
+// `_N` otherwise.
+fn local_names(cfg: &Cfg) -> Vec<String> {
+    let is_identifier = |name: &str| {
+        let mut chars = name.chars();
+        chars
+            .next()
+            .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+            && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+    };
+    let is_generated = |name: &str| {
+        name.strip_prefix('_')
+            .is_some_and(|rest| !rest.is_empty() && rest.chars().all(|c| c.is_ascii_digit()))
+    };
+    // The names of the helpers that convert floats, like `int32_of_double`.
+    let is_helper = |name: &str| {
+        name.split_once("_of_").is_some_and(|(int, float)| {
+            (int.starts_with("int") || int.starts_with("uint"))
+                && ["float", "double", "float128"].contains(&float)
+        })
+    };
+    let mut taken = BTreeSet::new();
+    cfg.locals
+        .iter()
+        .map(|(idx, local)| match &local.name {
+            Some(name)
+                if is_identifier(name)
+                    && !is_generated(name)
+                    && !is_helper(name)
+                    && !RESERVED.contains(&name.as_str())
+                    && taken.insert(name.clone()) =>
+            {
+                name.clone()
+            }
+            _ => format!("_{}", idx.to_usize()),
+        })
+        .collect()
+}
+
+fn c_type(ty: &CType) -> String {
+    match ty {
+        CType::Void => "void".to_string(),
+        CType::Float(4) => "float".to_string(),
+        CType::Float(8) => "double".to_string(),
+        CType::Float(16) => "_Float128".to_string(),
+        CType::Int(16) => "__int128".to_string(),
+        CType::UInt(16) => "unsigned __int128".to_string(),
+        CType::Int(bytes @ (1 | 2 | 4 | 8)) => format!("int{}_t", bytes * 8),
+        CType::UInt(bytes @ (1 | 2 | 4 | 8)) => format!("uint{}_t", bytes * 8),
+        CType::Bool => "bool".to_string(),
+        CType::Ptr(ty) => {
+            let ty = c_type(ty);
+            if ty.ends_with('*') {
+                format!("{ty}*")
+            } else {
+                format!("{ty} *")
+            }
+        }
+        CType::Float(_) | CType::Int(_) | CType::UInt(_) => {
+            panic!("{ty} has no C equivalent")
+        }
+    }
+}
+
+fn mentions_bool(ty: &CType) -> bool {
+    match ty {
+        CType::Bool => true,
+        CType::Ptr(ty) => mentions_bool(ty),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::process::{Command, Stdio};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::test_util::*;
+    use CType::{Int, UInt};
+
+    // Compiles the C for `cfg` with a harness for each of `cases`, which are the
+    // arguments and what `_0` should be, and runs it.
+    fn check(cfg: &Cfg, config: &LoopifyConfig, cases: &[(&[i128], i128)]) {
+        static BINARIES: AtomicUsize = AtomicUsize::new(0);
+        let code = emit_c(cfg, &cfg.loopify_with(config), "f");
+        for (args, expected) in cases {
+            let test = TestCase {
+                args: args.iter().map(|arg| TestValue::Int(*arg)).collect(),
+                memory: vec![],
+                expected: Some(TestValue::Int(*expected)),
+                expected_memory: vec![],
+            };
+            let source = code.clone() + &emit_c_harness(cfg, "f", &test);
+            let binary = std::env::temp_dir().join(format!(
+                "c_test_{}_{}",
+                std::process::id(),
+                BINARIES.fetch_add(1, Ordering::Relaxed)
+            ));
+            let mut cc = Command::new("cc")
+                .args(["-x", "c", "-", "-o"])
+                .arg(&binary)
+                .stdin(Stdio::piped())
+                .spawn()
+                .expect("cc runs");
+            cc.stdin
+                .take()
+                .unwrap()
+                .write_all(source.as_bytes())
+                .unwrap();
+            assert!(cc.wait().unwrap().success(), "{source}");
+            let status = Command::new(&binary).status().unwrap();
+            let _ = std::fs::remove_file(&binary);
+            assert!(
+                status.success(),
+                "{args:?} should give {expected}\n{source}"
+            );
+        }
+    }
+
+    // Compiles `code`, the C for `cfg`, with a `main` that calls it on each of
+    // `cases`, which are the arguments and what `_0` should be, and runs it. It is
+    // optimized, where undefined behavior does the most harm, and any that the
+    // sanitizer sees fails the test.
+    fn run_cases(cfg: &Cfg, code: &str, cases: &[(impl AsRef<[i128]>, i128)]) {
+        let cases: Vec<_> = cases
+            .iter()
+            .map(|(args, expected)| {
+                let args = args.as_ref().iter().map(|arg| TestValue::Int(*arg));
+                (args.collect(), TestValue::Int(*expected))
+            })
+            .collect();
+        run_values(cfg, code, &cases);
+    }
+
+    // Like `run_cases`, with values of any type.
+    fn run_values(cfg: &Cfg, code: &str, cases: &[(Vec<TestValue>, TestValue)]) {
+        static BINARIES: AtomicUsize = AtomicUsize::new(0);
+        let literal = |value: &TestValue, idx: usize| {
+            let ty = &cfg.locals[Idx::from_usize(idx)].ty;
+            test_value(value, ty, &[], 1)
+        };
+        let mut source = format!("#include <stdio.h>\n{code}\nint main(void)\n{{\n");
+        for (i, (args, expected)) in cases.iter().enumerate() {
+            let args: Vec<_> = args
+                .iter()
+                .enumerate()
+                .map(|(k, arg)| literal(arg, k + 1))
+                .collect();
+            let expected = literal(expected, 0);
+            source += &format!("    if (f({}) != {expected}) {{\n", args.join(", "));
+            source += &format!("        printf(\"{i}\");\n        return 1;\n    }}\n");
+        }
+        source += "    return 0;\n}\n";
+
+        let binary = std::env::temp_dir().join(format!(
+            "c_cases_{}_{}",
+            std::process::id(),
+            BINARIES.fetch_add(1, Ordering::Relaxed)
+        ));
+        let mut cc = Command::new("cc")
+            .args(["-x", "c", "-", "-O2", "-fno-sanitize-recover"])
+            .arg("-fsanitize=undefined,float-cast-overflow")
+            .arg("-o")
+            .arg(&binary)
+            .stdin(Stdio::piped())
+            .spawn()
+            .expect("cc runs");
+        cc.stdin
+            .take()
+            .unwrap()
+            .write_all(source.as_bytes())
+            .unwrap();
+        assert!(cc.wait().unwrap().success(), "{source}");
+        let output = Command::new(&binary).output().unwrap();
+        let _ = std::fs::remove_file(&binary);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.is_empty(), "{stderr}\n{source}");
+        if let Ok(failed) = String::from_utf8_lossy(&output.stdout).parse::<usize>() {
+            let (args, expected) = &cases[failed];
+            panic!("{args:?} should give {expected:?}\n{source}");
+        }
+        assert!(output.status.success(), "{}", output.status);
+    }
+
+    fn structured(cfg: &Cfg, config: &LoopifyConfig) -> String {
+        emit_c(cfg, &cfg.loopify_with(config), "f")
+    }
+
+    fn configs() -> [LoopifyConfig; 2] {
+        [Structurer::Relooper, Structurer::Dominators].map(|structurer| LoopifyConfig {
+            structurer,
+            ..Default::default()
+        })
+    }
+
+    // _0 = (l < r) as a branch, with _1 and _2 of the given types.
+    fn less_than(tys: [CType; 2], l: usize, r: usize) -> Cfg {
+        let [l_ty, r_ty] = tys;
+        let mut cfg = with_locals([CType::Int(4), l_ty, r_ty]);
+        cfg.arg_count = 2;
+        cfg.bb.alloc(block(
+            vec![],
+            branch(binop(local(l), Binop::Lt, local(r)), 1, 2),
+        ));
+        cfg.bb.alloc(block(
+            vec![assign(0, Value::Literal(1))],
+            Terminator::Return,
+        ));
+        cfg.bb.alloc(block(
+            vec![assign(0, Value::Literal(0))],
+            Terminator::Return,
+        ));
+        cfg
+    }
+
+    #[test]
+    fn test_mixed_width_comparisons() {
+        let tys = [CType::Int(1), CType::UInt(4)];
+        for config in configs() {
+            // Compared as `int8_t`: 300 is 44 and 260 is 4 there.
+            let cfg = less_than(tys.clone(), 1, 2);
+            check(
+                &cfg,
+                &config,
+                &[(&[-1, 300], 1), (&[5, 260], 0), (&[-1, 1], 1)],
+            );
+            // Compared as `uint32_t`, where -1 is the largest value.
+            let cfg = less_than(tys.clone(), 2, 1);
+            check(
+                &cfg,
+                &config,
+                &[(&[-1, 300], 1), (&[5, 260], 0), (&[-1, 1], 1)],
+            );
+        }
+        // A comparison as `uint8_t` of an `int32_t`: -1 is 255.
+        let cfg = less_than([CType::UInt(1), CType::Int(4)], 1, 2);
+        check(
+            &cfg,
+            &configs()[0],
+            &[(&[200, -1], 1), (&[200, 255], 1), (&[200, 100], 0)],
+        );
+    }
+
+    #[test]
+    fn test_negated_exit_conditions() {
+        // bb0: _0 = 0; _2 = 0; goto bb1
+        // bb1: if _2 < _1 { goto bb3 } else { goto bb2 }
+        // bb2: _0 = _0 + 1; _2 = _2 - 1; goto bb1
+        // bb3: return
+        //
+        // Counts down from 0 to `_1`, compared as `int32_t`. The loop runs while the
+        // exit condition doesn't hold, which turned around would compare as `int64_t`.
+        let mut cfg = with_locals([CType::Int(4), CType::Int(8), CType::Int(4)]);
+        cfg.arg_count = 1;
+        let zero = |i| assign(i, Value::Literal(0));
+        cfg.bb.alloc(block(vec![zero(0), zero(2)], goto(1)));
+        cfg.bb.alloc(block(
+            vec![],
+            branch(binop(local(2), Binop::Lt, local(1)), 3, 2),
+        ));
+        cfg.bb.alloc(block(
+            vec![
+                assign(0, binop(local(0), Binop::Add, Value::Literal(1))),
+                assign(2, binop(local(2), Binop::Sub, Value::Literal(1))),
+            ],
+            goto(1),
+        ));
+        cfg.bb.alloc(block(vec![], Terminator::Return));
+        // 0xfffffffd is -3 as an `int32_t`.
+        let cases: &[(&[i128], i128)] = &[(&[-3], 4), (&[5], 0), (&[0xfffffffd], 4)];
+        for config in configs() {
+            check(&cfg, &config, cases);
+        }
+
+        // A do-while loop that counts _2 up as a `uint16_t` until _1 < _2. bb1 runs
+        // the body and then branches, back to itself while the exit condition fails.
+        let mut cfg = with_locals([CType::Int(4), CType::Int(4), CType::UInt(2)]);
+        cfg.arg_count = 1;
+        cfg.bb.alloc(block(vec![zero(0), zero(2)], goto(1)));
+        cfg.bb.alloc(block(
+            vec![
+                assign(0, binop(local(0), Binop::Add, Value::Literal(1))),
+                assign(2, binop(local(2), Binop::Add, Value::Literal(1))),
+            ],
+            branch(binop(local(1), Binop::Lt, local(2)), 2, 1),
+        ));
+        cfg.bb.alloc(block(vec![], Terminator::Return));
+        // Compared as `int32_t`. As `uint16_t`, -1 would be more than any count.
+        let cases: &[(&[i128], i128)] = &[(&[3], 4), (&[-1], 1), (&[0], 1)];
+        for config in configs() {
+            check(&cfg, &config, cases);
+        }
+    }
+
+    // A function of `_1` and `_2` that runs `stmts` and returns.
+    fn straight(tys: impl IntoIterator<Item = CType>, stmts: Vec<Stmt>) -> Cfg {
+        let mut cfg = with_locals(tys);
+        cfg.arg_count = 2;
+        cfg.bb.alloc(block(stmts, Terminator::Return));
+        cfg
+    }
+
+    // Checks both exact kinds of arithmetic against the IR on all pairs of some
+    // values around the edges of the types.
+    fn check_arithmetic(cfg: &Cfg) {
+        let edges = [
+            0,
+            1,
+            2,
+            7,
+            15,
+            16,
+            17,
+            31,
+            33,
+            100,
+            200,
+            255,
+            300,
+            -1,
+            -2,
+            -128,
+            32767,
+            -32768,
+            65535,
+            i32::MAX.into(),
+            i32::MIN.into(),
+        ];
+        let cases: Vec<_> = edges
+            .iter()
+            .flat_map(|l| edges.map(|r| [*l, r]))
+            .map(|args| (args, run(cfg, &args, 10).unwrap()))
+            .collect();
+        let node = cfg.loopify();
+        for arithmetic in [CArithmetic::Exact, CArithmetic::Readable] {
+            let code = emit_c_with(cfg, &node, "f", &CConfig { arithmetic });
+            run_cases(cfg, &code, &cases);
+        }
+    }
+
+    #[test]
+    fn test_exact_arithmetic() {
+        let op = |op| vec![assign(0, binop(local(1), op, local(2)))];
+        // Done in `int` after the promotion, where these don't wrap, or overflow.
+        check_arithmetic(&straight([Int(4), UInt(1), UInt(1)], op(Binop::Mul)));
+        check_arithmetic(&straight([UInt(4), UInt(2), UInt(2)], op(Binop::Mul)));
+        check_arithmetic(&straight([Int(4), Int(1), Int(1)], op(Binop::Add)));
+        check_arithmetic(&straight([Int(4), UInt(1), UInt(1)], op(Binop::Sub)));
+        // Shifts of `int16_t` by more than 15 and the sign bit of the promoted value.
+        for shift in [Binop::Shl, Binop::Shr, Binop::Sar] {
+            check_arithmetic(&straight([Int(4), Int(2), Int(2)], op(shift)));
+        }
+        check_arithmetic(&straight([Int(4), UInt(1), Int(4)], op(Binop::Sar)));
+        // `MIN / -1` and dividing by zero.
+        for div in [Binop::Div, Binop::Rem] {
+            check_arithmetic(&straight([Int(4), Int(4), Int(4)], op(div)));
+            check_arithmetic(&straight([Int(4), Int(2), Int(2)], op(div)));
+            check_arithmetic(&straight([UInt(4), UInt(4), UInt(4)], op(div)));
+        }
+        // The overflow check `_1 + _2 < _1` compares the wrapped sum.
+        let sum = binop(local(1), Binop::Add, local(2));
+        let lt = vec![assign(0, binop(sum, Binop::Lt, local(1)))];
+        check_arithmetic(&straight([Int(4), UInt(1), UInt(1)], lt));
+    }
+
+    #[test]
+    fn test_casts() {
+        // _3 = _1 as u8; _4 = _3 as u16; _0 = _4 * _4 + _2
+        //
+        // The product of two bytes fits in 16 bits, and only the sum can wrap.
+        let square = binop(local(4), Binop::Mul, local(4));
+        let cfg = straight(
+            [Int(4), Int(4), UInt(2), UInt(1), UInt(2)],
+            vec![
+                assign(3, local(1)),
+                assign(4, local(3)),
+                assign(0, binop(square, Binop::Add, local(2))),
+            ],
+        );
+        check_arithmetic(&cfg);
+        // _3 = _1 - _2 wraps as a `uint16_t`, and `_3 >> 1` shifts in its top bit.
+        let cfg = straight(
+            [Int(4), UInt(2), UInt(2), UInt(2)],
+            vec![
+                assign(3, binop(local(1), Binop::Sub, local(2))),
+                assign(0, binop(local(3), Binop::Sar, Value::Literal(1))),
+            ],
+        );
+        check_arithmetic(&cfg);
+    }
+
+    #[test]
+    fn test_binary128() {
+        // _3 = _1 + _2; _0 = _3 - _1
+        //
+        // With 113 bits, 2^70 + 1 is exact, which it isn't in a 64-bit x87 `long double`.
+        let f128 = || CType::Float(16);
+        let cfg = straight(
+            [f128(), f128(), f128(), f128()],
+            vec![
+                assign(3, binop(local(1), Binop::Add, local(2))),
+                assign(0, binop(local(3), Binop::Sub, local(1))),
+            ],
+        );
+        let code = structured(&cfg, &configs()[0]);
+        assert!(
+            code.contains("_Float128 f(_Float128 _1, _Float128 _2)"),
+            "{code}"
+        );
+        run_cases(
+            &cfg,
+            &code,
+            &[([1 << 70, 1], 1), ([1 << 70, 1 << 20], 1 << 20)],
+        );
+    }
+
+    #[test]
+    fn test_float_to_int() {
+        // _0 = _1, which C leaves undefined out of the range of `_0`, and which
+        // saturates like Rust's `as` in the IR.
+        let values = [
+            f64::NAN,
+            f64::INFINITY,
+            -f64::INFINITY,
+            1e20,
+            -1e20,
+            -0.9,
+            2.9,
+        ];
+        let tys = [Int(4), UInt(1), Int(8), UInt(8)];
+        for (ty, float) in tys.iter().flat_map(|ty| [(ty, 4), (ty, 8)]) {
+            let mut cfg = with_locals([ty.clone(), CType::Float(float)]);
+            cfg.arg_count = 1;
+            cfg.bb
+                .alloc(block(vec![assign(0, local(1))], Terminator::Return));
+            let range = Range::of_type(ty).unwrap();
+            let cases: Vec<_> = values
+                .iter()
+                .map(|value| {
+                    let (bits, value) = match float {
+                        4 => ((*value as f32).to_bits().into(), f64::from(*value as f32)),
+                        _ => (value.to_bits().into(), *value),
+                    };
+                    let expected = (value as i128).clamp(range.min, range.max);
+                    (vec![TestValue::Float(bits)], TestValue::Int(expected))
+                })
+                .collect();
+            let node = cfg.loopify();
+            for arithmetic in [CArithmetic::Exact, CArithmetic::Readable] {
+                let code = emit_c_with(&cfg, &node, "f", &CConfig { arithmetic });
+                run_values(&cfg, &code, &cases);
+            }
+        }
+    }
+
+    #[test]
+    fn test_declaration_placement() {
+        // bb0: _0 = 0; _2 = 0; goto bb1
+        // bb1: if _2 < _1 { goto bb2 } else { goto bb6 }
+        // bb2: _3 = _2 * _2; if _3 < 10 { goto bb3 } else { goto bb4 }
+        // bb3: _4 = _3 + 1; _5 = _4; goto bb5
+        // bb4: _5 = 1; goto bb5
+        // bb5: _0 = _0 + _5; _2 = _2 + 1; goto bb1
+        // bb6: return
+        let mut cfg = with_locals(vec![Int(4); 6]);
+        cfg.arg_count = 1;
+        let inc = |i, by| assign(i, binop(local(i), Binop::Add, by));
+        cfg.bb.alloc(block(
+            vec![assign(0, Value::Literal(0)), assign(2, Value::Literal(0))],
+            goto(1),
+        ));
+        cfg.bb.alloc(block(
+            vec![],
+            branch(binop(local(2), Binop::Lt, local(1)), 2, 6),
+        ));
+        cfg.bb.alloc(block(
+            vec![assign(3, binop(local(2), Binop::Mul, local(2)))],
+            branch(binop(local(3), Binop::Lt, Value::Literal(10)), 3, 4),
+        ));
+        cfg.bb.alloc(block(
+            vec![
+                assign(4, binop(local(3), Binop::Add, Value::Literal(1))),
+                assign(5, local(4)),
+            ],
+            goto(5),
+        ));
+        cfg.bb
+            .alloc(block(vec![assign(5, Value::Literal(1))], goto(5)));
+        cfg.bb.alloc(block(
+            vec![inc(0, local(5)), inc(2, Value::Literal(1))],
+            goto(1),
+        ));
+        cfg.bb.alloc(block(vec![], Terminator::Return));
+        for config in configs() {
+            let code = structured(&cfg, &config);
+            let lines: Vec<_> = code.lines().collect();
+            // The loop body declares what only it uses, and the `if` what only one
+            // branch does. `_5` is assigned in both, so it is declared before them.
+            for declaration in [
+                "    int32_t _0 = 0;",
+                "        int32_t _5;",
+                "        int32_t _3 = (int32_t)((uint32_t)_2 * (uint32_t)_2);",
+                "            int32_t _4 = _3 + 1;",
+            ] {
+                assert!(lines.contains(&declaration), "{declaration}\n{code}");
+            }
+            for i in 0..6 {
+                let declared = lines.iter().filter(|line| {
+                    let line = line.trim_start();
+                    line.starts_with(&format!("int32_t _{i} ")) || line == format!("int32_t _{i};")
+                });
+                assert_eq!(declared.count(), usize::from(i != 1), "_{i}\n{code}");
+            }
+            // 1 + 2 + 5 + 10 + 1
+            run_cases(&cfg, &code, &[([5], 19), ([1], 1), ([0], 0), ([-1], 0)]);
+        }
+    }
+
+    #[test]
+    fn test_pointer_width() {
+        // _0 = _1 + 1, a pointer as wide as the layout says converted to an `i32`.
+        let mut cfg = with_locals([Int(4), CType::Ptr(Box::new(UInt(1)))]);
+        cfg.arg_count = 1;
+        let next = binop(local(1), Binop::Add, Value::Literal(1));
+        cfg.bb
+            .alloc(block(vec![assign(0, next)], Terminator::Return));
+        // Converted through `intptr_t` of 64 bits, which C has to do when an integer
+        // isn't as wide as a pointer, and the top half is cut off.
+        let code = structured(&cfg, &configs()[0]);
+        assert!(code.contains("(int32_t)(int64_t)"), "{code}");
+        run_cases(&cfg, &code, &[([0x1_0000_0005], 6), ([0xffff_fff0], -15)]);
+        cfg.layout = DataLayout::RV32;
+        let code = structured(&cfg, &configs()[0]);
+        assert!(code.contains("(int32_t)"), "{code}");
+        assert!(!code.contains("int64_t"), "{code}");
+    }
+}
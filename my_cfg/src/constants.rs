@@ -0,0 +1,391 @@
+// Sparse conditional constant propagation. Tracks which integer locals hold a known
+// value at the start of every block, following only the branches that can be taken
+// with what is known, so that a constant is still one after a branch on a constant
+// condition. Locals that are assigned different values on paths that meet aren't
+// constants anymore, which settles any loop after each of its locals changed once.
+//
+// Values are computed in their own type like everywhere else, wrapping at its width.
+// Memory isn't tracked.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::eval::{self, wrap};
+use crate::ranges::{Range, operand_ty, value_ty};
+use crate::*;
+
+/// The locals with a known value, in their own type.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct State {
+    locals: BTreeMap<Idx<Local>, i128>,
+}
+
+impl State {
+    fn assign(&mut self, stmt: &Stmt, cfg: &Cfg) {
+        let Stmt::Assign { place, value, .. } = stmt;
+        let Place::Local(idx) = place else {
+            // Locals can't be pointed to, so writing memory doesn't change them.
+            return;
+        };
+        let ty = &cfg.locals[*idx].ty;
+        match eval(value, self, cfg) {
+            Some(i) if Range::of_type(ty).is_some() => {
+                self.locals.insert(*idx, wrap(i, ty));
+            }
+            _ => {
+                self.locals.remove(idx);
+            }
+        }
+    }
+
+    fn join(&self, other: &State) -> State {
+        let locals = self
+            .locals
+            .iter()
+            .filter(|(idx, i)| other.locals.get(idx) == Some(i))
+            .map(|(idx, i)| (*idx, *i))
+            .collect();
+        State { locals }
+    }
+}
+
+/// The value of `value` in its own type, if it is known.
+fn eval(value: &Value, state: &State, cfg: &Cfg) -> Option<i128> {
+    eval::value(value, cfg, &|idx| state.locals.get(&idx).copied())
+}
+
+/// Replaces the values that are constants with literals, wherever that doesn't
+/// change types, and branches on constant conditions with jumps. The blocks that
+/// can't be reached anymore are left in place, for `remove_unreachable_blocks`.
+pub fn propagate_constants(cfg: &mut Cfg) {
+    let entry = Idx::from_usize(0);
+    let mut entry_states = BTreeMap::from([(entry, State::default())]);
+    let mut worklist = vec![entry];
+    while let Some(bb) = worklist.pop() {
+        let mut state = entry_states[&bb].clone();
+        for stmt in &cfg.bb[bb].stmts {
+            state.assign(stmt, cfg);
+        }
+        let successors = match cfg.bb[bb].terminator() {
+            Terminator::Return => vec![],
+            Terminator::Goto { bb } => vec![*bb],
+            Terminator::If { cond, then, else_ } => match eval(cond, &state, cfg) {
+                Some(0) => vec![*else_],
+                Some(_) => vec![*then],
+                None => vec![*then, *else_],
+            },
+        };
+        for succ in successors {
+            let new = match entry_states.get(&succ) {
+                Some(old) => old.join(&state),
+                None => state.clone(),
+            };
+            if entry_states.get(&succ) != Some(&new) {
+                entry_states.insert(succ, new);
+                if !worklist.contains(&succ) {
+                    worklist.push(succ);
+                }
+            }
+        }
+    }
+
+    for (bb, mut state) in entry_states {
+        let mut block = std::mem::take(&mut cfg.bb[bb]);
+        for stmt in &mut block.stmts {
+            let Stmt::Assign { place, value, .. } = stmt;
+            let ty = place.ty(cfg);
+            let folded = (
+                fold_place(place, &state, cfg),
+                fold(value, Some(&ty), &state, cfg),
+            );
+            state.assign(stmt, cfg);
+            let Stmt::Assign { place, value, .. } = stmt;
+            (*place, *value) = folded;
+        }
+        if let Some(Terminator::If { cond, then, else_ }) = &block.terminator {
+            block.terminator = Some(match eval(cond, &state, cfg) {
+                Some(0) => Terminator::Goto { bb: *else_ },
+                Some(_) => Terminator::Goto { bb: *then },
+                None => Terminator::If {
+                    cond: fold(cond, Some(&CType::Bool), &state, cfg),
+                    then: *then,
+                    else_: *else_,
+                },
+            });
+        }
+        cfg.bb[bb] = block;
+    }
+}
+
+// `value` with its constant parts replaced by literals. `ty` is what it is converted
+// to where it is used, `None` where it decides the type of an operation, which a
+// literal doesn't.
+fn fold(value: &Value, ty: Option<&CType>, state: &State, cfg: &Cfg) -> Value {
+    if let (Some(ty), Some(i)) = (ty, eval(value, state, cfg))
+        && Range::of_type(ty).is_some()
+    {
+        let literal = wrap(i, &CType::Int(4));
+        if wrap(literal, ty) == wrap(i, ty) {
+            return Value::Literal(literal as i32);
+        }
+    }
+    match value {
+        Value::Literal(_) => value.clone(),
+        Value::Place(place) => Value::Place(fold_place(place, state, cfg)),
+        Value::Binop(l, binop, r) => {
+            let (l_ty, r_ty) = match binop {
+                Binop::And | Binop::Or => (Some(CType::Bool), Some(CType::Bool)),
+                _ if matches!(**l, Value::Literal(_)) => (None, None),
+                _ => (None, Some(operand_ty(l, r, cfg))),
+            };
+            Value::Binop(
+                Box::new(fold(l, l_ty.as_ref(), state, cfg)),
+                *binop,
+                Box::new(fold(r, r_ty.as_ref(), state, cfg)),
+            )
+        }
+        Value::Select(cond, then, else_) => {
+            let ty = match **then {
+                Value::Literal(_) => None,
+                _ => Some(value_ty(value, cfg)),
+            };
+            Value::Select(
+                Box::new(fold(cond, Some(&CType::Bool), state, cfg)),
+                Box::new(fold(then, None, state, cfg)),
+                Box::new(fold(else_, ty.as_ref(), state, cfg)),
+            )
+        }
+    }
+}
+
+fn fold_place(place: &Place, state: &State, cfg: &Cfg) -> Place {
+    match place {
+        Place::Local(_) => place.clone(),
+        Place::Deref(inner) => Place::Deref(Box::new(fold_place(inner, state, cfg))),
+        Place::Offset(inner, offset) => Place::Offset(
+            Box::new(fold_place(inner, state, cfg)),
+            Box::new(fold(offset, Some(&cfg.layout.intptr()), state, cfg)),
+        ),
+    }
+}
+
+/// Removes the blocks that can't be reached from the entry, numbering the others
+/// in the order they were in.
+pub fn remove_unreachable_blocks(cfg: &mut Cfg) {
+    let entry = Idx::from_usize(0);
+    let reachable: BTreeSet<_> = reverse_postorder(cfg, entry).into_iter().collect();
+    let mut blocks = Arena::default();
+    let mut map = BTreeMap::new();
+    for (idx, block) in cfg.bb.iter() {
+        if reachable.contains(&idx) {
+            map.insert(idx, blocks.alloc(block.clone()));
+        }
+    }
+    for (_, block) in blocks.iter_mut() {
+        match block.terminator.as_mut() {
+            Some(Terminator::Goto { bb }) => *bb = map[bb],
+            Some(Terminator::If { then, else_, .. }) => {
+                *then = map[then];
+                *else_ = map[else_];
+            }
+            _ => {}
+        }
+    }
+    cfg.bb = blocks;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn local(i: usize) -> Value {
+        Value::Place(Place::Local(Idx::from_usize(i)))
+    }
+
+    fn binop(l: Value, binop: Binop, r: Value) -> Value {
+        Value::Binop(Box::new(l), binop, Box::new(r))
+    }
+
+    fn assign(i: usize, value: Value) -> Stmt {
+        Stmt::Assign {
+            place: Place::Local(Idx::from_usize(i)),
+            value,
+            origin: None,
+        }
+    }
+
+    fn block(stmts: Vec<Stmt>, terminator: Terminator) -> BasicBlock {
+        BasicBlock {
+            stmts,
+            terminator: Some(terminator),
+        }
+    }
+
+    fn goto(bb: usize) -> Terminator {
+        Terminator::Goto {
+            bb: Idx::from_usize(bb),
+        }
+    }
+
+    fn values(cfg: &Cfg, bb: usize) -> Vec<Value> {
+        let stmts = &cfg.bb[Idx::from_usize(bb)].stmts;
+        stmts
+            .iter()
+            .map(|Stmt::Assign { value, .. }| value.clone())
+            .collect()
+    }
+
+    #[test]
+    fn test_propagate_constants() {
+        let mut cfg = Cfg::default();
+        for ty in [
+            CType::Int(4),
+            CType::Int(4),
+            CType::Int(4),
+            CType::UInt(1),
+            CType::Int(8),
+        ] {
+            cfg.locals.alloc(Local { name: None, ty });
+        }
+        cfg.arg_count = 1;
+        // bb0: _2 = 1717985280; _2 = _2 + 1639; _3 = 200; _3 = _3 + 100;
+        //      if _3 < 50 { goto bb1 } else { goto bb2 }
+        // bb1: _4 = _1 * _2; goto bb3
+        // bb2: _4 = 1; goto bb3
+        // bb3: _0 = _1 + (_4 + _3); if _1 < 10 { goto bb3 } else { goto bb4 }
+        // bb4: _1 = _1 + 1; _0 = _0 + _1; return
+        cfg.bb.alloc(block(
+            vec![
+                assign(2, Value::Literal(1717985280)),
+                assign(2, binop(local(2), Binop::Add, Value::Literal(1639))),
+                assign(3, Value::Literal(200)),
+                assign(3, binop(local(3), Binop::Add, Value::Literal(100))),
+            ],
+            Terminator::If {
+                cond: binop(local(3), Binop::Lt, Value::Literal(50)),
+                then: Idx::from_usize(1),
+                else_: Idx::from_usize(2),
+            },
+        ));
+        cfg.bb.alloc(block(
+            vec![assign(4, binop(local(1), Binop::Mul, local(2)))],
+            goto(3),
+        ));
+        cfg.bb
+            .alloc(block(vec![assign(4, Value::Literal(1))], goto(3)));
+        cfg.bb.alloc(block(
+            vec![assign(
+                0,
+                binop(local(1), Binop::Add, binop(local(4), Binop::Add, local(3))),
+            )],
+            Terminator::If {
+                cond: binop(local(1), Binop::Lt, Value::Literal(10)),
+                then: Idx::from_usize(3),
+                else_: Idx::from_usize(4),
+            },
+        ));
+        cfg.bb.alloc(block(
+            vec![
+                assign(1, binop(local(1), Binop::Add, Value::Literal(1))),
+                assign(0, binop(local(0), Binop::Add, local(1))),
+            ],
+            Terminator::Return,
+        ));
+
+        propagate_constants(&mut cfg);
+        // `u8` wraps: 200 + 100 is 44.
+        assert_eq!(
+            values(&cfg, 0),
+            vec![
+                Value::Literal(1717985280),
+                Value::Literal(1717986919),
+                Value::Literal(200),
+                Value::Literal(44),
+            ]
+        );
+        assert!(
+            matches!(cfg.bb[Idx::from_usize(0)].terminator(), Terminator::Goto { bb } if bb.to_usize() == 1)
+        );
+        // The product is an `int` like `_1`, so `_2` is folded where it is converted
+        // to one.
+        assert_eq!(
+            values(&cfg, 1),
+            vec![binop(local(1), Binop::Mul, Value::Literal(1717986919))]
+        );
+        // Only the edge from bb1 is taken, where `_4` isn't known, but `_3` is.
+        assert_eq!(
+            values(&cfg, 3),
+            vec![binop(
+                local(1),
+                Binop::Add,
+                binop(local(4), Binop::Add, Value::Literal(44))
+            )]
+        );
+        // Nothing is known about the argument.
+        assert_eq!(
+            values(&cfg, 4),
+            vec![
+                binop(local(1), Binop::Add, Value::Literal(1)),
+                binop(local(0), Binop::Add, local(1)),
+            ]
+        );
+
+        remove_unreachable_blocks(&mut cfg);
+        assert_eq!(cfg.bb.iter().count(), 4);
+        let Terminator::If { then, else_, .. } = cfg.bb[Idx::from_usize(2)].terminator() else {
+            panic!("bb2 should be the old bb3");
+        };
+        assert_eq!((then.to_usize(), else_.to_usize()), (2, 3));
+    }
+
+    #[test]
+    fn test_eval() {
+        let mut cfg = Cfg::default();
+        for ty in [CType::Int(4), CType::UInt(4), CType::Int(8), CType::Int(1)] {
+            cfg.locals.alloc(Local { name: None, ty });
+        }
+        let state = State {
+            locals: BTreeMap::from([
+                (Idx::from_usize(1), 0xffff_fff0),
+                (Idx::from_usize(2), i128::from(i64::MIN)),
+                (Idx::from_usize(3), -128),
+            ]),
+        };
+        let eval = |value: &Value| eval(value, &state, &cfg);
+        // Unsigned, so logical and compared as such.
+        assert_eq!(
+            eval(&binop(local(1), Binop::Sar, Value::Literal(4))),
+            Some(0xffff_ffff)
+        );
+        assert_eq!(
+            eval(&binop(local(1), Binop::Lt, Value::Literal(0))),
+            Some(0)
+        );
+        assert_eq!(
+            eval(&binop(local(2), Binop::Div, Value::Literal(-1))),
+            Some(i128::from(i64::MIN))
+        );
+        assert_eq!(
+            eval(&binop(local(3), Binop::Rem, Value::Literal(0))),
+            Some(-128)
+        );
+        assert_eq!(
+            eval(&binop(local(3), Binop::Div, Value::Literal(0))),
+            Some(-1)
+        );
+        // The shift amount is masked to the width.
+        assert_eq!(
+            eval(&binop(local(3), Binop::Shr, Value::Literal(9))),
+            Some(64)
+        );
+        // An unknown right side doesn't matter when the left one decides.
+        assert_eq!(
+            eval(&binop(
+                binop(local(3), Binop::Lt, Value::Literal(0)),
+                Binop::Or,
+                local(0)
+            )),
+            Some(1)
+        );
+        assert_eq!(eval(&binop(local(0), Binop::Add, Value::Literal(1))), None);
+    }
+}
@@ -0,0 +1,478 @@
+// Structuring based on the dominator tree and the loop forest, in the style of the
+// Stackifier ("Beyond Relooper", Ramsey 2022). Unlike `Relooper`, this never falls
+// back to `Dispatch`: for a reducible graph every jump ends up as a fallthrough, a
+// `continue` to an enclosing loop or a `break` out of an enclosing loop or `Block`.
+//
+// A block is placed inside the code of its immediate dominator. If it is the only
+// forward successor of its dominator, it is inlined at the branch. Otherwise it is a
+// merge point and is placed after its dominator, which is wrapped in a `Block` so
+// that the branches can break out to it. The branches of a block join at its
+// immediate post-dominator, which is placed last, and the other merge points are
+// nested inside of it. Loop exits are placed after the loop.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::hash::Hash;
+
+use crate::loopified::{Relooper, StructuredNode};
+use crate::*;
+
+pub struct DominatorStructurer<'a> {
+    cfg: &'a Cfg,
+    // Reverse postorder of the reachable blocks.
+    rpo: Vec<Idx<BasicBlock>>,
+    rpo_index: HashMap<Idx<BasicBlock>, usize>,
+    idom: HashMap<Idx<BasicBlock>, Idx<BasicBlock>>,
+    // Sorted by reverse postorder.
+    dom_children: HashMap<Idx<BasicBlock>, Vec<Idx<BasicBlock>>>,
+    // Number of incoming edges that are not back edges.
+    forward_preds: HashMap<Idx<BasicBlock>, usize>,
+    loop_body: HashMap<Idx<BasicBlock>, HashSet<Idx<BasicBlock>>>,
+    // The header of the innermost loop containing each block. Headers map to themselves.
+    innermost_loop: HashMap<Idx<BasicBlock>, Idx<BasicBlock>>,
+    loop_parent: HashMap<Idx<BasicBlock>, Idx<BasicBlock>>,
+    // See `immediate_post_dominators`.
+    ipdom: HashMap<Idx<BasicBlock>, Idx<BasicBlock>>,
+}
+
+impl<'a> DominatorStructurer<'a> {
+    /// `cfg` must be reducible, see `find_irreducible_regions`.
+    pub fn new(cfg: &'a Cfg, entry: Idx<BasicBlock>) -> Self {
+        let rpo = reverse_postorder(cfg, entry);
+        let rpo_index: HashMap<_, _> = rpo.iter().enumerate().map(|(i, idx)| (*idx, i)).collect();
+        let predecessors = Relooper::compute_predecessors(cfg);
+        let idom = immediate_dominators(&rpo, &rpo_index, &predecessors);
+
+        let mut dom_children: HashMap<_, Vec<_>> = HashMap::new();
+        for &idx in &rpo[1..] {
+            dom_children.entry(idom[&idx]).or_default().push(idx);
+        }
+
+        let dominates = |a: Idx<BasicBlock>, mut b: Idx<BasicBlock>| loop {
+            if a == b {
+                return true;
+            }
+            if b == entry {
+                return false;
+            }
+            b = idom[&b];
+        };
+
+        let mut forward_preds = HashMap::new();
+        let mut loop_body: HashMap<_, HashSet<_>> = HashMap::new();
+        for &idx in &rpo {
+            for succ in Relooper::get_successors_from_block(&cfg.bb[idx]) {
+                if dominates(succ, idx) {
+                    loop_body.entry(succ).or_default().insert(idx);
+                } else {
+                    *forward_preds.entry(succ).or_insert(0) += 1;
+                }
+            }
+        }
+        // Turn the sources of the back edges into natural loops.
+        for (&header, body) in loop_body.iter_mut() {
+            let mut stack: Vec<_> = body.iter().copied().collect();
+            body.insert(header);
+            while let Some(idx) = stack.pop() {
+                if idx == header {
+                    continue;
+                }
+                for &pred in &predecessors[&idx] {
+                    if rpo_index.contains_key(&pred) && body.insert(pred) {
+                        stack.push(pred);
+                    }
+                }
+            }
+        }
+
+        // Loops nest, and an inner header comes after the outer one in reverse postorder.
+        let mut headers: Vec<_> = loop_body.keys().copied().collect();
+        headers.sort_by_key(|idx| rpo_index[idx]);
+        let mut innermost_loop = HashMap::new();
+        let mut loop_parent = HashMap::new();
+        for &header in &headers {
+            if let Some(&parent) = innermost_loop.get(&header) {
+                loop_parent.insert(header, parent);
+            }
+            for &idx in &loop_body[&header] {
+                innermost_loop.insert(idx, header);
+            }
+        }
+
+        let mut structurer = Self {
+            cfg,
+            rpo,
+            rpo_index,
+            idom,
+            dom_children,
+            forward_preds,
+            loop_body,
+            innermost_loop,
+            loop_parent,
+            ipdom: HashMap::new(),
+        };
+        structurer.ipdom = structurer.immediate_post_dominators();
+        structurer
+    }
+
+    // The immediate post-dominator of each block, in the graph without back edges
+    // where returning and jumping out of the innermost loop both go to an exit node,
+    // `None`. Blocks that only the exit post-dominates are left out.
+    fn immediate_post_dominators(&self) -> HashMap<Idx<BasicBlock>, Idx<BasicBlock>> {
+        let mut successors: BTreeMap<_, Vec<_>> = BTreeMap::new();
+        let mut predecessors: BTreeMap<_, Vec<_>> = BTreeMap::new();
+        for &idx in &self.rpo {
+            let mut targets: Vec<_> = Relooper::get_successors_from_block(&self.cfg.bb[idx])
+                .into_iter()
+                .map(|succ| {
+                    let back = self
+                        .loop_body
+                        .get(&succ)
+                        .is_some_and(|body| body.contains(&idx));
+                    (!back && self.in_loops_of(idx, succ)).then_some(succ)
+                })
+                .collect();
+            if targets.is_empty() {
+                targets.push(None);
+            }
+            targets.dedup();
+            for &target in &targets {
+                predecessors.entry(target).or_default().push(Some(idx));
+            }
+            successors.insert(Some(idx), targets);
+        }
+        // Post-dominators are the dominators of the reversed graph, starting at the exit.
+        let order = depth_first_order(None, |node| {
+            predecessors.get(&node).cloned().unwrap_or_default()
+        });
+        let order_index = order
+            .iter()
+            .enumerate()
+            .map(|(i, node)| (*node, i))
+            .collect();
+        immediate_dominators(&order, &order_index, &successors)
+            .into_iter()
+            .filter_map(|(node, ipdom)| Some((node?, ipdom?)))
+            .collect()
+    }
+
+    pub fn structure(&self) -> StructuredNode {
+        self.do_tree(self.rpo[0])
+    }
+
+    // The code for `idx` and everything it dominates.
+    fn do_tree(&self, idx: Idx<BasicBlock>) -> StructuredNode {
+        let code = self.code(idx);
+        let followers = self.merge_children(idx);
+        if !self.loop_body.contains_key(&idx) {
+            return self.place_followers(code, followers);
+        }
+        let body = self.place_followers(code, followers);
+        let mut node = StructuredNode::Loop(Box::new(body));
+        // A plain `break` already reaches the first exit, it doesn't need a `Block`.
+        let mut exits = self.loop_exits(idx);
+        if !exits.is_empty() {
+            let first = exits.remove(0);
+            node = StructuredNode::Sequence(vec![node, self.do_tree(first)]);
+        }
+        self.place_followers(node, exits)
+    }
+
+    fn code(&self, idx: Idx<BasicBlock>) -> StructuredNode {
+        match self.cfg.bb[idx].terminator() {
+            Terminator::Return => StructuredNode::Basic(idx),
+            Terminator::Goto { bb } => {
+                StructuredNode::Sequence(vec![StructuredNode::Basic(idx), self.branch(idx, *bb)])
+            }
+            Terminator::If { cond, then, else_ } => StructuredNode::Sequence(vec![
+                StructuredNode::Basic(idx),
+                StructuredNode::If {
+                    cond: cond.clone(),
+                    then_node: Box::new(self.branch(idx, *then)),
+                    else_node: Box::new(self.branch(idx, *else_)),
+                },
+            ]),
+        }
+    }
+
+    // What runs when `from` jumps to `target`. Either the target is inlined here, or
+    // the jump is left to the terminator and resolves to a fallthrough, break or continue.
+    fn branch(&self, from: Idx<BasicBlock>, target: Idx<BasicBlock>) -> StructuredNode {
+        let inline = self.idom.get(&target) == Some(&from)
+            && self.forward_preds[&target] == 1
+            && self.in_loops_of(from, target);
+        if inline {
+            self.do_tree(target)
+        } else {
+            StructuredNode::Sequence(vec![])
+        }
+    }
+
+    // Blocks immediately dominated by `idx` that are reached from more than one place,
+    // so they can't be inlined into a branch. Loop exits are left to the loop. The
+    // post-dominator of `idx` goes last: every path from `idx` leads to it, so the
+    // other ones are reached before it.
+    fn merge_children(&self, idx: Idx<BasicBlock>) -> Vec<Idx<BasicBlock>> {
+        let mut children: Vec<_> = self
+            .dom_children
+            .get(&idx)
+            .into_iter()
+            .flatten()
+            .copied()
+            .filter(|child| self.forward_preds[child] > 1 && self.in_loops_of(idx, *child))
+            .collect();
+        let merge = self.ipdom.get(&idx);
+        children.sort_by_key(|child| (Some(child) == merge, self.rpo_index[child]));
+        children
+    }
+
+    // Blocks outside of the loop of `header` whose immediate dominator is inside it.
+    // If such a block leaves more than one loop, the outermost loop it leaves places it.
+    fn loop_exits(&self, header: Idx<BasicBlock>) -> Vec<Idx<BasicBlock>> {
+        let body = &self.loop_body[&header];
+        let parent = self.loop_parent.get(&header).map(|p| &self.loop_body[p]);
+        self.rpo[1..]
+            .iter()
+            .copied()
+            .filter(|idx| {
+                !body.contains(idx)
+                    && body.contains(&self.idom[idx])
+                    && parent.is_none_or(|parent| parent.contains(idx))
+            })
+            .collect()
+    }
+
+    // Whether `inner` is inside every loop that contains `outer`.
+    fn in_loops_of(&self, outer: Idx<BasicBlock>, inner: Idx<BasicBlock>) -> bool {
+        match self.innermost_loop.get(&outer) {
+            Some(header) => self.loop_body[header].contains(&inner),
+            None => true,
+        }
+    }
+
+    // Places `followers` after `node`, in reverse postorder. Everything before a
+    // follower goes into a `Block`, so that it can be reached with a break.
+    fn place_followers(
+        &self,
+        node: StructuredNode,
+        followers: Vec<Idx<BasicBlock>>,
+    ) -> StructuredNode {
+        debug_assert!(followers.is_sorted_by_key(|idx| self.rpo_index[idx]));
+        followers.into_iter().fold(node, |node, follower| {
+            StructuredNode::Sequence(vec![
+                StructuredNode::Block(Box::new(node)),
+                self.do_tree(follower),
+            ])
+        })
+    }
+}
+
+/// The blocks reachable from `entry`, in reverse postorder. The edges that go backwards
+/// in it are the back edges of the depth-first search, and every cycle has one.
+pub fn reverse_postorder(cfg: &Cfg, entry: Idx<BasicBlock>) -> Vec<Idx<BasicBlock>> {
+    depth_first_order(entry, |idx| {
+        Relooper::get_successors_from_block(&cfg.bb[idx])
+    })
+}
+
+// The nodes reachable from `entry` in the graph given by `successors`, in reverse
+// postorder.
+fn depth_first_order<N: Copy + Eq + Hash>(entry: N, successors: impl Fn(N) -> Vec<N>) -> Vec<N> {
+    let mut visited = HashSet::from([entry]);
+    let mut postorder = vec![];
+    // Each entry is a node and the successors of it that are still to be visited.
+    let mut stack = vec![(entry, successors(entry))];
+    while let Some((node, succs)) = stack.last_mut() {
+        if succs.is_empty() {
+            postorder.push(*node);
+            stack.pop();
+            continue;
+        }
+        let succ = succs.remove(0);
+        if visited.insert(succ) {
+            stack.push((succ, successors(succ)));
+        }
+    }
+    postorder.reverse();
+    postorder
+}
+
+// "A Simple, Fast Dominance Algorithm", Cooper, Harvey and Kennedy.
+fn immediate_dominators<N: Copy + Ord + Hash>(
+    rpo: &[N],
+    rpo_index: &HashMap<N, usize>,
+    predecessors: &BTreeMap<N, Vec<N>>,
+) -> HashMap<N, N> {
+    let entry = rpo[0];
+    let mut idom = HashMap::from([(entry, entry)]);
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for &idx in &rpo[1..] {
+            let mut new_idom = None;
+            for &pred in &predecessors[&idx] {
+                if !idom.contains_key(&pred) {
+                    continue;
+                }
+                new_idom = Some(match new_idom {
+                    None => pred,
+                    Some(mut a) => {
+                        let mut b = pred;
+                        while a != b {
+                            while rpo_index[&a] > rpo_index[&b] {
+                                a = idom[&a];
+                            }
+                            while rpo_index[&b] > rpo_index[&a] {
+                                b = idom[&b];
+                            }
+                        }
+                        a
+                    }
+                });
+            }
+            let new_idom = new_idom.expect("reachable blocks have a processed predecessor");
+            if idom.insert(idx, new_idom) != Some(new_idom) {
+                changed = true;
+            }
+        }
+    }
+    idom.remove(&entry);
+    idom
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::*;
+
+    fn lt(l: Value, r: Value) -> Value {
+        binop(l, Binop::Lt, r)
+    }
+
+    fn inc(i: usize) -> Stmt {
+        assign(i, binop(local(i), Binop::Add, Value::Literal(1)))
+    }
+
+    // Structures `cfg` and checks that the C for it needs no `goto`.
+    fn structure(cfg: &Cfg) -> StructuredNode {
+        let node = DominatorStructurer::new(cfg, Idx::from_usize(0)).structure();
+        let c = emit_c(cfg, &node, "f");
+        assert!(!c.contains("goto bb"), "{node:?}\n{c}");
+        node
+    }
+
+    #[test]
+    fn test_nested_loops() {
+        // for (_1 = 0; _1 < 10; _1++) for (_2 = 0; _2 < _1; _2++) _0++;
+        let mut cfg = with_locals(vec![CType::Int(4); 3]);
+        cfg.bb
+            .alloc(block(vec![assign(1, Value::Literal(0))], goto(1)));
+        cfg.bb.alloc(block(
+            vec![],
+            branch(lt(local(1), Value::Literal(10)), 2, 6),
+        ));
+        cfg.bb
+            .alloc(block(vec![assign(2, Value::Literal(0))], goto(3)));
+        cfg.bb
+            .alloc(block(vec![], branch(lt(local(2), local(1)), 4, 5)));
+        cfg.bb.alloc(block(vec![inc(0), inc(2)], goto(3)));
+        cfg.bb.alloc(block(vec![inc(1)], goto(1)));
+        cfg.bb.alloc(block(vec![], Terminator::Return));
+
+        let node = structure(&cfg);
+        assert_eq!(loops(&node), [(1, 0), (3, 1)], "{node:?}");
+    }
+
+    #[test]
+    fn test_if_without_else() {
+        // if (_1 < 0) _1 = -_1; _0 = _1;
+        let mut cfg = with_locals(vec![CType::Int(4); 2]);
+        cfg.bb
+            .alloc(block(vec![], branch(lt(local(1), Value::Literal(0)), 1, 2)));
+        cfg.bb.alloc(block(
+            vec![assign(1, binop(Value::Literal(0), Binop::Sub, local(1)))],
+            goto(2),
+        ));
+        cfg.bb
+            .alloc(block(vec![assign(0, local(1))], Terminator::Return));
+
+        // The merge point comes after the `if`, which has nothing in its else branch.
+        let node = structure(&cfg);
+        let StructuredNode::Sequence(nodes) = &node else {
+            panic!("{node:?}");
+        };
+        let [StructuredNode::Block(head), StructuredNode::Basic(merge)] = nodes.as_slice() else {
+            panic!("{node:?}");
+        };
+        assert_eq!(merge.to_usize(), 2);
+        let StructuredNode::Sequence(head) = &**head else {
+            panic!("{node:?}");
+        };
+        let [
+            StructuredNode::Basic(_),
+            StructuredNode::If {
+                then_node,
+                else_node,
+                ..
+            },
+        ] = head.as_slice()
+        else {
+            panic!("{node:?}");
+        };
+        assert_eq!(then_node.entry(), Some(Idx::from_usize(1)));
+        assert!(else_node.is_empty());
+    }
+
+    #[test]
+    fn test_nested_diamonds() {
+        // if (_1) { if (_2) _0 = 1; else _0 = 2; _0++; } else _0 = 3; return;
+        let mut cfg = with_locals(vec![CType::Int(4); 3]);
+        cfg.bb.alloc(block(vec![], branch(local(1), 1, 4)));
+        cfg.bb.alloc(block(vec![], branch(local(2), 2, 3)));
+        cfg.bb
+            .alloc(block(vec![assign(0, Value::Literal(1))], goto(5)));
+        cfg.bb
+            .alloc(block(vec![assign(0, Value::Literal(2))], goto(5)));
+        cfg.bb
+            .alloc(block(vec![assign(0, Value::Literal(3))], goto(6)));
+        cfg.bb.alloc(block(vec![inc(0)], goto(6)));
+        cfg.bb.alloc(block(vec![], Terminator::Return));
+
+        // Each diamond joins at its own post-dominator, the inner one inside the
+        // then branch of the outer one.
+        let structurer = DominatorStructurer::new(&cfg, Idx::from_usize(0));
+        let ipdom = |i| structurer.ipdom[&Idx::from_usize(i)].to_usize();
+        assert_eq!((ipdom(0), ipdom(1), ipdom(2)), (6, 5, 5));
+        let node = structure(&cfg);
+        let mut order = vec![];
+        node.for_each_block(&mut |idx| order.push(idx.to_usize()));
+        assert_eq!(order, [0, 1, 2, 3, 5, 4, 6]);
+    }
+
+    #[test]
+    fn test_multi_exit_loop() {
+        // while (_1 < 10) { if (_2 == 0) { _0 = 2; goto done; } _1++; } _0 = 1; done:
+        let mut cfg = with_locals(vec![CType::Int(4); 3]);
+        cfg.bb.alloc(block(vec![], goto(1)));
+        cfg.bb.alloc(block(
+            vec![],
+            branch(lt(local(1), Value::Literal(10)), 2, 4),
+        ));
+        cfg.bb.alloc(block(
+            vec![],
+            branch(binop(local(2), Binop::Eq, Value::Literal(0)), 5, 3),
+        ));
+        cfg.bb.alloc(block(vec![inc(1)], goto(1)));
+        cfg.bb
+            .alloc(block(vec![assign(0, Value::Literal(1))], goto(6)));
+        cfg.bb
+            .alloc(block(vec![assign(0, Value::Literal(2))], goto(6)));
+        cfg.bb.alloc(block(vec![], Terminator::Return));
+
+        // Both exits are placed after the loop, the second one behind a `Block`.
+        let node = structure(&cfg);
+        assert_eq!(loops(&node), [(1, 0)], "{node:?}");
+        let mut order = vec![];
+        node.for_each_block(&mut |idx| order.push(idx.to_usize()));
+        assert_eq!(order, [0, 1, 2, 3, 4, 5, 6]);
+    }
+}
@@ -0,0 +1,592 @@
+// Simplification of values by equality saturation. Instead of rewriting a value into
+// the first thing a rule gives, every value a rule gives is added to an e-graph of
+// classes of equal values, until no rule adds anything. The most readable value of
+// the class of the original one is then taken out, by a `CostModel`.
+//
+// Every value of a class has the same type, which rules keep. Literals take the type
+// of what they are used with instead, so a literal is in a class of its own, which
+// rules don't add to because a left side can't match a literal and a right side
+// can't be one. Operations on literals are folded where that doesn't change types:
+// into right operands, and at the top of a value.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::ranges::{is_condition, value_ty};
+use crate::rules::{Pattern, Rule, TypeClass, fold_ints};
+use crate::{Binop, CType, Cfg, Place, Stmt, Terminator, Value};
+
+/// How unreadable a value is, as a weighted sum. Weights are per operator, per bit of
+/// the magnitude of each literal, per conversion of an operand to the type of its
+/// operation and per level of nesting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CostModel {
+    pub operator: u64,
+    pub constant_bit: u64,
+    pub cast: u64,
+    pub depth: u64,
+}
+
+impl Default for CostModel {
+    fn default() -> Self {
+        CostModel {
+            operator: 4,
+            constant_bit: 1,
+            cast: 3,
+            depth: 1,
+        }
+    }
+}
+
+/// What `CostModel` weighs, for a value.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Cost {
+    pub operators: u64,
+    pub constant_bits: u64,
+    pub casts: u64,
+    pub depth: u64,
+}
+
+impl CostModel {
+    pub fn score(&self, cost: &Cost) -> u64 {
+        self.operator * cost.operators
+            + self.constant_bit * cost.constant_bits
+            + self.cast * cost.casts
+            + self.depth * cost.depth
+    }
+}
+
+// Saturation stops early on values that keep growing.
+const MAX_ITERATIONS: usize = 16;
+const MAX_NODES: usize = 4096;
+
+type Id = usize;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Node {
+    Literal(i32),
+    /// The place of that index in `EGraph::places`.
+    Place(usize),
+    Binop(Id, Binop, Id),
+    /// The condition, the value if it holds and the value if it doesn't. Rules only
+    /// match inside its operands.
+    Select(Id, Id, Id),
+}
+
+#[derive(Debug)]
+struct Class {
+    nodes: Vec<Node>,
+    ty: CType,
+    /// The value of the class, if it is computed from literals in `int`.
+    constant: Option<i32>,
+}
+
+struct EGraph<'a> {
+    cfg: &'a Cfg,
+    /// Union-find of the classes, merged ones pointing to where they were merged.
+    parents: Vec<Id>,
+    classes: Vec<Class>,
+    memo: HashMap<Node, Id>,
+    places: Vec<Place>,
+}
+
+impl<'a> EGraph<'a> {
+    fn new(cfg: &'a Cfg) -> Self {
+        EGraph {
+            cfg,
+            parents: vec![],
+            classes: vec![],
+            memo: HashMap::new(),
+            places: vec![],
+        }
+    }
+
+    fn find(&self, mut id: Id) -> Id {
+        while self.parents[id] != id {
+            id = self.parents[id];
+        }
+        id
+    }
+
+    fn canonical(&self, node: &Node) -> Node {
+        match node {
+            Node::Binop(l, binop, r) => Node::Binop(self.find(*l), *binop, self.find(*r)),
+            Node::Select(cond, then, else_) => {
+                Node::Select(self.find(*cond), self.find(*then), self.find(*else_))
+            }
+            _ => node.clone(),
+        }
+    }
+
+    fn is_literal(&self, id: Id) -> bool {
+        matches!(self.classes[self.find(id)].nodes[..], [Node::Literal(_)])
+    }
+
+    fn class(&self, id: Id) -> &Class {
+        &self.classes[self.find(id)]
+    }
+
+    // Like `operand_ty`.
+    fn operand_ty(&self, l: Id, r: Id) -> CType {
+        let typed = if self.is_literal(l) { r } else { l };
+        self.class(typed).ty.clone()
+    }
+
+    fn add(&mut self, node: Node) -> Id {
+        let node = self.canonical(&node);
+        if let Some(id) = self.memo.get(&node) {
+            return self.find(*id);
+        }
+        let (ty, constant) = match &node {
+            Node::Literal(i) => (CType::Int(4), Some(*i)),
+            Node::Place(place) => (Value::Place(self.places[*place].clone()).ty(self.cfg), None),
+            Node::Binop(l, binop, r) => {
+                let ty = match self.operand_ty(*l, *r) {
+                    _ if is_condition(*binop) => CType::Bool,
+                    CType::Bool => CType::Int(4),
+                    ty => ty,
+                };
+                (ty, self.fold(*l, *binop, *r))
+            }
+            Node::Select(_, then, else_) => (self.operand_ty(*then, *else_), None),
+        };
+        let id = self.classes.len();
+        self.parents.push(id);
+        self.classes.push(Class {
+            nodes: vec![node.clone()],
+            ty,
+            constant,
+        });
+        self.memo.insert(node, id);
+        id
+    }
+
+    fn fold(&self, l: Id, binop: Binop, r: Id) -> Option<i32> {
+        fold_ints(self.class(l).constant?, binop, self.class(r).constant?)
+    }
+
+    fn add_value(&mut self, value: &Value) -> Id {
+        match value {
+            Value::Literal(i) => self.add(Node::Literal(*i)),
+            Value::Place(place) => {
+                let index = match self.places.iter().position(|known| known == place) {
+                    Some(index) => index,
+                    None => {
+                        self.places.push(place.clone());
+                        self.places.len() - 1
+                    }
+                };
+                self.add(Node::Place(index))
+            }
+            Value::Binop(l, binop, r) => {
+                let (l, r) = (self.add_value(l), self.add_value(r));
+                self.add(Node::Binop(l, *binop, r))
+            }
+            Value::Select(cond, then, else_) => {
+                let cond = self.add_value(cond);
+                let (then, else_) = (self.add_value(then), self.add_value(else_));
+                self.add(Node::Select(cond, then, else_))
+            }
+        }
+    }
+
+    fn add_pattern(&mut self, pattern: &Pattern, bindings: &[Id]) -> Id {
+        match pattern {
+            Pattern::Var(var) => bindings[*var],
+            Pattern::Literal(i) => self.add(Node::Literal(*i)),
+            Pattern::Binop(l, binop, r) => {
+                let (l, r) = (self.add_pattern(l, bindings), self.add_pattern(r, bindings));
+                self.add(Node::Binop(l, *binop, r))
+            }
+        }
+    }
+
+    // Whether anything changed. Classes of different types are left apart, which
+    // proven rules never ask for.
+    fn union(&mut self, a: Id, b: Id) -> bool {
+        let (a, b) = (self.find(a), self.find(b));
+        if a == b || self.classes[a].ty != self.classes[b].ty {
+            return false;
+        }
+        self.parents[b] = a;
+        let nodes = std::mem::take(&mut self.classes[b].nodes);
+        self.classes[a].nodes.extend(nodes);
+        self.classes[a].constant = self.classes[a].constant.or(self.classes[b].constant);
+        true
+    }
+
+    // Merges the classes that became the same because their operands did, until
+    // there are none.
+    fn rebuild(&mut self) {
+        loop {
+            let mut merged = false;
+            self.memo.clear();
+            for id in 0..self.classes.len() {
+                if self.find(id) != id {
+                    continue;
+                }
+                let mut nodes: Vec<Node> = self.classes[id]
+                    .nodes
+                    .iter()
+                    .map(|node| self.canonical(node))
+                    .collect();
+                let mut seen = HashSet::new();
+                nodes.retain(|node| seen.insert(node.clone()));
+                let constant = nodes.iter().find_map(|node| match node {
+                    Node::Binop(l, binop, r) => self.fold(*l, *binop, *r),
+                    _ => None,
+                });
+                let class = &mut self.classes[id];
+                class.constant = class.constant.or(constant);
+                class.nodes = nodes;
+                for node in self.classes[id].nodes.clone() {
+                    match self.memo.get(&node) {
+                        Some(other) => merged |= self.union(*other, id),
+                        None => {
+                            self.memo.insert(node, id);
+                        }
+                    }
+                }
+            }
+            if !merged {
+                return;
+            }
+        }
+    }
+
+    fn node_count(&self) -> usize {
+        self.classes.iter().map(|class| class.nodes.len()).sum()
+    }
+
+    // The bindings by which `pattern` matches the class `id`, one per way it does.
+    fn search(
+        &self,
+        rule: &Rule,
+        pattern: &Pattern,
+        id: Id,
+        bindings: Vec<Option<Id>>,
+    ) -> Vec<Vec<Option<Id>>> {
+        let id = self.find(id);
+        match pattern {
+            Pattern::Var(var) => {
+                if let Some(bound) = bindings[*var] {
+                    return if self.find(bound) == id {
+                        vec![bindings]
+                    } else {
+                        vec![]
+                    };
+                }
+                let matches = match &rule.vars[*var].class {
+                    TypeClass::Const => self.is_literal(id),
+                    _ if self.is_literal(id) => false,
+                    class => class.types().contains(&self.classes[id].ty),
+                };
+                if !matches {
+                    return vec![];
+                }
+                let mut bindings = bindings;
+                bindings[*var] = Some(id);
+                vec![bindings]
+            }
+            Pattern::Literal(i) => {
+                if self.classes[id].nodes.contains(&Node::Literal(*i)) {
+                    vec![bindings]
+                } else {
+                    vec![]
+                }
+            }
+            Pattern::Binop(pl, pattern_binop, pr) => {
+                let mut found = vec![];
+                for node in &self.classes[id].nodes {
+                    let Node::Binop(l, binop, r) = node else {
+                        continue;
+                    };
+                    if binop != pattern_binop {
+                        continue;
+                    }
+                    for bindings in self.search(rule, pl, *l, bindings.clone()) {
+                        found.extend(self.search(rule, pr, *r, bindings));
+                    }
+                }
+                found
+            }
+        }
+    }
+
+    // Applies every rule wherever it matches, and folds operations on literals into
+    // right operands. Whether anything changed.
+    fn step(&mut self, rules: &[Rule]) -> bool {
+        let mut unions = vec![];
+        for id in 0..self.classes.len() {
+            if self.find(id) != id {
+                continue;
+            }
+            for rule in rules {
+                for bindings in self.search(rule, &rule.lhs, id, vec![None; rule.vars.len()]) {
+                    // Every metavariable is on the left side.
+                    let bindings: Vec<Id> = bindings.into_iter().map(Option::unwrap).collect();
+                    let types: Vec<CType> = bindings
+                        .iter()
+                        .map(|id| self.class(*id).ty.clone())
+                        .collect();
+                    if rule.allows(&types) {
+                        unions.push((id, Ok((rule, bindings))));
+                    }
+                }
+            }
+            for node in &self.classes[id].nodes {
+                let Node::Binop(l, binop, r) = node else {
+                    continue;
+                };
+                if let (false, Some(constant)) = (self.is_literal(*r), self.class(*r).constant) {
+                    unions.push((id, Err((*l, *binop, constant))));
+                }
+            }
+        }
+        let mut changed = false;
+        for (id, rewrite) in unions {
+            let rewritten = match rewrite {
+                Ok((rule, bindings)) => self.add_pattern(&rule.rhs, &bindings),
+                Err((l, binop, constant)) => {
+                    let r = self.add(Node::Literal(constant));
+                    self.add(Node::Binop(l, binop, r))
+                }
+            };
+            changed |= self.union(id, rewritten);
+        }
+        self.rebuild();
+        changed
+    }
+
+    // The cheapest value of every class, as the node it is made of.
+    fn costs(&self, model: &CostModel) -> Vec<Option<(Cost, Node)>> {
+        let mut best: Vec<Option<(Cost, Node)>> = vec![None; self.classes.len()];
+        // Comparing operators as well makes sure a class is never cheaper than the
+        // classes its value is made of, which could otherwise form a cycle.
+        let key = |cost: &Cost| (model.score(cost), cost.operators);
+        loop {
+            let mut changed = false;
+            for id in 0..self.classes.len() {
+                if self.find(id) != id {
+                    continue;
+                }
+                for node in &self.classes[id].nodes {
+                    let Some(cost) = self.node_cost(node, &best) else {
+                        continue;
+                    };
+                    if best[id]
+                        .as_ref()
+                        .is_none_or(|(known, _)| key(&cost) < key(known))
+                    {
+                        best[id] = Some((cost, node.clone()));
+                        changed = true;
+                    }
+                }
+            }
+            if !changed {
+                return best;
+            }
+        }
+    }
+
+    fn node_cost(&self, node: &Node, best: &[Option<(Cost, Node)>]) -> Option<Cost> {
+        match node {
+            Node::Literal(i) => Some(Cost {
+                constant_bits: u64::from(32 - i.unsigned_abs().leading_zeros()),
+                ..Cost::default()
+            }),
+            Node::Place(_) => Some(Cost::default()),
+            Node::Binop(l, _, r) => self.operation_cost(None, *l, *r, best),
+            Node::Select(cond, then, else_) => {
+                self.operation_cost(Some(*cond), *then, *else_, best)
+            }
+        }
+    }
+
+    // The cost of an operation on `l` and `r`, which are converted to the same type,
+    // and on `cond` if it is a select.
+    fn operation_cost(
+        &self,
+        cond: Option<Id>,
+        l: Id,
+        r: Id,
+        best: &[Option<(Cost, Node)>],
+    ) -> Option<Cost> {
+        let (l_cost, _) = best[self.find(l)].as_ref()?;
+        let (r_cost, _) = best[self.find(r)].as_ref()?;
+        let cond_cost = match cond {
+            Some(cond) => best[self.find(cond)].as_ref()?.0,
+            None => Cost::default(),
+        };
+        let ty = self.operand_ty(l, r);
+        let casts = [l, r]
+            .iter()
+            .filter(|id| !self.is_literal(**id) && self.class(**id).ty != ty)
+            .count();
+        Some(Cost {
+            operators: cond_cost.operators + l_cost.operators + r_cost.operators + 1,
+            constant_bits: cond_cost.constant_bits + l_cost.constant_bits + r_cost.constant_bits,
+            casts: cond_cost.casts + l_cost.casts + r_cost.casts + casts as u64,
+            depth: cond_cost.depth.max(l_cost.depth).max(r_cost.depth) + 1,
+        })
+    }
+
+    fn extract(&self, id: Id, best: &[Option<(Cost, Node)>]) -> Value {
+        // Every class has a value, the one it was made from if nothing else.
+        let (_, node) = best[self.find(id)].as_ref().unwrap();
+        match node {
+            Node::Literal(i) => Value::Literal(*i),
+            Node::Place(place) => Value::Place(self.places[*place].clone()),
+            Node::Binop(l, binop, r) => Value::Binop(
+                Box::new(self.extract(*l, best)),
+                *binop,
+                Box::new(self.extract(*r, best)),
+            ),
+            Node::Select(cond, then, else_) => Value::Select(
+                Box::new(self.extract(*cond, best)),
+                Box::new(self.extract(*then, best)),
+                Box::new(self.extract(*else_, best)),
+            ),
+        }
+    }
+}
+
+/// The most readable value equal to `value` by `rules`.
+pub fn simplify(value: &Value, rules: &[Rule], cfg: &Cfg, model: &CostModel) -> Value {
+    let value = simplify_places(value, rules, cfg, model);
+    let mut egraph = EGraph::new(cfg);
+    let root = egraph.add_value(&value);
+    for _ in 0..MAX_ITERATIONS {
+        if !egraph.step(rules) || egraph.node_count() > MAX_NODES {
+            break;
+        }
+    }
+    // At the top, a literal is converted like the `int` operation it replaces.
+    if let Some(constant) = egraph.class(root).constant {
+        return Value::Literal(constant);
+    }
+    let best = egraph.costs(model);
+    let simplified = egraph.extract(root, &best);
+    debug_assert_eq!(value_ty(&simplified, cfg), value_ty(&value, cfg));
+    simplified
+}
+
+// The offsets of the places in `value` simplified, each by itself.
+fn simplify_places(value: &Value, rules: &[Rule], cfg: &Cfg, model: &CostModel) -> Value {
+    match value {
+        Value::Literal(_) => value.clone(),
+        Value::Place(place) => Value::Place(simplify_place(place, rules, cfg, model)),
+        Value::Binop(l, binop, r) => Value::Binop(
+            Box::new(simplify_places(l, rules, cfg, model)),
+            *binop,
+            Box::new(simplify_places(r, rules, cfg, model)),
+        ),
+        Value::Select(cond, then, else_) => Value::Select(
+            Box::new(simplify_places(cond, rules, cfg, model)),
+            Box::new(simplify_places(then, rules, cfg, model)),
+            Box::new(simplify_places(else_, rules, cfg, model)),
+        ),
+    }
+}
+
+fn simplify_place(place: &Place, rules: &[Rule], cfg: &Cfg, model: &CostModel) -> Place {
+    match place {
+        Place::Local(_) => place.clone(),
+        Place::Deref(inner) => Place::Deref(Box::new(simplify_place(inner, rules, cfg, model))),
+        Place::Offset(inner, offset) => Place::Offset(
+            Box::new(simplify_place(inner, rules, cfg, model)),
+            Box::new(simplify(offset, rules, cfg, model)),
+        ),
+    }
+}
+
+/// Simplifies the values of every statement and condition of `cfg`.
+pub fn simplify_values(cfg: &mut Cfg, rules: &[Rule], model: &CostModel) {
+    let bbs: Vec<_> = cfg.bb.iter().map(|(bb, _)| bb).collect();
+    for bb in bbs {
+        let mut block = std::mem::take(&mut cfg.bb[bb]);
+        for stmt in &mut block.stmts {
+            let Stmt::Assign { place, value, .. } = stmt;
+            *place = simplify_place(place, rules, cfg, model);
+            *value = simplify(value, rules, cfg, model);
+        }
+        if let Some(Terminator::If { cond, .. }) = &mut block.terminator {
+            *cond = simplify(cond, rules, cfg, model);
+        }
+        cfg.bb[bb] = block;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Idx, Local};
+
+    fn local(i: usize) -> Value {
+        Value::Place(Place::Local(Idx::from_usize(i)))
+    }
+
+    fn binop(l: Value, binop: Binop, r: Value) -> Value {
+        Value::Binop(Box::new(l), binop, Box::new(r))
+    }
+
+    #[test]
+    fn test_simplify() {
+        // Both directions of `shl2`, which a greedy rewrite couldn't have.
+        let rules = Rule::parse_all(
+            "shl2: x << 2 => x * 4 for x: int\n\
+             mul4: x * 4 => x << 2 for x: int\n\
+             mul_add: x * a + x => x * (a + 1) for x: int, a: const if width(x) <= 32\n\
+             mul_mul: x * a * b => x * (a * b) for x: int, a: const, b: const if width(x) <= 32",
+        )
+        .unwrap();
+        let mut cfg = Cfg::default();
+        for ty in [CType::Int(4), CType::Int(4), CType::Int(8)] {
+            cfg.locals.alloc(Local { name: None, ty });
+        }
+        let model = CostModel::default();
+        // `a4 = a5 << 2; a4 = a4 + a5; a4 = a4 * 2`, substituted.
+        let times_ten = |x: Value| {
+            let times_five = binop(
+                binop(x.clone(), Binop::Shl, Value::Literal(2)),
+                Binop::Add,
+                x,
+            );
+            binop(times_five, Binop::Mul, Value::Literal(2))
+        };
+        assert_eq!(
+            simplify(&times_ten(local(1)), &rules, &cfg, &model),
+            binop(local(1), Binop::Mul, Value::Literal(10))
+        );
+        // `mul_add` and `mul_mul` don't claim `i64`. The shift is as readable as the
+        // multiplication but has a smaller constant.
+        assert_eq!(
+            simplify(&times_ten(local(2)), &rules, &cfg, &model),
+            times_ten(local(2))
+        );
+        let times_four = binop(local(1), Binop::Mul, Value::Literal(4));
+        assert_eq!(
+            simplify(&times_four, &rules, &cfg, &model),
+            binop(local(1), Binop::Shl, Value::Literal(2))
+        );
+        let constants_cheap = CostModel {
+            constant_bit: 0,
+            depth: 0,
+            ..model
+        };
+        assert_eq!(
+            simplify(&times_four, &rules, &cfg, &constants_cheap),
+            times_four
+        );
+
+        // Operations on literals are folded at the top, but not where they decide the
+        // type of an operation.
+        let sum = binop(Value::Literal(2), Binop::Add, Value::Literal(3));
+        assert_eq!(simplify(&sum, &rules, &cfg, &model), Value::Literal(5));
+        let typed = binop(sum.clone(), Binop::Mul, local(2));
+        assert_eq!(simplify(&typed, &rules, &cfg, &model), typed);
+        assert_eq!(
+            simplify(&binop(local(2), Binop::Mul, sum), &rules, &cfg, &model),
+            binop(local(2), Binop::Mul, Value::Literal(5))
+        );
+    }
+}
@@ -0,0 +1,147 @@
+// The meaning of integer values. Values are kept in their own type, as `wrap` leaves
+// them: signed types as themselves, unsigned ones as non-negative numbers, except
+// that 128-bit unsigned values are their bits. Whatever computes values on concrete
+// integers goes through here, so that constant folding, the value ranges, rewrite
+// rules and synthesis can't disagree about what the IR means. Floats, pointers and
+// memory aren't computed.
+
+use crate::ranges::{is_condition, operand_ty, value_ty};
+use crate::*;
+
+/// `i` converted to the integer type `ty`, wrapping around at its width. `bool` is 1
+/// for anything but 0.
+pub fn wrap(i: i128, ty: &CType) -> i128 {
+    match ty {
+        CType::Bool => (i != 0) as i128,
+        CType::Int(bytes) => {
+            let unused = 128 - u32::from(*bytes) * 8;
+            (i << unused) >> unused
+        }
+        CType::UInt(bytes) => {
+            let unused = 128 - u32::from(*bytes) * 8;
+            (((i as u128) << unused) >> unused) as i128
+        }
+        _ => panic!("{ty} is not an integer type"),
+    }
+}
+
+pub fn is_integer(ty: &CType) -> bool {
+    matches!(ty, CType::Int(_) | CType::UInt(_) | CType::Bool)
+}
+
+/// `l binop r` for operands of the integer type `ty`, in the type of the result,
+/// which is `bool` for comparisons and `ty` otherwise.
+pub fn binop(l: i128, binop: Binop, r: i128, ty: &CType) -> i128 {
+    let bytes = match ty {
+        CType::Int(bytes) | CType::UInt(bytes) => *bytes,
+        CType::Bool => 1,
+        _ => panic!("{ty} is not an integer type"),
+    };
+    let (l, r) = (wrap(l, ty), wrap(r, ty));
+    let signed = matches!(ty, CType::Int(_));
+    let amount = (r & i128::from(bytes * 8 - 1)) as u32;
+    let (ul, ur) = (l as u128, r as u128);
+    let result = match binop {
+        Binop::Add => l.wrapping_add(r),
+        Binop::Sub => l.wrapping_sub(r),
+        Binop::Mul => l.wrapping_mul(r),
+        Binop::Div if r == 0 => -1,
+        Binop::Div if signed => l.wrapping_div(r),
+        Binop::Div => (ul / ur) as i128,
+        Binop::Rem if r == 0 => l,
+        Binop::Rem if signed => l.wrapping_rem(r),
+        Binop::Rem => (ul % ur) as i128,
+        Binop::Shl => l << amount,
+        Binop::Shr => ((wrap(l, &CType::UInt(bytes)) as u128) >> amount) as i128,
+        // Unsigned values are shifted as if the top bit were the sign.
+        Binop::Sar => wrap(l, &CType::Int(bytes)) >> amount,
+        Binop::Xor => l ^ r,
+        Binop::Lt if signed => (l < r).into(),
+        Binop::Lt => (ul < ur).into(),
+        Binop::Le if signed => (l <= r).into(),
+        Binop::Le => (ul <= ur).into(),
+        Binop::Eq => (l == r).into(),
+        Binop::Ne => (l != r).into(),
+        Binop::And => (l != 0 && r != 0).into(),
+        Binop::Or => (l != 0 || r != 0).into(),
+    };
+    match is_condition(binop) {
+        true => result,
+        false => wrap(result, ty),
+    }
+}
+
+/// The value of `value` in its own type, where `local` gives the values of the
+/// locals that are known. `None` if it needs one that isn't, or anything that isn't
+/// an integer.
+pub fn value(
+    value: &Value,
+    cfg: &Cfg,
+    local: &impl Fn(Idx<Local>) -> Option<i128>,
+) -> Option<i128> {
+    let eval = |value| self::value(value, cfg, local);
+    match value {
+        Value::Literal(i) => Some((*i).into()),
+        Value::Place(Place::Local(idx)) if is_integer(&cfg.locals[*idx].ty) => {
+            Some(wrap(local(*idx)?, &cfg.locals[*idx].ty))
+        }
+        Value::Place(_) => None,
+        // The right side doesn't matter when the left one decides.
+        Value::Binop(l, op @ (Binop::And | Binop::Or), r) => {
+            let l = eval(l)?;
+            match (l != 0) == (*op == Binop::Or) {
+                true => Some((l != 0).into()),
+                false => Some(binop(l, *op, eval(r)?, &CType::Bool)),
+            }
+        }
+        Value::Binop(l, op, r) => {
+            let ty = match is_condition(*op) {
+                true => operand_ty(l, r, cfg),
+                false => value_ty(value, cfg),
+            };
+            if !is_integer(&ty) {
+                return None;
+            }
+            Some(binop(eval(l)?, *op, eval(r)?, &ty))
+        }
+        Value::Select(cond, then, else_) => {
+            let ty = value_ty(value, cfg);
+            if !is_integer(&ty) {
+                return None;
+            }
+            let chosen = if eval(cond)? != 0 { then } else { else_ };
+            Some(wrap(eval(chosen)?, &ty))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use CType::{Int, UInt};
+
+    #[test]
+    fn test_binop() {
+        // Dividing by zero, and `MIN / -1`, like on RISC-V.
+        assert_eq!(binop(7, Binop::Div, 0, &Int(4)), -1);
+        assert_eq!(binop(7, Binop::Div, 0, &UInt(2)), 0xffff);
+        assert_eq!(binop(7, Binop::Rem, 0, &UInt(2)), 7);
+        let min = i32::MIN.into();
+        assert_eq!(binop(min, Binop::Div, -1, &Int(4)), min);
+        assert_eq!(binop(min, Binop::Rem, -1, &Int(4)), 0);
+        assert_eq!(binop(-7, Binop::Rem, 2, &Int(1)), -1);
+        // The shift amount is masked, and `Sar` takes the top bit as the sign.
+        assert_eq!(binop(1, Binop::Shl, 33, &Int(4)), 2);
+        assert_eq!(binop(0x80, Binop::Sar, 4, &UInt(1)), 0xf8);
+        assert_eq!(binop(-128, Binop::Shr, 4, &Int(1)), 8);
+        // Operands are converted to the type first.
+        assert_eq!(binop(-1, Binop::Lt, 1, &UInt(4)), 0);
+        assert_eq!(binop(300, Binop::Eq, 44, &Int(1)), 1);
+        assert_eq!(binop(i32::MAX.into(), Binop::Add, 1, &Int(4)), min);
+        // 128-bit unsigned values are their bits.
+        let max = u128::MAX as i128;
+        assert_eq!(binop(max, Binop::Shr, 127, &UInt(16)), 1);
+        assert_eq!(binop(1, Binop::Lt, max, &UInt(16)), 1);
+        assert_eq!(binop(max, Binop::Div, 2, &UInt(16)), i128::MAX);
+    }
+}
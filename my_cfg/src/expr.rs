@@ -0,0 +1,608 @@
+// Printing of values as C or Rust expressions. Values are first turned into an
+// `Expr`, which has the shape of the output, and then printed with only the
+// parentheses that precedence and associativity need.
+
+use crate::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Syntax {
+    C,
+    Rust,
+}
+
+/// A binary operator as it is printed, which includes some that the IR doesn't have.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operator {
+    Mul,
+    Div,
+    Rem,
+    Add,
+    Sub,
+    Shl,
+    /// `>>`, which shifts in zeros or the sign bit depending on the type of the
+    /// left side.
+    Shr,
+    Lt,
+    Le,
+    Eq,
+    Ne,
+    BitAnd,
+    BitXor,
+    And,
+    Or,
+}
+
+impl From<Binop> for Operator {
+    fn from(binop: Binop) -> Self {
+        match binop {
+            Binop::Add => Operator::Add,
+            Binop::Sub => Operator::Sub,
+            Binop::Mul => Operator::Mul,
+            Binop::Div => Operator::Div,
+            Binop::Rem => Operator::Rem,
+            Binop::Shl => Operator::Shl,
+            Binop::Shr | Binop::Sar => Operator::Shr,
+            Binop::Xor => Operator::BitXor,
+            Binop::Lt => Operator::Lt,
+            Binop::Le => Operator::Le,
+            Binop::Eq => Operator::Eq,
+            Binop::Ne => Operator::Ne,
+            Binop::And => Operator::And,
+            Binop::Or => Operator::Or,
+        }
+    }
+}
+
+impl std::fmt::Display for Operator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let token = match self {
+            Operator::Mul => "*",
+            Operator::Div => "/",
+            Operator::Rem => "%",
+            Operator::Add => "+",
+            Operator::Sub => "-",
+            Operator::Shl => "<<",
+            Operator::Shr => ">>",
+            Operator::Lt => "<",
+            Operator::Le => "<=",
+            Operator::Eq => "==",
+            Operator::Ne => "!=",
+            Operator::BitAnd => "&",
+            Operator::BitXor => "^",
+            Operator::And => "&&",
+            Operator::Or => "||",
+        };
+        write!(f, "{token}")
+    }
+}
+
+/// An expression in the form it is printed in.
+#[derive(Debug, Clone)]
+pub enum Expr {
+    /// Printed as is, e.g. a name or a non-negative literal.
+    Atom(String),
+    Binary(Box<Expr>, Operator, Box<Expr>),
+    /// `*x`, `-x`, `!x` or `~x`.
+    Prefix(&'static str, Box<Expr>),
+    /// `x[i]`.
+    Index(Box<Expr>, Box<Expr>),
+    /// `x.name(args)`.
+    Method(Box<Expr>, String, Vec<Expr>),
+    /// `(T)x` in C and `x as T` in Rust.
+    Cast(Box<Expr>, String),
+    /// `c ? x : y` in C and `if c { x } else { y }` in Rust.
+    Conditional(Box<Expr>, Box<Expr>, Box<Expr>),
+}
+
+// Precedence levels, higher binds tighter.
+const POSTFIX: u8 = 16;
+const PREFIX: u8 = 15;
+const RUST_AS: u8 = 14;
+
+const CONDITIONAL: u8 = 3;
+
+fn precedence(operator: Operator, syntax: Syntax) -> u8 {
+    match (operator, syntax) {
+        (Operator::Mul | Operator::Div | Operator::Rem, _) => 13,
+        (Operator::Add | Operator::Sub, _) => 12,
+        (Operator::Shl | Operator::Shr, _) => 11,
+        (Operator::Lt | Operator::Le, Syntax::C) => 10,
+        (Operator::Eq | Operator::Ne, Syntax::C) => 9,
+        (Operator::BitAnd, Syntax::C) => 8,
+        (Operator::BitXor, Syntax::C) => 7,
+        (Operator::BitAnd, Syntax::Rust) => 10,
+        (Operator::BitXor, Syntax::Rust) => 9,
+        (Operator::Lt | Operator::Le | Operator::Eq | Operator::Ne, Syntax::Rust) => 8,
+        (Operator::And, _) => 5,
+        (Operator::Or, _) => 4,
+    }
+}
+
+impl Expr {
+    pub fn binary(l: Expr, operator: impl Into<Operator>, r: Expr) -> Expr {
+        Expr::Binary(Box::new(l), operator.into(), Box::new(r))
+    }
+
+    pub fn conditional(cond: Expr, then: Expr, else_: Expr) -> Expr {
+        Expr::Conditional(Box::new(cond), Box::new(then), Box::new(else_))
+    }
+
+    pub fn prefix(op: &'static str, e: Expr) -> Expr {
+        Expr::Prefix(op, Box::new(e))
+    }
+
+    pub fn cast(e: Expr, ty: impl Into<String>) -> Expr {
+        Expr::Cast(Box::new(e), ty.into())
+    }
+
+    pub fn method(receiver: Expr, name: impl Into<String>, args: Vec<Expr>) -> Expr {
+        Expr::Method(Box::new(receiver), name.into(), args)
+    }
+
+    fn precedence(&self, syntax: Syntax) -> u8 {
+        match self {
+            Expr::Atom(_) | Expr::Index(..) | Expr::Method(..) => POSTFIX,
+            Expr::Prefix(..) => PREFIX,
+            Expr::Cast(..) => match syntax {
+                Syntax::C => PREFIX,
+                Syntax::Rust => RUST_AS,
+            },
+            Expr::Binary(_, operator, _) => precedence(*operator, syntax),
+            Expr::Conditional(..) => CONDITIONAL,
+        }
+    }
+
+    // Whether the Rust form ends with `as T`, as in `x ^ y as T`.
+    fn ends_with_cast(&self) -> bool {
+        match self {
+            Expr::Cast(..) => true,
+            Expr::Binary(_, _, r) => r.ends_with_cast(),
+            _ => false,
+        }
+    }
+
+    pub fn print(&self, syntax: Syntax) -> String {
+        self.print_at(syntax, 0)
+    }
+
+    // Prints `self` where an expression of at least `min` precedence is expected.
+    fn print_at(&self, syntax: Syntax, min: u8) -> String {
+        let precedence = self.precedence(syntax);
+        let s = match self {
+            Expr::Atom(s) => s.clone(),
+            Expr::Binary(l, operator, r) => {
+                // Everything is left associative, except that comparisons don't
+                // chain: Rust rejects that and C compilers warn about it.
+                let comparison = matches!(
+                    operator,
+                    Operator::Lt | Operator::Le | Operator::Eq | Operator::Ne
+                );
+                let (mut l_min, mut r_min) = if comparison {
+                    let above = self::precedence(Operator::Lt, syntax) + 1;
+                    (above, above)
+                } else {
+                    (precedence, precedence + 1)
+                };
+                // C compilers warn about relying on precedence for `&&` inside `||`
+                // and for anything inside shifts and bitwise operators.
+                if *operator == Operator::Or {
+                    l_min = l_min.max(self::precedence(Operator::And, syntax) + 1);
+                    r_min = r_min.max(self::precedence(Operator::And, syntax) + 1);
+                }
+                let bitwise = matches!(
+                    operator,
+                    Operator::Shl | Operator::Shr | Operator::BitAnd | Operator::BitXor
+                );
+                if syntax == Syntax::C && bitwise {
+                    l_min = l_min.max(PREFIX);
+                    r_min = r_min.max(PREFIX);
+                }
+                // `x as T < y` and `x as T << y` would start generic arguments.
+                if syntax == Syntax::Rust
+                    && matches!(operator, Operator::Lt | Operator::Le | Operator::Shl)
+                    && l.ends_with_cast()
+                {
+                    l_min = POSTFIX;
+                }
+                format!(
+                    "{} {operator} {}",
+                    l.print_at(syntax, l_min),
+                    r.print_at(syntax, r_min)
+                )
+            }
+            Expr::Prefix(op, e) => {
+                let inner = e.print_at(syntax, PREFIX);
+                // `- -x` must not turn into `--x`.
+                if inner.starts_with(op) && *op != "*" {
+                    format!("{op}({inner})")
+                } else {
+                    format!("{op}{inner}")
+                }
+            }
+            Expr::Index(e, index) => {
+                format!(
+                    "{}[{}]",
+                    e.print_at(syntax, POSTFIX),
+                    index.print_at(syntax, 0)
+                )
+            }
+            Expr::Method(receiver, name, args) => {
+                let args: Vec<_> = args.iter().map(|arg| arg.print_at(syntax, 0)).collect();
+                format!(
+                    "{}.{name}({})",
+                    receiver.print_at(syntax, POSTFIX),
+                    args.join(", ")
+                )
+            }
+            Expr::Cast(e, ty) => match syntax {
+                Syntax::C => format!("({ty}){}", e.print_at(syntax, PREFIX)),
+                Syntax::Rust => format!("{} as {ty}", e.print_at(syntax, RUST_AS)),
+            },
+            Expr::Conditional(cond, then, else_) => match syntax {
+                Syntax::C => format!(
+                    "{} ? {} : {}",
+                    cond.print_at(syntax, CONDITIONAL + 1),
+                    then.print_at(syntax, 0),
+                    else_.print_at(syntax, CONDITIONAL)
+                ),
+                Syntax::Rust => format!(
+                    "if {} {{ {} }} else {{ {} }}",
+                    cond.print_at(syntax, 0),
+                    then.print_at(syntax, 0),
+                    else_.print_at(syntax, 0)
+                ),
+            },
+        };
+        if precedence < min {
+            format!("({s})")
+        } else {
+            s
+        }
+    }
+}
+
+/// `name` gives the name of every local.
+pub fn value_expr(value: &Value, syntax: Syntax, name: &impl Fn(Idx<Local>) -> String) -> Expr {
+    match value {
+        Value::Place(place) => place_expr(place, syntax, name),
+        Value::Literal(i) => literal_expr(*i as i64, syntax),
+        Value::Binop(l, binop, r) => Expr::binary(
+            value_expr(l, syntax, name),
+            *binop,
+            value_expr(r, syntax, name),
+        ),
+        Value::Select(cond, then, else_) => Expr::conditional(
+            value_expr(cond, syntax, name),
+            value_expr(then, syntax, name),
+            value_expr(else_, syntax, name),
+        ),
+    }
+}
+
+pub fn place_expr(place: &Place, syntax: Syntax, name: &impl Fn(Idx<Local>) -> String) -> Expr {
+    place_expr_with(place, syntax, name, &|value| {
+        value_expr(value, syntax, name)
+    })
+}
+
+/// Like `place_expr`, with `value` printing the offsets.
+pub fn place_expr_with(
+    place: &Place,
+    syntax: Syntax,
+    name: &impl Fn(Idx<Local>) -> String,
+    value: &impl Fn(&Value) -> Expr,
+) -> Expr {
+    let place_expr = |place| place_expr_with(place, syntax, name, value);
+    match (place, syntax) {
+        (Place::Local(idx), _) => Expr::Atom(name(*idx)),
+        (Place::Deref(inner), Syntax::C) => match &**inner {
+            Place::Offset(base, offset) => {
+                Expr::Index(Box::new(place_expr(base)), Box::new(value(offset)))
+            }
+            _ => Expr::prefix("*", place_expr(inner)),
+        },
+        (Place::Deref(inner), Syntax::Rust) => Expr::prefix("*", place_expr(inner)),
+        (Place::Offset(base, offset), Syntax::C) => {
+            Expr::binary(place_expr(base), Operator::Add, value(offset))
+        }
+        (Place::Offset(base, offset), Syntax::Rust) => {
+            Expr::method(place_expr(base), "offset", vec![value(offset)])
+        }
+    }
+}
+
+pub fn literal_expr(i: i64, syntax: Syntax) -> Expr {
+    // In C, `-2147483648` is the negation of a literal that doesn't fit in `int`.
+    if syntax == Syntax::C && i == i32::MIN as i64 {
+        return Expr::binary(
+            literal_expr(i + 1, syntax),
+            Binop::Sub,
+            literal_expr(1, syntax),
+        );
+    }
+    if i < 0 {
+        Expr::prefix("-", Expr::Atom(i.unsigned_abs().to_string()))
+    } else {
+        Expr::Atom(i.to_string())
+    }
+}
+
+/// The name that `Display` and `Cfg::print` use for a local.
+pub fn default_name(idx: Idx<Local>) -> String {
+    format!("_{}", idx.to_usize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, n: u64) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0 % n
+        }
+    }
+
+    // _0 and _1 are integers, _2 points to an integer, _3 to a pointer to one.
+    fn local(i: usize) -> Place {
+        Place::Local(Idx::from_usize(i))
+    }
+
+    // `Sar` is left out, it prints like `Shr`.
+    const BINOPS: [Binop; 14] = [
+        Binop::Add,
+        Binop::Sub,
+        Binop::Mul,
+        Binop::Div,
+        Binop::Rem,
+        Binop::Shl,
+        Binop::Shr,
+        Binop::Xor,
+        Binop::Lt,
+        Binop::Le,
+        Binop::Eq,
+        Binop::Ne,
+        Binop::And,
+        Binop::Or,
+    ];
+
+    fn random_int(rng: &mut Rng, depth: u32) -> Value {
+        match rng.below(if depth == 0 { 2 } else { 4 }) {
+            0 => Value::Literal(rng.below(21) as i32 - 10),
+            1 => Value::Place(random_int_place(rng, depth)),
+            _ => Value::Binop(
+                Box::new(random_int(rng, depth - 1)),
+                BINOPS[rng.below(BINOPS.len() as u64) as usize],
+                Box::new(random_int(rng, depth - 1)),
+            ),
+        }
+    }
+
+    fn random_int_place(rng: &mut Rng, depth: u32) -> Place {
+        match rng.below(if depth == 0 { 1 } else { 2 }) {
+            0 => local(rng.below(2) as usize),
+            _ => Place::Deref(Box::new(random_ptr_place(rng, depth - 1, 1))),
+        }
+    }
+
+    // A place holding a pointer with `level` levels of indirection.
+    fn random_ptr_place(rng: &mut Rng, depth: u32, level: usize) -> Place {
+        match rng.below(if depth == 0 { 1 } else { 3 }) {
+            0 => local(level + 1),
+            1 => Place::Offset(
+                Box::new(random_ptr_place(rng, depth - 1, level)),
+                Box::new(random_int(rng, depth - 1)),
+            ),
+            _ if level == 1 => Place::Deref(Box::new(random_ptr_place(rng, depth - 1, 2))),
+            _ => local(level + 1),
+        }
+    }
+
+    // Parses the printed expressions back, knowing which locals are pointers.
+    struct Parser {
+        tokens: Vec<String>,
+        pos: usize,
+        syntax: Syntax,
+    }
+
+    fn tokenize(s: &str) -> Vec<String> {
+        let mut tokens = vec![];
+        let chars: Vec<char> = s.chars().collect();
+        let mut i = 0;
+        while i < chars.len() {
+            let c = chars[i];
+            if c.is_whitespace() {
+                i += 1;
+            } else if c.is_ascii_alphanumeric() || c == '_' {
+                let start = i;
+                while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                    i += 1;
+                }
+                tokens.push(chars[start..i].iter().collect());
+            } else {
+                let two: String = chars[i..(i + 2).min(chars.len())].iter().collect();
+                if ["<=", "==", "!=", "&&", "||", "<<", ">>"].contains(&two.as_str()) {
+                    tokens.push(two);
+                    i += 2;
+                } else {
+                    tokens.push(c.to_string());
+                    i += 1;
+                }
+            }
+        }
+        tokens
+    }
+
+    impl Parser {
+        fn peek(&self) -> Option<&str> {
+            self.tokens.get(self.pos).map(|s| s.as_str())
+        }
+
+        fn expect(&mut self, token: &str) {
+            assert_eq!(self.peek(), Some(token), "in {:?}", self.tokens);
+            self.pos += 1;
+        }
+
+        fn binary(&mut self, min: u8) -> Value {
+            let mut l = self.unary();
+            loop {
+                let Some(binop) = BINOPS
+                    .iter()
+                    .copied()
+                    .find(|b| Some(b.to_string().as_str()) == self.peek())
+                else {
+                    return l;
+                };
+                let precedence = precedence(binop.into(), self.syntax);
+                if precedence < min {
+                    return l;
+                }
+                self.pos += 1;
+                let r = self.binary(precedence + 1);
+                l = match (binop, pointer_level(&l)) {
+                    (Binop::Add, Some(_)) if self.syntax == Syntax::C => {
+                        Value::Place(Place::Offset(Box::new(into_place(l)), Box::new(r)))
+                    }
+                    _ => Value::Binop(Box::new(l), binop, Box::new(r)),
+                };
+            }
+        }
+
+        fn unary(&mut self) -> Value {
+            match self.peek() {
+                Some("*") => {
+                    self.pos += 1;
+                    let inner = self.unary();
+                    Value::Place(Place::Deref(Box::new(into_place(inner))))
+                }
+                Some("-") => {
+                    self.pos += 1;
+                    let Value::Literal(i) = self.unary() else {
+                        panic!("only literals are negated")
+                    };
+                    Value::Literal(-i)
+                }
+                _ => self.postfix(),
+            }
+        }
+
+        fn postfix(&mut self) -> Value {
+            let mut value = self.primary();
+            loop {
+                match self.peek() {
+                    Some("[") => {
+                        self.pos += 1;
+                        let index = self.binary(0);
+                        self.expect("]");
+                        let offset = Place::Offset(Box::new(into_place(value)), Box::new(index));
+                        value = Value::Place(Place::Deref(Box::new(offset)));
+                    }
+                    Some(".") => {
+                        self.pos += 1;
+                        self.expect("offset");
+                        self.expect("(");
+                        let offset = self.binary(0);
+                        self.expect(")");
+                        value = Value::Place(Place::Offset(
+                            Box::new(into_place(value)),
+                            Box::new(offset),
+                        ));
+                    }
+                    _ => return value,
+                }
+            }
+        }
+
+        fn primary(&mut self) -> Value {
+            let token = self.peek().unwrap().to_string();
+            self.pos += 1;
+            if token == "(" {
+                let value = self.binary(0);
+                self.expect(")");
+                value
+            } else if let Some(local) = token.strip_prefix('_') {
+                Value::Place(Place::Local(Idx::from_usize(local.parse().unwrap())))
+            } else {
+                Value::Literal(token.parse().unwrap())
+            }
+        }
+    }
+
+    fn into_place(value: Value) -> Place {
+        match value {
+            Value::Place(place) => place,
+            _ => panic!("expected a place"),
+        }
+    }
+
+    fn pointer_level(value: &Value) -> Option<usize> {
+        fn level(place: &Place) -> usize {
+            match place {
+                Place::Local(idx) => idx.to_usize().saturating_sub(1),
+                Place::Deref(inner) => level(inner) - 1,
+                Place::Offset(base, _) => level(base),
+            }
+        }
+        match value {
+            Value::Place(place) if level(place) > 0 => Some(level(place)),
+            _ => None,
+        }
+    }
+
+    fn reparse(value: &Value, syntax: Syntax) -> (String, Value) {
+        let printed = value_expr(value, syntax, &default_name).print(syntax);
+        let mut parser = Parser {
+            tokens: tokenize(&printed),
+            pos: 0,
+            syntax,
+        };
+        let parsed = parser.binary(0);
+        assert_eq!(
+            parser.pos,
+            parser.tokens.len(),
+            "trailing tokens in {printed}"
+        );
+        (printed, parsed)
+    }
+
+    #[test]
+    fn test_printed_values_parse_back() {
+        let mut rng = Rng(0x2545f4914f6cdd1d);
+        for _ in 0..2000 {
+            let value = random_int(&mut rng, 5);
+            for syntax in [Syntax::C, Syntax::Rust] {
+                let (printed, parsed) = reparse(&value, syntax);
+                assert_eq!(parsed, value, "{syntax:?}: {printed}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_parentheses() {
+        let a = || Box::new(Value::Place(local(0)));
+        let b = || Box::new(Value::Place(local(1)));
+        let sum = Value::Binop(a(), Binop::Add, b());
+        let product = Value::Binop(Box::new(sum.clone()), Binop::Mul, b());
+        assert_eq!(product.to_string(), "(_0 + _1) * _1");
+        let difference = Value::Binop(a(), Binop::Sub, Box::new(sum));
+        assert_eq!(difference.to_string(), "_0 - (_0 + _1)");
+        // Rust would take `as i64 <` for the start of generic arguments.
+        let atom = |name: &str| Expr::Atom(name.to_string());
+        let xor = Expr::binary(atom("_0"), Binop::Xor, Expr::cast(atom("_1"), "i64"));
+        let compared = Expr::binary(xor, Binop::Lt, atom("_0"));
+        assert_eq!(compared.print(Syntax::Rust), "(_0 ^ _1 as i64) < _0");
+
+        let element = Place::Deref(Box::new(Place::Offset(
+            Box::new(local(2)),
+            Box::new(Value::Literal(1)),
+        )));
+        let print_c = |place: &Place| place_expr(place, Syntax::C, &default_name).print(Syntax::C);
+        assert_eq!(print_c(&element), "_2[1]");
+        assert_eq!(print_c(&Place::Deref(Box::new(local(2)))), "*_2");
+        assert_eq!(element.to_string(), "*_2.offset(1)");
+    }
+}
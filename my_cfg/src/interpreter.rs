@@ -0,0 +1,318 @@
+// Runs functions on concrete inputs, to check what the rest of the crate makes of a
+// function against what it does. Integers are computed by `eval`, pointers like
+// unsigned integers as wide as the `DataLayout` of the function says, and memory
+// holds bytes in the order it says. Floats aren't computed.
+
+use std::collections::BTreeMap;
+
+use crate::eval::{self, is_integer, wrap};
+use crate::ranges::{is_condition, operand_ty, value_ty};
+use crate::*;
+
+/// Runs `cfg` on `args`, the values of `_1` to `_arg_count`, and returns `_0`. The
+/// other locals start out as 0, and memory as empty. `None` if it doesn't return
+/// within `steps` blocks, or needs something that isn't computed, like a float or
+/// memory it didn't write.
+pub fn run(cfg: &Cfg, args: &[i128], steps: usize) -> Option<i128> {
+    let args: Vec<_> = args.iter().map(|i| Word::int(*i)).collect();
+    let mut machine = Machine::new(cfg, &args);
+    machine.run(steps)?;
+    machine
+        .locals
+        .get(&Idx::from_usize(0))
+        .map(|word| word.value)
+}
+
+/// Runs `cfg` on the inputs of `test`, and whether it returns what `test` expects and
+/// leaves memory as it expects. `None` if it can't tell, like `run`, or if it returns
+/// a float.
+pub fn replay(cfg: &Cfg, test: &TestCase, steps: usize) -> Option<bool> {
+    let args: Vec<_> = test.args.iter().map(word).collect();
+    let mut machine = Machine::new(cfg, &args);
+    for chunk in &test.memory {
+        for (i, byte) in chunk.bytes.iter().enumerate() {
+            let at = machine.address(start(chunk), i);
+            machine.memory.insert(at, (*byte, 0));
+        }
+    }
+    machine.run(steps)?;
+    let returned = machine.locals.get(&Idx::from_usize(0));
+    let returned = match (&test.expected, returned) {
+        (Some(TestValue::Float(_)), _) => return None,
+        // Integers made from pointers still have a region, which doesn't matter.
+        (Some(TestValue::Int(i)), Some(returned)) => returned.value == *i,
+        (Some(expected), returned) => returned == Some(&word(expected)),
+        (None, _) => true,
+    };
+    let expected_memory = test.expected_memory.iter().all(|chunk| {
+        chunk.bytes.iter().enumerate().all(|(i, byte)| {
+            let at = machine.address(start(chunk), i);
+            machine.memory.get(&at).map(|(byte, _)| byte) == Some(byte)
+        })
+    });
+    Some(returned && expected_memory)
+}
+
+// The argument or return value `value`. Floats aren't computed, so their bits don't
+// matter.
+fn word(value: &TestValue) -> Word {
+    match *value {
+        TestValue::Int(i) => Word::int(i),
+        TestValue::Float(bits) => Word::int(bits as i128),
+        TestValue::Pointer { region, address } => Word {
+            value: address.into(),
+            region,
+        },
+    }
+}
+
+// A pointer to the first byte of `chunk`.
+fn start(chunk: &MemoryChunk) -> Word {
+    Word {
+        value: chunk.address.into(),
+        region: chunk.region,
+    }
+}
+
+// An integer or a pointer, and the region of memory it points into. Like in
+// z3-of-cfg, arithmetic keeps the region of its first operand that isn't a literal,
+// and other integers are in region 0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Word {
+    value: i128,
+    region: u64,
+}
+
+impl Word {
+    fn int(value: i128) -> Self {
+        Word { value, region: 0 }
+    }
+}
+
+struct Machine<'a> {
+    cfg: &'a Cfg,
+    locals: BTreeMap<Idx<Local>, Word>,
+    // By region and address, each byte and the region of the value it is part of.
+    memory: BTreeMap<(u64, u64), (u8, u64)>,
+}
+
+impl<'a> Machine<'a> {
+    fn new(cfg: &'a Cfg, args: &[Word]) -> Self {
+        let mut locals = BTreeMap::new();
+        for (idx, local) in cfg.locals.iter() {
+            let Some(ty) = integer(&local.ty, &cfg.layout) else {
+                continue;
+            };
+            let word = match idx.to_usize() {
+                i @ 1.. if i <= cfg.arg_count => args[i - 1],
+                _ => Word::int(0),
+            };
+            let value = wrap(word.value, &ty);
+            locals.insert(idx, Word { value, ..word });
+        }
+        Machine {
+            cfg,
+            locals,
+            memory: BTreeMap::new(),
+        }
+    }
+
+    fn run(&mut self, steps: usize) -> Option<()> {
+        let cfg = self.cfg;
+        let mut bb = Idx::from_usize(0);
+        for _ in 0..steps {
+            for stmt in &cfg.bb[bb].stmts {
+                let Stmt::Assign { place, value, .. } = stmt;
+                let word = self.value(value)?;
+                self.write(place, word)?;
+            }
+            bb = match cfg.bb[bb].terminator() {
+                Terminator::Return => return Some(()),
+                Terminator::Goto { bb } => *bb,
+                Terminator::If { cond, then, else_ } => match self.value(cond)?.value {
+                    0 => *else_,
+                    _ => *then,
+                },
+            };
+        }
+        None
+    }
+
+    // `value` in its own type, like `eval::value`.
+    fn value(&self, value: &Value) -> Option<Word> {
+        let cfg = self.cfg;
+        match value {
+            Value::Literal(i) => Some(Word::int((*i).into())),
+            Value::Place(place) => self.read(place),
+            // The right side doesn't matter when the left one decides.
+            Value::Binop(l, op @ (Binop::And | Binop::Or), r) => {
+                let l = self.value(l)?.value;
+                match (l != 0) == (*op == Binop::Or) {
+                    true => Some(Word::int((l != 0).into())),
+                    false => {
+                        let r = self.value(r)?.value;
+                        Some(Word::int(eval::binop(l, *op, r, &CType::Bool)))
+                    }
+                }
+            }
+            Value::Binop(l, op, r) => {
+                let ty = match is_condition(*op) {
+                    true => operand_ty(l, r, cfg),
+                    false => value_ty(value, cfg),
+                };
+                let ty = integer(&ty, &cfg.layout)?;
+                let (l_word, r_word) = (self.value(l)?, self.value(r)?);
+                let region = match **l {
+                    _ if is_condition(*op) => 0,
+                    Value::Literal(_) => r_word.region,
+                    _ => l_word.region,
+                };
+                let value = eval::binop(l_word.value, *op, r_word.value, &ty);
+                Some(Word { value, region })
+            }
+            Value::Select(cond, then, else_) => {
+                let ty = integer(&value_ty(value, cfg), &cfg.layout)?;
+                let chosen = if self.value(cond)?.value != 0 {
+                    then
+                } else {
+                    else_
+                };
+                let word = self.value(chosen)?;
+                let value = wrap(word.value, &ty);
+                Some(Word { value, ..word })
+            }
+        }
+    }
+
+    fn read(&self, place: &Place) -> Option<Word> {
+        let layout = &self.cfg.layout;
+        match place {
+            Place::Local(idx) => self.locals.get(idx).copied(),
+            Place::Deref(inner) => {
+                let ty = place.ty(self.cfg);
+                let integer = integer(&ty, layout)?;
+                let pointer = self.read(inner)?;
+                let mut bits = 0;
+                let mut region = None;
+                for (i, shift) in shifts(ty.size(layout), layout.endian).enumerate() {
+                    let (byte, byte_region) = *self.memory.get(&self.address(pointer, i))?;
+                    bits |= u128::from(byte) << shift;
+                    region.get_or_insert(byte_region);
+                }
+                Some(Word {
+                    value: wrap(bits as i128, &integer),
+                    region: region?,
+                })
+            }
+            // Not a place in memory but the pointer `base + offset`.
+            Place::Offset(base, offset) => {
+                let CType::Ptr(element) = place.ty(self.cfg) else {
+                    panic!("Invalid offset of non pointer type");
+                };
+                let base = self.read(base)?;
+                let offset = wrap(self.value(offset)?.value, &layout.intptr());
+                let address = base.value + offset * i128::from(element.size(layout));
+                Some(Word {
+                    value: wrap(address, &CType::UInt(layout.pointer_size)),
+                    region: base.region,
+                })
+            }
+        }
+    }
+
+    fn write(&mut self, place: &Place, word: Word) -> Option<()> {
+        let layout = &self.cfg.layout;
+        let ty = place.ty(self.cfg);
+        let value = wrap(word.value, &integer(&ty, layout)?);
+        match place {
+            Place::Local(idx) => {
+                self.locals.insert(*idx, Word { value, ..word });
+            }
+            Place::Deref(inner) => {
+                let pointer = self.read(inner)?;
+                for (i, shift) in shifts(ty.size(layout), layout.endian).enumerate() {
+                    let byte = (value >> shift) as u8;
+                    let at = self.address(pointer, i);
+                    self.memory.insert(at, (byte, word.region));
+                }
+            }
+            Place::Offset(..) => panic!("`Place::Offset` is a pointer, not a place in memory"),
+        }
+        Some(())
+    }
+
+    // Where the `i`th byte after the one `pointer` points to is.
+    fn address(&self, pointer: Word, i: usize) -> (u64, u64) {
+        let ty = CType::UInt(self.cfg.layout.pointer_size);
+        (pointer.region, wrap(pointer.value + i as i128, &ty) as u64)
+    }
+}
+
+// How far each byte of a value of `size` bytes in memory, from the first address on,
+// is shifted in the value.
+fn shifts(size: u8, endian: Endian) -> impl Iterator<Item = u32> {
+    (0..u32::from(size)).map(move |i| match endian {
+        Endian::Little => 8 * i,
+        Endian::Big => 8 * (u32::from(size) - 1 - i),
+    })
+}
+
+// The integer type values of `ty` are computed in. Pointers are unsigned integers as
+// wide as they are.
+fn integer(ty: &CType, layout: &DataLayout) -> Option<CType> {
+    match ty {
+        CType::Ptr(_) => Some(CType::UInt(layout.pointer_size)),
+        ty if is_integer(ty) => Some(ty.clone()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::*;
+
+    #[test]
+    fn test_layout() {
+        // _2 = _1 as *mut u8; *_1 = 0x01020304; _0 = *_2; _3 = _1.offset(-1)
+        let byte_ptr = CType::Ptr(Box::new(CType::UInt(1)));
+        let int_ptr = CType::Ptr(Box::new(CType::UInt(4)));
+        let mut cfg = with_locals([CType::UInt(1), int_ptr.clone(), byte_ptr, int_ptr]);
+        cfg.arg_count = 1;
+        let back = Place::Offset(Box::new(place(1)), Box::new(Value::Literal(-1)));
+        cfg.bb.alloc(block(
+            vec![
+                assign(2, local(1)),
+                Stmt::Assign {
+                    place: Place::Deref(Box::new(place(1))),
+                    value: Value::Literal(0x01020304),
+                    origin: None,
+                },
+                assign(0, Value::Place(Place::Deref(Box::new(place(2))))),
+                assign(3, Value::Place(back)),
+            ],
+            Terminator::Return,
+        ));
+        for (layout, first_byte) in [
+            (DataLayout::RV64, 4),
+            (DataLayout::RV32, 4),
+            (
+                DataLayout {
+                    endian: Endian::Big,
+                    ..DataLayout::RV32
+                },
+                1,
+            ),
+        ] {
+            cfg.layout = layout;
+            let mut machine = Machine::new(&cfg, &[Word::int(0)]);
+            machine.run(1).unwrap();
+            let returned = machine.locals[&Idx::from_usize(0)].value;
+            assert_eq!(returned, first_byte, "{layout:?}");
+            // The pointer wraps around at its width.
+            let bits = u32::from(layout.pointer_size) * 8;
+            let back = machine.locals[&Idx::from_usize(3)].value;
+            assert_eq!(back, (1 << bits) - 4, "{layout:?}");
+        }
+    }
+}
@@ -0,0 +1,426 @@
+// Detection and handling of irreducible control flow, i.e. cycles that can be
+// entered through more than one block. The relooper can't turn those into plain
+// loops, so `IrreducibleStrategy` decides what happens to them.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use crate::loopified::{Relooper, StmtRef, StructuredNode};
+use crate::*;
+
+/// How a multi-entry region is turned into structured code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IrreducibleStrategy {
+    /// Run the region as a state machine over a synthetic label variable.
+    #[default]
+    Dispatch,
+    /// Lay the blocks of the region out one after the other and jump between them
+    /// with `goto`.
+    Goto,
+    /// Copy blocks until every cycle has a single entry. Falls back to `Goto` when
+    /// that would copy more than `budget` statements.
+    NodeSplitting { budget: usize },
+}
+
+/// A strongly connected set of blocks with more than one entry.
+#[derive(Debug, Clone)]
+pub struct IrreducibleRegion {
+    /// In block order.
+    pub blocks: Vec<Idx<BasicBlock>>,
+    /// Blocks of the region that are entered from outside of it, in block order.
+    pub entries: Vec<Idx<BasicBlock>>,
+}
+
+pub fn find_irreducible_regions(cfg: &Cfg) -> Vec<IrreducibleRegion> {
+    let predecessors = Relooper::compute_predecessors(cfg);
+    let all_blocks: Vec<_> = cfg.bb.iter().map(|(idx, _)| idx).collect();
+    let mut regions = vec![];
+    find_regions_in(cfg, &predecessors, &all_blocks, &mut regions);
+    regions
+}
+
+// Looks for irreducible regions among `blocks`. A single-entry cycle is a natural
+// loop, so we drop its header and look for irreducible regions nested in its body.
+fn find_regions_in(
+    cfg: &Cfg,
+    predecessors: &BTreeMap<Idx<BasicBlock>, Vec<Idx<BasicBlock>>>,
+    blocks: &[Idx<BasicBlock>],
+    regions: &mut Vec<IrreducibleRegion>,
+) {
+    let function_entry = Idx::from_usize(0);
+    for scc in strongly_connected_components(cfg, blocks) {
+        let is_cycle =
+            scc.len() > 1 || Relooper::get_successors_from_block(&cfg.bb[scc[0]]).contains(&scc[0]);
+        if !is_cycle {
+            continue;
+        }
+        let entries: Vec<_> = scc
+            .iter()
+            .copied()
+            .filter(|idx| {
+                *idx == function_entry || predecessors[idx].iter().any(|pred| !scc.contains(pred))
+            })
+            .collect();
+        match entries.as_slice() {
+            // Unreachable cycle, nothing will ever be emitted for it.
+            [] => {}
+            [header] => {
+                let body: Vec<_> = scc.iter().copied().filter(|idx| idx != header).collect();
+                find_regions_in(cfg, predecessors, &body, regions);
+            }
+            _ => regions.push(IrreducibleRegion {
+                blocks: scc,
+                entries,
+            }),
+        }
+    }
+}
+
+// Tarjan's algorithm on the subgraph induced by `blocks`. Components and the blocks
+// inside them are sorted by block order.
+fn strongly_connected_components(
+    cfg: &Cfg,
+    blocks: &[Idx<BasicBlock>],
+) -> Vec<Vec<Idx<BasicBlock>>> {
+    struct Tarjan<'a> {
+        cfg: &'a Cfg,
+        blocks: HashSet<Idx<BasicBlock>>,
+        index: HashMap<Idx<BasicBlock>, usize>,
+        low_link: HashMap<Idx<BasicBlock>, usize>,
+        stack: Vec<Idx<BasicBlock>>,
+        on_stack: HashSet<Idx<BasicBlock>>,
+        components: Vec<Vec<Idx<BasicBlock>>>,
+    }
+
+    impl Tarjan<'_> {
+        fn visit(&mut self, idx: Idx<BasicBlock>) {
+            let index = self.index.len();
+            self.index.insert(idx, index);
+            self.low_link.insert(idx, index);
+            self.stack.push(idx);
+            self.on_stack.insert(idx);
+
+            for succ in Relooper::get_successors_from_block(&self.cfg.bb[idx]) {
+                if !self.blocks.contains(&succ) {
+                    continue;
+                }
+                if !self.index.contains_key(&succ) {
+                    self.visit(succ);
+                    let low = self.low_link[&idx].min(self.low_link[&succ]);
+                    self.low_link.insert(idx, low);
+                } else if self.on_stack.contains(&succ) {
+                    let low = self.low_link[&idx].min(self.index[&succ]);
+                    self.low_link.insert(idx, low);
+                }
+            }
+
+            if self.low_link[&idx] == self.index[&idx] {
+                let mut component = vec![];
+                loop {
+                    let member = self.stack.pop().unwrap();
+                    self.on_stack.remove(&member);
+                    component.push(member);
+                    if member == idx {
+                        break;
+                    }
+                }
+                component.sort_by_key(|idx| idx.to_usize());
+                self.components.push(component);
+            }
+        }
+    }
+
+    let mut tarjan = Tarjan {
+        cfg,
+        blocks: blocks.iter().copied().collect(),
+        index: HashMap::new(),
+        low_link: HashMap::new(),
+        stack: vec![],
+        on_stack: HashSet::new(),
+        components: vec![],
+    };
+    for &idx in blocks {
+        if !tarjan.index.contains_key(&idx) {
+            tarjan.visit(idx);
+        }
+    }
+    let mut components = tarjan.components;
+    components.sort_by_key(|component| component[0].to_usize());
+    components
+}
+
+/// Makes every cycle of `cfg` single-entry by copying blocks. For each extra entry of
+/// a region, the part of the region it reaches without passing through the first
+/// entry is copied, and the edges coming from outside are moved to the copy.
+///
+/// Returns the new graph, together with the original block of every block in it.
+/// Returns `None` if `cfg` is already reducible or if more than `budget` statements
+/// would need to be copied.
+pub(crate) fn split_irreducible(cfg: &Cfg, budget: usize) -> Option<(Cfg, Vec<Idx<BasicBlock>>)> {
+    let mut cfg = cfg.clone();
+    let mut origin: Vec<_> = cfg.bb.iter().map(|(idx, _)| idx).collect();
+    let mut copied = 0;
+    let mut changed = false;
+
+    while let Some(region) = find_irreducible_regions(&cfg).into_iter().next() {
+        changed = true;
+        let header = region.entries[0];
+        let entry = region.entries[1];
+
+        // The part of the region that `entry` reaches without going through `header`.
+        let mut to_copy = vec![];
+        let mut stack = vec![entry];
+        while let Some(idx) = stack.pop() {
+            if idx == header || to_copy.contains(&idx) || !region.blocks.contains(&idx) {
+                continue;
+            }
+            to_copy.push(idx);
+            stack.extend(Relooper::get_successors_from_block(&cfg.bb[idx]));
+        }
+
+        copied += to_copy
+            .iter()
+            .map(|idx| cfg.bb[*idx].stmts.len())
+            .sum::<usize>();
+        if copied > budget {
+            return None;
+        }
+
+        let mut copies = HashMap::new();
+        for &idx in &to_copy {
+            let copy = cfg.bb.alloc(cfg.bb[idx].clone());
+            origin.push(origin[idx.to_usize()]);
+            copies.insert(idx, copy);
+        }
+        for &copy in copies.values() {
+            retarget(&mut cfg.bb[copy], &|target| {
+                copies.get(&target).copied().unwrap_or(target)
+            });
+        }
+        let copy_of_entry = copies[&entry];
+        let copy_set: HashSet<_> = copies.values().copied().collect();
+        for (idx, bb) in cfg.bb.iter_mut() {
+            if region.blocks.contains(&idx) || copy_set.contains(&idx) {
+                continue;
+            }
+            retarget(bb, &|target| {
+                if target == entry {
+                    copy_of_entry
+                } else {
+                    target
+                }
+            });
+        }
+    }
+
+    changed.then_some((cfg, origin))
+}
+
+fn retarget(bb: &mut BasicBlock, f: &impl Fn(Idx<BasicBlock>) -> Idx<BasicBlock>) {
+    match bb.terminator.as_mut().unwrap() {
+        Terminator::Return => {}
+        Terminator::Goto { bb } => *bb = f(*bb),
+        Terminator::If { then, else_, .. } => {
+            *then = f(*then);
+            *else_ = f(*else_);
+        }
+    }
+}
+
+/// Rewrites a node shaped over the output of `split_irreducible` to refer to the
+/// original blocks. Copies become `Duplicate`s of the block they were made from.
+pub(crate) fn map_to_original(
+    node: StructuredNode,
+    origin: &[Idx<BasicBlock>],
+    original_len: usize,
+) -> StructuredNode {
+    let map = |idx: Idx<BasicBlock>| origin[idx.to_usize()];
+    let map_stmt = |stmt: StmtRef| StmtRef {
+        bb: map(stmt.bb),
+        index: stmt.index,
+    };
+    let recurse =
+        |node: Box<StructuredNode>| Box::new(map_to_original(*node, origin, original_len));
+    match node {
+        StructuredNode::Basic(idx) if idx.to_usize() >= original_len => {
+            StructuredNode::Duplicate(map(idx))
+        }
+        StructuredNode::Basic(idx) => StructuredNode::Basic(idx),
+        StructuredNode::Duplicate(idx) => StructuredNode::Duplicate(map(idx)),
+        StructuredNode::Sequence(nodes) => StructuredNode::Sequence(
+            nodes
+                .into_iter()
+                .map(|node| map_to_original(node, origin, original_len))
+                .collect(),
+        ),
+        StructuredNode::If {
+            cond,
+            then_node,
+            else_node,
+        } => StructuredNode::If {
+            cond,
+            then_node: recurse(then_node),
+            else_node: recurse(else_node),
+        },
+        StructuredNode::Loop(body) => StructuredNode::Loop(recurse(body)),
+        StructuredNode::Block(body) => StructuredNode::Block(recurse(body)),
+        StructuredNode::While { header, cond, body } => StructuredNode::While {
+            header: map(header),
+            cond,
+            body: recurse(body),
+        },
+        StructuredNode::DoWhile { body, latch, cond } => StructuredNode::DoWhile {
+            body: recurse(body),
+            latch: map(latch),
+            cond,
+        },
+        StructuredNode::For {
+            init,
+            header,
+            cond,
+            step,
+            body,
+        } => StructuredNode::For {
+            init: map_stmt(init),
+            header: map(header),
+            cond,
+            step: map_stmt(step),
+            body: recurse(body),
+        },
+        StructuredNode::Labeled(blocks) => {
+            StructuredNode::Labeled(blocks.into_iter().map(map).collect())
+        }
+        StructuredNode::Dispatch {
+            entry_map,
+            handlers,
+        } => StructuredNode::Dispatch {
+            entry_map: entry_map
+                .into_iter()
+                .map(|(idx, label)| (map(idx), label))
+                .collect(),
+            handlers: handlers
+                .into_iter()
+                .map(|(label, node)| (label, map_to_original(node, origin, original_len)))
+                .collect(),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::*;
+
+    // A cycle between bb1 and bb2 that is entered through both of them.
+    //
+    // bb0: if _1 { goto bb1 } else { goto bb2 }
+    // bb1: _0 = _0 + 1; if _2 { goto bb2 } else { goto bb3 }
+    // bb2: _0 = _0 * 2; if _3 { goto bb1 } else { goto bb3 }
+    // bb3: return
+    fn two_entries() -> Cfg {
+        let mut cfg = with_locals(vec![CType::Int(4); 4]);
+        cfg.arg_count = 3;
+        cfg.bb.alloc(block(vec![], branch(local(1), 1, 2)));
+        cfg.bb.alloc(block(
+            vec![assign(0, binop(local(0), Binop::Add, Value::Literal(1)))],
+            branch(local(2), 2, 3),
+        ));
+        cfg.bb.alloc(block(
+            vec![assign(0, binop(local(0), Binop::Mul, Value::Literal(2)))],
+            branch(local(3), 1, 3),
+        ));
+        cfg.bb.alloc(block(vec![], Terminator::Return));
+        cfg
+    }
+
+    #[test]
+    fn test_find_irreducible_regions() {
+        let cfg = two_entries();
+        let regions = find_irreducible_regions(&cfg);
+        let [region] = regions.as_slice() else {
+            panic!("{regions:?}");
+        };
+        let indices = |blocks: &[Idx<BasicBlock>]| {
+            blocks.iter().map(|idx| idx.to_usize()).collect::<Vec<_>>()
+        };
+        assert_eq!(indices(&region.blocks), [1, 2]);
+        assert_eq!(indices(&region.entries), [1, 2]);
+
+        // Only entered through bb1, the cycle is a loop.
+        let mut cfg = cfg;
+        cfg.bb[Idx::from_usize(0)].terminator = Some(goto(1));
+        assert!(find_irreducible_regions(&cfg).is_empty());
+    }
+
+    #[test]
+    fn test_split_irreducible() {
+        let cfg = two_entries();
+        // bb2 is copied for the edge from bb0, one statement.
+        let (split, origin) = split_irreducible(&cfg, 1).unwrap();
+        assert_eq!(split.bb.iter().count(), 5);
+        assert_eq!(origin[4].to_usize(), 2);
+        assert!(find_irreducible_regions(&split).is_empty());
+        let Terminator::If { else_, .. } = split.bb[Idx::from_usize(0)].terminator() else {
+            panic!("bb0 lost its branch");
+        };
+        assert_eq!(else_.to_usize(), 4);
+
+        assert!(split_irreducible(&cfg, 0).is_none());
+        assert!(split_irreducible(&split, usize::MAX).is_none());
+    }
+
+    // The blocks that `node` places a copy of.
+    fn duplicates(node: &StructuredNode) -> Vec<usize> {
+        match node {
+            StructuredNode::Duplicate(idx) => vec![idx.to_usize()],
+            StructuredNode::Sequence(nodes) => nodes.iter().flat_map(duplicates).collect(),
+            StructuredNode::If {
+                then_node,
+                else_node,
+                ..
+            } => [duplicates(then_node), duplicates(else_node)].concat(),
+            StructuredNode::Loop(body)
+            | StructuredNode::While { body, .. }
+            | StructuredNode::DoWhile { body, .. }
+            | StructuredNode::For { body, .. }
+            | StructuredNode::Block(body) => duplicates(body),
+            StructuredNode::Dispatch { handlers, .. } => handlers
+                .iter()
+                .flat_map(|(_, node)| duplicates(node))
+                .collect(),
+            StructuredNode::Basic(_) | StructuredNode::Labeled(_) => vec![],
+        }
+    }
+
+    #[test]
+    fn test_strategies() {
+        let cfg = two_entries();
+        for structurer in [Structurer::Relooper, Structurer::Dominators] {
+            let structure = |irreducible| {
+                let node = cfg.loopify_with(&LoopifyConfig {
+                    structurer,
+                    irreducible,
+                });
+                // Breaks out of nested scopes are `goto break_N` in C, a jump that
+                // isn't structured is a `goto` to the block's label.
+                let c = emit_c(&cfg, &node, "f");
+                (
+                    format!("{node:?}"),
+                    c.contains("goto bb"),
+                    duplicates(&node),
+                )
+            };
+
+            let (node, goto, _) = structure(IrreducibleStrategy::Dispatch);
+            assert!(node.contains("Dispatch") && !goto, "{node}");
+            let (node, goto, _) = structure(IrreducibleStrategy::Goto);
+            assert!(node.contains("Labeled") && goto, "{node}");
+            // Within the budget the copy makes the graph reducible, without one
+            // it falls back to `Goto`.
+            let (node, goto, copies) = structure(IrreducibleStrategy::NodeSplitting { budget: 1 });
+            assert!(copies == [2] && !goto, "{node}");
+            assert!(!node.contains("Labeled") && !node.contains("Dispatch"));
+            let (node, goto, _) = structure(IrreducibleStrategy::NodeSplitting { budget: 0 });
+            assert!(node.contains("Labeled") && goto, "{node}");
+        }
+    }
+}
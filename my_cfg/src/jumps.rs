@@ -0,0 +1,111 @@
+// Jumps are implicit in `StructuredNode`: a block's terminator says where control
+// goes, and the nodes around the block say what that means. Emitters use this to
+// turn a terminator into a fallthrough, a break or continue, or a goto.
+
+use std::collections::BTreeMap;
+
+use crate::loopified::StructuredNode;
+use crate::*;
+
+/// What runs when a node falls off its end.
+#[derive(Debug, Clone, Copy)]
+pub enum Next<'a> {
+    Block(Idx<BasicBlock>),
+    /// A `Dispatch`, which runs the handler that the label variable selects.
+    Dispatch(&'a BTreeMap<Idx<BasicBlock>, i32>),
+    /// The end of the function.
+    Nothing,
+}
+
+impl Next<'_> {
+    // `Some(label)` if falling off into this reaches `target`, after setting the
+    // label variable if there is one.
+    fn reaches(&self, target: Idx<BasicBlock>) -> Option<Option<i32>> {
+        match self {
+            Next::Block(idx) => (*idx == target).then_some(None),
+            Next::Dispatch(entry_map) => entry_map.get(&target).map(|label| Some(*label)),
+            Next::Nothing => None,
+        }
+    }
+}
+
+/// A node that jumps can leave or restart, as seen from a block inside of it.
+#[derive(Debug, Clone, Copy)]
+pub enum ScopeKind<'a> {
+    /// Any of the loop nodes. `continue` goes to `header`.
+    Loop { header: Idx<BasicBlock> },
+    /// A `StructuredNode::Block`.
+    Block,
+    /// The loop around the handlers of a `Dispatch`. `continue` runs the handler
+    /// of the label variable.
+    Dispatch {
+        entry_map: &'a BTreeMap<Idx<BasicBlock>, i32>,
+    },
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Scope<'a> {
+    pub kind: ScopeKind<'a>,
+    /// What runs after the scope, which is where a `break` out of it goes.
+    pub next: Next<'a>,
+}
+
+/// How a jump is written, `scope` is an index into the stack of open scopes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Jump {
+    /// The target runs next anyway, there is nothing to write.
+    Fallthrough,
+    /// Set the label variable and fall into the `Dispatch` that runs next.
+    SetLabel(i32),
+    /// Set the label variable if `label` is some, then leave `scope`.
+    Break { scope: usize, label: Option<i32> },
+    /// Set the label variable if `label` is some, then start the next iteration of
+    /// `scope`.
+    Continue { scope: usize, label: Option<i32> },
+    /// No enclosing node reaches the target.
+    Goto(Idx<BasicBlock>),
+}
+
+/// Resolves a jump to `target` from a block that is followed by `next`, with
+/// `scopes` open around it, innermost last.
+pub fn resolve(scopes: &[Scope<'_>], next: Next<'_>, target: Idx<BasicBlock>) -> Jump {
+    match next.reaches(target) {
+        Some(None) => return Jump::Fallthrough,
+        Some(Some(label)) => return Jump::SetLabel(label),
+        None => {}
+    }
+    for (index, scope) in scopes.iter().enumerate().rev() {
+        match scope.kind {
+            ScopeKind::Loop { header } if header == target => {
+                return Jump::Continue {
+                    scope: index,
+                    label: None,
+                };
+            }
+            ScopeKind::Dispatch { entry_map } if entry_map.contains_key(&target) => {
+                return Jump::Continue {
+                    scope: index,
+                    label: Some(entry_map[&target]),
+                };
+            }
+            _ => {}
+        }
+        if let Some(label) = scope.next.reaches(target) {
+            return Jump::Break {
+                scope: index,
+                label,
+            };
+        }
+    }
+    Jump::Goto(target)
+}
+
+/// What runs first when control falls into `node`, `None` if `node` is empty.
+pub fn next_of(node: &StructuredNode) -> Option<Next<'_>> {
+    match node {
+        StructuredNode::Dispatch { entry_map, .. } => Some(Next::Dispatch(entry_map)),
+        StructuredNode::Sequence(nodes) => nodes.iter().find_map(next_of),
+        StructuredNode::Block(body) => next_of(body),
+        _ => node.entry().map(Next::Block),
+    }
+}
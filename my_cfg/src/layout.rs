@@ -0,0 +1,47 @@
+// How the target lays out data in memory. A `Cfg` lifted from RV32 code has 4-byte
+// pointers, one from RV64 code 8-byte ones.
+
+use serde::{Deserialize, Serialize};
+
+use crate::CType;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Endian {
+    Little,
+    Big,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DataLayout {
+    /// Bytes in a pointer.
+    pub pointer_size: u8,
+    pub endian: Endian,
+    /// Values are aligned to their size, but to no more than this.
+    pub max_align: u8,
+}
+
+impl DataLayout {
+    pub const RV32: DataLayout = DataLayout {
+        pointer_size: 4,
+        endian: Endian::Little,
+        max_align: 16,
+    };
+
+    pub const RV64: DataLayout = DataLayout {
+        pointer_size: 8,
+        endian: Endian::Little,
+        max_align: 16,
+    };
+
+    /// The signed integer type as wide as a pointer, C's `intptr_t`.
+    pub fn intptr(&self) -> CType {
+        CType::Int(self.pointer_size)
+    }
+}
+
+/// Code without a layout is from RV64.
+impl Default for DataLayout {
+    fn default() -> Self {
+        Self::RV64
+    }
+}
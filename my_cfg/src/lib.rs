@@ -1,10 +1,43 @@
-use std::{fmt::Display, ops::Index, usize};
+use std::{
+    fmt::Display,
+    ops::{Index, IndexMut},
+};
 
 use serde::{Deserialize, Serialize};
 
-use crate::loopified::{Relooper, StructuredNode};
-
+use crate::dominators::DominatorStructurer;
+use crate::loopified::Relooper;
+
+pub use crate::c::{CArithmetic, CConfig, emit_c, emit_c_harness, emit_c_mapped, emit_c_with};
+pub use crate::constants::{propagate_constants, remove_unreachable_blocks};
+pub use crate::dominators::reverse_postorder;
+pub use crate::irreducible::{IrreducibleRegion, IrreducibleStrategy, find_irreducible_regions};
+pub use crate::layout::{DataLayout, Endian};
+pub use crate::loopified::{LoopifyConfig, StmtRef, StructuredNode, Structurer};
+pub use crate::short_circuit::recover_short_circuits;
+pub use crate::source_map::SourceMap;
+pub use crate::test_case::{MemoryChunk, TestCase, TestValue};
+
+mod c;
+mod constants;
+mod dominators;
+pub mod egraph;
+pub mod eval;
+pub mod expr;
+pub mod interpreter;
+mod irreducible;
+pub mod jumps;
+pub mod layout;
+pub mod liveness;
+mod loop_recognition;
 mod loopified;
+pub mod ranges;
+pub mod rules;
+mod short_circuit;
+pub mod source_map;
+mod test_case;
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
 
 #[derive(Debug, Clone)]
 pub struct Arena<T>(la_arena::Arena<T>);
@@ -31,6 +64,12 @@ impl<T> Index<Idx<T>> for Arena<T> {
     }
 }
 
+impl<T> IndexMut<Idx<T>> for Arena<T> {
+    fn index_mut(&mut self, index: Idx<T>) -> &mut Self::Output {
+        &mut self.0[index.0]
+    }
+}
+
 impl<T> Default for Arena<T> {
     fn default() -> Self {
         Self(Default::default())
@@ -73,6 +112,18 @@ impl<T> PartialEq for Idx<T> {
     }
 }
 
+impl<T> PartialOrd for Idx<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for Idx<T> {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.to_usize().cmp(&other.to_usize())
+    }
+}
+
 impl<T> Copy for Idx<T> {}
 
 impl<T> Clone for Idx<T> {
@@ -113,9 +164,14 @@ impl<'de, T> Deserialize<'de> for Idx<T> {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum CType {
     Void,
+    /// An IEEE 754 binary floating point number of that many bytes, `float`, `double`
+    /// and `_Float128` for 4, 8 and 16. Arithmetic rounds to nearest, ties to even.
+    /// Converting to an integer rounds towards zero and saturates, with NaN giving 0.
+    /// That is Rust's `as`, not RISC-V's `fcvt`, which gives the largest value for
+    /// NaN. Converting from an integer rounds to nearest.
     Float(u8),
     Int(u8),
     UInt(u8),
@@ -124,12 +180,13 @@ pub enum CType {
 }
 
 impl CType {
-    pub fn size(&self) -> u8 {
+    pub fn size(&self, layout: &DataLayout) -> u8 {
         match self {
+            // Like in GNU C, so that arithmetic on `void *` counts bytes.
             CType::Void => 1,
             CType::Float(n) | CType::Int(n) | CType::UInt(n) => *n,
             CType::Bool => 1,
-            CType::Ptr(_) => 8,
+            CType::Ptr(_) => layout.pointer_size,
         }
     }
 }
@@ -190,7 +247,7 @@ impl Place {
                 if l == *idx {
                     return my_value;
                 }
-                return Value::Place(self.clone());
+                Value::Place(self.clone())
             }
             Place::Deref(place) => Value::Place(Place::Deref(Box::new(
                 place.replace_local(l, my_value).as_place().unwrap().clone(),
@@ -211,24 +268,44 @@ impl Place {
 
 impl Display for Place {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Place::Local(idx) => write!(f, "_{}", idx.to_usize()),
-            Place::Deref(place) => write!(f, "*{place}"),
-            Place::Offset(place, value) => {
-                write!(f, "{place}.offset({value})")
-            }
-        }
+        let expr = expr::place_expr(self, expr::Syntax::Rust, &expr::default_name);
+        write!(f, "{}", expr.print(expr::Syntax::Rust))
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+/// On floats, `Add`, `Sub`, `Mul` and `Div` are the IEEE 754 operations, a NaN they
+/// give has no particular sign or payload, and comparisons are like in IEEE 754 too:
+/// NaN is unequal to everything, itself included, and `-0.0 == 0.0`. Floats can't be
+/// shifted and have no `Rem`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum Binop {
     Add,
     Sub,
     Mul,
+    /// Division rounding towards zero. Like on RISC-V, dividing by zero gives all ones
+    /// (-1 for signed types) and the overflowing `MIN / -1` gives `MIN`.
     Div,
+    /// The remainder of `Div`, which has the sign of the left side. Like on RISC-V,
+    /// the remainder of dividing by zero is the left side and `MIN % -1` is 0.
+    Rem,
+    /// Left shift. Like on RISC-V, only the low bits of the shift amount are used,
+    /// as many as it takes to count the bits of the left side.
+    Shl,
+    /// Logical right shift, which shifts in zeros. The amount is masked like for `Shl`.
+    Shr,
+    /// Arithmetic right shift, which shifts in copies of the sign bit. The amount is
+    /// masked like for `Shl`.
+    Sar,
+    /// Bitwise exclusive or. Bitwise not is `^ -1`.
+    Xor,
     Lt,
     Le,
+    Eq,
+    Ne,
+    /// Logical `&&`, the right side is only evaluated if the left one holds.
+    And,
+    /// Logical `||`, the right side is only evaluated if the left one doesn't hold.
+    Or,
 }
 
 impl Display for Binop {
@@ -238,8 +315,16 @@ impl Display for Binop {
             Binop::Sub => write!(f, "-"),
             Binop::Mul => write!(f, "*"),
             Binop::Div => write!(f, "/"),
+            Binop::Rem => write!(f, "%"),
+            Binop::Shl => write!(f, "<<"),
+            Binop::Shr | Binop::Sar => write!(f, ">>"),
+            Binop::Xor => write!(f, "^"),
             Binop::Lt => write!(f, "<"),
             Binop::Le => write!(f, "<="),
+            Binop::Eq => write!(f, "=="),
+            Binop::Ne => write!(f, "!="),
+            Binop::And => write!(f, "&&"),
+            Binop::Or => write!(f, "||"),
         }
     }
 }
@@ -249,6 +334,9 @@ pub enum Value {
     Place(Place),
     Literal(i32),
     Binop(Box<Value>, Binop, Box<Value>),
+    /// The second value if the first one isn't zero, else the third, like `?:`. Both
+    /// are converted to the type of the first one that isn't a literal.
+    Select(Box<Value>, Box<Value>, Box<Value>),
 }
 
 impl Value {
@@ -256,7 +344,7 @@ impl Value {
         match self {
             Value::Place(place) => place.ty(cfg),
             Value::Literal(_) => CType::Int(4),
-            Value::Binop(value, _, _) => value.ty(cfg),
+            Value::Binop(value, _, _) | Value::Select(_, value, _) => value.ty(cfg),
         }
     }
 
@@ -284,11 +372,48 @@ impl Value {
                 *binop,
                 Box::new(value2.replace_local(l, my_value)),
             ),
+            Value::Select(cond, then, else_) => Value::Select(
+                Box::new(cond.replace_local(l, my_value.clone())),
+                Box::new(then.replace_local(l, my_value.clone())),
+                Box::new(else_.replace_local(l, my_value)),
+            ),
         };
         // println!("result {result}");
         result
     }
 
+    /// The condition that holds exactly when `self` doesn't. Comparisons are turned
+    /// around only when that doesn't change the type they are done in.
+    pub fn negated(&self, cfg: &Cfg) -> Value {
+        let swappable =
+            |l: &Value, r: &Value| ranges::operand_ty(l, r, cfg) == ranges::operand_ty(r, l, cfg);
+        match self {
+            Value::Binop(l, Binop::Lt, r) if swappable(l, r) => {
+                Value::Binop(r.clone(), Binop::Le, l.clone())
+            }
+            Value::Binop(l, Binop::Le, r) if swappable(l, r) => {
+                Value::Binop(r.clone(), Binop::Lt, l.clone())
+            }
+            Value::Binop(l, Binop::Eq, r) => Value::Binop(l.clone(), Binop::Ne, r.clone()),
+            Value::Binop(l, Binop::Ne, r) => Value::Binop(l.clone(), Binop::Eq, r.clone()),
+            Value::Binop(l, Binop::And, r) => Value::Binop(
+                Box::new(l.negated(cfg)),
+                Binop::Or,
+                Box::new(r.negated(cfg)),
+            ),
+            Value::Binop(l, Binop::Or, r) => Value::Binop(
+                Box::new(l.negated(cfg)),
+                Binop::And,
+                Box::new(r.negated(cfg)),
+            ),
+            _ => Value::Binop(
+                Box::new(self.clone()),
+                Binop::Eq,
+                Box::new(Value::Literal(0)),
+            ),
+        }
+    }
+
     pub fn has_local(&self, l: Idx<Local>) -> bool {
         let invalid_local = Idx::from_usize(usize::MAX);
         self.replace_local(l, Value::Place(Place::Local(invalid_local))) != *self
@@ -297,19 +422,35 @@ impl Value {
 
 impl Display for Value {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Value::Place(place) => write!(f, "{place}"),
-            Value::Literal(i) => write!(f, "{i}"),
-            Value::Binop(value1, binop, value2) => {
-                write!(f, "{value1} {binop} {value2}")
-            }
-        }
+        let expr = expr::value_expr(self, expr::Syntax::Rust, &expr::default_name);
+        write!(f, "{}", expr.print(expr::Syntax::Rust))
     }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Stmt {
-    Assign { place: Place, value: Value },
+    Assign {
+        place: Place,
+        value: Value,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        origin: Option<Origin>,
+    },
+}
+
+impl Stmt {
+    pub fn origin(&self) -> Option<Origin> {
+        match self {
+            Stmt::Assign { origin, .. } => *origin,
+        }
+    }
+}
+
+/// Where a statement comes from. Passes that rewrite or move statements keep it,
+/// so that emitted code can be traced back to the input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Origin {
+    /// Address of the machine instruction the statement was lifted from.
+    pub address: u64,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -341,8 +482,13 @@ impl BasicBlock {
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct Cfg {
+    /// `_0` holds the return value and `_1` to `_arg_count` are the arguments.
     pub locals: Arena<Local>,
+    #[serde(default)]
+    pub arg_count: usize,
     pub bb: Arena<BasicBlock>,
+    #[serde(default)]
+    pub layout: DataLayout,
 }
 
 impl Cfg {
@@ -351,8 +497,30 @@ impl Cfg {
     }
 
     pub fn loopify(&self) -> StructuredNode {
-        let relooper = Relooper::new(self);
-        relooper.reloop(Idx::from_usize(0))
+        self.loopify_with(&LoopifyConfig::default())
+    }
+
+    pub fn loopify_with(&self, config: &LoopifyConfig) -> StructuredNode {
+        if let IrreducibleStrategy::NodeSplitting { budget } = config.irreducible
+            && let Some((split, origin)) = irreducible::split_irreducible(self, budget)
+        {
+            let node = split.structure(config);
+            return irreducible::map_to_original(node, &origin, self.bb.iter().count());
+        }
+        self.structure(config)
+    }
+
+    fn structure(&self, config: &LoopifyConfig) -> StructuredNode {
+        let entry = Idx::from_usize(0);
+        let node = match config.structurer {
+            Structurer::Dominators if find_irreducible_regions(self).is_empty() => {
+                DominatorStructurer::new(self, entry).structure()
+            }
+            Structurer::Relooper | Structurer::Dominators => {
+                Relooper::new(self, config.irreducible).reloop(entry)
+            }
+        };
+        loop_recognition::recognize_loops(self, node)
     }
 
     pub fn print(&self) {
@@ -364,7 +532,7 @@ impl Cfg {
             println!("    bb{}: {{", idx.to_usize());
             for stmt in &bb.stmts {
                 match stmt {
-                    Stmt::Assign { place, value } => {
+                    Stmt::Assign { place, value, .. } => {
                         println!("        {place} = {value};");
                     }
                 }
@@ -0,0 +1,190 @@
+// Liveness of locals: which locals hold a value that might still be read. Reads
+// through pointers don't count, locals can't be pointed to.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::loopified::Relooper;
+use crate::*;
+
+/// The result of the analysis for a whole `Cfg`.
+#[derive(Debug, Clone)]
+pub struct Liveness {
+    // The locals live at the start of each block.
+    live_in: BTreeMap<Idx<BasicBlock>, BTreeSet<Idx<Local>>>,
+}
+
+impl Liveness {
+    pub fn new(cfg: &Cfg) -> Self {
+        let mut live_in: BTreeMap<_, BTreeSet<_>> = BTreeMap::new();
+        let mut preds: BTreeMap<_, Vec<_>> = BTreeMap::new();
+        for (idx, block) in cfg.bb.iter() {
+            for succ in Relooper::get_successors_from_block(block) {
+                preds.entry(succ).or_default().push(idx);
+            }
+        }
+        let mut worklist: Vec<_> = cfg.bb.iter().map(|(idx, _)| idx).collect();
+        while let Some(bb) = worklist.pop() {
+            let block = &cfg.bb[bb];
+            let mut live = BTreeSet::new();
+            for succ in Relooper::get_successors_from_block(block) {
+                live.extend(live_in.get(&succ).into_iter().flatten().copied());
+            }
+            let live = backwards(cfg, bb, 0, live);
+            if live_in.get(&bb) != Some(&live) {
+                live_in.insert(bb, live);
+                for pred in preds.get(&bb).into_iter().flatten() {
+                    if !worklist.contains(pred) {
+                        worklist.push(*pred);
+                    }
+                }
+            }
+        }
+        Self { live_in }
+    }
+
+    /// The locals live before statement `index` of `bb`. An index one past the last
+    /// statement is the terminator.
+    pub fn before(&self, cfg: &Cfg, bb: Idx<BasicBlock>, index: usize) -> BTreeSet<Idx<Local>> {
+        let mut live = BTreeSet::new();
+        for succ in Relooper::get_successors_from_block(&cfg.bb[bb]) {
+            live.extend(self.live_in.get(&succ).into_iter().flatten().copied());
+        }
+        backwards(cfg, bb, index, live)
+    }
+}
+
+// Steps back from the end of `bb`, where `live` are live, to before statement
+// `index`.
+fn backwards(
+    cfg: &Cfg,
+    bb: Idx<BasicBlock>,
+    index: usize,
+    mut live: BTreeSet<Idx<Local>>,
+) -> BTreeSet<Idx<Local>> {
+    let block = &cfg.bb[bb];
+    terminator_reads(cfg, block.terminator(), &mut live);
+    for stmt in block.stmts[index.min(block.stmts.len())..].iter().rev() {
+        let Stmt::Assign { place, value, .. } = stmt;
+        if let Place::Local(idx) = place {
+            live.remove(idx);
+        }
+        place_reads(place, false, &mut live);
+        value_reads(value, &mut live);
+    }
+    live
+}
+
+/// Adds the locals that `value` reads to `locals`.
+pub fn value_reads(value: &Value, locals: &mut BTreeSet<Idx<Local>>) {
+    match value {
+        Value::Place(place) => place_reads(place, true, locals),
+        Value::Literal(_) => {}
+        Value::Binop(l, _, r) => {
+            value_reads(l, locals);
+            value_reads(r, locals);
+        }
+        Value::Select(cond, then, else_) => {
+            value_reads(cond, locals);
+            value_reads(then, locals);
+            value_reads(else_, locals);
+        }
+    }
+}
+
+/// Adds the locals read to get at `place` to `locals`, and to get its value if
+/// `read`.
+pub fn place_reads(place: &Place, read: bool, locals: &mut BTreeSet<Idx<Local>>) {
+    match place {
+        Place::Local(idx) => {
+            if read {
+                locals.insert(*idx);
+            }
+        }
+        Place::Deref(place) => place_reads(place, true, locals),
+        Place::Offset(place, offset) => {
+            place_reads(place, true, locals);
+            value_reads(offset, locals);
+        }
+    }
+}
+
+/// Adds the locals that `terminator` reads to `locals`. Returning reads `_0`.
+pub fn terminator_reads(cfg: &Cfg, terminator: &Terminator, locals: &mut BTreeSet<Idx<Local>>) {
+    match terminator {
+        Terminator::Return => {
+            if let Some((idx, local)) = cfg.locals.iter().next()
+                && !matches!(local.ty, CType::Void)
+            {
+                locals.insert(idx);
+            }
+        }
+        Terminator::Goto { .. } => {}
+        Terminator::If { cond, .. } => value_reads(cond, locals),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn local(i: usize) -> Value {
+        Value::from_local(Idx::from_usize(i))
+    }
+
+    fn assign(i: usize, value: Value) -> Stmt {
+        Stmt::Assign {
+            place: Place::Local(Idx::from_usize(i)),
+            value,
+            origin: None,
+        }
+    }
+
+    fn block(stmts: Vec<Stmt>, terminator: Terminator) -> BasicBlock {
+        BasicBlock {
+            stmts,
+            terminator: Some(terminator),
+        }
+    }
+
+    #[test]
+    fn test_loop_carried_local() {
+        // _0 = 0; while (_0 < _1) { _2 = _0; _0 = _2 + 1; } return _0;
+        let mut cfg = Cfg::default();
+        for _ in 0..3 {
+            cfg.locals.alloc(Local {
+                name: None,
+                ty: CType::Int(4),
+            });
+        }
+        let goto = |bb| Terminator::Goto {
+            bb: Idx::from_usize(bb),
+        };
+        let step = Value::Binop(Box::new(local(2)), Binop::Add, Box::new(Value::Literal(1)));
+        cfg.bb
+            .alloc(block(vec![assign(0, Value::Literal(0))], goto(1)));
+        cfg.bb.alloc(block(
+            vec![],
+            Terminator::If {
+                cond: Value::Binop(Box::new(local(0)), Binop::Lt, Box::new(local(1))),
+                then: Idx::from_usize(2),
+                else_: Idx::from_usize(3),
+            },
+        ));
+        cfg.bb
+            .alloc(block(vec![assign(2, local(0)), assign(0, step)], goto(1)));
+        cfg.bb.alloc(block(vec![], Terminator::Return));
+
+        let liveness = Liveness::new(&cfg);
+        let live = |bb, index| {
+            liveness
+                .before(&cfg, Idx::from_usize(bb), index)
+                .into_iter()
+                .map(|idx| idx.to_usize())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(live(0, 0), [1]);
+        assert_eq!(live(1, 0), [0, 1]);
+        assert_eq!(live(2, 1), [1, 2]);
+        assert_eq!(live(3, 0), [0]);
+    }
+}
@@ -0,0 +1,487 @@
+// Post-pass over the relooper output. `Relooper` only produces infinite loops,
+// here we look at their headers and latches and turn them into `While`, `DoWhile`
+// and `For` when the condition can be moved into the loop itself.
+
+use std::collections::{BTreeMap, HashSet};
+
+use crate::loopified::{Relooper, StmtRef, StructuredNode};
+use crate::*;
+
+pub fn recognize_loops(cfg: &Cfg, node: StructuredNode) -> StructuredNode {
+    let recognizer = LoopRecognizer {
+        cfg,
+        predecessors: Relooper::compute_predecessors(cfg),
+    };
+    recognizer.visit(node, None)
+}
+
+struct LoopRecognizer<'a> {
+    cfg: &'a Cfg,
+    predecessors: BTreeMap<Idx<BasicBlock>, Vec<Idx<BasicBlock>>>,
+}
+
+impl LoopRecognizer<'_> {
+    // `follow` is the block that control reaches when `node` falls off its end,
+    // which is also where a `break` out of a loop at this position goes.
+    fn visit(&self, node: StructuredNode, follow: Option<Idx<BasicBlock>>) -> StructuredNode {
+        match node {
+            StructuredNode::Basic(_)
+            | StructuredNode::Duplicate(_)
+            | StructuredNode::Labeled(_)
+            | StructuredNode::While { .. }
+            | StructuredNode::DoWhile { .. }
+            | StructuredNode::For { .. } => node,
+            StructuredNode::Sequence(nodes) => self.visit_sequence(nodes, follow),
+            StructuredNode::If {
+                cond,
+                then_node,
+                else_node,
+            } => StructuredNode::If {
+                cond,
+                then_node: Box::new(self.visit(*then_node, follow)),
+                else_node: Box::new(self.visit(*else_node, follow)),
+            },
+            StructuredNode::Block(body) => {
+                StructuredNode::Block(Box::new(self.visit(*body, follow)))
+            }
+            StructuredNode::Loop(body) => {
+                // Falling off the end of a loop body starts the next iteration.
+                let header = body.entry();
+                let body = self.visit(*body, header);
+                self.recognize_loop(body, follow)
+            }
+            StructuredNode::Dispatch {
+                entry_map,
+                handlers,
+            } => StructuredNode::Dispatch {
+                entry_map,
+                handlers: handlers
+                    .into_iter()
+                    .map(|(label, handler)| (label, self.visit(handler, None)))
+                    .collect(),
+            },
+        }
+    }
+
+    fn visit_sequence(
+        &self,
+        nodes: Vec<StructuredNode>,
+        follow: Option<Idx<BasicBlock>>,
+    ) -> StructuredNode {
+        // Nested sequences are flattened first, so that a loop and the block that
+        // initializes its counter end up next to each other.
+        let nodes = flatten(nodes);
+        let follows: Vec<_> = (0..nodes.len())
+            .map(|i| nodes.get(i + 1).map_or(follow, |next| next.entry()))
+            .collect();
+
+        let mut result: Vec<StructuredNode> = vec![];
+        for (node, follow) in nodes.into_iter().zip(follows) {
+            let node = self.visit(node, follow);
+            let node = self.try_shape_for(result.last(), &node).unwrap_or(node);
+            result.push(node);
+        }
+        StructuredNode::Sequence(result)
+    }
+
+    fn recognize_loop(
+        &self,
+        body: StructuredNode,
+        follow: Option<Idx<BasicBlock>>,
+    ) -> StructuredNode {
+        let Some(header) = body.entry() else {
+            return StructuredNode::Loop(Box::new(body));
+        };
+        let mut loop_blocks = HashSet::new();
+        body.for_each_block(&mut |bb| {
+            loop_blocks.insert(bb);
+        });
+        let nodes = flatten(vec![body]);
+
+        if let Some(node) = self.try_shape_while(header, &loop_blocks, &nodes, follow) {
+            return node;
+        }
+        if let Some(node) = self.try_shape_do_while(header, &loop_blocks, &nodes, follow) {
+            return node;
+        }
+        StructuredNode::Loop(Box::new(StructuredNode::Sequence(nodes)))
+    }
+
+    // loop { if cond { body } else { break } } => while cond { body }
+    fn try_shape_while(
+        &self,
+        header: Idx<BasicBlock>,
+        loop_blocks: &HashSet<Idx<BasicBlock>>,
+        nodes: &[StructuredNode],
+        follow: Option<Idx<BasicBlock>>,
+    ) -> Option<StructuredNode> {
+        let [
+            StructuredNode::Basic(bb),
+            StructuredNode::If {
+                cond,
+                then_node,
+                else_node,
+            },
+            rest @ ..,
+        ] = nodes
+        else {
+            return None;
+        };
+        if *bb != header || !self.cfg.bb[header].stmts.is_empty() {
+            return None;
+        }
+        let Terminator::If { then, else_, .. } = self.cfg.bb[header].terminator() else {
+            return None;
+        };
+        let follow = follow?;
+        let exits = |target: &Idx<BasicBlock>, node: &StructuredNode| {
+            *target == follow && !loop_blocks.contains(target) && node.is_empty()
+        };
+        let (cond, body) = if exits(else_, else_node) {
+            (cond.clone(), then_node)
+        } else if exits(then, then_node) {
+            (cond.negated(self.cfg), else_node)
+        } else {
+            return None;
+        };
+
+        let mut body_nodes = vec![(**body).clone()];
+        body_nodes.extend(rest.iter().cloned());
+        Some(StructuredNode::While {
+            header,
+            cond,
+            body: Box::new(StructuredNode::Sequence(flatten(body_nodes))),
+        })
+    }
+
+    // loop { body; if cond { continue } else { break } } => do { body } while cond
+    fn try_shape_do_while(
+        &self,
+        header: Idx<BasicBlock>,
+        loop_blocks: &HashSet<Idx<BasicBlock>>,
+        nodes: &[StructuredNode],
+        follow: Option<Idx<BasicBlock>>,
+    ) -> Option<StructuredNode> {
+        let [
+            body @ ..,
+            StructuredNode::Basic(latch),
+            StructuredNode::If {
+                cond,
+                then_node,
+                else_node,
+            },
+        ] = nodes
+        else {
+            return None;
+        };
+        if !then_node.is_empty() || !else_node.is_empty() {
+            return None;
+        }
+        let Terminator::If { then, else_, .. } = self.cfg.bb[*latch].terminator() else {
+            return None;
+        };
+        let follow = follow?;
+        let cond = if *then == header && *else_ == follow {
+            cond.clone()
+        } else if *else_ == header && *then == follow {
+            cond.negated(self.cfg)
+        } else {
+            return None;
+        };
+        // A `continue` would evaluate the condition instead of jumping to the top of
+        // the body, so the latch must be the only way back to the header.
+        if self.predecessors[&header]
+            .iter()
+            .any(|pred| loop_blocks.contains(pred) && pred != latch)
+        {
+            return None;
+        }
+
+        let mut body_nodes = body.to_vec();
+        body_nodes.push(StructuredNode::Basic(*latch));
+        Some(StructuredNode::DoWhile {
+            body: Box::new(StructuredNode::Sequence(body_nodes)),
+            latch: *latch,
+            cond,
+        })
+    }
+
+    // i = init; while cond(i) { body; i = i + step } => for (i = init; cond(i); i = i + step) { body }
+    fn try_shape_for(
+        &self,
+        prev: Option<&StructuredNode>,
+        node: &StructuredNode,
+    ) -> Option<StructuredNode> {
+        let StructuredNode::While { header, cond, body } = node else {
+            return None;
+        };
+        let Some(StructuredNode::Basic(pre)) = prev else {
+            return None;
+        };
+
+        // The induction variable is initialized by the last statement before the loop.
+        let pre_block = &self.cfg.bb[*pre];
+        if !matches!(pre_block.terminator(), Terminator::Goto { bb } if bb == header) {
+            return None;
+        }
+        let init_index = pre_block.stmts.len().checked_sub(1)?;
+        let Stmt::Assign { place, .. } = &pre_block.stmts[init_index];
+        let var = place.as_local()?;
+        if !cond.has_local(var) {
+            return None;
+        }
+
+        // Nothing else may jump into the loop, or it would skip the initialization.
+        let mut loop_blocks = HashSet::new();
+        body.for_each_block(&mut |bb| {
+            loop_blocks.insert(bb);
+        });
+        let (latches, entries): (Vec<_>, Vec<_>) = self.predecessors[header]
+            .iter()
+            .copied()
+            .partition(|pred| loop_blocks.contains(pred));
+        let ([latch], [entry]) = (latches.as_slice(), entries.as_slice()) else {
+            return None;
+        };
+        if entry != pre {
+            return None;
+        }
+
+        // The induction variable is stepped by a constant as the last statement of
+        // the latch, and not assigned anywhere else in the loop.
+        let latch_block = &self.cfg.bb[*latch];
+        if !matches!(latch_block.terminator(), Terminator::Goto { bb } if bb == header) {
+            return None;
+        }
+        let step_index = latch_block.stmts.len().checked_sub(1)?;
+        let Stmt::Assign { place, value, .. } = &latch_block.stmts[step_index];
+        if place.as_local() != Some(var) || !is_constant_step(value, var) {
+            return None;
+        }
+        for &bb in &loop_blocks {
+            for (index, stmt) in self.cfg.bb[bb].stmts.iter().enumerate() {
+                let Stmt::Assign { place, .. } = stmt;
+                if place.as_local() == Some(var) && (bb, index) != (*latch, step_index) {
+                    return None;
+                }
+            }
+        }
+
+        Some(StructuredNode::For {
+            init: StmtRef {
+                bb: *pre,
+                index: init_index,
+            },
+            header: *header,
+            cond: cond.clone(),
+            step: StmtRef {
+                bb: *latch,
+                index: step_index,
+            },
+            body: body.clone(),
+        })
+    }
+}
+
+// `var + c` or `var - c` for a literal `c`.
+fn is_constant_step(value: &Value, var: Idx<Local>) -> bool {
+    let Value::Binop(l, Binop::Add | Binop::Sub, r) = value else {
+        return false;
+    };
+    **l == Value::from_local(var) && r.as_literal().is_some()
+}
+
+fn flatten(nodes: Vec<StructuredNode>) -> Vec<StructuredNode> {
+    let mut result = vec![];
+    for node in nodes {
+        match node {
+            StructuredNode::Sequence(inner) => result.extend(flatten(inner)),
+            node => result.push(node),
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn local(i: usize) -> Value {
+        Value::from_local(Idx::from_usize(i))
+    }
+
+    fn binop(l: Value, binop: Binop, r: Value) -> Value {
+        Value::Binop(Box::new(l), binop, Box::new(r))
+    }
+
+    fn assign(i: usize, value: Value) -> Stmt {
+        Stmt::Assign {
+            place: Place::Local(Idx::from_usize(i)),
+            value,
+            origin: None,
+        }
+    }
+
+    fn block(stmts: Vec<Stmt>, terminator: Terminator) -> BasicBlock {
+        BasicBlock {
+            stmts,
+            terminator: Some(terminator),
+        }
+    }
+
+    fn goto(bb: usize) -> Terminator {
+        Terminator::Goto {
+            bb: Idx::from_usize(bb),
+        }
+    }
+
+    fn branch(cond: Value, then: usize, else_: usize) -> Terminator {
+        Terminator::If {
+            cond,
+            then: Idx::from_usize(then),
+            else_: Idx::from_usize(else_),
+        }
+    }
+
+    fn with_locals(tys: impl IntoIterator<Item = CType>) -> Cfg {
+        let mut cfg = Cfg::default();
+        for ty in tys {
+            cfg.locals.alloc(Local { name: None, ty });
+        }
+        cfg
+    }
+
+    // The loop the function's body has, at the top level.
+    fn the_loop(node: StructuredNode) -> StructuredNode {
+        let loops: Vec<_> = flatten(vec![node])
+            .into_iter()
+            .filter(|node| {
+                matches!(
+                    node,
+                    StructuredNode::Loop(_)
+                        | StructuredNode::While { .. }
+                        | StructuredNode::DoWhile { .. }
+                        | StructuredNode::For { .. }
+                )
+            })
+            .collect();
+        let [node] = loops.as_slice() else {
+            panic!("not one loop: {loops:?}");
+        };
+        node.clone()
+    }
+
+    // bb0: _0 = 0; goto bb1
+    // bb1: if _2 < _1 { goto bb3 } else { goto bb2 }
+    // bb2: _0 = _0 + _2; _2 = _2 + 1; goto bb1
+    // bb3: return
+    fn exit_on_true(ty: CType) -> Cfg {
+        let mut cfg = with_locals([CType::Int(4), ty, CType::Int(4)]);
+        cfg.bb
+            .alloc(block(vec![assign(0, Value::Literal(0))], goto(1)));
+        cfg.bb.alloc(block(
+            vec![],
+            branch(binop(local(2), Binop::Lt, local(1)), 3, 2),
+        ));
+        cfg.bb.alloc(block(
+            vec![
+                assign(0, binop(local(0), Binop::Add, local(2))),
+                assign(2, binop(local(2), Binop::Add, Value::Literal(1))),
+            ],
+            goto(1),
+        ));
+        cfg.bb.alloc(block(vec![], Terminator::Return));
+        cfg
+    }
+
+    #[test]
+    fn test_while() {
+        let cfg = exit_on_true(CType::Int(4));
+        let StructuredNode::While { header, cond, .. } = the_loop(cfg.loopify()) else {
+            panic!("not a while loop");
+        };
+        assert_eq!(header.to_usize(), 1);
+        assert_eq!(cond, binop(local(1), Binop::Le, local(2)));
+
+        // Compared in `i32`, which `_1 <= _2` wouldn't be.
+        let cfg = exit_on_true(CType::Int(8));
+        let StructuredNode::While { cond, .. } = the_loop(cfg.loopify()) else {
+            panic!("not a while loop");
+        };
+        assert_eq!(
+            cond,
+            binop(
+                binop(local(2), Binop::Lt, local(1)),
+                Binop::Eq,
+                Value::Literal(0)
+            )
+        );
+    }
+
+    #[test]
+    fn test_do_while() {
+        // bb0: goto bb1
+        // bb1: _0 = _0 + 1; if _0 < _1 { goto bb2 } else { goto bb1 }
+        // bb2: return
+        let mut cfg = with_locals([CType::Int(2), CType::UInt(4)]);
+        cfg.bb.alloc(block(vec![], goto(1)));
+        cfg.bb.alloc(block(
+            vec![assign(0, binop(local(0), Binop::Add, Value::Literal(1)))],
+            branch(binop(local(0), Binop::Lt, local(1)), 2, 1),
+        ));
+        cfg.bb.alloc(block(vec![], Terminator::Return));
+        let StructuredNode::DoWhile { latch, cond, .. } = the_loop(cfg.loopify()) else {
+            panic!("not a do-while loop");
+        };
+        assert_eq!(latch.to_usize(), 1);
+        // Compared in `i16`, which `_1 <= _0` wouldn't be.
+        assert_eq!(
+            cond,
+            binop(
+                binop(local(0), Binop::Lt, local(1)),
+                Binop::Eq,
+                Value::Literal(0)
+            )
+        );
+    }
+
+    #[test]
+    fn test_for() {
+        // for (_2 = 0; _2 < _1; _2 = _2 + 1) { body; _0 = _0 + _2; }
+        let counting = |body: Vec<Stmt>| {
+            let mut cfg = with_locals(vec![CType::Int(4); 3]);
+            cfg.bb
+                .alloc(block(vec![assign(2, Value::Literal(0))], goto(1)));
+            cfg.bb.alloc(block(
+                vec![],
+                branch(binop(local(2), Binop::Lt, local(1)), 2, 3),
+            ));
+            let step = [
+                assign(0, binop(local(0), Binop::Add, local(2))),
+                assign(2, binop(local(2), Binop::Add, Value::Literal(1))),
+            ];
+            cfg.bb
+                .alloc(block(body.into_iter().chain(step).collect(), goto(1)));
+            cfg.bb.alloc(block(vec![], Terminator::Return));
+            cfg
+        };
+        let StructuredNode::For {
+            init,
+            header,
+            cond,
+            step,
+            ..
+        } = the_loop(counting(vec![]).loopify())
+        else {
+            panic!("not a for loop");
+        };
+        assert_eq!((init.bb.to_usize(), init.index), (0, 0));
+        assert_eq!(header.to_usize(), 1);
+        assert_eq!(cond, binop(local(2), Binop::Lt, local(1)));
+        assert_eq!((step.bb.to_usize(), step.index), (2, 1));
+
+        // Assigning the counter in the body leaves a while loop.
+        let node = the_loop(counting(vec![assign(2, local(0))]).loopify());
+        assert!(matches!(node, StructuredNode::While { .. }), "{node:?}");
+    }
+}
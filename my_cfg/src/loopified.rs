@@ -1,10 +1,11 @@
 // relooper.rs
 
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use crate::irreducible::{IrreducibleRegion, IrreducibleStrategy, find_irreducible_regions};
 use crate::*;
 
 // (StructuredNode enum remains the same)
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum StructuredNode {
     Basic(Idx<BasicBlock>),
     Sequence(Vec<StructuredNode>),
@@ -14,27 +15,165 @@ pub enum StructuredNode {
         else_node: Box<StructuredNode>,
     },
     Loop(Box<StructuredNode>),
+    /// A pre-tested loop. `header` only evaluates `cond`, so it has no statements
+    /// and is not part of `body`. Jumps to `header` are `continue`s.
+    While {
+        header: Idx<BasicBlock>,
+        cond: Value,
+        body: Box<StructuredNode>,
+    },
+    /// A post-tested loop. `latch` is the last block of `body`, and its `If`
+    /// terminator is replaced by `cond` (true means another iteration).
+    DoWhile {
+        body: Box<StructuredNode>,
+        latch: Idx<BasicBlock>,
+        cond: Value,
+    },
+    /// A counted loop. It is a `While` whose `init` statement is the last statement
+    /// before the loop and whose `step` is the last statement of its only latch.
+    /// Both statements are owned by the loop and should not be emitted as part of
+    /// their blocks.
+    For {
+        init: StmtRef,
+        header: Idx<BasicBlock>,
+        cond: Value,
+        step: StmtRef,
+        body: Box<StructuredNode>,
+    },
     Dispatch {
-        entry_map: HashMap<Idx<BasicBlock>, i32>,
+        entry_map: BTreeMap<Idx<BasicBlock>, i32>,
         handlers: Vec<(i32, StructuredNode)>,
     },
+    /// An irreducible region laid out with `IrreducibleStrategy::Goto`. Every block
+    /// gets a label and jumps between them are `goto`s.
+    Labeled(Vec<Idx<BasicBlock>>),
+    /// Another copy of a block, made by `IrreducibleStrategy::NodeSplitting`. It is
+    /// emitted like `Basic`.
+    Duplicate(Idx<BasicBlock>),
+    /// Code that jumps can leave early, made by `Structurer::Dominators`. A jump to
+    /// the block that follows it is a break out of it. Emitters can leave the label
+    /// out when every such jump is a fallthrough.
+    Block(Box<StructuredNode>),
+}
+
+/// Options for `Cfg::loopify_with`.
+#[derive(Debug, Clone, Default)]
+pub struct LoopifyConfig {
+    pub structurer: Structurer,
+    pub irreducible: IrreducibleStrategy,
+}
+
+/// The algorithm that turns the graph into structured code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Structurer {
+    #[default]
+    Relooper,
+    /// Structures along the dominator tree. It never needs `Dispatch` or `Labeled`
+    /// for a reducible graph. Irreducible graphs are left to the relooper, unless
+    /// `IrreducibleStrategy::NodeSplitting` makes them reducible first.
+    Dominators,
+}
+
+/// Position of a statement inside the `Cfg`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StmtRef {
+    pub bb: Idx<BasicBlock>,
+    pub index: usize,
+}
+
+impl StmtRef {
+    pub fn stmt<'a>(&self, cfg: &'a Cfg) -> &'a Stmt {
+        &cfg.bb[self.bb].stmts[self.index]
+    }
+}
+
+impl StructuredNode {
+    /// The block that control enters first when this node runs.
+    pub fn entry(&self) -> Option<Idx<BasicBlock>> {
+        match self {
+            StructuredNode::Basic(bb) | StructuredNode::Duplicate(bb) => Some(*bb),
+            StructuredNode::Labeled(blocks) => blocks.first().copied(),
+            StructuredNode::Sequence(nodes) => nodes.iter().find_map(|n| n.entry()),
+            StructuredNode::If { .. } | StructuredNode::Dispatch { .. } => None,
+            StructuredNode::Loop(body)
+            | StructuredNode::DoWhile { body, .. }
+            | StructuredNode::Block(body) => body.entry(),
+            StructuredNode::While { header, .. } | StructuredNode::For { header, .. } => {
+                Some(*header)
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        match self {
+            StructuredNode::Sequence(nodes) => nodes.iter().all(|n| n.is_empty()),
+            _ => false,
+        }
+    }
+
+    /// Calls `f` for every block that is placed inside this node.
+    pub fn for_each_block(&self, f: &mut impl FnMut(Idx<BasicBlock>)) {
+        match self {
+            StructuredNode::Basic(bb) | StructuredNode::Duplicate(bb) => f(*bb),
+            StructuredNode::Labeled(blocks) => blocks.iter().copied().for_each(f),
+            StructuredNode::Sequence(nodes) => {
+                for node in nodes {
+                    node.for_each_block(f);
+                }
+            }
+            StructuredNode::If {
+                then_node,
+                else_node,
+                ..
+            } => {
+                then_node.for_each_block(f);
+                else_node.for_each_block(f);
+            }
+            StructuredNode::Loop(body)
+            | StructuredNode::DoWhile { body, .. }
+            | StructuredNode::Block(body) => body.for_each_block(f),
+            StructuredNode::While { header, body, .. }
+            | StructuredNode::For { header, body, .. } => {
+                f(*header);
+                body.for_each_block(f);
+            }
+            StructuredNode::Dispatch { handlers, .. } => {
+                for (_, handler) in handlers {
+                    handler.for_each_block(f);
+                }
+            }
+        }
+    }
 }
 
 pub struct Relooper<'a> {
     cfg: &'a Cfg,
-    processed: HashSet<Idx<BasicBlock>>,
-    predecessors: HashMap<Idx<BasicBlock>, Vec<Idx<BasicBlock>>>,
+    processed: BTreeSet<Idx<BasicBlock>>,
+    // Blocks that belong to an enclosing structure (what comes after an `if`, blocks
+    // outside of the loop being shaped). They are treated like processed ones until the
+    // enclosing structure is done with its inner parts.
+    stops: BTreeSet<Idx<BasicBlock>>,
+    predecessors: BTreeMap<Idx<BasicBlock>, Vec<Idx<BasicBlock>>>,
     label_counter: i32,
+    strategy: IrreducibleStrategy,
+    irreducible_regions: Vec<IrreducibleRegion>,
+    // Headers whose loop body is currently being shaped, so that the body
+    // doesn't get wrapped in the same loop again.
+    loop_headers: BTreeSet<Idx<BasicBlock>>,
 }
 
 impl<'a> Relooper<'a> {
-    pub fn new(cfg: &'a Cfg) -> Self {
+    pub fn new(cfg: &'a Cfg, strategy: IrreducibleStrategy) -> Self {
         let predecessors = Self::compute_predecessors(cfg);
         Self {
             cfg,
-            processed: HashSet::new(),
+            processed: BTreeSet::new(),
+            stops: BTreeSet::new(),
             predecessors,
             label_counter: 0,
+            strategy,
+            irreducible_regions: find_irreducible_regions(cfg),
+            loop_headers: BTreeSet::new(),
         }
     }
 
@@ -45,7 +184,7 @@ impl<'a> Relooper<'a> {
     fn shape_blocks(&mut self, entries: Vec<Idx<BasicBlock>>) -> StructuredNode {
         let entries: Vec<_> = entries
             .into_iter()
-            .filter(|e| !self.processed.contains(e))
+            .filter(|e| !self.is_done(*e))
             .collect();
 
         if entries.is_empty() {
@@ -54,15 +193,20 @@ impl<'a> Relooper<'a> {
 
         if entries.len() == 1 {
             let entry_idx = entries[0];
-            // The order here is important: Simple chains are the most basic,
-            // then we check for specific structures like If and Loop.
-            if let Some(node) = self.try_shape_simple(entry_idx) {
+            // The order here is important: a loop header must be recognized before
+            // anything else consumes it, then simple chains are the most basic, and
+            // finally we check for If structures. Irreducible regions contain loops
+            // that `try_shape_loop` would reject, so they go before everything.
+            if let Some(node) = self.try_shape_irreducible(entry_idx) {
                 return node;
             }
-            if let Some(node) = self.try_shape_if(entry_idx) {
+            if let Some(node) = self.try_shape_loop(entry_idx) {
                 return node;
             }
-            if let Some(node) = self.try_shape_loop(entry_idx) {
+            if let Some(node) = self.try_shape_simple(entry_idx) {
+                return node;
+            }
+            if let Some(node) = self.try_shape_if(entry_idx) {
                 return node;
             }
         }
@@ -86,8 +230,11 @@ impl<'a> Relooper<'a> {
             // A block can be part of a simple sequence if it has not been processed
             // and has exactly one predecessor (unless it's the very first entry).
             let preds = self.predecessors.get(&current_idx).map_or(0, |v| v.len());
-            if self.processed.contains(&current_idx) || (current_idx != entry_idx && preds != 1) {
-                // We've hit a merge point or an already processed block.
+            if self.is_done(current_idx)
+                || (current_idx != entry_idx && preds != 1)
+                || matches!(self.cfg.bb[current_idx].terminator(), Terminator::If { .. })
+            {
+                // We've hit a merge point, an already processed block or a branch.
                 // Shape what comes next and append it to our sequence.
                 let next_node = self.shape_blocks(vec![current_idx]);
                 sequence.push(next_node);
@@ -106,12 +253,7 @@ impl<'a> Relooper<'a> {
                     // End of a path.
                     return Some(StructuredNode::Sequence(sequence));
                 }
-                Terminator::If { .. } => {
-                    // This is the last block in the simple sequence.
-                    // The branch itself will be handled by the next call to `shape_blocks`.
-                    let next_node = self.shape_blocks(vec![current_idx]);
-                    return Some(StructuredNode::Sequence(vec![next_node]));
-                }
+                Terminator::If { .. } => unreachable!("branches end the sequence above"),
             }
         }
     }
@@ -124,183 +266,325 @@ impl<'a> Relooper<'a> {
             _ => return None,
         };
 
-        // Find a merge point for the two branches.
-        let then_reachable = self.find_reachable(&[then_idx], &self.processed);
-        let else_reachable = self.find_reachable(&[else_idx], &self.processed);
-        
-        let mut merge_point = None;
-        // A simple merge is when one branch's successor is the other branch's entry.
-        if self.successors(then_idx).len() == 1 && self.successors(then_idx)[0] == else_idx {
-             // then -> else, not a diamond, handle differently or not at all here.
-        } else if let Some(mp) = self.find_merge_point(&then_reachable, &else_reachable) {
-             merge_point = Some(mp);
-        }
+        self.processed.insert(entry_idx);
 
-        if let Some(mp) = merge_point {
-             self.processed.insert(entry_idx);
-             let then_node = self.shape_blocks(vec![then_idx]);
-             let else_node = self.shape_blocks(vec![else_idx]);
-             let next_node = self.shape_blocks(vec![mp]);
-
-             let if_node = StructuredNode::If {
-                 cond,
-                 then_node: Box::new(then_node),
-                 else_node: Box::new(else_node),
-             };
-             
-             // Combine the `if` and the code after it into a sequence.
-             return Some(StructuredNode::Sequence(vec![
-                 StructuredNode::Basic(entry_idx),
-                 if_node,
-                 next_node,
-             ]));
+        // Everything both branches reach comes after the `if`, so the branches stop
+        // there. Targets that are already processed (loop headers, loop exits) are
+        // left to the terminator and shape to nothing. An irreducible region is only
+        // entered as a whole, so if a shared block is inside one, none of the
+        // branches may enter it.
+        let then_reachable = self.find_reachable_ordered(then_idx);
+        let else_reachable = self.find_reachable_ordered(else_idx);
+        let mut shared: Vec<_> = then_reachable
+            .into_iter()
+            .filter(|idx| else_reachable.contains(idx))
+            .collect();
+        for region in &self.irreducible_regions {
+            if region.blocks.iter().any(|idx| shared.contains(idx)) {
+                shared.extend(region.blocks.iter().copied());
+            }
         }
+        let added_stops = self.add_stops(shared);
+        let then_node = self.shape_blocks(vec![then_idx]);
+        let else_node = self.shape_blocks(vec![else_idx]);
+        self.remove_stops(added_stops);
+
+        let if_node = StructuredNode::If {
+            cond,
+            then_node: Box::new(then_node),
+            else_node: Box::new(else_node),
+        };
 
-        None
+        // Combine the `if` and the code after it into a sequence.
+        Some(self.shape_after(StructuredNode::Sequence(vec![
+            StructuredNode::Basic(entry_idx),
+            if_node,
+        ])))
     }
 
     // BUGFIX #2: Rewritten `try_shape_loop` for clarity and correctness.
     fn try_shape_loop(&mut self, entry_idx: Idx<BasicBlock>) -> Option<StructuredNode> {
-        // Find all blocks reachable from the entry, forming a potential loop body.
-        let reachable_from_entry = self.find_reachable(&[entry_idx], &self.processed);
+        if self.loop_headers.contains(&entry_idx) {
+            return None;
+        }
 
-        // A natural loop exists if one of the reachable nodes can branch back to the entry.
-        let is_loop = reachable_from_entry
+        // Find all blocks reachable from the entry, the loop body is a subset of them.
+        let reachable_from_entry = self.find_reachable(&[entry_idx]);
+
+        // The natural loop is every reachable block that can branch back to the entry.
+        let mut loop_body = BTreeSet::from([entry_idx]);
+        let mut queue: VecDeque<_> = self.predecessors[&entry_idx]
             .iter()
-            .any(|&idx| self.successors(idx).contains(&entry_idx));
-        
-        if !is_loop {
+            .copied()
+            .filter(|p| reachable_from_entry.contains(p))
+            .collect();
+        if queue.is_empty() {
             return None;
         }
-
-        // Check that this is a single-entry loop. All predecessors of the header (entry_idx)
-        // must either be from inside the loop body or be the single entry point.
-        let mut external_preds = 0;
-        for pred in self.predecessors.get(&entry_idx).unwrap_or(&vec![]) {
-            if !reachable_from_entry.contains(pred) {
-                external_preds += 1;
+        while let Some(idx) = queue.pop_front() {
+            if !loop_body.insert(idx) {
+                continue;
+            }
+            for &pred in &self.predecessors[&idx] {
+                if reachable_from_entry.contains(&pred) {
+                    queue.push_back(pred);
+                }
             }
-        }
-        
-        // If more than one external block can enter the loop header, it's not a simple loop.
-        if external_preds > 1 {
-            return None;
         }
 
-        // Mark the entire loop body as processed.
-        for &idx in &reachable_from_entry {
-            self.processed.insert(idx);
+        // Check that this is a single-entry loop. Only the header (entry_idx) may have
+        // predecessors from outside of the loop body.
+        let has_side_entry = loop_body.iter().any(|&idx| {
+            idx != entry_idx
+                && self.predecessors[&idx]
+                    .iter()
+                    .any(|pred| !loop_body.contains(pred))
+        });
+        if has_side_entry {
+            return None;
         }
 
-        // The loop body itself needs to be structured. We tell the recursive call
-        // to only process the blocks inside the loop set.
+        // The loop body itself needs to be structured. We hide everything outside of
+        // the loop from the recursive call for the duration.
+        let outside: Vec<_> = self
+            .cfg
+            .bb
+            .iter()
+            .map(|(idx, _)| idx)
+            .filter(|idx| !loop_body.contains(idx))
+            .collect();
+        let added_stops = self.add_stops(outside);
+        self.loop_headers.insert(entry_idx);
         let body_node = self.shape_blocks(vec![entry_idx]);
+        self.loop_headers.remove(&entry_idx);
+        self.remove_stops(added_stops);
 
-        // Find all exits from the loop. These are the entry points for the next structure.
-        let mut loop_exits = vec![];
-        for &idx in &reachable_from_entry {
-            for succ in self.successors(idx) {
-                if !reachable_from_entry.contains(&succ) {
-                    loop_exits.push(succ);
+        // The exits from the loop are the entry points for the next structure.
+        let loop_node = StructuredNode::Loop(Box::new(body_node));
+        Some(self.shape_after(loop_node))
+    }
+
+    fn try_shape_irreducible(&mut self, entry_idx: Idx<BasicBlock>) -> Option<StructuredNode> {
+        let region = self
+            .irreducible_regions
+            .iter()
+            .find(|region| region.blocks.contains(&entry_idx))?;
+        // Control falls into the region through `entry_idx`, so it goes first.
+        let mut blocks = vec![entry_idx];
+        blocks.extend(region.blocks.iter().copied().filter(|idx| *idx != entry_idx));
+        let mut entries = vec![entry_idx];
+        entries.extend(region.entries.iter().copied().filter(|idx| *idx != entry_idx));
+
+        match self.strategy {
+            IrreducibleStrategy::Dispatch => {
+                Some(self.shape_dispatch(entries, blocks.into_iter().collect()))
+            }
+            // Node splitting happens on the whole `Cfg` before relooping. If we still
+            // see an irreducible region here, it didn't fit in the budget.
+            IrreducibleStrategy::Goto | IrreducibleStrategy::NodeSplitting { .. } => {
+                for &idx in &blocks {
+                    self.processed.insert(idx);
                 }
+                Some(self.shape_after(StructuredNode::Labeled(blocks)))
             }
         }
+    }
 
-        let next_node = self.shape_blocks(loop_exits);
-        
-        let loop_node = StructuredNode::Loop(Box::new(body_node));
-        Some(StructuredNode::Sequence(vec![loop_node, next_node]))
+    // Appends to the already placed `node` what it jumps to, one block at a time. The
+    // next one is a block that none of the others lead to, and comes with what only
+    // it leads to. While more than one is waiting, everything before the next one is
+    // wrapped in a `Block`, so that jumps to the later ones are breaks out of it.
+    // Blocks that all lead to each other are an irreducible region, or a dispatch.
+    fn shape_after(&mut self, node: StructuredNode) -> StructuredNode {
+        let mut node = node;
+        let mut waiting = 0;
+        let mut leaves = false;
+        loop {
+            let mut placed = vec![];
+            node.for_each_block(&mut |idx| placed.push(idx));
+            let targets = self.find_exits(&placed);
+            leaves |= targets.iter().any(|idx| self.stops.contains(idx));
+            let next: Vec<_> = targets
+                .into_iter()
+                .filter(|idx| !self.is_done(*idx))
+                .collect();
+            if next.is_empty() {
+                break;
+            }
+
+            if waiting > 1 {
+                node = StructuredNode::Block(Box::new(node));
+            }
+            waiting = next.len();
+            let reachable: Vec<_> = next
+                .iter()
+                .map(|idx| self.find_reachable(&[*idx]))
+                .collect();
+            let first = (0..next.len())
+                .find(|&i| (0..next.len()).all(|j| i == j || !reachable[j].contains(&next[i])));
+            let next_node = match first {
+                Some(i) => {
+                    let later = reachable
+                        .iter()
+                        .enumerate()
+                        .filter(|(j, _)| *j != i)
+                        .flat_map(|(_, blocks)| blocks.iter().copied())
+                        .collect();
+                    let added_stops = self.add_stops(later);
+                    let next_node = self.shape_blocks(vec![next[i]]);
+                    self.remove_stops(added_stops);
+                    next_node
+                }
+                // Entries into one irreducible region are the region's business.
+                None if self
+                    .irreducible_regions
+                    .iter()
+                    .any(|region| next.iter().all(|idx| region.blocks.contains(idx))) =>
+                {
+                    self.shape_blocks(vec![next[0]])
+                }
+                None => self.shape_blocks(next),
+            };
+            node = StructuredNode::Sequence(vec![node, next_node]);
+        }
+
+        // Jumps to the blocks of enclosing structures have to get past what was
+        // appended, so they break out of a `Block` too.
+        if leaves && waiting > 0 {
+            node = StructuredNode::Block(Box::new(node));
+        }
+        node
     }
 
-    // `shape_multiple` remains the fallback for complex cases and is largely the same.
+    // `shape_multiple` remains the fallback for complex cases.
     fn shape_multiple(&mut self, entries: Vec<Idx<BasicBlock>>) -> StructuredNode {
-        // (Implementation is identical to the previous version)
-        let reachable_set = self.find_reachable(&entries, &self.processed);
-        for &idx in &reachable_set { self.processed.insert(idx); }
+        let reachable_set = self.find_reachable(&entries);
+        self.shape_dispatch(entries, reachable_set)
+    }
+
+    fn shape_dispatch(
+        &mut self,
+        entries: Vec<Idx<BasicBlock>>,
+        region: BTreeSet<Idx<BasicBlock>>,
+    ) -> StructuredNode {
+        for &idx in &region { self.processed.insert(idx); }
 
-        let mut entry_map = HashMap::new();
-        for &entry in &entries {
-            entry_map.insert(entry, self.label_counter);
+        // Every block that isn't only reached by a `Goto` from inside the region needs
+        // its own handler, the rest are chained after their predecessor.
+        let mut heads = entries.clone();
+        for (idx, _) in self.cfg.bb.iter() {
+            if !region.contains(&idx) || heads.contains(&idx) {
+                continue;
+            }
+            let chained = match self.predecessors[&idx].as_slice() {
+                [pred] => {
+                    region.contains(pred)
+                        && matches!(self.cfg.bb[*pred].terminator(), Terminator::Goto { .. })
+                }
+                _ => false,
+            };
+            if !chained {
+                heads.push(idx);
+            }
+        }
+
+        let mut entry_map = BTreeMap::new();
+        for &head in &heads {
+            entry_map.insert(head, self.label_counter);
             self.label_counter += 1;
         }
         
         let mut handlers = Vec::new();
-        for &entry in &entries {
-            let label = entry_map[&entry];
-            let handler_node = self.shape_handler(entry, &entry_map);
+        for &head in &heads {
+            let label = entry_map[&head];
+            let handler_node = self.shape_handler(head, &entry_map, &region);
             handlers.push((label, handler_node));
         }
 
-        let mut exits = vec![];
-        for &idx in &reachable_set {
-            for succ in self.successors(idx) {
-                if !reachable_set.contains(&succ) {
-                    exits.push(succ);
-                }
-            }
-        }
-        
-        let dispatch_node = StructuredNode::Dispatch { entry_map, handlers };
-        let next_node = self.shape_blocks(exits);
-        StructuredNode::Sequence(vec![dispatch_node, next_node])
+        self.shape_after(StructuredNode::Dispatch {
+            entry_map,
+            handlers,
+        })
     }
 
     fn shape_handler(
         &self,
         entry: Idx<BasicBlock>,
-        entry_map: &HashMap<Idx<BasicBlock>, i32>,
+        entry_map: &BTreeMap<Idx<BasicBlock>, i32>,
+        region: &BTreeSet<Idx<BasicBlock>>,
     ) -> StructuredNode {
-        // (Implementation is identical to the previous version)
         let mut sequence = vec![];
         let mut current_idx = entry;
         loop {
             sequence.push(StructuredNode::Basic(current_idx));
-            let successors = self.successors(current_idx);
-            if successors.len() == 1 {
-                let succ = successors[0];
-                if entry_map.contains_key(&succ) && succ != entry { break; }
-                current_idx = succ;
-            } else {
-                break;
+            match self.successors(current_idx).as_slice() {
+                [succ] if !entry_map.contains_key(succ) && region.contains(succ) => {
+                    current_idx = *succ;
+                }
+                _ => break,
             }
         }
         StructuredNode::Sequence(sequence)
     }
-    
-    // (Helper functions `find_reachable`, `compute_predecessors`, `successors` are the same,
-    // plus a new one `find_merge_point`)
-    fn find_merge_point(
-        &self,
-        set1: &HashSet<Idx<BasicBlock>>,
-        set2: &HashSet<Idx<BasicBlock>>,
-    ) -> Option<Idx<BasicBlock>> {
-        // A simple but effective way to find a merge point is to find a successor
-        // of set1 that is also a successor of set2.
-        for &idx1 in set1 {
-            for succ1 in self.successors(idx1) {
-                if !set1.contains(&succ1) { // It's an exit of set1
-                    for &idx2 in set2 {
-                        for succ2 in self.successors(idx2) {
-                            if succ1 == succ2 {
-                                return Some(succ1);
-                            }
-                        }
-                    }
+
+    // Successors of `blocks` that are outside of them, in order of appearance.
+    fn find_exits(&self, blocks: &[Idx<BasicBlock>]) -> Vec<Idx<BasicBlock>> {
+        let mut exits = vec![];
+        for &idx in blocks {
+            for succ in self.successors(idx) {
+                if !blocks.contains(&succ) && !exits.contains(&succ) {
+                    exits.push(succ);
                 }
             }
         }
-        None
+        exits
+    }
+    
+    // Blocks reachable from `entry` without passing through processed blocks, in
+    // breadth-first order. Stops are included, but not walked through, so that what
+    // comes after an enclosing structure is shared by the branches of a nested `if`.
+    fn find_reachable_ordered(&self, entry: Idx<BasicBlock>) -> Vec<Idx<BasicBlock>> {
+        let mut reachable = vec![];
+        let mut queue = VecDeque::from([entry]);
+        while let Some(idx) = queue.pop_front() {
+            if self.processed.contains(&idx) || reachable.contains(&idx) {
+                continue;
+            }
+            reachable.push(idx);
+            if !self.stops.contains(&idx) {
+                queue.extend(self.successors(idx));
+            }
+        }
+        reachable
+    }
+
+    fn is_done(&self, idx: Idx<BasicBlock>) -> bool {
+        self.processed.contains(&idx) || self.stops.contains(&idx)
+    }
+
+    // Returns the blocks that weren't stops already, to be given to `remove_stops`.
+    fn add_stops(&mut self, blocks: Vec<Idx<BasicBlock>>) -> Vec<Idx<BasicBlock>> {
+        blocks
+            .into_iter()
+            .filter(|idx| self.stops.insert(*idx))
+            .collect()
+    }
+
+    fn remove_stops(&mut self, blocks: Vec<Idx<BasicBlock>>) {
+        for idx in blocks {
+            self.stops.remove(&idx);
+        }
     }
 
-    fn find_reachable(&self, entries: &[Idx<BasicBlock>], processed: &HashSet<Idx<BasicBlock>>) -> HashSet<Idx<BasicBlock>> {
-        let mut reachable = HashSet::new();
+    fn find_reachable(&self, entries: &[Idx<BasicBlock>]) -> BTreeSet<Idx<BasicBlock>> {
+        let mut reachable = BTreeSet::new();
         let mut queue: VecDeque<_> = entries.iter().cloned().collect();
-        let initial_entries: HashSet<_> = entries.iter().cloned().collect();
+        let initial_entries: BTreeSet<_> = entries.iter().cloned().collect();
 
         while let Some(idx) = queue.pop_front() {
             if !reachable.insert(idx) { continue; }
             for succ in self.successors(idx) {
-                if !processed.contains(&succ) || initial_entries.contains(&succ) {
+                if !self.is_done(succ) || initial_entries.contains(&succ) {
                     queue.push_back(succ);
                 }
             }
@@ -308,8 +592,8 @@ impl<'a> Relooper<'a> {
         reachable
     }
     
-    fn compute_predecessors(cfg: &Cfg) -> HashMap<Idx<BasicBlock>, Vec<Idx<BasicBlock>>> {
-        let mut preds = HashMap::new();
+    pub(crate) fn compute_predecessors(cfg: &Cfg) -> BTreeMap<Idx<BasicBlock>, Vec<Idx<BasicBlock>>> {
+        let mut preds = BTreeMap::new();
         for (idx, _) in cfg.bb.iter() { preds.insert(idx, vec![]); }
         for (idx, block) in cfg.bb.iter() {
             for succ in Self::get_successors_from_block(block) {
@@ -323,11 +607,283 @@ impl<'a> Relooper<'a> {
         Self::get_successors_from_block(&self.cfg.bb[idx])
     }
     
-    fn get_successors_from_block(block: &BasicBlock) -> Vec<Idx<BasicBlock>> {
+    pub(crate) fn get_successors_from_block(block: &BasicBlock) -> Vec<Idx<BasicBlock>> {
          match block.terminator() {
             Terminator::Return => vec![],
             Terminator::Goto { bb } => vec![*bb],
             Terminator::If { then, else_, .. } => vec![*then, *else_],
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{assign, binop, local, loops, with_locals};
+
+    fn block(stmts: Vec<Stmt>, terminator: Terminator) -> BasicBlock {
+        BasicBlock {
+            stmts,
+            terminator: Some(terminator),
+        }
+    }
+
+    fn goto(bb: usize) -> Terminator {
+        Terminator::Goto {
+            bb: Idx::from_usize(bb),
+        }
+    }
+
+    fn branch(local: usize, then: usize, else_: usize) -> Terminator {
+        Terminator::If {
+            cond: Value::from_local(Idx::from_usize(local)),
+            then: Idx::from_usize(then),
+            else_: Idx::from_usize(else_),
+        }
+    }
+
+    // A loop with several exits that lead into an irreducible region.
+    fn sample_cfg() -> Cfg {
+        let mut cfg = Cfg::default();
+        for _ in 0..4 {
+            cfg.locals.alloc(Local {
+                name: None,
+                ty: CType::Int(4),
+            });
+        }
+        let step = Stmt::Assign {
+            place: Place::Local(Idx::from_usize(1)),
+            value: Value::Binop(
+                Box::new(Value::from_local(Idx::from_usize(1))),
+                Binop::Add,
+                Box::new(Value::Literal(1)),
+            ),
+            origin: None,
+        };
+        cfg.bb.alloc(block(vec![], goto(1)));
+        cfg.bb.alloc(block(vec![], branch(1, 2, 5)));
+        cfg.bb.alloc(block(vec![], branch(2, 3, 6)));
+        cfg.bb.alloc(block(vec![], branch(3, 4, 7)));
+        cfg.bb.alloc(block(vec![step], goto(1)));
+        cfg.bb.alloc(block(vec![], branch(2, 6, 7)));
+        cfg.bb.alloc(block(vec![], branch(3, 7, 8)));
+        cfg.bb.alloc(block(vec![], branch(0, 6, 8)));
+        cfg.bb.alloc(block(vec![], Terminator::Return));
+        cfg
+    }
+
+    #[test]
+    fn test_loopify_is_deterministic() {
+        let cfg = sample_cfg();
+        for structurer in [Structurer::Relooper, Structurer::Dominators] {
+            for irreducible in [
+                IrreducibleStrategy::Dispatch,
+                IrreducibleStrategy::Goto,
+                IrreducibleStrategy::NodeSplitting { budget: 10 },
+            ] {
+                let config = LoopifyConfig {
+                    structurer,
+                    irreducible,
+                };
+                let expected = format!("{:?}", cfg.loopify_with(&config));
+                for _ in 0..50 {
+                    assert_eq!(format!("{:?}", cfg.loopify_with(&config)), expected);
+                }
+            }
+        }
+    }
+
+    fn cfg_with_blocks(blocks: Vec<BasicBlock>) -> Cfg {
+        let mut cfg = Cfg::default();
+        for _ in 0..3 {
+            cfg.locals.alloc(Local {
+                name: None,
+                ty: CType::Int(4),
+            });
+        }
+        for block in blocks {
+            cfg.bb.alloc(block);
+        }
+        cfg
+    }
+
+    fn blocks(node: &StructuredNode) -> Vec<usize> {
+        let mut blocks = vec![];
+        node.for_each_block(&mut |idx| blocks.push(idx.to_usize()));
+        blocks
+    }
+
+    #[test]
+    fn test_shared_successors_follow_the_if() {
+        // bb3 and bb4 are reached from both branches of bb0, bb1 from none.
+        let cfg = cfg_with_blocks(vec![
+            block(vec![], branch(1, 3, 2)),
+            block(vec![], Terminator::Return),
+            block(vec![], branch(2, 4, 3)),
+            block(vec![], goto(4)),
+            block(vec![], Terminator::Return),
+        ]);
+        // { bb0; if _1 {} else { bb2; if _2 { break } } bb3 } bb4
+        let StructuredNode::Sequence(nodes) = cfg.loopify() else {
+            panic!("not a sequence");
+        };
+        let [StructuredNode::Block(inner), after] = nodes.as_slice() else {
+            panic!("not a block and what follows it: {nodes:?}");
+        };
+        assert_eq!(blocks(after), [4]);
+        let StructuredNode::Sequence(inner) = &**inner else {
+            panic!("not a sequence");
+        };
+        let [
+            StructuredNode::Basic(_),
+            StructuredNode::If {
+                then_node,
+                else_node,
+                ..
+            },
+            next,
+        ] = inner.as_slice()
+        else {
+            panic!("not an if and what follows it: {inner:?}");
+        };
+        assert!(blocks(then_node).is_empty());
+        assert_eq!(blocks(else_node), [2]);
+        assert_eq!(blocks(next), [3]);
+    }
+
+    #[test]
+    fn test_loop_exits_follow_the_loop() {
+        // The loop bb1, bb2 exits to bb3 and bb4, and bb3 falls into bb4.
+        let cfg = cfg_with_blocks(vec![
+            block(vec![], goto(1)),
+            block(vec![], branch(1, 3, 2)),
+            block(vec![], branch(2, 4, 1)),
+            block(vec![], goto(4)),
+            block(vec![], Terminator::Return),
+        ]);
+        // bb0; { while !_1 { bb2; if _2 { break } } bb3 } bb4
+        let StructuredNode::Sequence(nodes) = cfg.loopify() else {
+            panic!("not a sequence");
+        };
+        let [_, StructuredNode::Block(inner), after] = nodes.as_slice() else {
+            panic!("not a block and what follows it: {nodes:?}");
+        };
+        assert_eq!(blocks(after), [4]);
+        let StructuredNode::Sequence(inner) = &**inner else {
+            panic!("not a sequence");
+        };
+        let [StructuredNode::While { body, .. }, exit] = inner.as_slice() else {
+            panic!("not a loop and what follows it: {inner:?}");
+        };
+        assert_eq!(blocks(body), [2]);
+        assert_eq!(blocks(exit), [3]);
+    }
+
+    fn reloop(cfg: &Cfg) -> StructuredNode {
+        Relooper::new(cfg, IrreducibleStrategy::Dispatch).reloop(Idx::from_usize(0))
+    }
+
+    // What runs right after the loop with `header`, if anything in `node` does.
+    fn after_loop(node: &StructuredNode, header: usize) -> Option<usize> {
+        match node {
+            StructuredNode::Sequence(nodes) => nodes.iter().enumerate().find_map(|(i, child)| {
+                let is_loop = matches!(child, StructuredNode::Loop(body)
+                    if body.entry().map(Idx::to_usize) == Some(header));
+                match is_loop {
+                    true => nodes[i + 1..]
+                        .iter()
+                        .find_map(|node| node.entry())
+                        .map(Idx::to_usize),
+                    false => after_loop(child, header),
+                }
+            }),
+            StructuredNode::Block(body) => after_loop(body, header),
+            StructuredNode::If {
+                then_node,
+                else_node,
+                ..
+            } => after_loop(then_node, header).or_else(|| after_loop(else_node, header)),
+            StructuredNode::Loop(body) => after_loop(body, header),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_while_loop() {
+        // bb0: goto bb1
+        // bb1: if _1 { goto bb2 } else { goto bb3 }
+        // bb2: _1 = _1 + 1; goto bb1
+        // bb3: return
+        let mut cfg = with_locals(vec![CType::Int(4); 2]);
+        let step = assign(1, binop(local(1), Binop::Add, Value::Literal(1)));
+        cfg.bb.alloc(block(vec![], goto(1)));
+        cfg.bb.alloc(block(vec![], branch(1, 2, 3)));
+        cfg.bb.alloc(block(vec![step], goto(1)));
+        cfg.bb.alloc(block(vec![], Terminator::Return));
+
+        let node = reloop(&cfg);
+        assert_eq!(loops(&node), [(1, 0)], "{node:?}");
+        assert_eq!(after_loop(&node, 1), Some(3), "{node:?}");
+    }
+
+    #[test]
+    fn test_nested_loops_with_two_exits() {
+        // bb0: goto bb1
+        // bb1: if _1 { goto bb2 } else { goto bb5 }
+        // bb2: if _2 { goto bb3 } else { goto bb1 }
+        // bb3: if _3 { goto bb6 } else { goto bb4 }
+        // bb4: _2 = _2 + 1; goto bb2
+        // bb5: return
+        // bb6: _0 = 1; goto bb5
+        let mut cfg = with_locals(vec![CType::Int(4); 4]);
+        let step = assign(2, binop(local(2), Binop::Add, Value::Literal(1)));
+        cfg.bb.alloc(block(vec![], goto(1)));
+        cfg.bb.alloc(block(vec![], branch(1, 2, 5)));
+        cfg.bb.alloc(block(vec![], branch(2, 3, 1)));
+        cfg.bb.alloc(block(vec![], branch(3, 6, 4)));
+        cfg.bb.alloc(block(vec![step], goto(2)));
+        cfg.bb.alloc(block(vec![], Terminator::Return));
+        cfg.bb
+            .alloc(block(vec![assign(0, Value::Literal(1))], goto(5)));
+
+        // The inner loop breaks out of both loops to bb6, which comes before bb5 as
+        // it leads there. No dispatch is needed for that.
+        let node = reloop(&cfg);
+        assert_eq!(loops(&node), [(1, 0), (2, 1)], "{node:?}");
+        assert_eq!(after_loop(&node, 1), Some(6), "{node:?}");
+        assert!(!format!("{node:?}").contains("Dispatch"), "{node:?}");
+        let c = emit_c(&cfg, &node, "f");
+        assert!(!c.contains("goto bb"), "{c}");
+    }
+
+    #[test]
+    fn test_shared_successors() {
+        // bb0: if _1 { goto bb3 } else { goto bb2 }
+        // bb1: return
+        // bb2: if _2 { goto bb4 } else { goto bb3 }
+        // bb3: _0 = 1; goto bb4
+        // bb4: return
+        let mut cfg = with_locals(vec![CType::Int(4); 3]);
+        cfg.bb.alloc(block(vec![], branch(1, 3, 2)));
+        cfg.bb.alloc(block(vec![], Terminator::Return));
+        cfg.bb.alloc(block(vec![], branch(2, 4, 3)));
+        cfg.bb
+            .alloc(block(vec![assign(0, Value::Literal(1))], goto(4)));
+        cfg.bb.alloc(block(vec![], Terminator::Return));
+
+        // bb3 and bb4 are both reached from both branches, so they come after the
+        // `if`, and bb2 breaks out to bb4 past bb3.
+        let node = reloop(&cfg);
+        let StructuredNode::Sequence(nodes) = &node else {
+            panic!("{node:?}");
+        };
+        let [StructuredNode::Block(head), bb4] = nodes.as_slice() else {
+            panic!("{node:?}");
+        };
+        assert_eq!(bb4.entry(), Some(Idx::from_usize(4)));
+        let mut order = vec![];
+        head.for_each_block(&mut |idx| order.push(idx.to_usize()));
+        assert_eq!(order, [0, 2, 3]);
+        let c = emit_c(&cfg, &node, "f");
+        assert!(!c.contains("goto bb"), "{c}");
+    }
+}
@@ -0,0 +1,511 @@
+// Value range analysis. Tracks an interval for every integer local at every
+// statement, which is enough to prove that most loop counters and small sums can't
+// overflow. Memory isn't tracked: a value read through a pointer can be anything its
+// type allows.
+
+use std::collections::BTreeMap;
+
+use crate::eval::{self, wrap};
+use crate::loopified::Relooper;
+use crate::*;
+
+/// The integers from `min` to `max`, both included.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Range {
+    pub min: i128,
+    pub max: i128,
+}
+
+impl Range {
+    pub fn new(min: i128, max: i128) -> Self {
+        debug_assert!(min <= max);
+        Self { min, max }
+    }
+
+    pub fn exact(i: i128) -> Self {
+        Self::new(i, i)
+    }
+
+    /// Every value of `ty`. `None` for types that aren't integers, and for 128-bit
+    /// integers, which the bounds can't hold the products of.
+    pub fn of_type(ty: &CType) -> Option<Self> {
+        match ty {
+            CType::Bool => Some(Self::new(0, 1)),
+            CType::Int(bytes @ (1 | 2 | 4 | 8)) => {
+                let bits = bytes * 8;
+                Some(Self::new(-(1 << (bits - 1)), (1 << (bits - 1)) - 1))
+            }
+            CType::UInt(bytes @ (1 | 2 | 4 | 8)) => Some(Self::new(0, (1 << (bytes * 8)) - 1)),
+            _ => None,
+        }
+    }
+
+    pub fn contains(&self, other: Range) -> bool {
+        self.min <= other.min && other.max <= self.max
+    }
+
+    /// Whether every value of `self` is a value of `ty`.
+    pub fn fits(&self, ty: &CType) -> bool {
+        Self::of_type(ty).is_some_and(|range| range.contains(*self))
+    }
+
+    pub fn join(&self, other: Range) -> Range {
+        Self::new(self.min.min(other.min), self.max.max(other.max))
+    }
+
+    /// `None` if no value is in both.
+    pub fn intersect(&self, other: Range) -> Option<Range> {
+        let min = self.min.max(other.min);
+        let max = self.max.min(other.max);
+        (min <= max).then(|| Self::new(min, max))
+    }
+
+    /// The values converted to `ty` like a C cast would, wrapping around for
+    /// integers.
+    pub fn convert(&self, ty: &CType) -> Option<Range> {
+        let full = Self::of_type(ty)?;
+        if full.contains(*self) {
+            Some(*self)
+        } else if self.min == self.max {
+            Some(Self::exact(wrap(self.min, ty)))
+        } else {
+            Some(full)
+        }
+    }
+}
+
+/// The range of `l binop r` computed on unbounded integers, for operands of a type
+/// that is `bits` wide. `None` if the result can't be bounded, which includes
+/// dividing by a range containing zero and any shift that isn't by a valid amount
+/// of a non-negative value.
+pub fn binop_range(l: Range, binop: Binop, r: Range, bits: u32) -> Option<Range> {
+    let from_corners = |f: fn(i128, i128) -> Option<i128>| {
+        let corners = [
+            f(l.min, r.min)?,
+            f(l.min, r.max)?,
+            f(l.max, r.min)?,
+            f(l.max, r.max)?,
+        ];
+        Some(Range::new(
+            *corners.iter().min().unwrap(),
+            *corners.iter().max().unwrap(),
+        ))
+    };
+    let valid_shift = l.min >= 0 && r.min >= 0 && r.max < i128::from(bits);
+    match binop {
+        Binop::Add => Some(Range::new(
+            l.min.checked_add(r.min)?,
+            l.max.checked_add(r.max)?,
+        )),
+        Binop::Sub => Some(Range::new(
+            l.min.checked_sub(r.max)?,
+            l.max.checked_sub(r.min)?,
+        )),
+        Binop::Mul => from_corners(i128::checked_mul),
+        // Truncating division is monotonic in both operands as long as the divisor
+        // doesn't change sign.
+        Binop::Div if r.min > 0 || r.max < 0 => from_corners(i128::checked_div),
+        Binop::Div => None,
+        // The remainder is smaller than the divisor and has the sign of the left side,
+        // and dividing by zero leaves the left side.
+        Binop::Rem => {
+            let bound = i128::try_from(r.min.unsigned_abs().max(r.max.unsigned_abs())).ok()? - 1;
+            let remainder = Range::new(l.min.max(-bound).min(0), l.max.min(bound).max(0));
+            if r.min <= 0 && 0 <= r.max {
+                Some(Range::new(
+                    remainder.min.min(l.min),
+                    remainder.max.max(l.max),
+                ))
+            } else {
+                Some(remainder)
+            }
+        }
+        Binop::Shl if valid_shift => {
+            Some(Range::new(l.min << r.min, l.max.checked_mul(1 << r.max)?))
+        }
+        Binop::Shr if valid_shift => Some(Range::new(l.min >> r.max, l.max >> r.min)),
+        // `Sar` sees the top bit of an unsigned value as the sign.
+        Binop::Sar if valid_shift && l.max >> (bits - 1) == 0 => {
+            Some(Range::new(l.min >> r.max, l.max >> r.min))
+        }
+        Binop::Shl | Binop::Shr | Binop::Sar => None,
+        // No bit above those of the larger operand can be set.
+        Binop::Xor if l.min >= 0 && r.min >= 0 => Some(Range::new(
+            0,
+            i128::MAX >> (l.max.max(r.max).leading_zeros() - 1),
+        )),
+        Binop::Xor => None,
+        Binop::Lt | Binop::Le | Binop::Eq | Binop::Ne | Binop::And | Binop::Or => {
+            Some(Range::new(0, 1))
+        }
+    }
+}
+
+/// The type of `value` as an operand: comparisons are `bool`, and arithmetic is done
+/// in the type of its operands, with `bool` promoted to `int`. A select has the type
+/// of its branches, which are converted like operands.
+pub fn value_ty(value: &Value, cfg: &Cfg) -> CType {
+    match value {
+        Value::Binop(_, binop, _) if is_condition(*binop) => CType::Bool,
+        Value::Binop(l, _, r) => match operand_ty(l, r, cfg) {
+            CType::Bool => CType::Int(4),
+            ty => ty,
+        },
+        Value::Select(_, then, else_) => operand_ty(then, else_, cfg),
+        _ => value.ty(cfg),
+    }
+}
+
+/// The type both operands of a binary operation are converted to: that of the first
+/// one that isn't a literal.
+pub fn operand_ty(l: &Value, r: &Value, cfg: &Cfg) -> CType {
+    match l {
+        Value::Literal(_) => value_ty(r, cfg),
+        _ => value_ty(l, cfg),
+    }
+}
+
+/// What is known about the integer locals at some point. Locals that aren't
+/// mentioned can have any value of their type.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct State {
+    locals: BTreeMap<Idx<Local>, Range>,
+}
+
+impl State {
+    /// The values `value` can have, in its own type. `None` if it isn't an integer.
+    pub fn range(&self, value: &Value, cfg: &Cfg) -> Option<Range> {
+        match value {
+            Value::Literal(i) => Some(Range::exact((*i).into())),
+            Value::Place(Place::Local(idx)) => self
+                .locals
+                .get(idx)
+                .copied()
+                .or_else(|| Range::of_type(&cfg.locals[*idx].ty)),
+            Value::Place(place) => Range::of_type(&place.ty(cfg)),
+            Value::Binop(l, binop, r) if is_condition(*binop) => {
+                let ty = operand_ty(l, r, cfg);
+                let exact = |value| {
+                    let range = self.range(value, cfg)?.convert(&ty)?;
+                    (range.min == range.max).then_some(range.min)
+                };
+                match (exact(l), exact(r)) {
+                    (Some(l), Some(r)) => Some(Range::exact(eval::binop(l, *binop, r, &ty))),
+                    _ => Some(Range::new(0, 1)),
+                }
+            }
+            Value::Binop(l, binop, r) => {
+                let ty = value_ty(value, cfg);
+                let l = self.range(l, cfg)?.convert(&ty)?;
+                let r = self.range(r, cfg)?.convert(&ty)?;
+                if l.min == l.max && r.min == r.max {
+                    return Some(Range::exact(eval::binop(l.min, *binop, r.min, &ty)));
+                }
+                let bits = u32::from(ty.size(&cfg.layout)) * 8;
+                match binop_range(l, *binop, r, bits) {
+                    Some(range) if range.fits(&ty) => Some(range),
+                    // It might wrap around.
+                    _ => Range::of_type(&ty),
+                }
+            }
+            Value::Select(_, then, else_) => {
+                let ty = value_ty(value, cfg);
+                let then = self.range(then, cfg)?.convert(&ty)?;
+                Some(then.join(self.range(else_, cfg)?.convert(&ty)?))
+            }
+        }
+    }
+
+    fn assign(&mut self, stmt: &Stmt, cfg: &Cfg) {
+        let Stmt::Assign { place, value, .. } = stmt;
+        let Place::Local(idx) = place else {
+            // Locals can't be pointed to, so writing memory doesn't change them.
+            return;
+        };
+        let ty = &cfg.locals[*idx].ty;
+        match self.range(value, cfg).and_then(|range| range.convert(ty)) {
+            Some(range) if Range::of_type(ty) != Some(range) => {
+                self.locals.insert(*idx, range);
+            }
+            _ => {
+                self.locals.remove(idx);
+            }
+        }
+    }
+
+    // The state in which `cond` holds, `None` if it can't.
+    fn refine(&self, cond: &Value, cfg: &Cfg) -> Option<State> {
+        match cond {
+            Value::Binop(l, Binop::And, r) => self.refine(l, cfg)?.refine(r, cfg),
+            Value::Binop(l, Binop::Or, r) => match (self.refine(l, cfg), self.refine(r, cfg)) {
+                (Some(l), Some(r)) => Some(l.join(&r)),
+                (l, r) => l.or(r),
+            },
+            Value::Binop(l, binop, r) if is_condition(*binop) => {
+                let ty = operand_ty(l, r, cfg);
+                let mut state = self.clone();
+                if let Value::Place(Place::Local(idx)) = **l {
+                    state = state.compare(idx, *binop, r, &ty, cfg, true)?;
+                }
+                if let Value::Place(Place::Local(idx)) = **r {
+                    state = state.compare(idx, *binop, l, &ty, cfg, false)?;
+                }
+                Some(state)
+            }
+            // A bare integer is a test against zero.
+            Value::Place(Place::Local(idx)) => {
+                let ty = &cfg.locals[*idx].ty;
+                self.compare(*idx, Binop::Ne, &Value::Literal(0), ty, cfg, true)
+            }
+            _ => Some(self.clone()),
+        }
+    }
+
+    // Refines local `idx` by `idx binop other` holding, or `other binop idx` if not
+    // `on_left`, where the comparison is done in `ty`.
+    fn compare(
+        &self,
+        idx: Idx<Local>,
+        binop: Binop,
+        other: &Value,
+        ty: &CType,
+        cfg: &Cfg,
+        on_left: bool,
+    ) -> Option<State> {
+        let Some(current) = self.range(&Value::from_local(idx), cfg) else {
+            return Some(self.clone());
+        };
+        let other = self.range(other, cfg).and_then(|range| range.convert(ty));
+        // A comparison in a type that doesn't hold the local sees a converted value,
+        // which says little about the original one.
+        let (Some(other), true) = (other, current.fits(ty)) else {
+            return Some(self.clone());
+        };
+        let bound = match (binop, on_left) {
+            (Binop::Lt, true) => Range::new(i128::MIN, other.max - 1),
+            (Binop::Le, true) => Range::new(i128::MIN, other.max),
+            (Binop::Lt, false) => Range::new(other.min + 1, i128::MAX),
+            (Binop::Le, false) => Range::new(other.min, i128::MAX),
+            (Binop::Eq, _) => other,
+            // Only a value at the edge can be cut off.
+            (Binop::Ne, _) if other.min == other.max && current.min == other.min => {
+                Range::new(current.min + 1, i128::MAX)
+            }
+            (Binop::Ne, _) if other.min == other.max && current.max == other.max => {
+                Range::new(i128::MIN, current.max - 1)
+            }
+            _ => current,
+        };
+        let refined = current.intersect(bound)?;
+        let mut state = self.clone();
+        state.locals.insert(idx, refined);
+        Some(state)
+    }
+
+    fn join(&self, other: &State) -> State {
+        let locals = self
+            .locals
+            .iter()
+            .filter_map(|(idx, range)| Some((*idx, range.join(*other.locals.get(idx)?))))
+            .collect();
+        State { locals }
+    }
+
+    // Joins `other` in, jumping every bound that keeps growing to the end of its
+    // type so that loops settle.
+    fn widen(&self, other: &State, cfg: &Cfg) -> State {
+        let mut joined = self.join(other);
+        for (idx, range) in joined.locals.iter_mut() {
+            let old = self.locals[idx];
+            let full = Range::of_type(&cfg.locals[*idx].ty).expect("only integers are tracked");
+            if range.min < old.min {
+                range.min = full.min;
+            }
+            if range.max > old.max {
+                range.max = full.max;
+            }
+        }
+        joined
+            .locals
+            .retain(|idx, range| Range::of_type(&cfg.locals[*idx].ty) != Some(*range));
+        joined
+    }
+}
+
+/// The result of the analysis for a whole `Cfg`.
+#[derive(Debug, Clone)]
+pub struct Ranges {
+    // The state before each statement of each reachable block, and before its
+    // terminator.
+    states: BTreeMap<Idx<BasicBlock>, Vec<State>>,
+}
+
+// Joins at a block before widening starts.
+const JOINS_BEFORE_WIDENING: usize = 3;
+
+impl Ranges {
+    pub fn new(cfg: &Cfg) -> Self {
+        let entry = Idx::from_usize(0);
+        let mut entry_states = BTreeMap::from([(entry, State::default())]);
+        // Blocks that are entered along a single edge just take its state, which
+        // keeps what the branch leading there learned.
+        let mut edges = BTreeMap::from([(entry, 1)]);
+        for (_, block) in cfg.bb.iter() {
+            for succ in Relooper::get_successors_from_block(block) {
+                *edges.entry(succ).or_insert(0) += 1;
+            }
+        }
+        let mut joins = BTreeMap::new();
+        let mut worklist = vec![entry];
+        while let Some(bb) = worklist.pop() {
+            let mut state = entry_states[&bb].clone();
+            for stmt in &cfg.bb[bb].stmts {
+                state.assign(stmt, cfg);
+            }
+            let successors = match cfg.bb[bb].terminator() {
+                Terminator::Return => vec![],
+                Terminator::Goto { bb } => vec![(*bb, Some(state))],
+                Terminator::If { cond, then, else_ } => vec![
+                    (*then, state.refine(cond, cfg)),
+                    (*else_, state.refine(&cond.negated(cfg), cfg)),
+                ],
+            };
+            for (succ, state) in successors {
+                let Some(state) = state else { continue };
+                let new = match entry_states.get(&succ) {
+                    Some(old) if edges[&succ] > 1 => {
+                        let count = joins.entry(succ).or_insert(0);
+                        *count += 1;
+                        if *count > JOINS_BEFORE_WIDENING {
+                            old.widen(&state, cfg)
+                        } else {
+                            old.join(&state)
+                        }
+                    }
+                    _ => state,
+                };
+                if entry_states.get(&succ) != Some(&new) {
+                    entry_states.insert(succ, new);
+                    if !worklist.contains(&succ) {
+                        worklist.push(succ);
+                    }
+                }
+            }
+        }
+
+        let states = entry_states
+            .into_iter()
+            .map(|(bb, mut state)| {
+                let mut states = vec![state.clone()];
+                for stmt in &cfg.bb[bb].stmts {
+                    state.assign(stmt, cfg);
+                    states.push(state.clone());
+                }
+                (bb, states)
+            })
+            .collect();
+        Self { states }
+    }
+
+    /// What is known before statement `index` of `bb`. An index one past the last
+    /// statement is the terminator.
+    pub fn before(&self, bb: Idx<BasicBlock>, index: usize) -> State {
+        // Nothing is known about unreachable blocks, which can't do anything wrong.
+        self.states
+            .get(&bb)
+            .map_or_else(State::default, |states| states[index].clone())
+    }
+}
+
+pub(crate) fn is_condition(binop: Binop) -> bool {
+    matches!(
+        binop,
+        Binop::Lt | Binop::Le | Binop::Eq | Binop::Ne | Binop::And | Binop::Or
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn local(i: usize) -> Value {
+        Value::from_local(Idx::from_usize(i))
+    }
+
+    fn assign(i: usize, value: Value) -> Stmt {
+        Stmt::Assign {
+            place: Place::Local(Idx::from_usize(i)),
+            value,
+            origin: None,
+        }
+    }
+
+    fn block(stmts: Vec<Stmt>, terminator: Terminator) -> BasicBlock {
+        BasicBlock {
+            stmts,
+            terminator: Some(terminator),
+        }
+    }
+
+    #[test]
+    fn test_counter_stays_below_bound() {
+        // for (_1 = 0; _1 < _2; _1 = _1 + 1) {}
+        let mut cfg = Cfg::default();
+        for _ in 0..3 {
+            cfg.locals.alloc(Local {
+                name: None,
+                ty: CType::Int(4),
+            });
+        }
+        let goto = |bb| Terminator::Goto {
+            bb: Idx::from_usize(bb),
+        };
+        let step = Value::Binop(Box::new(local(1)), Binop::Add, Box::new(Value::Literal(1)));
+        cfg.bb
+            .alloc(block(vec![assign(1, Value::Literal(0))], goto(1)));
+        cfg.bb.alloc(block(
+            vec![],
+            Terminator::If {
+                cond: Value::Binop(Box::new(local(1)), Binop::Lt, Box::new(local(2))),
+                then: Idx::from_usize(2),
+                else_: Idx::from_usize(3),
+            },
+        ));
+        cfg.bb.alloc(block(vec![assign(1, step)], goto(1)));
+        cfg.bb.alloc(block(vec![], Terminator::Return));
+
+        let ranges = Ranges::new(&cfg);
+        let max = i32::MAX.into();
+        let counter = |bb, index| {
+            ranges
+                .before(Idx::from_usize(bb), index)
+                .range(&local(1), &cfg)
+        };
+        assert_eq!(counter(1, 0), Some(Range::new(0, max)));
+        assert_eq!(counter(2, 0), Some(Range::new(0, max - 1)));
+        assert_eq!(counter(3, 0), Some(Range::new(0, max)));
+    }
+
+    #[test]
+    fn test_binop_range_overflow() {
+        let byte = Range::of_type(&CType::Int(1)).unwrap();
+        assert_eq!(
+            binop_range(Range::new(0, 10), Binop::Add, Range::exact(5), 8),
+            Some(Range::new(5, 15))
+        );
+        assert!(
+            !binop_range(byte, Binop::Add, Range::exact(1), 8)
+                .unwrap()
+                .fits(&CType::Int(1))
+        );
+        assert_eq!(binop_range(byte, Binop::Div, Range::new(-1, 1), 8), None);
+        assert_eq!(
+            binop_range(Range::new(-20, 7), Binop::Rem, Range::new(3, 5), 8),
+            Some(Range::new(-4, 4))
+        );
+        assert_eq!(
+            binop_range(Range::new(3, 7), Binop::Rem, Range::new(-1, 1), 8),
+            Some(Range::new(0, 7))
+        );
+    }
+}
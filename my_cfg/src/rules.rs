@@ -0,0 +1,715 @@
+// Peephole rewrite rules, read from text with one rule per line:
+//
+//     shl_add: (x << 2) + x => x * 5 for x: int
+//     mul_mul: (x * a) * b => x * (a * b) for x: int, a: const, b: const if width(x) <= 32
+//
+// The sides are written with the operators of `Binop` and C's precedences, `>>` being
+// `Shr` and `>>s` `Sar`. The names in them are metavariables, declared after `for`
+// with what they match: `int`, `sint` or `uint` for a value of an integer type of 8
+// to 64 bits, an exact type like `i32` or `bool`, or `const` for a literal. Conditions
+// after `if` limit the widths, in bits, of what metavariables match. `#` starts a
+// comment.
+//
+// A rule claims that both sides are the same for every type its metavariables can
+// have, which `z3_of_cfg::prove_rule` checks, and that both have the same type, so
+// that a rewrite doesn't change the type of the operations around it.
+
+use crate::eval;
+use crate::ranges::{is_condition, value_ty};
+use crate::{Binop, CType, Cfg, DataLayout, Place, Stmt, Terminator, Value};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rule {
+    pub name: String,
+    /// Where the rule was read from, counting from 1.
+    pub line: usize,
+    pub vars: Vec<Var>,
+    pub lhs: Pattern,
+    pub rhs: Pattern,
+    pub conditions: Vec<Condition>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Var {
+    pub name: String,
+    pub class: TypeClass,
+}
+
+/// What a metavariable matches.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TypeClass {
+    /// A value of any integer type of 8 to 64 bits.
+    Int,
+    SInt,
+    UInt,
+    Exact(CType),
+    /// A literal, which is an `int` converted to the type it is used with.
+    Const,
+}
+
+impl TypeClass {
+    /// The types of the values it matches.
+    pub fn types(&self) -> Vec<CType> {
+        const BYTES: [u8; 4] = [1, 2, 4, 8];
+        match self {
+            TypeClass::Int => BYTES
+                .iter()
+                .flat_map(|bytes| [CType::Int(*bytes), CType::UInt(*bytes)])
+                .collect(),
+            TypeClass::SInt => BYTES.map(CType::Int).to_vec(),
+            TypeClass::UInt => BYTES.map(CType::UInt).to_vec(),
+            TypeClass::Exact(ty) => vec![ty.clone()],
+            TypeClass::Const => vec![CType::Int(4)],
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Pattern {
+    /// The metavariable of that index in `Rule::vars`.
+    Var(usize),
+    Literal(i32),
+    Binop(Box<Pattern>, Binop, Box<Pattern>),
+}
+
+impl Pattern {
+    /// The value with the metavariables replaced by `vars`, by index.
+    pub fn instantiate(&self, vars: &[Value]) -> Value {
+        match self {
+            Pattern::Var(var) => vars[*var].clone(),
+            Pattern::Literal(i) => Value::Literal(*i),
+            Pattern::Binop(l, binop, r) => Value::Binop(
+                Box::new(l.instantiate(vars)),
+                *binop,
+                Box::new(r.instantiate(vars)),
+            ),
+        }
+    }
+
+    fn vars(&self, vars: &mut Vec<usize>) {
+        match self {
+            Pattern::Var(var) => vars.push(*var),
+            Pattern::Literal(_) => {}
+            Pattern::Binop(l, _, r) => {
+                l.vars(vars);
+                r.vars(vars);
+            }
+        }
+    }
+}
+
+/// `width(var) cmp bits`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Condition {
+    pub var: usize,
+    pub cmp: Cmp,
+    pub bits: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cmp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Cmp {
+    fn holds(self, l: u32, r: u32) -> bool {
+        match self {
+            Cmp::Eq => l == r,
+            Cmp::Ne => l != r,
+            Cmp::Lt => l < r,
+            Cmp::Le => l <= r,
+            Cmp::Gt => l > r,
+            Cmp::Ge => l >= r,
+        }
+    }
+}
+
+impl Rule {
+    /// Reads rules, one per line. Errors say on which line they are.
+    pub fn parse_all(text: &str) -> Result<Vec<Rule>, String> {
+        text.lines()
+            .enumerate()
+            .map(|(i, line)| (i + 1, line.split('#').next().unwrap()))
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(line, text)| {
+                Rule::parse(text, line).map_err(|error| format!("line {line}: {error}"))
+            })
+            .collect()
+    }
+
+    /// Reads a rule, `line` being where it is.
+    pub fn parse(text: &str, line: usize) -> Result<Rule, String> {
+        let mut parser = Parser {
+            tokens: tokenize(text)?,
+            pos: 0,
+            names: vec![],
+        };
+        let name = parser.ident()?;
+        parser.expect(":")?;
+        let lhs = parser.pattern(0)?;
+        parser.expect("=>")?;
+        let rhs = parser.pattern(0)?;
+        let mut vars: Vec<Option<TypeClass>> = vec![None; parser.names.len()];
+        if parser.eat("for") {
+            loop {
+                let var = parser.var()?;
+                parser.expect(":")?;
+                let class = parser.class()?;
+                if vars[var].replace(class).is_some() {
+                    return Err(format!("`{}` is declared twice", parser.names[var]));
+                }
+                if !parser.eat(",") {
+                    break;
+                }
+            }
+        }
+        let mut conditions = vec![];
+        if parser.eat("if") {
+            loop {
+                conditions.push(parser.condition()?);
+                if !parser.eat(",") {
+                    break;
+                }
+            }
+        }
+        if let Some(token) = parser.peek() {
+            return Err(format!("unexpected `{token}`"));
+        }
+        let vars = vars
+            .into_iter()
+            .zip(parser.names)
+            .map(|(class, name)| match class {
+                Some(class) => Ok(Var { name, class }),
+                None => Err(format!("`{name}` isn't declared")),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let rule = Rule {
+            name,
+            line,
+            vars,
+            lhs,
+            rhs,
+            conditions,
+        };
+        rule.check()?;
+        Ok(rule)
+    }
+
+    // What the parser can't tell by itself.
+    fn check(&self) -> Result<(), String> {
+        let Pattern::Binop(..) = self.lhs else {
+            return Err("the left side has to be an operation".into());
+        };
+        let mut lhs_vars = vec![];
+        self.lhs.vars(&mut lhs_vars);
+        if let Some(var) = (0..self.vars.len()).find(|var| !lhs_vars.contains(var)) {
+            return Err(format!("`{}` isn't on the left side", self.vars[var].name));
+        }
+        // Operations take their type from the first operand that isn't a literal,
+        // which a literal put in place of an operation would change.
+        let is_const = |pattern: &Pattern| match pattern {
+            Pattern::Var(var) => self.vars[*var].class == TypeClass::Const,
+            Pattern::Literal(_) => true,
+            Pattern::Binop(..) => false,
+        };
+        if is_const(&self.rhs) {
+            return Err("the right side can't be a literal".into());
+        }
+        // Constants are proven as `int`s that aren't literals, so they can't decide
+        // the type of an operation either.
+        for pattern in [&self.lhs, &self.rhs] {
+            if let Some(var) = self.typing_const(pattern) {
+                return Err(format!(
+                    "`{}` can't be the left operand of an operation that isn't on constants",
+                    self.vars[var].name
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    // A constant metavariable that is the left operand of an operation whose right
+    // operand isn't a constant.
+    fn typing_const(&self, pattern: &Pattern) -> Option<usize> {
+        let Pattern::Binop(l, _, r) = pattern else {
+            return None;
+        };
+        let constant = |pattern: &Pattern| {
+            let mut vars = vec![];
+            pattern.vars(&mut vars);
+            vars.iter()
+                .all(|var| self.vars[*var].class == TypeClass::Const)
+        };
+        match &**l {
+            Pattern::Var(var) if self.vars[*var].class == TypeClass::Const && !constant(r) => {
+                Some(*var)
+            }
+            _ => self.typing_const(l).or_else(|| self.typing_const(r)),
+        }
+    }
+
+    /// The types the metavariables can have together, by index, as far as the
+    /// conditions allow.
+    pub fn instantiations(&self) -> Vec<Vec<CType>> {
+        let mut instantiations = vec![vec![]];
+        for var in &self.vars {
+            instantiations = instantiations
+                .into_iter()
+                .flat_map(|types: Vec<CType>| {
+                    var.class.types().into_iter().map(move |ty| {
+                        let mut types = types.clone();
+                        types.push(ty);
+                        types
+                    })
+                })
+                .collect();
+        }
+        instantiations.retain(|types| self.allows(types));
+        instantiations
+    }
+
+    /// Whether the conditions hold for the metavariables having `types`, by index.
+    pub fn allows(&self, types: &[CType]) -> bool {
+        self.conditions.iter().all(|condition| {
+            let bits = u32::from(types[condition.var].size(&DataLayout::default())) * 8;
+            condition.cmp.holds(bits, condition.bits)
+        })
+    }
+
+    /// `value` rewritten, if the left side matches it.
+    pub fn apply(&self, value: &Value, cfg: &Cfg) -> Option<Value> {
+        let mut bindings = vec![None; self.vars.len()];
+        if !self.matches(&self.lhs, value, cfg, &mut bindings) {
+            return None;
+        }
+        // Every metavariable is on the left side.
+        let bindings: Vec<Value> = bindings.into_iter().map(Option::unwrap).collect();
+        let types: Vec<CType> = bindings.iter().map(|value| value_ty(value, cfg)).collect();
+        if !self.allows(&types) {
+            return None;
+        }
+        Some(self.rhs.instantiate(&bindings))
+    }
+
+    fn matches(
+        &self,
+        pattern: &Pattern,
+        value: &Value,
+        cfg: &Cfg,
+        bindings: &mut [Option<Value>],
+    ) -> bool {
+        match (pattern, value) {
+            (Pattern::Literal(i), Value::Literal(j)) => i == j,
+            (Pattern::Binop(pl, pattern_binop, pr), Value::Binop(l, binop, r)) => {
+                pattern_binop == binop
+                    && self.matches(pl, l, cfg, bindings)
+                    && self.matches(pr, r, cfg, bindings)
+            }
+            (Pattern::Var(var), value) => {
+                if let Some(bound) = &bindings[*var] {
+                    return bound == value;
+                }
+                let matches = match (&self.vars[*var].class, value) {
+                    (TypeClass::Const, value) => matches!(value, Value::Literal(_)),
+                    (_, Value::Literal(_)) => false,
+                    (class, value) => class.types().contains(&value_ty(value, cfg)),
+                };
+                if matches {
+                    bindings[*var] = Some(value.clone());
+                }
+                matches
+            }
+            _ => false,
+        }
+    }
+}
+
+// Rules are bound to end, but sets of them don't have to.
+const MAX_REWRITES: usize = 64;
+
+/// `value` with `rules` applied, innermost values first and again to what they
+/// give, as long as any applies.
+pub fn rewrite(value: &Value, rules: &[Rule], cfg: &Cfg) -> Value {
+    let mut fuel = MAX_REWRITES;
+    fold(rewrite_with(value, rules, cfg, &mut fuel))
+}
+
+fn rewrite_with(value: &Value, rules: &[Rule], cfg: &Cfg, fuel: &mut usize) -> Value {
+    let value = match value {
+        Value::Literal(_) => value.clone(),
+        Value::Place(place) => Value::Place(rewrite_place(place, rules, cfg, fuel)),
+        // A left operand that is a literal doesn't decide the type of the operation,
+        // so only right ones are folded.
+        Value::Binop(l, binop, r) => Value::Binop(
+            Box::new(rewrite_with(l, rules, cfg, fuel)),
+            *binop,
+            Box::new(fold(rewrite_with(r, rules, cfg, fuel))),
+        ),
+        Value::Select(cond, then, else_) => Value::Select(
+            Box::new(rewrite_with(cond, rules, cfg, fuel)),
+            Box::new(rewrite_with(then, rules, cfg, fuel)),
+            Box::new(rewrite_with(else_, rules, cfg, fuel)),
+        ),
+    };
+    if *fuel == 0 {
+        return value;
+    }
+    match rules.iter().find_map(|rule| rule.apply(&value, cfg)) {
+        Some(rewritten) => {
+            *fuel -= 1;
+            rewrite_with(&rewritten, rules, cfg, fuel)
+        }
+        None => value,
+    }
+}
+
+fn rewrite_place(place: &Place, rules: &[Rule], cfg: &Cfg, fuel: &mut usize) -> Place {
+    match place {
+        Place::Local(_) => place.clone(),
+        Place::Deref(inner) => Place::Deref(Box::new(rewrite_place(inner, rules, cfg, fuel))),
+        Place::Offset(inner, offset) => Place::Offset(
+            Box::new(rewrite_place(inner, rules, cfg, fuel)),
+            Box::new(fold(rewrite_with(offset, rules, cfg, fuel))),
+        ),
+    }
+}
+
+// An operation on two literals as the literal it gives, computed in `int`.
+fn fold(value: Value) -> Value {
+    let Value::Binop(l, binop, r) = &value else {
+        return value;
+    };
+    let (Value::Literal(l), Value::Literal(r)) = (&**l, &**r) else {
+        return value;
+    };
+    fold_ints(*l, *binop, *r).map_or(value, Value::Literal)
+}
+
+/// `l binop r` computed in `int`, if it is one.
+pub(crate) fn fold_ints(l: i32, binop: Binop, r: i32) -> Option<i32> {
+    // Comparisons are `bool`s.
+    let folded = eval::binop(l.into(), binop, r.into(), &CType::Int(4));
+    (!is_condition(binop)).then_some(folded as i32)
+}
+
+/// Applies `rules` to the values of every statement and condition of `cfg`.
+pub fn apply_rules(cfg: &mut Cfg, rules: &[Rule]) {
+    let bbs: Vec<_> = cfg.bb.iter().map(|(bb, _)| bb).collect();
+    for bb in bbs {
+        let mut block = std::mem::take(&mut cfg.bb[bb]);
+        for stmt in &mut block.stmts {
+            let Stmt::Assign { place, value, .. } = stmt;
+            let mut fuel = MAX_REWRITES;
+            *place = rewrite_place(place, rules, cfg, &mut fuel);
+            *value = rewrite(value, rules, cfg);
+        }
+        if let Some(Terminator::If { cond, .. }) = &mut block.terminator {
+            *cond = rewrite(cond, rules, cfg);
+        }
+        cfg.bb[bb] = block;
+    }
+}
+
+struct Parser {
+    tokens: Vec<String>,
+    pos: usize,
+    /// The metavariables in the order they appear.
+    names: Vec<String>,
+}
+
+const BINOPS: [(&str, Binop, u8); 15] = [
+    ("*", Binop::Mul, 13),
+    ("/", Binop::Div, 13),
+    ("%", Binop::Rem, 13),
+    ("+", Binop::Add, 12),
+    ("-", Binop::Sub, 12),
+    ("<<", Binop::Shl, 11),
+    (">>", Binop::Shr, 11),
+    (">>s", Binop::Sar, 11),
+    ("<", Binop::Lt, 10),
+    ("<=", Binop::Le, 10),
+    ("==", Binop::Eq, 9),
+    ("!=", Binop::Ne, 9),
+    ("^", Binop::Xor, 7),
+    ("&&", Binop::And, 5),
+    ("||", Binop::Or, 4),
+];
+
+impl Parser {
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.pos).map(|token| token.as_str())
+    }
+
+    fn next(&mut self) -> Result<String, String> {
+        let token = self.peek().ok_or("unexpected end")?.to_string();
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        let found = self.peek() == Some(token);
+        self.pos += usize::from(found);
+        found
+    }
+
+    fn expect(&mut self, token: &str) -> Result<(), String> {
+        match self.next()? {
+            found if found == token => Ok(()),
+            found => Err(format!("expected `{token}`, found `{found}`")),
+        }
+    }
+
+    fn ident(&mut self) -> Result<String, String> {
+        let token = self.next()?;
+        if token.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+            Ok(token)
+        } else {
+            Err(format!("expected a name, found `{token}`"))
+        }
+    }
+
+    fn var(&mut self) -> Result<usize, String> {
+        let name = self.ident()?;
+        match self.names.iter().position(|known| *known == name) {
+            Some(var) => Ok(var),
+            None => Err(format!("`{name}` isn't on either side")),
+        }
+    }
+
+    fn pattern(&mut self, min: u8) -> Result<Pattern, String> {
+        let mut l = self.primary()?;
+        loop {
+            let Some((_, binop, precedence)) = BINOPS
+                .iter()
+                .find(|(token, ..)| Some(*token) == self.peek())
+            else {
+                return Ok(l);
+            };
+            if *precedence < min {
+                return Ok(l);
+            }
+            self.pos += 1;
+            let r = self.pattern(precedence + 1)?;
+            l = Pattern::Binop(Box::new(l), *binop, Box::new(r));
+        }
+    }
+
+    fn primary(&mut self) -> Result<Pattern, String> {
+        let token = self.next()?;
+        match token.as_str() {
+            "(" => {
+                let pattern = self.pattern(0)?;
+                self.expect(")")?;
+                Ok(pattern)
+            }
+            "-" => {
+                let token = self.next()?;
+                let i = format!("-{token}");
+                i.parse()
+                    .map(Pattern::Literal)
+                    .map_err(|_| format!("`{i}` isn't an `int`"))
+            }
+            _ if token.starts_with(|c: char| c.is_ascii_digit()) => token
+                .parse()
+                .map(Pattern::Literal)
+                .map_err(|_| format!("`{token}` isn't an `int`")),
+            _ if token.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') => {
+                let var = match self.names.iter().position(|name| *name == token) {
+                    Some(var) => var,
+                    None => {
+                        self.names.push(token);
+                        self.names.len() - 1
+                    }
+                };
+                Ok(Pattern::Var(var))
+            }
+            _ => Err(format!("unexpected `{token}`")),
+        }
+    }
+
+    fn class(&mut self) -> Result<TypeClass, String> {
+        let token = self.next()?;
+        let exact = [1, 2, 4, 8, 16]
+            .into_iter()
+            .flat_map(|bytes| [CType::Int(bytes), CType::UInt(bytes)])
+            .chain([CType::Bool])
+            .find(|ty| ty.to_string() == token);
+        match (token.as_str(), exact) {
+            ("int", _) => Ok(TypeClass::Int),
+            ("sint", _) => Ok(TypeClass::SInt),
+            ("uint", _) => Ok(TypeClass::UInt),
+            ("const", _) => Ok(TypeClass::Const),
+            (_, Some(ty)) => Ok(TypeClass::Exact(ty)),
+            _ => Err(format!("`{token}` isn't a type")),
+        }
+    }
+
+    fn condition(&mut self) -> Result<Condition, String> {
+        self.expect("width")?;
+        self.expect("(")?;
+        let var = self.var()?;
+        self.expect(")")?;
+        let cmp = match self.next()?.as_str() {
+            "==" => Cmp::Eq,
+            "!=" => Cmp::Ne,
+            "<" => Cmp::Lt,
+            "<=" => Cmp::Le,
+            ">" => Cmp::Gt,
+            ">=" => Cmp::Ge,
+            token => return Err(format!("expected a comparison, found `{token}`")),
+        };
+        let token = self.next()?;
+        let bits = token
+            .parse()
+            .map_err(|_| format!("`{token}` isn't a number of bits"))?;
+        Ok(Condition { var, cmp, bits })
+    }
+}
+
+fn tokenize(text: &str) -> Result<Vec<String>, String> {
+    const SYMBOLS: [&str; 21] = [
+        ">>s", "=>", "<<", ">>", "<=", ">=", "==", "!=", "&&", "||", "<", ">", "+", "-", "*", "/",
+        "%", "^", "(", ")", ":",
+    ];
+    let mut tokens = vec![];
+    let mut rest = text.trim_start();
+    while !rest.is_empty() {
+        let len = if rest.starts_with(|c: char| c.is_ascii_alphanumeric() || c == '_') {
+            rest.find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                .unwrap_or(rest.len())
+        } else if rest.starts_with(',') {
+            1
+        } else {
+            // `>>s` is only `Sar` when the `s` isn't the start of a name.
+            let symbol = SYMBOLS.iter().find(|symbol| {
+                rest.starts_with(**symbol)
+                    && !(**symbol == ">>s"
+                        && rest[3..].starts_with(|c: char| c.is_ascii_alphanumeric() || c == '_'))
+            });
+            match symbol {
+                Some(symbol) => symbol.len(),
+                None => return Err(format!("unexpected `{}`", rest.chars().next().unwrap())),
+            }
+        };
+        tokens.push(rest[..len].to_string());
+        rest = rest[len..].trim_start();
+    }
+    Ok(tokens)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BasicBlock, Idx, Local};
+
+    fn local(i: usize) -> Value {
+        Value::Place(Place::Local(Idx::from_usize(i)))
+    }
+
+    fn binop(l: Value, binop: Binop, r: Value) -> Value {
+        Value::Binop(Box::new(l), binop, Box::new(r))
+    }
+
+    #[test]
+    fn test_parse() {
+        let rules = Rule::parse_all(
+            "# Comment\n\
+             shl_add: (x << 2) + x => x * 5 for x: int  # strength reduction\n\
+             \n\
+             mul_mul: x * a * b => x * (a * b) for x: int, a: const, b: const if width(x) <= 32\n\
+             sign: x >>s 31 != 0 => x < 0 for x: i32\n",
+        )
+        .unwrap();
+        assert_eq!(rules.len(), 3);
+        assert_eq!(rules[0].line, 2);
+        assert_eq!(
+            rules[0].lhs,
+            Pattern::Binop(
+                Box::new(Pattern::Binop(
+                    Box::new(Pattern::Var(0)),
+                    Binop::Shl,
+                    Box::new(Pattern::Literal(2))
+                )),
+                Binop::Add,
+                Box::new(Pattern::Var(0))
+            )
+        );
+        assert_eq!(rules[0].instantiations().len(), 8);
+        assert_eq!(rules[1].instantiations().len(), 6);
+        let Pattern::Binop(l, Binop::Ne, _) = &rules[2].lhs else {
+            panic!("{:?}", rules[2].lhs);
+        };
+        assert!(matches!(**l, Pattern::Binop(_, Binop::Sar, _)));
+
+        let error = |text| Rule::parse_all(text).unwrap_err();
+        assert_eq!(
+            error("a: x + y => x for x: int"),
+            "line 1: `y` isn't declared"
+        );
+        assert_eq!(
+            error("\na: x + 0 => y for x: int"),
+            "line 2: `y` isn't declared"
+        );
+        assert!(error("a: x - x => 0 for x: int").contains("literal"));
+        assert!(error("a: c - x => x for x: int, c: const").contains("left operand"));
+        assert!(error("a: x + 1 => x for x: float").contains("isn't a type"));
+    }
+
+    #[test]
+    fn test_apply() {
+        let rules = Rule::parse_all(
+            "shl_add: (x << 2) + x => x * 5 for x: int\n\
+             mul_mul: x * a * b => x * (a * b) for x: int, a: const, b: const if width(x) <= 32",
+        )
+        .unwrap();
+        let mut cfg = Cfg::default();
+        for ty in [CType::Int(4), CType::Int(4), CType::Int(8)] {
+            cfg.locals.alloc(Local { name: None, ty });
+        }
+        let times_ten = |x: Value| {
+            let times_five = binop(
+                binop(x.clone(), Binop::Shl, Value::Literal(2)),
+                Binop::Add,
+                x,
+            );
+            binop(times_five, Binop::Mul, Value::Literal(2))
+        };
+        assert_eq!(
+            rewrite(&times_ten(local(1)), &rules, &cfg),
+            binop(local(1), Binop::Mul, Value::Literal(10))
+        );
+        // `mul_mul` doesn't claim `i64`, where `a * b` could overflow as an `int`.
+        assert_eq!(
+            rewrite(&times_ten(local(2)), &rules, &cfg),
+            binop(
+                binop(local(2), Binop::Mul, Value::Literal(5)),
+                Binop::Mul,
+                Value::Literal(2)
+            )
+        );
+        // The `x`s have to be the same.
+        let mixed = binop(
+            binop(local(1), Binop::Shl, Value::Literal(2)),
+            Binop::Add,
+            local(2),
+        );
+        assert_eq!(rewrite(&mixed, &rules, &cfg), mixed);
+
+        cfg.bb.alloc(BasicBlock {
+            stmts: vec![Stmt::Assign {
+                place: Place::Local(Idx::from_usize(0)),
+                value: times_ten(local(1)),
+                origin: None,
+            }],
+            terminator: Some(Terminator::Return),
+        });
+        apply_rules(&mut cfg, &rules);
+        let Stmt::Assign { value, .. } = &cfg.bb[Idx::from_usize(0)].stmts[0];
+        assert_eq!(*value, binop(local(1), Binop::Mul, Value::Literal(10)));
+    }
+}
@@ -0,0 +1,265 @@
+// Compilers lower `a && b` and `a || b` to a chain of branches that share a target:
+//
+//     bb0: if a { goto bb1 } else { goto bb2 }
+//     bb1: if b { goto bb3 } else { goto bb2 }
+//
+// Structuring that as is gives nested ifs with a copy of the else branch in each of
+// them, so we fold such chains back into a single `If` before structuring. The
+// condition of the inner block is often computed into a temporary first, like
+// `bb1: _5 = _2 < 3; if _5 { .. }`, which is folded along with it.
+
+use std::collections::BTreeMap;
+
+use crate::constants::remove_unreachable_blocks;
+use crate::liveness::Liveness;
+use crate::loopified::Relooper;
+use crate::ranges::value_ty;
+use crate::*;
+
+/// Folds chains of branches into `Binop::And` and `Binop::Or` conditions. The inner
+/// block of a chain must be only reachable from the outer one, since its condition
+/// won't be evaluated on every path anymore. Its statements may only compute its
+/// condition, see `inlined`. Folded blocks are removed, which renumbers the others.
+pub fn recover_short_circuits(cfg: &mut Cfg) {
+    // Every fold changes the predecessors, so start over after each one. Inner
+    // blocks are folded into their predecessor, which may then fold into its own.
+    let mut folded = false;
+    while let Some((outer, inner, terminator)) = find_fold(cfg) {
+        cfg.bb[outer].terminator = Some(terminator);
+        cfg.bb[inner] = BasicBlock {
+            stmts: vec![],
+            terminator: Some(Terminator::Return),
+        };
+        folded = true;
+    }
+    if folded {
+        remove_unreachable_blocks(cfg);
+    }
+}
+
+fn find_fold(cfg: &Cfg) -> Option<(Idx<BasicBlock>, Idx<BasicBlock>, Terminator)> {
+    let predecessors = Relooper::compute_predecessors(cfg);
+    let liveness = Liveness::new(cfg);
+    cfg.bb.iter().find_map(|(idx, _)| {
+        let (inner, terminator) = fold_into(cfg, &predecessors, &liveness, idx)?;
+        Some((idx, inner, terminator))
+    })
+}
+
+// The terminator of `outer` after folding one of its successors into it.
+fn fold_into(
+    cfg: &Cfg,
+    predecessors: &BTreeMap<Idx<BasicBlock>, Vec<Idx<BasicBlock>>>,
+    liveness: &Liveness,
+    outer: Idx<BasicBlock>,
+) -> Option<(Idx<BasicBlock>, Terminator)> {
+    let Terminator::If { cond, then, else_ } = cfg.bb[outer].terminator() else {
+        return None;
+    };
+    for (inner, shared, inner_is_then) in [(*then, *else_, true), (*else_, *then, false)] {
+        let block = &cfg.bb[inner];
+        if inner == shared || inner == Idx::from_usize(0) || predecessors[&inner] != [outer] {
+            continue;
+        }
+        let Terminator::If {
+            then: inner_then,
+            else_: inner_else,
+            ..
+        } = block.terminator()
+        else {
+            continue;
+        };
+        let other = if *inner_else == shared {
+            *inner_then
+        } else if *inner_then == shared {
+            *inner_else
+        } else {
+            continue;
+        };
+        if other == inner {
+            continue;
+        }
+        let Some(inner_cond) = inlined(cfg, liveness, inner, [other, shared]) else {
+            continue;
+        };
+        // The condition under which `inner` goes to `target`.
+        let towards = |target| {
+            if target == *inner_then {
+                inner_cond.clone()
+            } else {
+                inner_cond.negated(cfg)
+            }
+        };
+        let combine = |binop, r: Value| Value::Binop(Box::new(cond.clone()), binop, Box::new(r));
+        let terminator = if inner_is_then {
+            // if cond { if .. { other } else { shared } } else { shared }
+            Terminator::If {
+                cond: combine(Binop::And, towards(other)),
+                then: other,
+                else_: shared,
+            }
+        } else {
+            // if cond { shared } else { if .. { shared } else { other } }
+            Terminator::If {
+                cond: combine(Binop::Or, towards(shared)),
+                then: shared,
+                else_: other,
+            }
+        };
+        return Some((inner, terminator));
+    }
+    None
+}
+
+// The condition of the branch that ends `bb`, with the values its statements assign
+// put in for the locals they assign. Only if all of them assign locals that aren't
+// pointers, in their own type, and that `successors` don't read.
+fn inlined(
+    cfg: &Cfg,
+    liveness: &Liveness,
+    bb: Idx<BasicBlock>,
+    successors: [Idx<BasicBlock>; 2],
+) -> Option<Value> {
+    let Terminator::If { cond, .. } = cfg.bb[bb].terminator() else {
+        return None;
+    };
+    let live: Vec<_> = successors
+        .iter()
+        .flat_map(|succ| liveness.before(cfg, *succ, 0))
+        .collect();
+    // Going backwards, each assignment is put in for the reads that come after it.
+    let mut cond = cond.clone();
+    for stmt in cfg.bb[bb].stmts.iter().rev() {
+        let Stmt::Assign {
+            place: Place::Local(idx),
+            value,
+            ..
+        } = stmt
+        else {
+            return None;
+        };
+        let ty = &cfg.locals[*idx].ty;
+        if live.contains(idx) || matches!(ty, CType::Ptr(_)) || value_ty(value, cfg) != *ty {
+            return None;
+        }
+        cond = cond.replace_local(*idx, value.clone());
+    }
+    Some(cond)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::*;
+
+    // The condition and targets of the branch that ends `bb`.
+    fn branch_of(cfg: &Cfg, bb: usize) -> (&Value, usize, usize) {
+        match cfg.bb[Idx::from_usize(bb)].terminator() {
+            Terminator::If { cond, then, else_ } => (cond, then.to_usize(), else_.to_usize()),
+            terminator => panic!("bb{bb} ends in {terminator:?}"),
+        }
+    }
+
+    // bb0 branches on `outer` to bb1 or bb2, bb1 on `inner` to bb2 or bb3.
+    fn chain(outer: (usize, usize), inner: (usize, usize)) -> Cfg {
+        let mut cfg = with_locals(vec![CType::Int(4); 4]);
+        cfg.arg_count = 3;
+        cfg.bb
+            .alloc(block(vec![], branch(local(1), outer.0, outer.1)));
+        cfg.bb
+            .alloc(block(vec![], branch(local(2), inner.0, inner.1)));
+        cfg.bb.alloc(block(
+            vec![assign(0, Value::Literal(0))],
+            Terminator::Return,
+        ));
+        cfg.bb.alloc(block(
+            vec![assign(0, Value::Literal(1))],
+            Terminator::Return,
+        ));
+        cfg
+    }
+
+    #[test]
+    fn test_and_or() {
+        // if (_1 && _2) { bb3 } else { bb2 }, with bb1 removed and the others moved
+        // up by one.
+        let mut cfg = chain((1, 2), (3, 2));
+        recover_short_circuits(&mut cfg);
+        let cond = binop(local(1), Binop::And, local(2));
+        assert_eq!(branch_of(&cfg, 0), (&cond, 2, 1));
+        assert_eq!(cfg.bb.iter().count(), 3);
+
+        // if (_1 || _2) { bb2 } else { bb3 }
+        let mut cfg = chain((2, 1), (2, 3));
+        recover_short_circuits(&mut cfg);
+        let cond = binop(local(1), Binop::Or, local(2));
+        assert_eq!(branch_of(&cfg, 0), (&cond, 1, 2));
+
+        // The inner branch goes to the shared block when its condition holds, so it
+        // is negated: if (_1 && !_2) { bb3 } else { bb2 }
+        let mut cfg = chain((1, 2), (2, 3));
+        recover_short_circuits(&mut cfg);
+        let negated = binop(local(2), Binop::Eq, Value::Literal(0));
+        let cond = binop(local(1), Binop::And, negated);
+        assert_eq!(branch_of(&cfg, 0), (&cond, 2, 1));
+    }
+
+    #[test]
+    fn test_longer_chains() {
+        // if (_1 && _2 && _3) { bb4 } else { bb2 }
+        let mut cfg = chain((1, 2), (3, 2));
+        cfg.bb[Idx::from_usize(3)] = block(vec![], branch(local(3), 4, 2));
+        cfg.bb.alloc(block(
+            vec![assign(0, Value::Literal(1))],
+            Terminator::Return,
+        ));
+        recover_short_circuits(&mut cfg);
+        let cond = binop(binop(local(1), Binop::And, local(2)), Binop::And, local(3));
+        assert_eq!(branch_of(&cfg, 0), (&cond, 2, 1));
+
+        // The structure has a single `if`, without a copy of the else branch.
+        let node = cfg.loopify();
+        let c = emit_c(&cfg, &node, "f");
+        assert_eq!(c.matches("if (").count(), 1, "{c}");
+    }
+
+    #[test]
+    fn test_inlined_temporaries() {
+        // bb1: _3 = _2 + 1; _3 = _3 * 2; if _3 < 10 { .. }, and _3 isn't read after.
+        let mut cfg = chain((1, 2), (3, 2));
+        let plus_one = binop(local(2), Binop::Add, Value::Literal(1));
+        let times_two = binop(local(3), Binop::Mul, Value::Literal(2));
+        cfg.bb[Idx::from_usize(1)] = block(
+            vec![assign(3, plus_one.clone()), assign(3, times_two)],
+            branch(binop(local(3), Binop::Lt, Value::Literal(10)), 3, 2),
+        );
+        recover_short_circuits(&mut cfg);
+        let inner = binop(
+            binop(plus_one, Binop::Mul, Value::Literal(2)),
+            Binop::Lt,
+            Value::Literal(10),
+        );
+        let cond = binop(local(1), Binop::And, inner);
+        assert_eq!(branch_of(&cfg, 0), (&cond, 2, 1));
+    }
+
+    #[test]
+    fn test_kept_chains() {
+        // Folding would drop a value bb3 reads.
+        let mut cfg = chain((1, 2), (3, 2));
+        cfg.bb[Idx::from_usize(1)]
+            .stmts
+            .push(assign(3, Value::Literal(2)));
+        cfg.bb[Idx::from_usize(3)].stmts = vec![assign(0, local(3))];
+        let expected = cfg.clone();
+        recover_short_circuits(&mut cfg);
+        assert_eq!(format!("{cfg:?}"), format!("{expected:?}"));
+
+        // Another predecessor still needs the inner block as it is.
+        let mut cfg = chain((1, 2), (3, 2));
+        cfg.bb[Idx::from_usize(2)].terminator = Some(goto(1));
+        let expected = cfg.clone();
+        recover_short_circuits(&mut cfg);
+        assert_eq!(format!("{cfg:?}"), format!("{expected:?}"));
+    }
+}
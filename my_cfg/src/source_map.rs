@@ -0,0 +1,142 @@
+// Source maps: which statement or terminator of the `Cfg` each line of emitted code
+// prints, so that a line can be traced back to its block and, through the `Origin`
+// of its statement, to the machine code it was lifted from.
+
+use std::collections::BTreeSet;
+
+use serde::{Deserialize, Serialize};
+
+use crate::*;
+
+/// A line of emitted code and one thing it prints. A line can have several, like
+/// the `init`, condition and `step` of a C `for`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Mapping {
+    /// Counting from 1.
+    pub line: usize,
+    pub bb: Idx<BasicBlock>,
+    /// The statement of `bb`, or its terminator if `None`.
+    pub stmt: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin: Option<Origin>,
+}
+
+/// Written next to the emitted code as JSON.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SourceMap {
+    /// In the order the lines are printed.
+    pub mappings: Vec<Mapping>,
+}
+
+impl SourceMap {
+    /// Records that `line` prints position `index` of `bb`, where the index one past
+    /// the last statement is the terminator.
+    pub fn add(&mut self, line: usize, bb: Idx<BasicBlock>, index: usize, cfg: &Cfg) {
+        let stmts = &cfg.bb[bb].stmts;
+        self.mappings.push(Mapping {
+            line,
+            bb,
+            stmt: (index < stmts.len()).then_some(index),
+            origin: stmts.get(index).and_then(Stmt::origin),
+        });
+    }
+
+    /// Appends the mappings of code that is printed after `lines` lines of this one.
+    pub fn append(&mut self, other: SourceMap, lines: usize) {
+        self.mappings
+            .extend(other.mappings.into_iter().map(|mapping| Mapping {
+                line: mapping.line + lines,
+                ..mapping
+            }));
+    }
+
+    /// What `line` prints.
+    pub fn line(&self, line: usize) -> impl Iterator<Item = &Mapping> {
+        self.mappings
+            .iter()
+            .filter(move |mapping| mapping.line == line)
+    }
+
+    /// The lines that print something of `bb`.
+    pub fn lines_of(&self, bb: Idx<BasicBlock>) -> BTreeSet<usize> {
+        self.mappings
+            .iter()
+            .filter(|mapping| mapping.bb == bb)
+            .map(|mapping| mapping.line)
+            .collect()
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::c::emit_c_mapped;
+    use crate::test_util::*;
+
+    fn at(address: u64, stmt: Stmt) -> Stmt {
+        let Stmt::Assign { place, value, .. } = stmt;
+        let origin = Some(Origin { address });
+        Stmt::Assign {
+            place,
+            value,
+            origin,
+        }
+    }
+
+    #[test]
+    fn test_c_lines() {
+        // bb0: _0 = 0; _2 = 0; goto bb1
+        // bb1: if _2 < _1 { goto bb3 } else { goto bb2 }
+        // bb2: _0 = _0 + 3; _2 = _2 - 1; goto bb1
+        // bb3: return
+        let mut cfg = with_locals([CType::Int(4), CType::Int(4), CType::Int(4)]);
+        cfg.arg_count = 1;
+        let zero = |i| assign(i, Value::Literal(0));
+        cfg.bb
+            .alloc(block(vec![at(0x10, zero(0)), at(0x14, zero(2))], goto(1)));
+        cfg.bb.alloc(block(
+            vec![],
+            branch(binop(local(2), Binop::Lt, local(1)), 3, 2),
+        ));
+        let add = assign(0, binop(local(0), Binop::Add, Value::Literal(3)));
+        let sub = assign(2, binop(local(2), Binop::Sub, Value::Literal(1)));
+        cfg.bb
+            .alloc(block(vec![at(0x20, add), at(0x24, sub)], goto(1)));
+        cfg.bb.alloc(block(vec![], Terminator::Return));
+
+        let (code, map) = emit_c_mapped(&cfg, &cfg.loopify(), "f", &Default::default());
+        // The line that has `text` on it, counting from 1.
+        let line_of = |text| code.lines().position(|line| line.contains(text)).unwrap() + 1;
+        let printed = |line| {
+            map.line(line)
+                .map(|mapping| (mapping.bb.to_usize(), mapping.stmt, mapping.origin))
+                .collect::<Vec<_>>()
+        };
+        let origin = |address| Some(Origin { address });
+
+        assert_eq!(printed(line_of("_0 = 0")), [(0, Some(0), origin(0x10))]);
+        // The `for` initializes _2, checks the exit condition and counts _2 down.
+        let header = line_of("for (");
+        assert_eq!(
+            printed(header),
+            [
+                (0, Some(1), origin(0x14)),
+                (1, None, None),
+                (2, Some(1), origin(0x24))
+            ]
+        );
+        let body = line_of("+ 3");
+        assert_eq!(printed(body), [(2, Some(0), origin(0x20))]);
+        assert_eq!(map.lines_of(Idx::from_usize(2)), [header, body].into());
+        assert_eq!(printed(line_of("return")), [(3, None, None)]);
+        // The gotos print nothing.
+        assert_eq!(map.mappings.len(), 6);
+
+        let json: SourceMap = serde_json::from_str(&map.to_json()).unwrap();
+        assert_eq!(json, map);
+    }
+}
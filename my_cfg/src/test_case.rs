@@ -0,0 +1,118 @@
+// Concrete inputs to run a function on and what it should do with them, like the
+// counterexamples z3 finds. They are saved as JSON and replayed by the C harness of
+// `emit_c_harness` and by `interpreter::replay`.
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TestCase {
+    /// The values of `_1` to `_arg_count`.
+    pub args: Vec<TestValue>,
+    /// Memory the function is called with, as far as it is used.
+    pub memory: Vec<MemoryChunk>,
+    /// What `_0` is at the end, `None` if the function returns `void`.
+    pub expected: Option<TestValue>,
+    /// What memory is at the end, at the same addresses as `memory`. Scratch memory
+    /// like the stack frame isn't part of it.
+    pub expected_memory: Vec<MemoryChunk>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TestValue {
+    /// An integer or `bool`, converted to the type of the local.
+    Int(i128),
+    /// The bits of a float.
+    Float(u128),
+    /// A pointer to `address` in `region`.
+    Pointer { region: u64, address: u64 },
+}
+
+/// Bytes at consecutive addresses. Chunks of different regions are separate
+/// objects, even where their addresses overlap.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MemoryChunk {
+    pub region: u64,
+    pub address: u64,
+    pub bytes: Vec<u8>,
+}
+
+impl MemoryChunk {
+    /// Splits bytes, given by region and address, into runs of consecutive addresses.
+    pub fn chunks(bytes: impl IntoIterator<Item = ((u64, u64), u8)>) -> Vec<MemoryChunk> {
+        let mut bytes: Vec<_> = bytes.into_iter().collect();
+        bytes.sort_by_key(|(at, _)| *at);
+        bytes.dedup_by_key(|(at, _)| *at);
+        let mut chunks: Vec<MemoryChunk> = vec![];
+        for ((region, address), byte) in bytes {
+            match chunks.last_mut() {
+                Some(chunk) if chunk.region == region && chunk.end() == Some(address) => {
+                    chunk.bytes.push(byte);
+                }
+                _ => chunks.push(MemoryChunk {
+                    region,
+                    address,
+                    bytes: vec![byte],
+                }),
+            }
+        }
+        chunks
+    }
+
+    /// The address just past the chunk, `None` if that wraps around.
+    pub fn end(&self) -> Option<u64> {
+        self.address.checked_add(self.bytes.len() as u64)
+    }
+
+    /// Whether a pointer to `address` in `region` points into the chunk or just past it.
+    pub fn contains(&self, region: u64, address: u64) -> bool {
+        self.region == region
+            && self.address <= address
+            && self.end().is_none_or(|end| address <= end)
+    }
+}
+
+impl TestCase {
+    pub fn from_json(x: &str) -> serde_json::Result<TestCase> {
+        serde_json::from_str(x)
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunks() {
+        let bytes = [
+            ((0, 5), 3),
+            ((0, 4), 2),
+            ((1, 6), 4),
+            ((0, 4), 2),
+            ((0, 7), 5),
+        ];
+        assert_eq!(
+            MemoryChunk::chunks(bytes),
+            vec![
+                MemoryChunk {
+                    region: 0,
+                    address: 4,
+                    bytes: vec![2, 3]
+                },
+                MemoryChunk {
+                    region: 0,
+                    address: 7,
+                    bytes: vec![5]
+                },
+                MemoryChunk {
+                    region: 1,
+                    address: 6,
+                    bytes: vec![4]
+                },
+            ]
+        );
+    }
+}
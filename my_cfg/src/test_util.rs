@@ -0,0 +1,88 @@
+// Builders for the small `Cfg`s that tests are written against, and a look at what
+// they are structured into. The tests of the other crates get them with the
+// `test-util` feature, as a dev-dependency.
+
+use crate::*;
+
+pub use crate::interpreter::run;
+
+pub fn place(i: usize) -> Place {
+    Place::Local(Idx::from_usize(i))
+}
+
+pub fn local(i: usize) -> Value {
+    Value::Place(place(i))
+}
+
+pub fn binop(l: Value, binop: Binop, r: Value) -> Value {
+    Value::Binop(Box::new(l), binop, Box::new(r))
+}
+
+pub fn assign(i: usize, value: Value) -> Stmt {
+    Stmt::Assign {
+        place: Place::Local(Idx::from_usize(i)),
+        value,
+        origin: None,
+    }
+}
+
+pub fn block(stmts: Vec<Stmt>, terminator: Terminator) -> BasicBlock {
+    BasicBlock {
+        stmts,
+        terminator: Some(terminator),
+    }
+}
+
+pub fn goto(bb: usize) -> Terminator {
+    Terminator::Goto {
+        bb: Idx::from_usize(bb),
+    }
+}
+
+pub fn branch(cond: Value, then: usize, else_: usize) -> Terminator {
+    Terminator::If {
+        cond,
+        then: Idx::from_usize(then),
+        else_: Idx::from_usize(else_),
+    }
+}
+
+/// A `Cfg` without blocks yet, with a local of each type. `_0` is the return value.
+pub fn with_locals(tys: impl IntoIterator<Item = CType>) -> Cfg {
+    let mut cfg = Cfg::default();
+    for ty in tys {
+        cfg.locals.alloc(Local { name: None, ty });
+    }
+    cfg
+}
+
+/// The headers of the `Loop`s in `node`, with how many loops each one is nested in.
+pub fn loops(node: &StructuredNode) -> Vec<(usize, usize)> {
+    fn walk(node: &StructuredNode, depth: usize, out: &mut Vec<(usize, usize)>) {
+        match node {
+            StructuredNode::Sequence(nodes) => {
+                nodes.iter().for_each(|node| walk(node, depth, out));
+            }
+            StructuredNode::If {
+                then_node,
+                else_node,
+                ..
+            } => {
+                walk(then_node, depth, out);
+                walk(else_node, depth, out);
+            }
+            StructuredNode::Block(body) => walk(body, depth, out),
+            StructuredNode::Dispatch { handlers, .. } => {
+                handlers.iter().for_each(|(_, node)| walk(node, depth, out));
+            }
+            StructuredNode::Loop(body) => {
+                out.push((body.entry().unwrap().to_usize(), depth));
+                walk(body, depth + 1, out);
+            }
+            _ => {}
+        }
+    }
+    let mut out = vec![];
+    walk(node, 0, &mut out);
+    out
+}
@@ -0,0 +1,872 @@
+// Compilers divide by a constant without dividing: they take the high half of `x`
+// times a magic number and shift it right, with corrections for the sign of `x` or
+// for magic numbers one bit too wide. The sequences differ between divisors, widths,
+// signedness and compilers, so instead of matching each of them, the values the
+// statements of a block compute are followed as terms of a single input, the divisor
+// is read off by evaluating them, and a rewrite is only made once z3 proves it.
+// Divisions by powers of two are shifts, which are left alone unless they are
+// corrected for the sign of `x`.
+
+use my_cfg::eval::{self, wrap};
+use my_cfg::ranges::{operand_ty, value_ty};
+use my_cfg::{Binop, CType, Cfg, Idx, Local, Place, Stmt, Value};
+use std::collections::HashSet;
+
+use z3::ast::{Ast, AstKind, BV, Bool, Dynamic};
+use z3::{DeclKind, Goal, Params, Tactic};
+use z3_of_cfg::{Frame, Inputs, MemoryConfig, Outcome, Query, Solver, Z3Solver, z3_of_stmts};
+
+use crate::TIMEOUT;
+
+/// Replaces multiplications by magic numbers that divide a 32 or 64-bit integer by a
+/// constant, and the remainders computed from them, with `Div` and `Rem`.
+pub fn recover_divisions(cfg: &mut Cfg) {
+    let bbs: Vec<_> = cfg.bb.iter().map(|(bb, _)| bb).collect();
+    for bb in bbs {
+        let stmts = std::mem::take(&mut cfg.bb[bb].stmts);
+        let mut terms = Terms::new(cfg);
+        let mut done: Vec<Stmt> = vec![];
+        for (i, stmt) in stmts.iter().enumerate() {
+            let mut replacement = vec![stmt.clone()];
+            for division in terms.divisions(cfg, stmt).unwrap_or_default() {
+                // The locals the division needs are only kept if it is.
+                let locals = cfg.locals.clone();
+                let divided = division.stmts(cfg, stmt);
+                // After the statements as they were, which leave the same in the
+                // locals as the ones that replaced them, without divisions for z3
+                // to bit-blast.
+                if proved(cfg, &stmts[..i], stmt, &division, &divided) {
+                    replacement = divided;
+                    break;
+                }
+                cfg.locals = locals;
+            }
+            for stmt in replacement {
+                terms.assign(cfg, &stmt);
+                done.push(stmt);
+            }
+        }
+        cfg.bb[bb].stmts = done;
+    }
+}
+
+// That `replacement`, which divides like `division`, leaves in the local `stmt`
+// assigns what `stmt` does, after `stmts` and from any values of the locals. Both are
+// run by z3 at the widths of their types. Bit-blasting a multiplication by a magic
+// number against a division takes z3 minutes, so the quotient is left to a fresh
+// local instead, and `x` is written as that times the divisor plus a remainder, see
+// `divided`. Then the multiplication by the magic number is one by the divisor times
+// it, which is a power of two and a small error, plus one of the remainder.
+// Some still take z3 longer than `TIMEOUT`, and those stay multiplications.
+fn proved(
+    cfg: &Cfg,
+    stmts: &[Stmt],
+    stmt: &Stmt,
+    division: &Division,
+    replacement: &[Stmt],
+) -> bool {
+    let (
+        Stmt::Assign {
+            place: Place::Local(local),
+            ..
+        },
+        Some((divided, before_dividing)),
+    ) = (stmt, replacement.split_last())
+    else {
+        return false;
+    };
+    let Stmt::Assign {
+        place,
+        value: Value::Binop(x, _, divisor),
+        origin,
+    } = divided
+    else {
+        return false;
+    };
+    let mut cfg = cfg.clone();
+    let q = cfg.locals.alloc(Local {
+        name: None,
+        ty: division.ty.clone(),
+    });
+    let value = match division.binop {
+        Binop::Div => Value::from_local(q),
+        // What is left of `x` once `q` times the divisor is taken away.
+        _ => Value::Binop(
+            x.clone(),
+            Binop::Sub,
+            Box::new(Value::Binop(
+                Box::new(Value::from_local(q)),
+                Binop::Mul,
+                divisor.clone(),
+            )),
+        ),
+    };
+    let divided = Stmt::Assign {
+        place: place.clone(),
+        value,
+        origin: *origin,
+    };
+    let inputs = Inputs::fresh(&cfg, &MemoryConfig::default());
+    let run = |last: &[Stmt]| z3_of_stmts(&cfg, &[stmts, last].concat(), &inputs);
+    let start = run(&[]);
+    let x = &start.locals[division.x.to_usize()];
+    let width = x.get_size();
+    let signed = matches!(division.ty, CType::Int(_));
+    let d = BV::from_u64(division.divisor as u64, width);
+    let (q_local, (q, r)) = (&start.locals[q.to_usize()], quotient(division));
+    let (wide, divides) = self::divided(&q, &r, &d, signed);
+    let extended = match signed {
+        true => x.sign_ext(width),
+        false => x.zero_ext(width),
+    };
+    let narrow = q.bvmul(&d).bvadd(&r);
+    let substituted = |frame: Frame| {
+        frame.locals[local.to_usize()].substitute(&[
+            (&extended, &wide),
+            (x, &narrow),
+            (q_local, &q),
+        ])
+    };
+    let before = substituted(run(std::slice::from_ref(stmt)));
+    let after = substituted(run(&[before_dividing, &[divided]].concat()));
+    let solver = Z3Solver {
+        timeout: Some(TIMEOUT),
+    };
+    let assertions = shifted(expanded(&[divides, before.eq(after).not()]));
+    matches!(solver.check(&Query::new(assertions)), Outcome::Unsat)
+}
+
+// `assertions` with the multiplications by constants written as sums and differences
+// of shifts, one for each digit of the constant in signed binary that isn't 0. z3
+// adds a shift for each bit set instead, which is more than half of them for
+// negative constants.
+fn shifted(assertions: Vec<Bool>) -> Vec<Bool> {
+    let mut products = vec![];
+    let mut seen = HashSet::new();
+    let mut asts: Vec<_> = assertions.iter().map(|a| Dynamic::from_ast(a)).collect();
+    while let Some(ast) = asts.pop() {
+        if !ast.is_app() || !seen.insert(ast.clone()) {
+            continue;
+        }
+        let children = ast.children();
+        if ast.decl().kind() == DeclKind::BMUL
+            && children.len() == 2
+            && let (Some(k), Some(t)) = (numeral(&children[0]), children[1].as_bv())
+        {
+            let digits = digits(k, t.get_size());
+            if digits.len() < k.count_ones() as usize {
+                let sum = sum_of_shifts(&t, &digits);
+                products.push((ast.clone(), Dynamic::from_ast(&sum)));
+            }
+        }
+        asts.extend(children);
+    }
+    if products.is_empty() {
+        return assertions;
+    }
+    let substitutions: Vec<_> = products.iter().map(|(from, to)| (from, to)).collect();
+    // The operands that are shifted can have products of their own.
+    shifted(
+        assertions
+            .iter()
+            .map(|assertion| assertion.substitute(&substitutions))
+            .collect(),
+    )
+}
+
+// The value of a bit-vector numeral.
+fn numeral(ast: &Dynamic) -> Option<u128> {
+    let bv = ast.as_bv()?;
+    if ast.kind() != AstKind::Numeral {
+        return None;
+    }
+    if let Some(k) = bv.as_u64() {
+        return Some(k.into());
+    }
+    let text = bv.to_string();
+    let hex = text.strip_prefix("#x")?;
+    u128::from_str_radix(hex, 16).ok()
+}
+
+// The nonzero digits of `k` in `width` bits in signed binary, by the power of two
+// they are at, with as few of them as there can be.
+fn digits(mut k: u128, width: u32) -> Vec<(u32, bool)> {
+    let mut digits = vec![];
+    for i in 0..width {
+        if k & 1 == 1 {
+            let negative = k & 2 == 2;
+            digits.push((i, negative));
+            k = if negative { k.wrapping_add(1) } else { k - 1 };
+        }
+        k >>= 1;
+    }
+    digits
+}
+
+fn sum_of_shifts(t: &BV, digits: &[(u32, bool)]) -> BV {
+    let width = t.get_size();
+    digits
+        .iter()
+        .fold(BV::from_u64(0, width), |sum, (i, negative)| {
+            let shifted = t.bvshl(BV::from_u64(u64::from(*i), width));
+            match negative {
+                true => sum.bvsub(shifted),
+                false => sum.bvadd(shifted),
+            }
+        })
+}
+
+/// Fresh values for the quotient and the remainder of `division`, in its type but
+/// extended from only as many bits as they can need, which z3 is much faster on.
+fn quotient(division: &Division) -> (BV, BV) {
+    let width = width(&division.ty).unwrap();
+    let signed = matches!(division.ty, CType::Int(_));
+    let magnitude = match signed {
+        true => sign_extend(division.divisor, width).unsigned_abs(),
+        false => division.divisor,
+    };
+    // The quotient is at most the largest value over `2^ilog2(magnitude)`, and the
+    // remainder is smaller than the magnitude, with a sign bit if it is signed.
+    let q_bits = width - magnitude.ilog2();
+    let r_bits = 128 - (magnitude - 1).leading_zeros() + u32::from(signed);
+    let [q, r] = [("q", q_bits), ("r", r_bits)].map(|(name, bits)| {
+        let bv = BV::fresh_const(name, bits);
+        match signed {
+            true => bv.sign_ext(width - bits),
+            false => bv.zero_ext(width - bits),
+        }
+    });
+    (q, r)
+}
+
+/// `q * d + r` in twice the width of the operands, and that it is `x` divided by a
+/// `d` other than 0 and -1: that the sum fits in the width, and that `r` is smaller
+/// than `d` and of the sign of the sum, so that `q` is the quotient rounded towards
+/// zero and `r` the remainder.
+fn divided(q: &BV, r: &BV, d: &BV, signed: bool) -> (BV, Bool) {
+    let width = q.get_size();
+    let wide = |bv: &BV| match signed {
+        true => bv.sign_ext(width),
+        false => bv.zero_ext(width),
+    };
+    let (r, d) = (wide(r), wide(d));
+    let sum = wide(q).bvmul(&d).bvadd(&r);
+    let fits = wide(&sum.extract(width - 1, 0)).eq(&sum);
+    if !signed {
+        return (sum, fits & r.bvult(&d));
+    }
+    let zero = BV::from_u64(0, width * 2);
+    let size = d.bvslt(&zero).ite(&d.bvneg(), &d);
+    let remainder = sum.bvsge(&zero).ite(
+        &(r.bvsge(&zero) & r.bvslt(&size)),
+        &(r.bvsle(&zero) & r.bvsgt(size.bvneg())),
+    );
+    (sum, fits & remainder)
+}
+
+// `assertions` with multiplications distributed over sums.
+fn expanded(assertions: &[Bool]) -> Vec<Bool> {
+    let goal = Goal::new(false, false, false);
+    for assertion in assertions {
+        goal.assert(assertion);
+    }
+    let mut params = Params::new();
+    params.set_bool("som", true);
+    match Tactic::new("simplify").apply(&goal, Some(&params)) {
+        Ok(result) => result
+            .list_subgoals()
+            .flat_map(|goal| goal.get_formulas())
+            .collect(),
+        Err(_) => assertions.to_vec(),
+    }
+}
+
+/// A value computed in a block, in terms of literals and of locals as they are
+/// before statements that aren't followed, like reads of memory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Term {
+    /// An integer type.
+    ty: CType,
+    kind: Kind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Kind {
+    /// The value a local has after as many assignments in the block.
+    Local(Idx<Local>, usize),
+    /// The bits of the value, none above its width set.
+    Const(u128),
+    /// Done in `ty`, which both operands have.
+    Binop(Box<Term>, Binop, Box<Term>),
+    /// The operand converted to `ty`, like on assignment.
+    Convert(Box<Term>),
+}
+
+impl Term {
+    fn constant(bits: u128, ty: &CType) -> Term {
+        Term {
+            ty: ty.clone(),
+            kind: Kind::Const(mask(bits, width(ty).unwrap())),
+        }
+    }
+
+    fn width(&self) -> u32 {
+        width(&self.ty).unwrap()
+    }
+
+    fn convert(self, ty: &CType) -> Term {
+        if self.ty == *ty {
+            return self;
+        }
+        let term = Term {
+            ty: ty.clone(),
+            kind: Kind::Convert(Box::new(self)),
+        };
+        term.folded()
+    }
+
+    fn binop(self, binop: Binop, r: Term) -> Term {
+        let term = Term {
+            ty: self.ty.clone(),
+            kind: Kind::Binop(Box::new(self), binop, Box::new(r)),
+        };
+        term.folded()
+    }
+
+    // Computed, if it doesn't depend on a local.
+    fn folded(self) -> Term {
+        let is_const = |term: &Term| matches!(term.kind, Kind::Const(_));
+        let foldable = match &self.kind {
+            Kind::Convert(term) => is_const(term),
+            Kind::Binop(l, _, r) => is_const(l) && is_const(r),
+            _ => false,
+        };
+        if foldable {
+            Term::constant(self.eval(0), &self.ty)
+        } else {
+            self
+        }
+    }
+
+    /// The value, where the locals it is made of have the bits `x`.
+    fn eval(&self, x: u128) -> u128 {
+        let width = self.width();
+        match &self.kind {
+            Kind::Local(..) => mask(x, width),
+            Kind::Const(bits) => *bits,
+            Kind::Convert(term) => mask(wrap(term.eval(x) as i128, &term.ty) as u128, width),
+            Kind::Binop(l, binop, r) => {
+                let (l, r) = (l.eval(x) as i128, r.eval(x) as i128);
+                mask(eval::binop(l, *binop, r, &self.ty) as u128, width)
+            }
+        }
+    }
+
+    fn subterms(&self) -> Vec<&Term> {
+        let mut subterms = vec![self];
+        match &self.kind {
+            Kind::Local(..) | Kind::Const(_) => {}
+            Kind::Convert(term) => subterms.extend(term.subterms()),
+            Kind::Binop(l, _, r) => {
+                subterms.extend(l.subterms());
+                subterms.extend(r.subterms());
+            }
+        }
+        subterms
+    }
+
+    // A right shift of a product with a constant or of a value corrected by its sign,
+    // or a division by a constant, whose first nonzero value is at the divisor.
+    fn is_quotient(&self) -> bool {
+        let is_const = |term: &Term| matches!(term.kind, Kind::Const(_));
+        let multiplies = |term: &Term| {
+            term.subterms().into_iter().any(|term| {
+                matches!(&term.kind, Kind::Binop(l, Binop::Mul, r) if is_const(l) != is_const(r))
+            })
+        };
+        // Shifted right by all but the sign bit, which is how signed divisions by
+        // powers of two round towards zero.
+        let corrected = |term: &Term| {
+            term.subterms().into_iter().any(|term| match &term.kind {
+                Kind::Binop(l, Binop::Shr | Binop::Sar, r) => {
+                    !is_const(l) && r.kind == Kind::Const(u128::from(l.width() - 1))
+                }
+                _ => false,
+            })
+        };
+        match &self.kind {
+            Kind::Binop(l, Binop::Shr | Binop::Sar, r) => {
+                is_const(r) && (multiplies(l) || corrected(l))
+            }
+            Kind::Binop(_, Binop::Div, r) => is_const(r),
+            _ => false,
+        }
+    }
+}
+
+const MAX_TERM_SIZE: usize = 64;
+
+/// What the statements of a block so far left in its integer locals.
+struct Terms {
+    terms: Vec<Option<Term>>,
+    assignments: Vec<usize>,
+}
+
+impl Terms {
+    fn new(cfg: &Cfg) -> Self {
+        let terms = cfg
+            .locals
+            .iter()
+            .map(|(idx, local)| {
+                width(&local.ty).map(|_| Term {
+                    ty: local.ty.clone(),
+                    kind: Kind::Local(idx, 0),
+                })
+            })
+            .collect();
+        Self {
+            terms,
+            assignments: vec![0; cfg.locals.iter().count()],
+        }
+    }
+
+    fn assign(&mut self, cfg: &Cfg, stmt: &Stmt) {
+        let Stmt::Assign {
+            place: Place::Local(local),
+            value,
+            ..
+        } = stmt
+        else {
+            return;
+        };
+        let i = local.to_usize();
+        // Locals the pass added.
+        if i >= self.terms.len() {
+            self.terms.resize(i + 1, None);
+            self.assignments.resize(i + 1, 0);
+        }
+        self.assignments[i] += 1;
+        let ty = &cfg.locals[*local].ty;
+        // Terms that grow too big are cut off like values that aren't followed.
+        let term = self
+            .term(cfg, value, ty)
+            .filter(|term| term.subterms().len() <= MAX_TERM_SIZE);
+        self.terms[i] = width(ty).map(|_| {
+            term.unwrap_or_else(|| Term {
+                ty: ty.clone(),
+                kind: Kind::Local(*local, self.assignments[i]),
+            })
+        });
+    }
+
+    /// `value` converted to `ty`, `None` if it isn't computed from integer locals.
+    fn term(&self, cfg: &Cfg, value: &Value, ty: &CType) -> Option<Term> {
+        let term = match value {
+            Value::Literal(i) => Term::constant(i128::from(*i) as u128, &CType::Int(4)),
+            Value::Place(Place::Local(local)) => self.terms[local.to_usize()].clone()?,
+            // Divisions are compiled without them.
+            Value::Place(_) | Value::Binop(_, Binop::Xor, _) | Value::Select(..) => return None,
+            Value::Binop(l, binop, r) => {
+                let operand_ty = operand_ty(l, r, cfg);
+                if value_ty(value, cfg) == CType::Bool || width(&operand_ty).is_none() {
+                    return None;
+                }
+                let l = self.term(cfg, l, &operand_ty)?;
+                l.binop(*binop, self.term(cfg, r, &operand_ty)?)
+            }
+        };
+        Some(term.convert(ty))
+    }
+
+    /// The divisions and remainders that an assignment of a local could be replaced
+    /// with, that it has been checked on enough values to ask z3 about. `None` if it
+    /// doesn't compute one from a single input.
+    fn divisions(&self, cfg: &Cfg, stmt: &Stmt) -> Option<Vec<Division>> {
+        let Stmt::Assign {
+            place: Place::Local(local),
+            value,
+            ..
+        } = stmt
+        else {
+            return None;
+        };
+        let ty = &cfg.locals[*local].ty;
+        width(ty)?;
+        let term = self.term(cfg, value, ty)?;
+        let subterms = term.subterms();
+        // A single input, which is still in its local.
+        let mut inputs = subterms
+            .iter()
+            .filter(|term| matches!(term.kind, Kind::Local(..)));
+        let input = (*inputs.next()?).clone();
+        if inputs.any(|other| **other != input) {
+            return None;
+        }
+        let Kind::Local(x, _) = input.kind else {
+            unreachable!()
+        };
+        if self.terms[x.to_usize()].as_ref() != Some(&input) {
+            return None;
+        }
+        let x_width = input.width();
+        if x_width != 32 && x_width != 64 {
+            return None;
+        }
+        let mut divisors: Vec<_> = subterms
+            .iter()
+            .filter(|term| term.is_quotient())
+            .filter_map(|quotient| first_nonzero(quotient, x_width))
+            .filter(|divisor| *divisor >= 2)
+            .collect();
+        divisors.sort();
+        divisors.dedup();
+        // The signedness of `x` is tried first, the other one is for code that
+        // converts it first or keeps everything unsigned.
+        let signed_first = matches!(input.ty, CType::Int(_));
+        let max_signed = (1 << (x_width - 1)) - 1;
+        let mut divisions = vec![];
+        for divisor in divisors {
+            for signed in [signed_first, !signed_first] {
+                let bytes = (x_width / 8) as u8;
+                let div_ty = if signed {
+                    CType::Int(bytes)
+                } else {
+                    CType::UInt(bytes)
+                };
+                for binop in [Binop::Div, Binop::Rem] {
+                    divisions.push(Division {
+                        x,
+                        ty: div_ty.clone(),
+                        binop,
+                        divisor,
+                    });
+                    if signed && binop == Binop::Div && divisor <= max_signed {
+                        divisions.push(Division {
+                            x,
+                            ty: div_ty.clone(),
+                            binop,
+                            divisor: mask(divisor.wrapping_neg(), x_width),
+                        });
+                    }
+                }
+            }
+        }
+        divisions.retain(|division| {
+            let replacement = division.term(&input, ty);
+            replacement != term
+                && samples(x_width, division.divisor)
+                    .into_iter()
+                    .all(|x| replacement.eval(x) == term.eval(x))
+        });
+        Some(divisions)
+    }
+}
+
+/// `x` divided by a constant, or the remainder of that.
+#[derive(Debug)]
+struct Division {
+    x: Idx<Local>,
+    /// The type divided in, an integer as wide as `x`.
+    ty: CType,
+    binop: Binop,
+    /// In the bits of `ty`.
+    divisor: u128,
+}
+
+impl Division {
+    // As a term assigned to a local of type `ty`, with `x` the term of the input.
+    fn term(&self, x: &Term, ty: &CType) -> Term {
+        let divisor = Term::constant(self.divisor, &self.ty);
+        x.clone()
+            .convert(&self.ty)
+            .binop(self.binop, divisor)
+            .convert(ty)
+    }
+
+    // Statements to replace `stmt` with, with the locals they need added to `cfg`.
+    fn stmts(&self, cfg: &mut Cfg, stmt: &Stmt) -> Vec<Stmt> {
+        let Stmt::Assign { place, origin, .. } = stmt;
+        let mut stmts = vec![];
+        let temporary = |cfg: &mut Cfg, stmts: &mut Vec<Stmt>, value: Value| {
+            let local = cfg.locals.alloc(Local {
+                name: None,
+                ty: self.ty.clone(),
+            });
+            stmts.push(Stmt::Assign {
+                place: Place::Local(local),
+                value,
+                origin: *origin,
+            });
+            local
+        };
+        // Done in the type of the left side.
+        let x = if cfg.locals[self.x].ty == self.ty {
+            self.x
+        } else {
+            temporary(cfg, &mut stmts, Value::from_local(self.x))
+        };
+        let width = width(&self.ty).unwrap();
+        let divisor = sign_extend(self.divisor, width);
+        // Literals are `int`s, wider divisors are put together from two halves.
+        let divisor = match i32::try_from(divisor) {
+            Ok(divisor) => Value::Literal(divisor),
+            Err(_) => {
+                let low = divisor as i32;
+                let high = ((divisor - i128::from(low)) >> 32) as i32;
+                let local = temporary(cfg, &mut stmts, Value::Literal(high));
+                for (binop, r) in [(Binop::Shl, 32), (Binop::Add, low)] {
+                    let value = Value::Binop(
+                        Box::new(Value::from_local(local)),
+                        binop,
+                        Box::new(Value::Literal(r)),
+                    );
+                    stmts.push(Stmt::Assign {
+                        place: Place::Local(local),
+                        value,
+                        origin: *origin,
+                    });
+                }
+                Value::from_local(local)
+            }
+        };
+        stmts.push(Stmt::Assign {
+            place: place.clone(),
+            value: Value::Binop(
+                Box::new(Value::from_local(x)),
+                self.binop,
+                Box::new(divisor),
+            ),
+            origin: *origin,
+        });
+        stmts
+    }
+}
+
+// The smallest positive `x` that `quotient` isn't zero for, the divisor if it is a
+// quotient of `x`, and `None` if it is zero for the largest signed and unsigned
+// values.
+fn first_nonzero(quotient: &Term, width: u32) -> Option<u128> {
+    let max_signed = (1 << (width - 1)) - 1;
+    let mut high = [max_signed, mask(u128::MAX, width)]
+        .into_iter()
+        .find(|x| quotient.eval(*x) != 0)?;
+    let mut low = 1;
+    while low < high {
+        let middle = low + (high - low) / 2;
+        if quotient.eval(middle) != 0 {
+            high = middle;
+        } else {
+            low = middle + 1;
+        }
+    }
+    Some(high)
+}
+
+// Values of `x` to compare a rewrite on before asking z3: around zero, the ends of
+// the signed and unsigned ranges and multiples of the divisor, and a spread of
+// others.
+fn samples(width: u32, divisor: u128) -> Vec<u128> {
+    let divisor = divisor.min(mask(divisor.wrapping_neg(), width));
+    let min_signed = 1 << (width - 1);
+    let mut samples = vec![0, 1, 2, 3, min_signed, min_signed + 1];
+    for multiple in [1, 2, 3, 1000, min_signed / divisor] {
+        let multiple = divisor.wrapping_mul(multiple);
+        samples.extend([multiple.wrapping_sub(1), multiple, multiple.wrapping_add(1)]);
+    }
+    let mut state: u64 = 0x2545f4914f6cdd1d;
+    for _ in 0..64 {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        samples.push(u128::from(state >> (state % 64)));
+    }
+    let negated: Vec<_> = samples.iter().map(|x| x.wrapping_neg()).collect();
+    samples.extend(negated);
+    samples.into_iter().map(|x| mask(x, width)).collect()
+}
+
+fn width(ty: &CType) -> Option<u32> {
+    match ty {
+        CType::Int(bytes) | CType::UInt(bytes) if *bytes <= 16 => Some(u32::from(*bytes) * 8),
+        _ => None,
+    }
+}
+
+fn mask(bits: u128, width: u32) -> u128 {
+    match width {
+        128 => bits,
+        _ => bits & ((1 << width) - 1),
+    }
+}
+
+fn sign_extend(bits: u128, width: u32) -> i128 {
+    ((bits << (128 - width)) as i128) >> (128 - width)
+}
+
+#[cfg(test)]
+mod tests {
+    use CType::{Int, UInt};
+    use my_cfg::Terminator;
+    use my_cfg::test_util::*;
+
+    use super::*;
+
+    fn divide(tys: impl IntoIterator<Item = CType>, stmts: Vec<Stmt>) -> Cfg {
+        let mut cfg = with_locals(tys);
+        cfg.arg_count = 1;
+        cfg.bb.alloc(block(stmts, Terminator::Return));
+        cfg
+    }
+
+    fn literal(i: i32) -> Value {
+        Value::Literal(i)
+    }
+
+    // What `_0` is assigned last, after the pass, which must not change the results.
+    fn recovered(cfg: &Cfg, args: &[i128]) -> String {
+        let mut recovered = cfg.clone();
+        recover_divisions(&mut recovered);
+        for x in args {
+            assert_eq!(run(&recovered, &[*x], 1), run(cfg, &[*x], 1), "{x}");
+        }
+        let stmts = &recovered.bb.iter().next().unwrap().1.stmts;
+        let Some(Stmt::Assign { value, .. }) = stmts.last() else {
+            unreachable!()
+        };
+        value.to_string()
+    }
+
+    // `x * magic >> shift` in `u32`s multiplied as `u64`s, and `x` minus that times
+    // 43.
+    fn unsigned_by_43(magic: u32) -> Cfg {
+        let tys = [UInt(4), UInt(4), UInt(4), UInt(8)];
+        let quotient = vec![
+            assign(2, literal(magic as i32)),
+            assign(3, local(1)),
+            assign(3, binop(local(3), Binop::Mul, local(2))),
+            assign(3, binop(local(3), Binop::Shr, literal(37))),
+            assign(2, local(3)),
+            assign(2, binop(local(2), Binop::Mul, literal(43))),
+            assign(0, binop(local(1), Binop::Sub, local(2))),
+        ];
+        divide(tys, quotient)
+    }
+
+    #[test]
+    fn test_unsigned() {
+        let cfg = unsigned_by_43(0xbe82fa0c);
+        assert_eq!(recovered(&cfg, &[0, 42, 43, 4294967279]), "_1 % 43");
+
+        // `x * 0xcccccccccccccccd >> 67` in `u64`s multiplied as `u128`s.
+        let tys = [UInt(8), UInt(8), UInt(8), UInt(4), UInt(16), UInt(16)];
+        let stmts = vec![
+            assign(2, literal(0xcccccccc_u32 as i32)),
+            assign(2, binop(local(2), Binop::Shl, literal(32))),
+            assign(3, literal(0xcccccccd_u32 as i32)),
+            assign(2, binop(local(2), Binop::Add, local(3))),
+            assign(4, local(1)),
+            assign(5, local(2)),
+            assign(4, binop(local(4), Binop::Mul, local(5))),
+            assign(0, binop(local(4), Binop::Shr, literal(67))),
+        ];
+        let cfg = divide(tys, stmts);
+        assert_eq!(recovered(&cfg, &[9, 10, u64::MAX.into()]), "_1 / 10");
+    }
+
+    #[test]
+    fn test_signed() {
+        // The high half of `x * -1840700269`, plus `x`, shifted by 2 and rounded
+        // towards zero by adding 1 if `x` is negative.
+        let tys = [Int(4), Int(4), Int(8), Int(4)];
+        let stmts = vec![
+            assign(2, local(1)),
+            assign(2, binop(local(2), Binop::Mul, literal(-1840700269))),
+            assign(2, binop(local(2), Binop::Sar, literal(32))),
+            assign(3, local(2)),
+            assign(3, binop(local(3), Binop::Add, local(1))),
+            assign(3, binop(local(3), Binop::Sar, literal(2))),
+            assign(
+                0,
+                binop(
+                    local(3),
+                    Binop::Sub,
+                    binop(local(1), Binop::Sar, literal(31)),
+                ),
+            ),
+        ];
+        let cfg = divide(tys, stmts);
+        let args = [-8, -7, -6, 6, 7, i32::MIN.into(), i32::MAX.into()];
+        assert_eq!(recovered(&cfg, &args), "_1 / 7");
+    }
+
+    #[test]
+    fn test_power_of_two() {
+        // 7 is added to negative numbers, so that they round towards zero.
+        let bias = binop(
+            binop(local(1), Binop::Sar, literal(31)),
+            Binop::Shr,
+            literal(29),
+        );
+        let shift = binop(binop(local(1), Binop::Add, bias), Binop::Sar, literal(3));
+        let cfg = divide([Int(4), Int(4)], vec![assign(0, shift)]);
+        assert_eq!(recovered(&cfg, &[-9, -8, -7, 7, 8]), "_1 / 8");
+
+        // Which is the same as a shift, and is kept as one.
+        let shift = binop(local(1), Binop::Shr, literal(3));
+        let cfg = divide([UInt(4), UInt(4)], vec![assign(0, shift)]);
+        assert_eq!(recovered(&cfg, &[7, 8]), "_1 >> 3");
+    }
+
+    #[test]
+    fn test_divided() {
+        // For every divisor at once: the quotient and the remainder are the only
+        // values that make up `x`.
+        for signed in [false, true] {
+            let [q, r, d, x] = ["q", "r", "d", "x"].map(|name| BV::fresh_const(name, 8));
+            let (sum, divides) = divided(&q, &r, &d, signed);
+            let (quotient, remainder) = match signed {
+                true => (x.bvsdiv(&d), x.bvsrem(&d)),
+                false => (x.bvudiv(&d), x.bvurem(&d)),
+            };
+            let all_ones = BV::from_i64(-1, 8);
+            let divisor = d.eq(0).not() & (d.eq(&all_ones) & Bool::from_bool(signed)).not();
+            let is_division = q.eq(&quotient) & r.eq(&remainder);
+            let differ = (divides & sum.extract(7, 0).eq(&x)).eq(is_division).not();
+            let outcome = Z3Solver::default().check(&Query::new([divisor, differ]));
+            assert!(matches!(outcome, Outcome::Unsat), "{signed}");
+        }
+    }
+
+    #[test]
+    fn test_near_magic() {
+        // One more than the magic number, which is only wrong for 4294967279.
+        let mut cfg = unsigned_by_43(0xbe82fa0d);
+        cfg.bb.iter_mut().next().unwrap().1.stmts.truncate(4);
+        let stmts = cfg.bb.iter().next().unwrap().1.stmts.clone();
+        let mut terms = Terms::new(&cfg);
+        stmts.iter().for_each(|stmt| terms.assign(&cfg, stmt));
+        let (input, term) = (terms.terms[1].clone(), terms.terms[3].clone());
+        let (input, term) = (input.unwrap(), term.unwrap());
+        let division = Division {
+            x: Idx::from_usize(1),
+            ty: UInt(4),
+            binop: Binop::Div,
+            divisor: 43,
+        };
+        let replacement = division.term(&input, &UInt(8));
+        // The values it is compared on before asking z3 miss it.
+        assert!(
+            samples(32, 43)
+                .into_iter()
+                .all(|x| replacement.eval(x) == term.eval(x))
+        );
+        assert_ne!(replacement.eval(4294967279), term.eval(4294967279));
+        let mut after = cfg.clone();
+        let divided = division.stmts(&mut after, &stmts[3]);
+        assert!(!proved(&after, &stmts[..3], &stmts[3], &division, &divided));
+
+        let mut recovered = cfg.clone();
+        recover_divisions(&mut recovered);
+        assert_eq!(format!("{recovered:?}"), format!("{cfg:?}"));
+    }
+}
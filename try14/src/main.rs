@@ -1,18 +1,190 @@
-use my_cfg::Cfg;
-use z3_of_cfg::z3_of_bb_stmts;
+use std::time::Duration;
 
-use crate::optimizations::{remove_unneeded_assigns, remove_unneeded_locals};
+use my_cfg::{
+    Cfg, emit_c, emit_c_harness, propagate_constants, recover_short_circuits,
+    remove_unreachable_blocks,
+};
+use z3_of_cfg::{Equivalence, MemoryConfig, Solver, SymbolicConfig, Z3Solver, check_equivalence};
 
+use crate::divisions::recover_divisions;
+use crate::optimizations::{
+    remove_unneeded_assigns, remove_unneeded_locals, simplify_expressions, synthesize_expressions,
+};
+
+mod divisions;
 mod optimizations;
 
+// How long the solver gets for each query. A pass it can't decide in time is undone
+// like any other that isn't proved.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
 fn main() {
     let mut cfg = Cfg::from_json(include_str!("../../../stable-mir-json/input.smir.json"));
     cfg.print();
-    remove_unneeded_assigns(&mut cfg);
-    remove_unneeded_locals(&mut cfg);
+    let passes: [(&str, fn(&mut Cfg)); 8] = [
+        ("recover_divisions", recover_divisions),
+        ("propagate_constants", propagate_constants),
+        ("remove_unreachable_blocks", remove_unreachable_blocks),
+        ("remove_unneeded_assigns", remove_unneeded_assigns),
+        ("remove_unneeded_locals", remove_unneeded_locals),
+        ("simplify_expressions", simplify_expressions),
+        ("synthesize_expressions", synthesize_expressions),
+        ("recover_short_circuits", recover_short_circuits),
+    ];
+    let solver = Z3Solver {
+        timeout: Some(TIMEOUT),
+    };
+    for (name, pass) in passes {
+        let before = cfg.clone();
+        match run_pass(&mut cfg, pass, &solver) {
+            Equivalence::Proved => println!("{name}: proved"),
+            Equivalence::Bounded(n) => println!("{name}: equivalent up to {n} iterations"),
+            Equivalence::Unknown(reason) => println!("{name}: unknown, {reason}, undone"),
+            Equivalence::Counterexample(counterexample) => {
+                // Saved as a regression test, with a C harness that replays it on the
+                // function before the pass.
+                if let Some(test) = &counterexample.test_case {
+                    std::fs::write(format!("{name}.test.json"), test.to_json()).unwrap();
+                    let harness = emit_c(&before, &before.loopify(), "sub")
+                        + &emit_c_harness(&before, "sub", test);
+                    std::fs::write(format!("{name}.test.c"), harness).unwrap();
+                }
+                panic!("{name} changed the function: {}", counterexample.model)
+            }
+        }
+    }
     cfg.print();
-    // for (_, bb) in cfg.bb.iter() {
-    //     z3_of_bb_stmts(bb, &cfg);
-    // }
-    dbg!(cfg.loopify());
+    println!("{}", emit_c(&cfg, &cfg.loopify(), "sub"));
+}
+
+// Runs `pass` on `cfg`. Passes are checked, not trusted: unless what it makes is
+// proved to compute the same, at least for as many iterations of the loops as they
+// are unrolled, `cfg` is left as it was.
+fn run_pass(cfg: &mut Cfg, pass: fn(&mut Cfg), solver: &dyn Solver) -> Equivalence {
+    let mut after = cfg.clone();
+    pass(&mut after);
+    let memory = MemoryConfig::default();
+    let config = SymbolicConfig::default();
+    let equivalence = check_equivalence(cfg, &after, config, &memory, &[], solver);
+    if let Equivalence::Proved | Equivalence::Bounded(_) = equivalence {
+        *cfg = after;
+    }
+    equivalence
+}
+
+#[cfg(test)]
+mod tests {
+    use my_cfg::test_util::*;
+    use my_cfg::{Binop, CType, Idx, Terminator, TestValue, Value};
+    use z3_of_cfg::{Outcome, Query};
+
+    use super::*;
+
+    // _0 = _1 >> 1
+    fn halve() -> Cfg {
+        let mut cfg = with_locals([CType::Int(4), CType::Int(4)]);
+        cfg.arg_count = 1;
+        let shift = binop(local(1), Binop::Sar, Value::Literal(1));
+        cfg.bb
+            .alloc(block(vec![assign(0, shift)], Terminator::Return));
+        cfg
+    }
+
+    // Turns shifts right by 1 into divisions by 2, which round the other way for
+    // negative numbers.
+    fn wrong_pass(cfg: &mut Cfg) {
+        let divide = binop(local(1), Binop::Div, Value::Literal(2));
+        cfg.bb.iter_mut().next().unwrap().1.stmts = vec![assign(0, divide)];
+    }
+
+    struct GivingUp;
+
+    impl Solver for GivingUp {
+        fn check(&self, _: &Query) -> Outcome {
+            Outcome::Unknown("timeout".to_string())
+        }
+    }
+
+    // Shift amounts are masked, so shifting by 33 is shifting by 1.
+    fn right_pass(cfg: &mut Cfg) {
+        let shift = binop(local(1), Binop::Sar, Value::Literal(33));
+        cfg.bb.iter_mut().next().unwrap().1.stmts = vec![assign(0, shift)];
+    }
+
+    fn statement(cfg: &Cfg) -> String {
+        format!("{:?}", cfg.bb.iter().next().unwrap().1.stmts)
+    }
+
+    #[test]
+    fn test_right_pass() {
+        let mut cfg = halve();
+        let result = run_pass(&mut cfg, right_pass, &Z3Solver::default());
+        assert!(matches!(result, Equivalence::Proved));
+        assert!(
+            statement(&cfg).contains("Literal(33)"),
+            "{}",
+            statement(&cfg)
+        );
+    }
+
+    #[test]
+    fn test_wrong_pass() {
+        let mut cfg = halve();
+        let Equivalence::Counterexample(counterexample) =
+            run_pass(&mut cfg, wrong_pass, &Z3Solver::default())
+        else {
+            panic!("the wrong pass wasn't caught");
+        };
+        assert_eq!(statement(&cfg), statement(&halve()));
+        // A negative odd number, which `>> 1` rounds down and `/ 2` towards zero.
+        let test = counterexample.test_case.unwrap();
+        let [TestValue::Int(x)] = test.args[..] else {
+            panic!("{test:?}");
+        };
+        let x = x as i32;
+        assert!(x < 0 && x % 2 != 0, "{x}");
+        assert_eq!(test.expected, Some(TestValue::Int((x >> 1).into())));
+    }
+
+    #[test]
+    fn test_bounded_is_kept() {
+        // bb0: _0 = 0; goto bb1
+        // bb1: if _1 != 0 { goto bb2 } else { goto bb3 }
+        // bb2: _0 = _0 + 2; _1 = _1 - 1; goto bb1
+        // bb3: return
+        let mut cfg = with_locals([CType::Int(4), CType::Int(4)]);
+        cfg.arg_count = 1;
+        let nonzero = binop(local(1), Binop::Ne, Value::Literal(0));
+        let decrement = binop(local(1), Binop::Sub, Value::Literal(1));
+        cfg.bb
+            .alloc(block(vec![assign(0, Value::Literal(0))], goto(1)));
+        cfg.bb.alloc(block(vec![], branch(nonzero, 2, 3)));
+        cfg.bb.alloc(block(
+            vec![
+                assign(0, binop(local(0), Binop::Add, Value::Literal(2))),
+                assign(1, decrement),
+            ],
+            goto(1),
+        ));
+        cfg.bb.alloc(block(vec![], Terminator::Return));
+        // The loop can go around more often than it is unrolled.
+        let commute = |cfg: &mut Cfg| {
+            let add = binop(Value::Literal(2), Binop::Add, local(0));
+            cfg.bb[Idx::from_usize(2)].stmts[0] = assign(0, add);
+        };
+        let result = run_pass(&mut cfg, commute, &Z3Solver::default());
+        let unroll = SymbolicConfig::default().unroll;
+        assert!(matches!(result, Equivalence::Bounded(n) if n == unroll));
+        assert!(format!("{cfg:?}").contains("Binop(Literal(2)"));
+    }
+
+    #[test]
+    fn test_unknown_is_undone() {
+        let mut cfg = halve();
+        for pass in [right_pass, wrong_pass] {
+            let result = run_pass(&mut cfg, pass, &GivingUp);
+            assert!(matches!(result, Equivalence::Unknown(_)));
+            assert_eq!(statement(&cfg), statement(&halve()));
+        }
+    }
 }
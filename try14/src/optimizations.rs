@@ -1,14 +1,21 @@
 use std::collections::{HashMap, HashSet};
+use std::path::Path;
 
-use my_cfg::{Arena, Cfg, Idx, Local, Place, Value};
+use my_cfg::egraph::{CostModel, simplify_values};
+use my_cfg::{Arena, Cfg, Idx, Local, Place, Stmt, Terminator, Value};
+use z3_of_cfg::{Grammar, Z3Solver, load_rules, synthesize};
 
 pub fn remove_unneeded_locals(cfg: &mut Cfg) {
     fn is_local_needed(cfg: &Cfg, local: Idx<Local>) -> bool {
+        // The return value and the arguments are part of the signature.
+        if local.to_usize() <= cfg.arg_count {
+            return true;
+        }
         let return_local = Idx::from_usize(0);
         let return_local = || my_cfg::Value::Place(Place::Local(return_local));
         cfg.bb.iter().any(|(_, bb)| {
             bb.stmts.iter().any(|stmt| match stmt {
-                my_cfg::Stmt::Assign { place, value } => {
+                my_cfg::Stmt::Assign { place, value, .. } => {
                     if place
                         .replace_local(local, return_local())
                         .as_place()
@@ -41,7 +48,7 @@ pub fn remove_unneeded_locals(cfg: &mut Cfg) {
         for (_, bb) in cfg.bb.iter_mut() {
             for stmt in &mut bb.stmts {
                 match stmt {
-                    my_cfg::Stmt::Assign { place, value } => {
+                    my_cfg::Stmt::Assign { place, value, .. } => {
                         *place = place
                             .replace_local(old_local, Value::from_local(new_local))
                             .as_place()
@@ -73,7 +80,7 @@ fn is_local_value_read_by_block(l: Idx<Local>, cfg: &Cfg) -> bool {
     cfg.bb.iter().any(|(_, bb)| {
         for stmt in &bb.stmts {
             match stmt {
-                my_cfg::Stmt::Assign { place, value } => {
+                my_cfg::Stmt::Assign { place, value, .. } => {
                     if value.has_local(l) {
                         return true;
                     }
@@ -109,6 +116,7 @@ pub fn remove_unneeded_assigns(cfg: &mut Cfg) {
                 my_cfg::Stmt::Assign {
                     place,
                     value: my_value,
+                    ..
                 } => {
                     let Some(l) = place.as_local() else {
                         continue;
@@ -120,7 +128,7 @@ pub fn remove_unneeded_assigns(cfg: &mut Cfg) {
                     let mut finished = false;
                     for rest in &mut bb.stmts[index + 1..] {
                         match rest {
-                            my_cfg::Stmt::Assign { place, value } => {
+                            my_cfg::Stmt::Assign { place, value, .. } => {
                                 if place.as_local() == Some(l) {
                                     finished = true;
                                     break;
@@ -154,3 +162,35 @@ pub fn remove_unneeded_assigns(cfg: &mut Cfg) {
         }
     }
 }
+
+/// Rewrites values into the most readable ones the rules of `rules/peephole.rules`,
+/// which are proven when they are loaded, make them equal to.
+pub fn simplify_expressions(cfg: &mut Cfg) {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("rules/peephole.rules");
+    let rules = load_rules(&path, &Z3Solver::default()).unwrap_or_else(|error| panic!("{error}"));
+    simplify_values(cfg, &rules, &CostModel::default());
+}
+
+/// Replaces values with the smallest ones z3 proves equal to them, which finds what
+/// no rule describes.
+pub fn synthesize_expressions(cfg: &mut Cfg) {
+    let (grammar, solver) = (Grammar::default(), Z3Solver::default());
+    let bbs: Vec<_> = cfg.bb.iter().map(|(bb, _)| bb).collect();
+    for bb in bbs {
+        let mut block = std::mem::take(&mut cfg.bb[bb]);
+        let values = block.stmts.iter_mut().map(|stmt| {
+            let Stmt::Assign { value, .. } = stmt;
+            value
+        });
+        let cond = match &mut block.terminator {
+            Some(Terminator::If { cond, .. }) => Some(cond),
+            _ => None,
+        };
+        for value in values.chain(cond) {
+            if let Some(smaller) = synthesize(value, cfg, &grammar, &solver) {
+                *value = smaller;
+            }
+        }
+        cfg.bb[bb] = block;
+    }
+}
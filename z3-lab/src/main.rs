@@ -1,8 +1,7 @@
-use z3::{Config, Context, Solver, ast::BV};
+use z3::ast::BV;
+use z3_of_cfg::{Outcome, Query, SmtLib2Solver, Solver, Z3Solver};
 
 fn main() {
-    let solver = Solver::new();
-
     // 32-bit unsigned variable
     let x = BV::fresh_const("x", 32);
 
@@ -21,20 +20,27 @@ fn main() {
     let div_true = x.bvudiv(&BV::from_u64(10, 32));
 
     // Check if any x violates the equality
-    solver.assert(div_magic32.eq(div_true).not());
-
-    dbg!(&solver);
-
-    // Solve
-    match solver.check() {
-        z3::SatResult::Sat => {
-            println!("Counterexample found: {}", solver.get_model().unwrap());
+    let query = Query::new([div_magic32.eq(div_true).not()]);
+
+    println!("{}", query.smtlib2());
+
+    // Solve, with another solver if `SMT_SOLVER` names one, like `cvc5 --lang smt2`
+    let solver: Box<dyn Solver> = match std::env::var("SMT_SOLVER") {
+        Ok(command) => Box::new(SmtLib2Solver {
+            command: Some(command.split_whitespace().map(String::from).collect()),
+            ..SmtLib2Solver::default()
+        }),
+        Err(_) => Box::new(Z3Solver::default()),
+    };
+    match solver.check(&query) {
+        Outcome::Sat(model) => {
+            println!("Counterexample found: {model}");
         }
-        z3::SatResult::Unsat => {
+        Outcome::Unsat => {
             println!("Optimization is correct for all 32-bit unsigned x");
         }
-        z3::SatResult::Unknown => {
-            println!("Solver returned unknown");
+        Outcome::Unknown(reason) => {
+            println!("Solver returned unknown: {reason}");
         }
     }
 }
@@ -0,0 +1,218 @@
+// Translation validation: instead of trusting a transformation of a `Cfg`, both
+// versions are run symbolically on the same inputs and z3 looks for inputs on which
+// they return something else or leave memory differently.
+//
+// Loops are unrolled as far as `SymbolicConfig` says, which only proves anything if
+// they can't go further, or cut at the headers of `Invariant`s. Each invariant is then
+// checked like an induction step: from any states in which it holds, both versions
+// get to the same next cut, where it holds again, or both return the same.
+
+use std::collections::HashSet;
+
+use my_cfg::{BasicBlock, CType, Cfg, Idx, TestCase};
+use z3::ast::{BV, Bool};
+
+use crate::memory::unknown_tag;
+use crate::solver::{Outcome, Query, Solver};
+use crate::symbolic::{Paths, Segment, execute};
+use crate::test_case::test_case;
+use crate::{Frame, Inputs, Memory, MemoryConfig, SymbolicConfig, Z3CfgState, and, bits, or};
+
+/// A loop of each version that goes around in lockstep with the other, and what
+/// holds between the two whenever both are at their header.
+pub struct Invariant<'a> {
+    pub before: Idx<BasicBlock>,
+    pub after: Idx<BasicBlock>,
+    /// Given the state of `before` and `after`. Their memory isn't known to be the
+    /// same unless it says so, with `Frame::same_memory`.
+    pub holds: Box<Relation<'a>>,
+}
+
+pub type Relation<'a> = dyn Fn(&Frame, &Frame) -> Bool + 'a;
+
+#[derive(Debug, Clone)]
+pub enum Equivalence {
+    Proved,
+    Counterexample(Counterexample),
+    /// Both do the same on every path that goes around loops at most this many
+    /// times, `SymbolicConfig::unroll`, but there are paths that go around more.
+    Bounded(usize),
+    /// Why it couldn't be decided either way.
+    Unknown(String),
+}
+
+/// Inputs on which the two versions differ.
+#[derive(Debug, Clone)]
+pub struct Counterexample {
+    /// The inputs, with what the version before the transformation does on them.
+    /// `None` if z3 can't read the model of an external solver.
+    pub test_case: Option<TestCase>,
+    /// All that the solver found.
+    pub model: String,
+}
+
+/// Checks that `after` computes what `before` does: the same return value and the
+/// same memory apart from the stack frame, for all inputs that `memory` allows. Both
+/// need the same signature. The queries go to `solver`.
+pub fn check_equivalence(
+    before: &Cfg,
+    after: &Cfg,
+    config: SymbolicConfig,
+    memory: &MemoryConfig,
+    invariants: &[Invariant],
+    solver: &dyn Solver,
+) -> Equivalence {
+    let signature = |cfg: &Cfg| {
+        cfg.locals
+            .iter()
+            .take(cfg.arg_count + 1)
+            .map(|(_, local)| local.ty.clone())
+            .collect::<Vec<_>>()
+    };
+    assert!(
+        before.arg_count == after.arg_count && signature(before) == signature(after),
+        "can only compare functions with the same signature"
+    );
+    let before_cuts: HashSet<_> = invariants
+        .iter()
+        .map(|invariant| invariant.before)
+        .collect();
+    let after_cuts: HashSet<_> = invariants.iter().map(|invariant| invariant.after).collect();
+
+    let inputs = Inputs::fresh(before, memory);
+    let entry = Idx::from_usize(0);
+    let a = execute(
+        Z3CfgState::new(before, &inputs),
+        entry,
+        &before_cuts,
+        config,
+    );
+    let b = execute(Z3CfgState::new(after, &inputs), entry, &after_cuts, config);
+    // Only returning differently from the same inputs is a counterexample, anything
+    // else could also be an invariant that is too weak.
+    if let (Some(a_returned), Some(b_returned)) = (&a.returned, &b.returned) {
+        let differ = same_outputs(a_returned, b_returned).not();
+        let query = Query::new([a_returned.cond.clone(), b_returned.cond.clone(), differ]);
+        match solver.check(&query) {
+            Outcome::Sat(model) => {
+                let text = model.to_string();
+                return Equivalence::Counterexample(Counterexample {
+                    test_case: model
+                        .into_z3(&query)
+                        .map(|model| test_case(&model, before, &inputs, &a_returned.state)),
+                    model: text,
+                });
+            }
+            Outcome::Unsat => {}
+            Outcome::Unknown(reason) => return Equivalence::Unknown(reason),
+        }
+    }
+    let mut complete = match check_segment(Bool::from_bool(true), &a, &b, invariants, solver) {
+        Ok(complete) => complete,
+        Err(reason) => {
+            return Equivalence::Unknown(format!("from the entry, {reason}"));
+        }
+    };
+
+    for invariant in invariants {
+        let (a_state, b_state) = (unknown(before, memory), unknown(after, memory));
+        let holds = (invariant.holds)(&a_state.frame(), &b_state.frame());
+        let a = execute(a_state, invariant.before, &before_cuts, config);
+        let b = execute(b_state, invariant.after, &after_cuts, config);
+        match check_segment(holds, &a, &b, invariants, solver) {
+            Ok(segment_complete) => complete &= segment_complete,
+            Err(reason) => {
+                return Equivalence::Unknown(format!(
+                    "from the invariant at bb{} and bb{}, {reason}",
+                    invariant.before.to_usize(),
+                    invariant.after.to_usize()
+                ));
+            }
+        }
+    }
+    if complete {
+        Equivalence::Proved
+    } else {
+        Equivalence::Bounded(config.unroll)
+    }
+}
+
+// A state about which nothing is known, like at a loop header with only the invariant
+// to go on.
+fn unknown<'a>(cfg: &'a Cfg, memory: &MemoryConfig) -> Z3CfgState<'a> {
+    let inputs = Inputs {
+        args: vec![],
+        arg_regions: vec![],
+        memory: Memory::fresh(memory, &cfg.layout),
+    };
+    let mut state = Z3CfgState::new(cfg, &inputs);
+    for ((_, local), (value, region)) in cfg
+        .locals
+        .iter()
+        .zip(state.locals.iter_mut().zip(&mut state.regions))
+    {
+        *value = BV::fresh_const("local", bits(&local.ty, &cfg.layout));
+        *region = unknown_tag();
+    }
+    state.memory.forget_tags();
+    state
+}
+
+fn same_outputs(a: &Paths, b: &Paths) -> Bool {
+    let (a_frame, b_frame) = (a.state.frame(), b.state.frame());
+    let same_memory = a_frame.same_memory(&b_frame);
+    match a.state.cfg.locals.iter().next() {
+        Some((_, local)) if local.ty != CType::Void => and(
+            &a_frame.locals[0].eq(b_frame.locals[0].clone()),
+            &same_memory,
+        ),
+        _ => same_memory,
+    }
+}
+
+// Checks that both versions leave the segment the same way from the states in which
+// `pre` holds: they return the same or get to the cuts of the same invariant, which
+// holds there. Whether they always get that far within the bound is the result, and
+// the way they can differ the error.
+fn check_segment(
+    pre: Bool,
+    a: &Segment,
+    b: &Segment,
+    invariants: &[Invariant],
+    solver: &dyn Solver,
+) -> Result<bool, String> {
+    let mut agree = together(a.returned.as_ref(), b.returned.as_ref(), same_outputs);
+    for invariant in invariants {
+        let stopped = together(
+            a.stopped.get(&invariant.before),
+            b.stopped.get(&invariant.after),
+            |a_paths, b_paths| (invariant.holds)(&a_paths.state.frame(), &b_paths.state.frame()),
+        );
+        agree = and(&agree, &stopped);
+    }
+    let done = and(&a.done(), &b.done());
+    match solver.check(&Query::new([pre.clone(), done.clone(), agree.not()])) {
+        Outcome::Sat(_) => return Err("the versions differ or an invariant doesn't hold".into()),
+        Outcome::Unsat => {}
+        Outcome::Unknown(reason) => return Err(reason),
+    }
+    match solver.check(&Query::new([pre, done.not()])) {
+        Outcome::Sat(_) => Ok(false),
+        Outcome::Unsat => Ok(true),
+        Outcome::Unknown(reason) => Err(reason),
+    }
+}
+
+// That both sets of paths are taken for the same inputs, and that `same` holds at
+// their ends when they are.
+fn together(
+    a: Option<&Paths>,
+    b: Option<&Paths>,
+    same: impl FnOnce(&Paths, &Paths) -> Bool,
+) -> Bool {
+    match (a, b) {
+        (Some(a), Some(b)) => and(&a.cond.eq(b.cond.clone()), &or(&a.cond.not(), &same(a, b))),
+        (Some(paths), None) | (None, Some(paths)) => paths.cond.not(),
+        (None, None) => Bool::from_bool(true),
+    }
+}
@@ -0,0 +1,200 @@
+// IEEE 754 floats. Like all values they are kept as bit-vectors, the way they are in
+// memory, and are only turned into z3's floating point sort to compute with, and back
+// with `fp.to_ieee_bv`. The semantics are those of `CType::Float`.
+
+use my_cfg::Binop;
+use z3::ast::{Ast, BV, Bool, Float, RoundingMode};
+use z3::{Context, Sort};
+use z3_sys::{Z3_ast, Z3_context, Z3_sort};
+
+use crate::and;
+
+// The exponent and significand bits of a float of `bits` bits.
+fn format(bits: u32) -> (u32, u32) {
+    match bits {
+        16 => (5, 11),
+        32 => (8, 24),
+        64 => (11, 53),
+        128 => (15, 113),
+        _ => panic!("no {bits}-bit floats"),
+    }
+}
+
+// The `z3` crate has no methods for the conversions, so they are made with the C API.
+// `make` gets the sort of floats with `bits` bits.
+fn convert<T: Ast>(bits: u32, make: impl FnOnce(Z3_context, Z3_sort) -> Option<Z3_ast>) -> T {
+    let (ebits, sbits) = format(bits);
+    let ctx = Context::thread_local();
+    let sort = Sort::float(ebits, sbits);
+    unsafe {
+        T::wrap(
+            &ctx,
+            make(ctx.get_z3_context(), sort.get_z3_sort()).unwrap(),
+        )
+    }
+}
+
+fn float(bits: &BV) -> Float {
+    convert(bits.get_size(), |ctx, sort| unsafe {
+        z3_sys::Z3_mk_fpa_to_fp_bv(ctx, bits.get_z3_ast(), sort)
+    })
+}
+
+fn nearest() -> RoundingMode {
+    RoundingMode::round_nearest_ties_to_even()
+}
+
+pub(crate) fn arithmetic(binop: Binop, l: &BV, r: &BV) -> BV {
+    let (l, r) = (float(l), float(r));
+    let result = match binop {
+        Binop::Add => nearest().add(&l, &r),
+        Binop::Sub => nearest().sub(&l, &r),
+        Binop::Mul => nearest().mul(&l, &r),
+        Binop::Div => nearest().div(&l, &r),
+        _ => panic!("no {binop} for floats"),
+    };
+    result.to_ieee_bv()
+}
+
+pub(crate) fn compare(binop: Binop, l: &BV, r: &BV) -> Bool {
+    let (l, r) = (float(l), float(r));
+    // Unlike equal bits, this is false for NaN and true for zeros of either sign.
+    let eq = || and(&l.le(&r), &r.le(&l));
+    match binop {
+        Binop::Lt => l.lt(&r),
+        Binop::Le => l.le(&r),
+        Binop::Eq => eq(),
+        Binop::Ne => eq().not(),
+        _ => panic!("{binop} doesn't compare"),
+    }
+}
+
+/// Whether the float isn't zero, which NaN isn't.
+pub(crate) fn is_nonzero(bits: &BV) -> Bool {
+    float(bits).is_zero().not()
+}
+
+pub(crate) fn resize(bits: &BV, to_bits: u32) -> BV {
+    let value = float(bits);
+    let resized: Float = convert(to_bits, |ctx, sort| unsafe {
+        z3_sys::Z3_mk_fpa_to_fp_float(ctx, nearest().get_z3_ast(), value.get_z3_ast(), sort)
+    });
+    resized.to_ieee_bv()
+}
+
+pub(crate) fn from_int(value: &BV, signed: bool, to_bits: u32) -> BV {
+    let converted: Float = convert(to_bits, |ctx, sort| unsafe {
+        let (rm, value) = (nearest().get_z3_ast(), value.get_z3_ast());
+        if signed {
+            z3_sys::Z3_mk_fpa_to_fp_signed(ctx, rm, value, sort)
+        } else {
+            z3_sys::Z3_mk_fpa_to_fp_unsigned(ctx, rm, value, sort)
+        }
+    });
+    converted.to_ieee_bv()
+}
+
+// Rounds towards zero and saturates, and NaN gives 0, like Rust's `as`. Outside of
+// the range of the integer type, z3 leaves the result unspecified, so the edges are
+// picked here.
+pub(crate) fn to_int(bits: &BV, signed: bool, to_bits: u32) -> BV {
+    let value = float(bits);
+    let zero = BV::from_u64(0, to_bits);
+    // `2^n` as an unsigned integer of `width` bits.
+    let power = |n: u32, width: u32| BV::from_u64(1, width).bvshl(BV::from_u64(n.into(), width));
+    let (min, max) = if signed {
+        let min = power(to_bits - 1, to_bits);
+        (min.clone(), min.bvnot())
+    } else {
+        (zero.clone(), BV::from_i64(-1, to_bits))
+    };
+    // The bounds are powers of two, which floats hold exactly.
+    let min_float = from_int(&min, signed, bits.get_size());
+    let limit = power(to_bits - u32::from(signed), to_bits + 1);
+    let limit = float(&from_int(&limit, false, bits.get_size()));
+    let ctx = Context::thread_local();
+    let truncated = unsafe {
+        let rtz = RoundingMode::round_towards_zero();
+        let (rtz, value) = (rtz.get_z3_ast(), value.get_z3_ast());
+        let truncated = if signed {
+            z3_sys::Z3_mk_fpa_to_sbv(ctx.get_z3_context(), rtz, value, to_bits)
+        } else {
+            z3_sys::Z3_mk_fpa_to_ubv(ctx.get_z3_context(), rtz, value, to_bits)
+        };
+        BV::wrap(&ctx, truncated.unwrap())
+    };
+    value.is_nan().ite(
+        &zero,
+        &limit
+            .le(&value)
+            .ite(&max, &value.lt(float(&min_float)).ite(&min, &truncated)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use z3::ast::Ast;
+
+    use super::*;
+
+    fn f64(value: f64) -> BV {
+        BV::from_u64(value.to_bits(), 64)
+    }
+
+    fn bits(bv: &BV) -> u64 {
+        bv.simplify().as_u64().unwrap()
+    }
+
+    fn holds(bool: &Bool) -> bool {
+        bool.simplify().as_bool().unwrap()
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        let sum = arithmetic(Binop::Add, &f64(0.1), &f64(0.2));
+        assert_eq!(bits(&sum), (0.1f64 + 0.2).to_bits());
+        assert_ne!(bits(&sum), 0.3f64.to_bits());
+        let quotient = arithmetic(Binop::Div, &f64(1.0), &f64(-0.0));
+        assert_eq!(bits(&quotient), f64::NEG_INFINITY.to_bits());
+        // Ties go to the even significand: 1 + 2^-53 is halfway to the next double.
+        let tie = arithmetic(Binop::Add, &f64(1.0), &f64(2f64.powi(-53)));
+        assert_eq!(bits(&tie), 1f64.to_bits());
+    }
+
+    #[test]
+    fn test_compare() {
+        let nan = f64(f64::NAN);
+        assert!(!holds(&compare(Binop::Eq, &nan, &nan)));
+        assert!(holds(&compare(Binop::Ne, &nan, &nan)));
+        assert!(!holds(&compare(Binop::Le, &nan, &f64(1.0))));
+        assert!(holds(&compare(Binop::Eq, &f64(-0.0), &f64(0.0))));
+        assert!(holds(&compare(Binop::Lt, &f64(-1.0), &f64(-0.0))));
+        assert!(!holds(&is_nonzero(&f64(-0.0))));
+        assert!(holds(&is_nonzero(&nan)));
+    }
+
+    #[test]
+    fn test_conversions() {
+        // Like Rust's `as`.
+        for value in [-1.9, 2.5, 1e20, -1e20, f64::NAN, f64::INFINITY, -0.0] {
+            assert_eq!(
+                bits(&to_int(&f64(value), true, 32)),
+                value as i32 as u32 as u64
+            );
+            assert_eq!(bits(&to_int(&f64(value), false, 8)), value as u8 as u64);
+        }
+        assert_eq!(bits(&to_int(&f64(-1e20), true, 64)), i64::MIN as u64);
+        assert_eq!(bits(&to_int(&f64(1e20), false, 64)), u64::MAX);
+
+        let max = BV::from_u64(u64::MAX, 64);
+        assert_eq!(
+            bits(&from_int(&max, false, 32)),
+            u64::from((u64::MAX as f32).to_bits())
+        );
+        assert_eq!(bits(&from_int(&max, true, 64)), (-1f64).to_bits());
+        let third = arithmetic(Binop::Div, &f64(1.0), &f64(3.0));
+        assert_eq!(bits(&resize(&third, 32)), u64::from((1f32 / 3.0).to_bits()));
+        let third = resize(&resize(&third, 128), 64);
+        assert_eq!(bits(&third), (1f64 / 3.0).to_bits());
+    }
+}
@@ -1,117 +1,499 @@
-use std::collections::HashMap;
+use my_cfg::{BasicBlock, Binop, CType, Cfg, DataLayout, Idx, Place, Stmt, Value};
+use z3::ast::{BV, Bool};
 
-use my_cfg::{BasicBlock, Cfg, Idx, Local, Place, Stmt, Value};
-use z3::ast::{Array, BV};
+pub use crate::equivalence::{Counterexample, Equivalence, Invariant, Relation, check_equivalence};
+pub use crate::memory::{Access, Memory, MemoryConfig, Region};
+pub use crate::rules::{load_rules, prove_rule};
+pub use crate::solver::{Cached, Model, Outcome, Query, SmtLib2Solver, Solver, Z3Solver};
+pub use crate::symbolic::{Branch, Summary, SymbolicConfig, z3_of_function};
+pub use crate::synthesis::{Grammar, synthesize};
 
-struct Z3CfgState {
-    cfg: Cfg,
-    local_addrs: HashMap<Idx<Local>, i64>,
-    memory: Array,
-}
+mod equivalence;
+mod float;
+mod memory;
+mod rules;
+mod solver;
+mod symbolic;
+mod synthesis;
+mod test_case;
 
-fn z3_new_general_memory() -> Array {
-    let memory = Array::fresh_const("memory", &z3::Sort::bitvector(64), &z3::Sort::bitvector(8));
-    memory
+/// The symbolic inputs of a function: its arguments and the memory it's called with.
+#[derive(Debug, Clone)]
+pub struct Inputs {
+    /// The values of `_1` to `_arg_count`.
+    pub args: Vec<BV>,
+    /// The region each argument points into, if it is a pointer.
+    pub arg_regions: Vec<Region>,
+    pub memory: Memory,
 }
 
-impl Z3CfgState {
-    fn new(cfg: Cfg) -> Self {
-        let mut addr = 1000;
-        let mut local_addrs = HashMap::new();
-        for (l, data) in cfg.locals.iter() {
-            local_addrs.insert(l, addr);
-            addr += data.ty.size() as i64;
+impl Inputs {
+    /// Unconstrained inputs, named after the locals they are the values of.
+    pub fn fresh(cfg: &Cfg, config: &MemoryConfig) -> Self {
+        let args: Vec<_> = cfg.locals.iter().skip(1).take(cfg.arg_count).collect();
+        Self {
+            args: args
+                .iter()
+                .map(|(idx, local)| {
+                    BV::new_const(format!("_{}", idx.to_usize()), bits(&local.ty, &cfg.layout))
+                })
+                .collect(),
+            arg_regions: args.iter().map(|(idx, _)| config.region(*idx)).collect(),
+            memory: Memory::fresh(config, &cfg.layout),
         }
+    }
+}
+
+/// What running code does, as a function of its `Inputs`.
+#[derive(Debug, Clone)]
+pub struct Effect {
+    /// Memory once the code has run.
+    pub memory: Memory,
+    /// The value of `_0` at the end, `None` if the function returns `void`.
+    pub return_value: Option<BV>,
+}
+
+/// The state of a function at some point.
+#[derive(Debug, Clone)]
+pub struct Frame {
+    /// The values of the locals, by index.
+    pub locals: Vec<BV>,
+    /// The tag of the region each local points into, see `Region::tag`.
+    pub regions: Vec<BV>,
+    pub memory: Memory,
+}
+
+impl Frame {
+    /// That both have the same memory, apart from their stack frames.
+    pub fn same_memory(&self, other: &Frame) -> Bool {
+        self.memory.same_as(&other.memory)
+    }
+}
+
+#[derive(Clone)]
+struct Z3CfgState<'a> {
+    cfg: &'a Cfg,
+    locals: Vec<BV>,
+    // The tag of the region each local points into, or that the pointer it was made
+    // from did, `Caller` for the locals that never were pointers.
+    regions: Vec<BV>,
+    memory: Memory,
+}
+
+impl<'a> Z3CfgState<'a> {
+    // Locals that aren't arguments start out as constants named after them.
+    fn new(cfg: &'a Cfg, inputs: &Inputs) -> Self {
+        let (locals, regions) = cfg
+            .locals
+            .iter()
+            .map(|(idx, local)| match idx.to_usize().checked_sub(1) {
+                Some(arg) if arg < inputs.args.len() => {
+                    (inputs.args[arg].clone(), inputs.arg_regions[arg].tag())
+                }
+                _ => (
+                    BV::new_const(format!("_{}", idx.to_usize()), bits(&local.ty, &cfg.layout)),
+                    Region::Caller.tag(),
+                ),
+            })
+            .unzip();
         Self {
             cfg,
-            local_addrs,
-            memory: z3_new_general_memory(),
+            locals,
+            regions,
+            memory: inputs.memory.clone(),
+        }
+    }
+
+    // `then` where `cond` holds and `else_` where it doesn't.
+    fn ite(cond: &Bool, then: &Self, else_: &Self) -> Self {
+        let ite = |then: &[BV], else_: &[BV]| {
+            then.iter()
+                .zip(else_)
+                .map(|(then, else_)| cond.ite(then, else_))
+                .collect()
+        };
+        Self {
+            cfg: then.cfg,
+            locals: ite(&then.locals, &else_.locals),
+            regions: ite(&then.regions, &else_.regions),
+            memory: Memory::ite(cond, &then.memory, &else_.memory),
+        }
+    }
+
+    fn effect(&self) -> Effect {
+        let (_, local) = self.cfg.locals.iter().next().unwrap();
+        Effect {
+            memory: self.memory.clone(),
+            return_value: (local.ty != CType::Void).then(|| self.locals[0].clone()),
         }
     }
 
-    fn read_memory(&self, addr: BV, size_bytes: u32) -> BV {
-        let mut r = self.memory.select(&addr).as_bv().unwrap();
-        for i in 1..size_bytes {
-            r = r.concat(self.memory.select(&addr.bvadd(i)).as_bv().unwrap());
+    fn frame(&self) -> Frame {
+        Frame {
+            locals: self.locals.clone(),
+            regions: self.regions.clone(),
+            memory: self.memory.clone(),
         }
-        r
     }
 
-    fn write_memory(&mut self, addr: BV, value: BV, size_bytes: u32) {
-        for i in 0..size_bytes {
-            self.memory = self
-                .memory
-                .store(&addr.bvadd(i), &value.extract(i * 8 + 7, i * 8));
+    fn read_place(&self, place: &Place) -> BV {
+        match place {
+            Place::Local(idx) => self.locals[idx.to_usize()].clone(),
+            Place::Deref(inner) => {
+                let (addr, region) = self.pointer(inner);
+                let size = place.ty(self.cfg).size(&self.cfg.layout);
+                self.memory.read(&region, &addr, size.into())
+            }
+            Place::Offset(..) => self.pointer(place).0,
+        }
+    }
+
+    // Writes `value`, which points into `region` if it is a pointer.
+    fn write_place(&mut self, place: &Place, value: BV, region: BV) {
+        match place {
+            Place::Local(idx) => {
+                self.locals[idx.to_usize()] = value;
+                self.regions[idx.to_usize()] = region;
+            }
+            Place::Deref(inner) => {
+                let (addr, tag) = self.pointer(inner);
+                let size = place.ty(self.cfg).size(&self.cfg.layout);
+                self.memory.write(&tag, &addr, &value, &region, size.into());
+            }
+            Place::Offset(..) => panic!("`Place::Offset` is a pointer, not a place in memory"),
         }
     }
 
-    fn z3_of_place_addr(&self, place: &Place) -> BV {
+    // The address in a place of pointer type, and the tag of the region it points into.
+    fn pointer(&self, place: &Place) -> (BV, BV) {
         match place {
-            Place::Local(idx) => BV::from_i64(self.local_addrs[idx], 64),
-            Place::Deref(place) => self.read_memory(self.z3_of_place_addr(place), 8),
-            Place::Offset(place, value) => {
-                let size_l = place.ty(&self.cfg).size() as u32 * 8;
-                let size_r = value.ty(&self.cfg).size() as u32 * 8;
-                let size = size_l.max(size_r);
-                self.z3_of_place_addr(place)
-                    .sign_ext(size - size_l)
-                    .bvadd(self.z3_of_value(value).sign_ext(size - size_r))
+            Place::Local(idx) => (
+                self.locals[idx.to_usize()].clone(),
+                self.regions[idx.to_usize()].clone(),
+            ),
+            Place::Deref(inner) => {
+                let (addr, region) = self.pointer(inner);
+                (self.read_place(place), self.memory.read_tag(&region, &addr))
+            }
+            // Not a place in memory but the pointer `base + offset`.
+            Place::Offset(base, offset) => {
+                let CType::Ptr(element) = place.ty(self.cfg) else {
+                    panic!("Invalid offset of non pointer type");
+                };
+                let (addr, region) = self.pointer(base);
+                let layout = &self.cfg.layout;
+                let offset = self.z3_of_value(offset, &layout.intptr());
+                let size = BV::from_u64(element.size(layout).into(), offset.get_size());
+                let addr = addr.bvadd(offset.bvmul(size));
+                (addr, region)
             }
         }
     }
 
+    // The tag of the region `value` points into, if it is a pointer or was made from
+    // one. Arithmetic keeps the region of its first operand that isn't a literal, the
+    // same one `ty` takes the type of.
+    fn region(&self, value: &Value) -> BV {
+        match value {
+            Value::Place(place) => self.pointer(place).1,
+            Value::Binop(_, binop, _) if is_condition(*binop) => Region::Caller.tag(),
+            Value::Binop(l, _, r) => match **l {
+                Value::Literal(_) => self.region(r),
+                _ => self.region(l),
+            },
+            Value::Select(cond, then, else_) => self
+                .z3_of_condition(cond)
+                .ite(&self.region(then), &self.region(else_)),
+            Value::Literal(_) => Region::Caller.tag(),
+        }
+    }
+
     fn z3_of_stmt(&mut self, stmt: &Stmt) {
         match stmt {
-            Stmt::Assign { place, value } => {
-                let size = place.ty(&self.cfg).size();
-                let addr = self.z3_of_place_addr(place);
-                let value = self.z3_of_value(value);
-                self.write_memory(addr, value, size as u32);
+            Stmt::Assign { place, value, .. } => {
+                let region = self.region(value);
+                let value = self.z3_of_value(value, &place.ty(self.cfg));
+                self.write_place(place, value, region);
             }
         }
     }
 
-    fn z3_of_value(&self, value: &Value) -> BV {
-        let r = match value {
-            Value::Place(place) => {
-                let size = place.ty(&self.cfg).size();
-                let addr = self.z3_of_place_addr(place);
-                self.read_memory(addr, size as _)
-            }
-            Value::Literal(i) => BV::from_i64(*i as i64, 32),
-            Value::Binop(l, binop, r) => {
-                let size_l = l.ty(&self.cfg).size() as u32 * 8;
-                let size_r = r.ty(&self.cfg).size() as u32 * 8;
+    // The type a value is computed in, the same as in the emitted Rust: comparisons
+    // are `Bool`, and arithmetic is done in the type of its first operand that isn't
+    // a literal.
+    fn ty(&self, value: &Value) -> CType {
+        match value {
+            Value::Place(place) => place.ty(self.cfg),
+            Value::Literal(_) => CType::Int(4),
+            Value::Binop(_, binop, _) if is_condition(*binop) => CType::Bool,
+            Value::Binop(l, _, r) => match self.operand_ty(l, r) {
+                CType::Bool => CType::Int(4),
+                ty => ty,
+            },
+            Value::Select(_, then, else_) => self.operand_ty(then, else_),
+        }
+    }
 
-                let mut l = self.z3_of_value(l);
-                let mut r = self.z3_of_value(r);
+    fn operand_ty(&self, l: &Value, r: &Value) -> CType {
+        match l {
+            Value::Literal(_) => self.ty(r),
+            _ => self.ty(l),
+        }
+    }
 
-                if size_l > size_r {
-                    r = r.sign_ext(size_l - size_r);
+    /// `value` converted to `ty`.
+    fn z3_of_value(&self, value: &Value, ty: &CType) -> BV {
+        match value {
+            Value::Literal(i) => self.convert(BV::from_i64((*i).into(), 32), &CType::Int(4), ty),
+            Value::Place(place) => self.convert(self.read_place(place), &place.ty(self.cfg), ty),
+            Value::Binop(_, binop, _) if is_condition(*binop) => {
+                self.convert(from_bool(&self.z3_of_condition(value)), &CType::Bool, ty)
+            }
+            Value::Select(cond, then, else_) => {
+                let operand_ty = self.ty(value);
+                let r = self.z3_of_condition(cond).ite(
+                    &self.z3_of_value(then, &operand_ty),
+                    &self.z3_of_value(else_, &operand_ty),
+                );
+                self.convert(r, &operand_ty, ty)
+            }
+            Value::Binop(l, binop, r) => {
+                let operand_ty = self.ty(value);
+                let l = self.z3_of_value(l, &operand_ty);
+                let r = self.z3_of_value(r, &operand_ty);
+                if let CType::Float(_) = operand_ty {
+                    return self.convert(float::arithmetic(*binop, &l, &r), &operand_ty, ty);
                 }
-                if size_r > size_l {
-                    l = l.sign_ext(size_r - size_l);
+                if !matches!(operand_ty, CType::Int(_) | CType::UInt(_)) {
+                    panic!("no {binop} for {operand_ty} in z3");
                 }
+                let signed = matches!(operand_ty, CType::Int(_));
+                let r = match binop {
+                    Binop::Add => l.bvadd(r),
+                    Binop::Sub => l.bvsub(r),
+                    Binop::Mul => l.bvmul(r),
+                    // Dividing by zero gives all ones.
+                    Binop::Div => {
+                        let quotient = if signed { l.bvsdiv(&r) } else { l.bvudiv(&r) };
+                        r.eq(BV::from_i64(0, l.get_size()))
+                            .ite(&BV::from_i64(-1, l.get_size()), &quotient)
+                    }
+                    // The remainder of dividing by zero is the left side, which is
+                    // what SMT-LIB defines too.
+                    Binop::Rem if signed => l.bvsrem(&r),
+                    Binop::Rem => l.bvurem(&r),
+                    Binop::Shl => l.bvshl(shift_amount(&l, r)),
+                    Binop::Shr => l.bvlshr(shift_amount(&l, r)),
+                    Binop::Sar => l.bvashr(shift_amount(&l, r)),
+                    Binop::Xor => l.bvxor(r),
+                    _ => unreachable!(),
+                };
+                self.convert(r, &operand_ty, ty)
+            }
+        }
+    }
 
+    /// Whether `value` holds, that is isn't zero.
+    fn z3_of_condition(&self, value: &Value) -> Bool {
+        match value {
+            Value::Binop(l, binop @ (Binop::And | Binop::Or), r) => {
+                let (l, r) = (self.z3_of_condition(l), self.z3_of_condition(r));
                 match binop {
-                    my_cfg::Binop::Add => l.bvadd(r),
-                    my_cfg::Binop::Sub => l.bvsub(r),
-                    my_cfg::Binop::Mul => l.bvmul(r),
-                    my_cfg::Binop::Div => l.bvsdiv(r),
-                    my_cfg::Binop::Lt => todo!(),
-                    my_cfg::Binop::Le => todo!(),
+                    Binop::And => and(&l, &r),
+                    _ => or(&l, &r),
                 }
             }
-        };
-        dbg!(&r);
-        BV::fresh_const("value", r.get_size())
+            Value::Binop(l, binop, r) if is_condition(*binop) => {
+                let operand_ty = self.operand_ty(l, r);
+                let signed = matches!(operand_ty, CType::Int(_));
+                let l = self.z3_of_value(l, &operand_ty);
+                let r = self.z3_of_value(r, &operand_ty);
+                if let CType::Float(_) = operand_ty {
+                    return float::compare(*binop, &l, &r);
+                }
+                match (binop, signed) {
+                    (Binop::Lt, true) => l.bvslt(&r),
+                    (Binop::Lt, false) => l.bvult(&r),
+                    (Binop::Le, true) => l.bvsle(&r),
+                    (Binop::Le, false) => l.bvule(&r),
+                    (Binop::Eq, _) => l.eq(r),
+                    _ => l.eq(r).not(),
+                }
+            }
+            _ => {
+                let ty = self.ty(value);
+                let value = self.z3_of_value(value, &ty);
+                if let CType::Float(_) = ty {
+                    return float::is_nonzero(&value);
+                }
+                value.eq(BV::from_i64(0, value.get_size())).not()
+            }
+        }
+    }
+
+    // Converts like a C cast or an assignment would.
+    fn convert(&self, value: BV, from: &CType, to: &CType) -> BV {
+        if from == to {
+            return value;
+        }
+        let (from_bits, to_bits) = (value.get_size(), bits(to, &self.cfg.layout));
+        // Pointers convert to and from floats like unsigned integers.
+        let signed = |ty: &CType| matches!(ty, CType::Int(_));
+        match (from, to) {
+            (CType::Float(_), CType::Float(_)) => return float::resize(&value, to_bits),
+            (CType::Float(_), CType::Bool) => return from_bool(&float::is_nonzero(&value)),
+            (CType::Float(_), _) => return float::to_int(&value, signed(to), to_bits),
+            (_, CType::Float(_)) => return float::from_int(&value, signed(from), to_bits),
+            _ => {}
+        }
+        if *to == CType::Bool {
+            return from_bool(&value.eq(BV::from_i64(0, value.get_size())).not());
+        }
+        if to_bits <= from_bits {
+            value.extract(to_bits - 1, 0)
+        } else if matches!(from, CType::Int(_)) {
+            value.sign_ext(to_bits - from_bits)
+        } else {
+            value.zero_ext(to_bits - from_bits)
+        }
+    }
+}
+
+fn bits(ty: &CType, layout: &DataLayout) -> u32 {
+    u32::from(ty.size(layout)) * 8
+}
+
+fn is_condition(binop: Binop) -> bool {
+    matches!(
+        binop,
+        Binop::Lt | Binop::Le | Binop::Eq | Binop::Ne | Binop::And | Binop::Or
+    )
+}
+
+fn and(a: &Bool, b: &Bool) -> Bool {
+    a.ite(b, &Bool::from_bool(false))
+}
+
+fn or(a: &Bool, b: &Bool) -> Bool {
+    a.ite(&Bool::from_bool(true), b)
+}
+
+fn from_bool(b: &Bool) -> BV {
+    b.ite(&BV::from_i64(1, 8), &BV::from_i64(0, 8))
+}
+
+/// The effect of the statements of `bb`, started with `inputs` as the arguments and
+/// memory.
+pub fn z3_of_bb(cfg: &Cfg, bb: Idx<BasicBlock>, inputs: &Inputs) -> Effect {
+    let mut this = Z3CfgState::new(cfg, inputs);
+    for x in &cfg.bb[bb].stmts {
+        this.z3_of_stmt(x);
     }
+    this.effect()
 }
 
-pub fn z3_of_bb_stmts(bb: &BasicBlock, cfg: &Cfg) {
-    let mut this = Z3CfgState::new(cfg.clone());
-    for x in &bb.stmts {
+/// The state after running `stmts`, started with `inputs` as the arguments and memory
+/// and the other locals as constants named after them, like `_5`. Running other
+/// statements from the same inputs gives states to compare with.
+pub fn z3_of_stmts(cfg: &Cfg, stmts: &[Stmt], inputs: &Inputs) -> Frame {
+    let mut this = Z3CfgState::new(cfg, inputs);
+    for x in stmts {
         this.z3_of_stmt(x);
     }
-    dbg!(this.memory);
+    this.frame()
+}
+
+// Only the low bits of a shift amount are used, as many as it takes to count the bits
+// of the shifted value.
+fn shift_amount(l: &BV, r: BV) -> BV {
+    r.bvand(BV::from_u64(u64::from(l.get_size()) - 1, l.get_size()))
+}
+
+#[cfg(test)]
+mod tests {
+    use my_cfg::Terminator;
+    use my_cfg::test_util::*;
+    use z3::ast::Ast;
+
+    use super::*;
+    use CType::{Int, UInt};
+
+    const EDGES: [i128; 12] = [0, 1, 2, 7, 15, 17, 100, 255, -1, -2, -128, i32::MIN as i128];
+
+    // The value of `_0` after `bb0` of `cfg`, on concrete arguments.
+    fn concrete(cfg: &Cfg, args: &[i128]) -> u64 {
+        let args = args
+            .iter()
+            .zip(cfg.locals.iter().skip(1))
+            .map(|(arg, (_, local))| BV::from_i64(*arg as i64, bits(&local.ty, &cfg.layout)))
+            .collect();
+        let inputs = Inputs {
+            args,
+            arg_regions: vec![Region::Caller; cfg.arg_count],
+            memory: Memory::fresh(&MemoryConfig::default(), &cfg.layout),
+        };
+        let effect = z3_of_bb(cfg, Idx::from_usize(0), &inputs);
+        effect.return_value.unwrap().simplify().as_u64().unwrap()
+    }
+
+    #[test]
+    fn test_values_match_the_ir() {
+        let cases = [
+            ([Int(4), UInt(1), UInt(1)], Binop::Mul),
+            ([Int(4), Int(2), Int(2)], Binop::Sar),
+            ([Int(4), Int(2), Int(2)], Binop::Shr),
+            ([Int(4), Int(2), Int(2)], Binop::Shl),
+            ([Int(4), Int(4), Int(4)], Binop::Div),
+            ([Int(4), Int(4), Int(4)], Binop::Rem),
+            ([UInt(4), UInt(4), UInt(4)], Binop::Div),
+            ([Int(4), Int(1), UInt(4)], Binop::Lt),
+            ([Int(4), UInt(4), Int(1)], Binop::Le),
+            ([Int(8), Int(1), UInt(1)], Binop::Sub),
+            ([Int(4), Int(2), UInt(1)], Binop::Xor),
+        ];
+        let cases = cases.map(|(tys, op)| (tys, binop(local(1), op, local(2))));
+        // The smaller one, compared and chosen as an `int8_t` like `_1`.
+        let less = binop(local(1), Binop::Lt, local(2));
+        let min = Value::Select(Box::new(less), Box::new(local(1)), Box::new(local(2)));
+        for (tys, value) in cases.into_iter().chain([([Int(8), Int(1), UInt(2)], min)]) {
+            let mut cfg = with_locals(tys);
+            cfg.arg_count = 2;
+            let name = format!("{value:?}");
+            cfg.bb
+                .alloc(block(vec![assign(0, value)], Terminator::Return));
+            let mask = u64::MAX >> (64 - bits(&cfg.locals[Idx::from_usize(0)].ty, &cfg.layout));
+            for l in EDGES {
+                for r in EDGES {
+                    let expected = run(&cfg, &[l, r], 1).unwrap() as u64 & mask;
+                    assert_eq!(concrete(&cfg, &[l, r]), expected, "{name} on {l}, {r}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_symbolic_values() {
+        let mut cfg = with_locals([CType::Int(4), CType::Int(4)]);
+        cfg.arg_count = 1;
+        let inputs = Inputs::fresh(&cfg, &MemoryConfig::default());
+        let value = |value| {
+            let frame = z3_of_stmts(&cfg, &[assign(0, value)], &inputs);
+            frame.locals[0].clone()
+        };
+        let shl = value(binop(local(1), Binop::Shl, Value::Literal(1)));
+        let mul = value(binop(local(1), Binop::Mul, Value::Literal(2)));
+        assert!(matches!(
+            Z3Solver::default().check(&Query::new([shl.ne(&mul)])),
+            Outcome::Unsat
+        ));
+        // Shifting rounds down and dividing towards zero, which differ for -1.
+        let sar = value(binop(local(1), Binop::Sar, Value::Literal(1)));
+        let div = value(binop(local(1), Binop::Div, Value::Literal(2)));
+        let Outcome::Sat(Model::Z3(model)) = Z3Solver::default().check(&Query::new([sar.ne(&div)]))
+        else {
+            panic!("`>> 1` and `/ 2` are the same");
+        };
+        let x = model.eval(&inputs.args[0], true).unwrap().as_u64().unwrap() as i32;
+        assert!(x < 0 && x % 2 != 0, "{x}");
+    }
 }
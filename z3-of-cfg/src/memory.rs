@@ -0,0 +1,539 @@
+// The memory model. Locals aren't in memory: nothing can point to them, so they are
+// plain bit-vectors. Memory is split into regions that can't overlap, each an array
+// of its own: what the caller provides, the stack frame of the function, and what
+// each `restrict` argument points to. Pointers carry the region they point into as a
+// tag, which is only symbolic where paths from different regions merge. So do the
+// integers made from them, and the bytes of memory they are stored in, so that a
+// pointer that is spilled or turned into an integer and back still has its region.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use my_cfg::{CType, Cfg, DataLayout, Endian, Idx, Local};
+use z3::ast::{Array, Ast, BV, Bool};
+
+const TAG_BITS: u32 = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Region {
+    /// Memory the caller provides that isn't in another region. Pointers the caller
+    /// left in memory and ones made from integers that didn't come from a pointer
+    /// point here.
+    Caller,
+    /// The stack frame of the function, see `MemoryConfig::frame`.
+    Frame,
+    /// What the `n`th of `MemoryConfig::restrict` points to.
+    Restricted(usize),
+}
+
+impl Region {
+    pub fn tag(self) -> BV {
+        let tag = match self {
+            Region::Caller => 0,
+            Region::Frame => 1,
+            Region::Restricted(n) => 2 + n as u64,
+        };
+        BV::from_u64(tag, TAG_BITS)
+    }
+}
+
+// The tag of a pointer that could point into any region.
+pub(crate) fn unknown_tag() -> BV {
+    BV::fresh_const("region", TAG_BITS)
+}
+
+/// Which memory the pointer arguments point into. By default it is all one region
+/// and any two pointers can overlap.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryConfig {
+    /// Pointer arguments that nothing else points into, like `restrict` in C. Each
+    /// gets a region of its own.
+    pub restrict: Vec<Idx<Local>>,
+    /// The pointer argument to the stack frame of the function, like the stack
+    /// pointer of lifted code. What is left there isn't part of the effect.
+    pub frame: Option<Idx<Local>>,
+}
+
+impl MemoryConfig {
+    /// Assumes that no two pointer arguments overlap.
+    pub fn restrict_all(cfg: &Cfg) -> Self {
+        Self {
+            restrict: (1..=cfg.arg_count)
+                .map(Idx::from_usize)
+                .filter(|idx| matches!(cfg.locals[*idx].ty, CType::Ptr(_)))
+                .collect(),
+            frame: None,
+        }
+    }
+
+    pub fn region(&self, arg: Idx<Local>) -> Region {
+        if self.frame == Some(arg) {
+            return Region::Frame;
+        }
+        match self.restrict.iter().position(|idx| *idx == arg) {
+            Some(n) => Region::Restricted(n),
+            None => Region::Caller,
+        }
+    }
+}
+
+/// All memory, by region, as arrays of bytes indexed by addresses as wide as a
+/// pointer.
+#[derive(Debug, Clone)]
+pub struct Memory {
+    pub caller: Array,
+    pub frame: Array,
+    /// By the position of the argument in `MemoryConfig::restrict`.
+    pub restricted: Vec<Array>,
+    /// The byte order of values wider than a byte.
+    pub endian: Endian,
+    // The tag each byte was stored with, by the tag of its region followed by its
+    // address. Not part of what the caller sees, so `same_as` ignores it.
+    tags: Array,
+    // Shared by the clones, so that all memory that is run on the same inputs
+    // records its accesses together.
+    accesses: Rc<RefCell<Vec<Access>>>,
+}
+
+/// Where memory was read or written, as a function of the inputs.
+#[derive(Debug, Clone)]
+pub struct Access {
+    /// The tag of the region, see `Region::tag`.
+    pub tag: BV,
+    pub addr: BV,
+    pub size_bytes: u32,
+}
+
+impl Memory {
+    /// Unconstrained memory of a target with `layout`.
+    pub fn fresh(config: &MemoryConfig, layout: &DataLayout) -> Self {
+        let addr = z3::Sort::bitvector(u32::from(layout.pointer_size) * 8);
+        let array = |prefix| Array::fresh_const(prefix, &addr, &z3::Sort::bitvector(8));
+        let tagged = z3::Sort::bitvector(TAG_BITS + u32::from(layout.pointer_size) * 8);
+        Self {
+            caller: array("memory"),
+            frame: array("frame"),
+            restricted: config
+                .restrict
+                .iter()
+                .map(|_| array("restricted"))
+                .collect(),
+            endian: layout.endian,
+            tags: Array::const_array(&tagged, &Region::Caller.tag()),
+            accesses: Rc::default(),
+        }
+    }
+
+    /// That the memory the caller can see is the same, which is all but the frame.
+    pub fn same_as(&self, other: &Memory) -> Bool {
+        self.restricted
+            .iter()
+            .zip(&other.restricted)
+            .fold(self.caller.eq(other.caller.clone()), |same, (a, b)| {
+                crate::and(&same, &a.eq(b.clone()))
+            })
+    }
+
+    pub(crate) fn ite(cond: &Bool, then: &Memory, else_: &Memory) -> Memory {
+        Memory {
+            caller: cond.ite(&then.caller, &else_.caller),
+            frame: cond.ite(&then.frame, &else_.frame),
+            restricted: then
+                .restricted
+                .iter()
+                .zip(&else_.restricted)
+                .map(|(then, else_)| cond.ite(then, else_))
+                .collect(),
+            endian: then.endian,
+            tags: cond.ite(&then.tags, &else_.tags),
+            accesses: then.accesses.clone(),
+        }
+    }
+
+    /// Every access to this memory or memory that came from it by running code,
+    /// whichever path it was on.
+    pub fn accesses(&self) -> Vec<Access> {
+        self.accesses.borrow().clone()
+    }
+
+    fn record(&self, tag: &BV, addr: &BV, size_bytes: u32) {
+        self.accesses.borrow_mut().push(Access {
+            tag: tag.clone(),
+            addr: addr.clone(),
+            size_bytes,
+        });
+    }
+
+    /// The array of the region with the tag `tag`.
+    pub fn array(&self, tag: u64) -> &Array {
+        self.regions()
+            .find(|(region, _)| region.tag().as_u64() == Some(tag))
+            .map_or(&self.caller, |(_, array)| array)
+    }
+
+    fn regions(&self) -> impl Iterator<Item = (Region, &Array)> {
+        [(Region::Caller, &self.caller), (Region::Frame, &self.frame)]
+            .into_iter()
+            .chain(
+                self.restricted
+                    .iter()
+                    .enumerate()
+                    .map(|(n, array)| (Region::Restricted(n), array)),
+            )
+    }
+
+    // The region `tag` stands for, if it is known. Unknown tags point to `Caller`.
+    fn known(&self, tag: &BV) -> Option<Region> {
+        let tag = tag.simplify().as_u64()?;
+        Some(
+            self.regions()
+                .map(|(region, _)| region)
+                .find(|region| region.tag().as_u64() == Some(tag))
+                .unwrap_or(Region::Caller),
+        )
+    }
+
+    pub(crate) fn read(&self, tag: &BV, addr: &BV, size_bytes: u32) -> BV {
+        self.record(tag, addr, size_bytes);
+        if let Some(known) = self.known(tag) {
+            let (_, array) = self.regions().find(|(region, _)| *region == known).unwrap();
+            return read_array(array, addr, size_bytes, self.endian);
+        }
+        self.regions().skip(1).fold(
+            read_array(&self.caller, addr, size_bytes, self.endian),
+            |value, (region, array)| {
+                region
+                    .tag()
+                    .eq(tag.clone())
+                    .ite(&read_array(array, addr, size_bytes, self.endian), &value)
+            },
+        )
+    }
+
+    /// Writes `value`, which points into the region with the tag `value_tag` if it is
+    /// a pointer.
+    pub(crate) fn write(
+        &mut self,
+        tag: &BV,
+        addr: &BV,
+        value: &BV,
+        value_tag: &BV,
+        size_bytes: u32,
+    ) {
+        self.record(tag, addr, size_bytes);
+        self.write_tag(tag, addr, value_tag, size_bytes);
+        let known = self.known(tag);
+        let elsewhere = self
+            .regions()
+            .skip(1)
+            .fold(Bool::from_bool(false), |elsewhere, (region, _)| {
+                crate::or(&elsewhere, &region.tag().eq(tag.clone()))
+            });
+        let endian = self.endian;
+        for (region, array) in self.regions_mut() {
+            *array = match known {
+                Some(known) if known == region => {
+                    write_array(array, addr, value, size_bytes, endian)
+                }
+                Some(_) => continue,
+                None if region == Region::Caller => {
+                    elsewhere.ite(array, &write_array(array, addr, value, size_bytes, endian))
+                }
+                None => region
+                    .tag()
+                    .eq(tag.clone())
+                    .ite(&write_array(array, addr, value, size_bytes, endian), array),
+            };
+        }
+    }
+
+    /// Forgets which regions the pointers in memory point into, like at a loop header
+    /// with only an invariant to go on.
+    pub(crate) fn forget_tags(&mut self) {
+        let tagged = self.tags.get_sort().array_domain().unwrap();
+        self.tags = Array::fresh_const("tags", &tagged, &z3::Sort::bitvector(TAG_BITS));
+    }
+
+    /// The tag of the region the pointer at `addr` in the region with the tag `tag`
+    /// points into.
+    pub(crate) fn read_tag(&self, tag: &BV, addr: &BV) -> BV {
+        self.tags
+            .select(&self.canonical(tag).concat(addr))
+            .as_bv()
+            .unwrap()
+    }
+
+    // Writes the tag of a value stored at `addr` into each of its bytes.
+    fn write_tag(&mut self, tag: &BV, addr: &BV, value_tag: &BV, size_bytes: u32) {
+        let tag = self.canonical(tag);
+        self.tags = (0..size_bytes).fold(self.tags.clone(), |tags, i| {
+            tags.store(&tag.concat(addr.bvadd(i)), value_tag)
+        });
+    }
+
+    // `tag`, or the tag of `Caller` if it isn't the tag of a region.
+    fn canonical(&self, tag: &BV) -> BV {
+        if let Some(known) = self.known(tag) {
+            return known.tag();
+        }
+        self.regions()
+            .skip(1)
+            .fold(Region::Caller.tag(), |canonical, (region, _)| {
+                region.tag().eq(tag.clone()).ite(&region.tag(), &canonical)
+            })
+    }
+
+    fn regions_mut(&mut self) -> impl Iterator<Item = (Region, &mut Array)> {
+        [
+            (Region::Caller, &mut self.caller),
+            (Region::Frame, &mut self.frame),
+        ]
+        .into_iter()
+        .chain(
+            self.restricted
+                .iter_mut()
+                .enumerate()
+                .map(|(n, array)| (Region::Restricted(n), array)),
+        )
+    }
+}
+
+// The address of the byte with bits `8 * i` and up of a value at `addr`.
+fn byte_addr(addr: &BV, i: u32, size_bytes: u32, endian: Endian) -> BV {
+    match endian {
+        Endian::Little => addr.bvadd(i),
+        Endian::Big => addr.bvadd(size_bytes - 1 - i),
+    }
+}
+
+fn read_array(array: &Array, addr: &BV, size_bytes: u32, endian: Endian) -> BV {
+    let byte = |i| {
+        array
+            .select(&byte_addr(addr, i, size_bytes, endian))
+            .as_bv()
+            .unwrap()
+    };
+    (1..size_bytes).fold(byte(0), |r, i| byte(i).concat(r))
+}
+
+fn write_array(array: &Array, addr: &BV, value: &BV, size_bytes: u32, endian: Endian) -> Array {
+    (0..size_bytes).fold(array.clone(), |array, i| {
+        array.store(
+            &byte_addr(addr, i, size_bytes, endian),
+            &value.extract(i * 8 + 7, i * 8),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use my_cfg::test_util::*;
+    use my_cfg::{Binop, Place, Stmt, Terminator, Value};
+
+    use super::*;
+    use crate::{
+        Equivalence, Inputs, Model, Outcome, Query, Solver, SymbolicConfig, Z3Solver,
+        check_equivalence, z3_of_function,
+    };
+
+    fn int_ptr() -> CType {
+        CType::Ptr(Box::new(CType::Int(4)))
+    }
+
+    // *_i = value
+    fn store(i: usize, value: Value) -> Stmt {
+        Stmt::Assign {
+            place: Place::Deref(Box::new(place(i))),
+            value,
+            origin: None,
+        }
+    }
+
+    fn load(i: usize) -> Value {
+        Value::Place(Place::Deref(Box::new(place(i))))
+    }
+
+    // *_1 = 1; *_2 = 2; _0 = *_1
+    fn store_twice() -> Cfg {
+        let mut cfg = with_locals([CType::Int(4), int_ptr(), int_ptr()]);
+        cfg.arg_count = 2;
+        cfg.bb.alloc(block(
+            vec![
+                store(1, Value::Literal(1)),
+                store(2, Value::Literal(2)),
+                assign(0, load(1)),
+            ],
+            Terminator::Return,
+        ));
+        cfg
+    }
+
+    // Whether `_0` can be anything but `value`, and the inputs if so.
+    fn other_than(cfg: &Cfg, memory: &MemoryConfig, value: i64) -> Option<(Inputs, Model)> {
+        let inputs = Inputs::fresh(cfg, memory);
+        let summary = z3_of_function(cfg, &inputs, SymbolicConfig::default());
+        let returned = summary.effect.return_value.unwrap();
+        match Z3Solver::default().check(&Query::new([returned.ne(BV::from_i64(value, 32))])) {
+            Outcome::Sat(model) => Some((inputs, model)),
+            Outcome::Unsat => None,
+            Outcome::Unknown(reason) => panic!("{reason}"),
+        }
+    }
+
+    #[test]
+    fn test_restrict() {
+        let cfg = store_twice();
+        // By default the pointers can overlap, and then the second store changes
+        // some of the bytes of the first.
+        let Some((inputs, Model::Z3(model))) = other_than(&cfg, &MemoryConfig::default(), 1) else {
+            panic!("the stores can't overlap");
+        };
+        let addr = |i: usize| model.eval(&inputs.args[i], true).unwrap().as_u64().unwrap();
+        let distance = addr(1).wrapping_sub(addr(0)).wrapping_add(3);
+        assert!(distance < 7, "{} and {}", addr(0), addr(1));
+        assert!(other_than(&cfg, &MemoryConfig::restrict_all(&cfg), 1).is_none());
+    }
+
+    #[test]
+    fn test_merged_regions() {
+        // bb0: if _3 < 0 { goto bb1 } else { goto bb2 }
+        // bb1: _4 = _1; goto bb3
+        // bb2: _4 = _2; goto bb3
+        // bb3: *_1 = 1; *_4 = 2; _0 = *_1; return
+        //
+        // `_4` points into either region, so the second store overwrites the first
+        // only where it points to what `_1` does.
+        let mut cfg = with_locals([
+            CType::Int(4),
+            int_ptr(),
+            int_ptr(),
+            CType::Int(4),
+            int_ptr(),
+        ]);
+        cfg.arg_count = 3;
+        let negative = binop(local(3), Binop::Lt, Value::Literal(0));
+        cfg.bb.alloc(block(vec![], branch(negative, 1, 2)));
+        cfg.bb.alloc(block(vec![assign(4, local(1))], goto(3)));
+        cfg.bb.alloc(block(vec![assign(4, local(2))], goto(3)));
+        cfg.bb.alloc(block(
+            vec![
+                store(1, Value::Literal(1)),
+                store(4, Value::Literal(2)),
+                assign(0, load(1)),
+            ],
+            Terminator::Return,
+        ));
+        let memory = MemoryConfig {
+            restrict: vec![Idx::from_usize(1), Idx::from_usize(2)],
+            frame: None,
+        };
+        let Some((inputs, Model::Z3(model))) = other_than(&cfg, &memory, 1) else {
+            panic!("the second store is never to `_1`");
+        };
+        let cond = model.eval(&inputs.args[2], true).unwrap().as_u64().unwrap();
+        assert!(cond as i32 <= 0, "{cond}");
+        assert!(other_than(&cfg, &memory, 2).is_some());
+    }
+
+    #[test]
+    fn test_spilled_pointers() {
+        // *_4 = 1; *_2 = 2; _0 = *_1, where `_4` is `_1` once it has been stored
+        // through `_3` and loaded back, or turned into an integer and back.
+        let int_ptr_ptr = CType::Ptr(Box::new(int_ptr()));
+        let mut cfg = with_locals([
+            CType::Int(4),
+            int_ptr(),
+            int_ptr(),
+            int_ptr_ptr,
+            int_ptr(),
+            CType::UInt(8),
+        ]);
+        cfg.arg_count = 3;
+        let spills = [
+            vec![store(3, local(1)), assign(4, load(3))],
+            vec![
+                assign(5, local(1)),
+                assign(5, binop(local(5), Binop::Add, Value::Literal(0))),
+                assign(4, local(5)),
+            ],
+        ];
+        let memory = MemoryConfig {
+            restrict: vec![Idx::from_usize(1), Idx::from_usize(2)],
+            frame: None,
+        };
+        for spill in spills {
+            let mut cfg = cfg.clone();
+            let stmts = [store(4, Value::Literal(1)), store(2, Value::Literal(2))];
+            let stmts = spill.into_iter().chain(stmts);
+            let stmts = stmts.chain([assign(0, load(1))]).collect();
+            cfg.bb.alloc(block(stmts, Terminator::Return));
+            // `_4` still points to what `_1` does, which `_2` doesn't.
+            assert!(other_than(&cfg, &memory, 1).is_none(), "{cfg:?}");
+        }
+    }
+
+    #[test]
+    fn test_frame() {
+        // *_1 = 5; _0 = *_2, and the same without the store.
+        let mut before = with_locals([CType::Int(4), int_ptr(), int_ptr()]);
+        before.arg_count = 2;
+        let mut after = before.clone();
+        before.bb.alloc(block(
+            vec![store(1, Value::Literal(5)), assign(0, load(2))],
+            Terminator::Return,
+        ));
+        after
+            .bb
+            .alloc(block(vec![assign(0, load(2))], Terminator::Return));
+        let check = |memory: &MemoryConfig| {
+            let config = SymbolicConfig::default();
+            check_equivalence(&before, &after, config, memory, &[], &Z3Solver::default())
+        };
+        // What is left in the stack frame isn't part of the effect, and the memory of
+        // the caller is another region.
+        let frame = MemoryConfig {
+            frame: Some(Idx::from_usize(1)),
+            ..Default::default()
+        };
+        assert!(matches!(check(&frame), Equivalence::Proved));
+        let caller = MemoryConfig::default();
+        assert!(matches!(check(&caller), Equivalence::Counterexample(_)));
+    }
+
+    #[test]
+    fn test_layout() {
+        // _2 = _1 as *mut u8; *_1 = 0x01020304; _0 = *_2
+        let byte_ptr = CType::Ptr(Box::new(CType::UInt(1)));
+        let mut cfg = with_locals([CType::UInt(1), int_ptr(), byte_ptr]);
+        cfg.arg_count = 1;
+        cfg.bb.alloc(block(
+            vec![
+                assign(2, local(1)),
+                store(1, Value::Literal(0x01020304)),
+                assign(0, load(2)),
+            ],
+            Terminator::Return,
+        ));
+        for (layout, first_byte) in [
+            (DataLayout::RV64, 4),
+            (DataLayout::RV32, 4),
+            (
+                DataLayout {
+                    endian: Endian::Big,
+                    ..DataLayout::RV32
+                },
+                1,
+            ),
+        ] {
+            cfg.layout = layout;
+            let inputs = Inputs::fresh(&cfg, &MemoryConfig::default());
+            assert_eq!(
+                inputs.args[0].get_size(),
+                u32::from(layout.pointer_size) * 8
+            );
+            let summary = z3_of_function(&cfg, &inputs, SymbolicConfig::default());
+            let returned = summary.effect.return_value.unwrap().simplify();
+            assert_eq!(returned.as_u64(), Some(first_byte), "{layout:?}");
+        }
+    }
+}
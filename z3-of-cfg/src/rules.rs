@@ -0,0 +1,85 @@
+// Proofs of the rewrite rules of `my_cfg::rules`. Each side is run as an assignment
+// in a function whose arguments are the metavariables, once for every type they can
+// have, through the same encoding as everything else.
+
+use std::path::Path;
+
+use my_cfg::ranges::value_ty;
+use my_cfg::rules::{Rule, TypeClass};
+use my_cfg::{CType, Cfg, Idx, Local, Place, Stmt, Value};
+
+use crate::{Inputs, MemoryConfig, Outcome, Query, Solver, z3_of_stmts};
+
+/// Checks that `rule` holds for every type its metavariables can have.
+pub fn prove_rule(rule: &Rule, solver: &dyn Solver) -> Result<(), String> {
+    let instantiations = rule.instantiations();
+    if instantiations.is_empty() {
+        return Err(format!("{}: no types meet the conditions", rule.name));
+    }
+    for types in instantiations {
+        // `_0` is assigned a side, the metavariables are `_1` and on. Constants are
+        // `int`s, which `Rule` makes sure only show up where a literal would behave
+        // the same.
+        let mut cfg = Cfg::default();
+        cfg.locals.alloc(Local {
+            name: None,
+            ty: CType::Void,
+        });
+        for (var, ty) in rule.vars.iter().zip(&types) {
+            cfg.locals.alloc(Local {
+                name: Some(var.name.clone()),
+                ty: ty.clone(),
+            });
+        }
+        cfg.arg_count = types.len();
+        let vars: Vec<_> = (1..=types.len())
+            .map(|i| Value::from_local(Idx::from_usize(i)))
+            .collect();
+        let (lhs, rhs) = (rule.lhs.instantiate(&vars), rule.rhs.instantiate(&vars));
+        let instance = || {
+            let vars = rule.vars.iter().zip(&types);
+            let vars = vars.filter(|(var, _)| var.class != TypeClass::Const);
+            let vars: Vec<_> = vars
+                .map(|(var, ty)| format!("{}: {ty}", var.name))
+                .collect();
+            format!("{} for {}", rule.name, vars.join(", "))
+        };
+        let ty = value_ty(&lhs, &cfg);
+        if value_ty(&rhs, &cfg) != ty {
+            return Err(format!(
+                "{}: the left side is {ty} and the right one {}",
+                instance(),
+                value_ty(&rhs, &cfg)
+            ));
+        }
+        cfg.locals[Idx::from_usize(0)].ty = ty;
+        let inputs = Inputs::fresh(&cfg, &MemoryConfig::default());
+        let run = |value| {
+            let stmt = Stmt::Assign {
+                place: Place::Local(Idx::from_usize(0)),
+                value,
+                origin: None,
+            };
+            z3_of_stmts(&cfg, &[stmt], &inputs).locals.swap_remove(0)
+        };
+        let (lhs, rhs) = (run(lhs), run(rhs));
+        match solver.check(&Query::new([lhs.eq(rhs).not()])) {
+            Outcome::Unsat => {}
+            Outcome::Sat(model) => return Err(format!("{} doesn't hold: {model}", instance())),
+            Outcome::Unknown(reason) => return Err(format!("{}: unknown, {reason}", instance())),
+        }
+    }
+    Ok(())
+}
+
+/// Reads the rules of a file, proving each of them.
+pub fn load_rules(path: &Path, solver: &dyn Solver) -> Result<Vec<Rule>, String> {
+    let text =
+        std::fs::read_to_string(path).map_err(|error| format!("{}: {error}", path.display()))?;
+    let rules = Rule::parse_all(&text).map_err(|error| format!("{}: {error}", path.display()))?;
+    for rule in &rules {
+        prove_rule(rule, solver)
+            .map_err(|error| format!("{}: line {}: {error}", path.display(), rule.line))?;
+    }
+    Ok(rules)
+}
@@ -0,0 +1,574 @@
+// Where queries are decided. The encoding builds z3 terms into a `Query`, which can
+// be checked by z3 itself or written out as a standalone SMT-LIB2 script, to rerun by
+// hand or to pipe to another solver.
+
+use std::fmt::{self, Debug, Display};
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+use z3::SatResult;
+use z3::ast::{BV, Bool};
+
+use crate::test_case::eval;
+
+/// The answer to a query.
+#[derive(Debug)]
+pub enum Outcome {
+    Sat(Model),
+    Unsat,
+    /// Why the solver couldn't tell, like running out of time.
+    Unknown(String),
+}
+
+/// Values for the constants that make the assertions hold.
+#[derive(Debug)]
+pub enum Model {
+    Z3(z3::Model),
+    /// As an external solver printed them: the names of the constants and their
+    /// values as SMT-LIB2 terms.
+    SmtLib2(Vec<(String, String)>),
+}
+
+impl Display for Model {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Model::Z3(model) => write!(f, "{model}"),
+            Model::SmtLib2(values) => {
+                for (name, value) in values {
+                    writeln!(f, "{name} -> {value}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Model {
+    /// The value of the bit-vector constant `name`, of `bits` bits, if the model
+    /// has one.
+    pub fn bits(&self, name: &str, bits: u32) -> Option<u128> {
+        match self {
+            Model::Z3(model) => Some(eval(model, &BV::new_const(name, bits))),
+            Model::SmtLib2(values) => {
+                let (_, value) = values.iter().find(|(known, _)| known == name)?;
+                if let Some(hex) = value.strip_prefix("#x") {
+                    u128::from_str_radix(hex, 16).ok()
+                } else {
+                    u128::from_str_radix(value.strip_prefix("#b")?, 2).ok()
+                }
+            }
+        }
+    }
+}
+
+impl Model {
+    /// The model as one of z3, to evaluate terms over the constants of `query`, which
+    /// it makes hold. `None` if z3 can't read the values of an external solver or
+    /// they don't make `query` hold.
+    pub fn into_z3(self, query: &Query) -> Option<z3::Model> {
+        let values = match self {
+            Model::Z3(model) => return Some(model),
+            Model::SmtLib2(values) => values,
+        };
+        // z3 parses the values along with the declarations of their constants, and
+        // then only has to evaluate the assertions.
+        let solver = z3::Solver::new();
+        let mut script = query.declarations();
+        for (name, value) in &values {
+            script += &format!("(assert (= |{name}| {value}))\n");
+        }
+        solver.from_string(script);
+        // It stops at what it can't parse.
+        if solver.get_assertions().len() != query.assertions.len() + values.len() {
+            return None;
+        }
+        match solver.check() {
+            SatResult::Sat => solver.get_model(),
+            _ => None,
+        }
+    }
+
+    /// The constants and their values, as SMT-LIB2 terms.
+    pub fn values(&self) -> Vec<(String, String)> {
+        match self {
+            Model::Z3(model) => model
+                .iter()
+                .filter(|decl| decl.arity() == 0)
+                .filter_map(|decl| {
+                    let value = model.eval(&decl.apply(&[]), true)?;
+                    Some((decl.name(), value.to_string()))
+                })
+                .collect(),
+            Model::SmtLib2(values) => values.clone(),
+        }
+    }
+}
+
+/// Assertions for a solver to decide whether they can all hold together. Each
+/// solver takes what it needs: z3 the terms, the others the SMT-LIB2 script.
+#[derive(Debug, Clone)]
+pub struct Query {
+    assertions: Vec<Bool>,
+}
+
+impl Query {
+    pub fn new(assertions: impl IntoIterator<Item = Bool>) -> Self {
+        Query {
+            assertions: assertions.into_iter().collect(),
+        }
+    }
+
+    pub fn assertions(&self) -> &[Bool] {
+        &self.assertions
+    }
+
+    /// The query as a standalone SMT-LIB2 script that prints the model if there is
+    /// one.
+    pub fn smtlib2(&self) -> String {
+        let declarations = self.declarations();
+        format!("(set-option :produce-models true)\n{declarations}(check-sat)\n(get-model)\n")
+    }
+
+    // The declarations of the constants in the assertions, followed by the
+    // assertions.
+    fn declarations(&self) -> String {
+        let solver = z3::Solver::new();
+        for assertion in &self.assertions {
+            solver.assert(assertion);
+        }
+        solver.to_string()
+    }
+}
+
+/// Decides queries.
+pub trait Solver {
+    fn check(&self, query: &Query) -> Outcome;
+}
+
+/// Checks with z3, through its API.
+#[derive(Debug, Clone, Default)]
+pub struct Z3Solver {
+    pub timeout: Option<Duration>,
+}
+
+impl Solver for Z3Solver {
+    fn check(&self, query: &Query) -> Outcome {
+        let solver = z3::Solver::new();
+        if let Some(timeout) = self.timeout {
+            let mut params = z3::Params::new();
+            params.set_u32("timeout", millis(timeout));
+            solver.set_params(&params);
+        }
+        for assertion in &query.assertions {
+            solver.assert(assertion);
+        }
+        match solver.check() {
+            SatResult::Sat => Outcome::Sat(Model::Z3(solver.get_model().unwrap())),
+            SatResult::Unsat => Outcome::Unsat,
+            SatResult::Unknown => Outcome::Unknown(
+                solver
+                    .get_reason_unknown()
+                    .unwrap_or_else(|| "z3 gave up".into()),
+            ),
+        }
+    }
+}
+
+/// Writes each query as an SMT-LIB2 script and runs a solver on it, like `cvc5` or
+/// `bitwuzla`. Without a solver the answer is always `Unknown`, which still leaves
+/// the scripts to look at.
+#[derive(Debug, Clone, Default)]
+pub struct SmtLib2Solver {
+    /// The program and its arguments. It gets the script on its standard input and
+    /// has to print `sat`, `unsat` or `unknown`, and after `sat` the model.
+    pub command: Option<Vec<String>>,
+    /// Where to write the scripts, each named after the hash of its contents.
+    pub dump: Option<PathBuf>,
+    /// How long the solver may run before it is killed.
+    pub timeout: Option<Duration>,
+}
+
+impl Solver for SmtLib2Solver {
+    fn check(&self, query: &Query) -> Outcome {
+        let script = query.smtlib2();
+        if let Some(dump) = &self.dump {
+            let path = dump.join(format!("{:016x}.smt2", fnv1a(&script)));
+            if let Err(error) = std::fs::write(&path, &script) {
+                return Outcome::Unknown(format!("can't write {}: {error}", path.display()));
+            }
+        }
+        let Some((program, args)) = self
+            .command
+            .as_ref()
+            .and_then(|command| command.split_first())
+        else {
+            return Outcome::Unknown("no solver to run".into());
+        };
+        match run(program, args, &script, self.timeout) {
+            Ok(output) => parse_output(&output),
+            Err(reason) => Outcome::Unknown(format!("{program}: {reason}")),
+        }
+    }
+}
+
+// The standard output of `program`, run on `input`, if it exits within `timeout`.
+fn run(
+    program: &str,
+    args: &[String],
+    input: &str,
+    timeout: Option<Duration>,
+) -> Result<String, String> {
+    let start = Instant::now();
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|error| error.to_string())?;
+    // Read and write on the side, so that neither the solver nor this blocks on a
+    // full pipe while the solver is waited for.
+    let mut stdout = child.stdout.take().unwrap();
+    let reader = std::thread::spawn(move || {
+        let mut output = String::new();
+        stdout.read_to_string(&mut output).map(|_| output)
+    });
+    let mut stdin = child.stdin.take().unwrap();
+    let input = input.to_string();
+    let writer = std::thread::spawn(move || stdin.write_all(input.as_bytes()));
+    let timed_out = loop {
+        match child.try_wait().map_err(|error| error.to_string())? {
+            Some(_) => break false,
+            None if timeout.is_some_and(|timeout| start.elapsed() > timeout) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                break true;
+            }
+            None => std::thread::sleep(Duration::from_millis(10)),
+        }
+    };
+    // Both end once the solver is gone and the pipes are closed.
+    let written = writer.join().unwrap();
+    let output = reader.join().unwrap();
+    if timed_out {
+        return Err("timed out".into());
+    }
+    written.map_err(|error| error.to_string())?;
+    output.map_err(|error| error.to_string())
+}
+
+fn parse_output(output: &str) -> Outcome {
+    let output = output.trim_start();
+    let (answer, rest) = output.split_once('\n').unwrap_or((output, ""));
+    match answer.trim() {
+        "sat" => match parse_model(rest) {
+            Some(values) => Outcome::Sat(Model::SmtLib2(values)),
+            None => Outcome::Unknown(format!("can't read the model: {rest}")),
+        },
+        "unsat" => Outcome::Unsat,
+        _ => Outcome::Unknown(output.trim().to_string()),
+    }
+}
+
+// Reads the `define-fun`s of constants in a model printed by `get-model`. Functions
+// with parameters, which the queries don't have, are left out.
+fn parse_model(model: &str) -> Option<Vec<(String, String)>> {
+    let Sexp::List(items) = Sexp::parse(model)? else {
+        return None;
+    };
+    let values = items.iter().filter_map(|item| match item {
+        Sexp::List(define) => match define.as_slice() {
+            [
+                Sexp::Atom(keyword),
+                Sexp::Atom(name),
+                Sexp::List(params),
+                _,
+                value,
+            ] if keyword == "define-fun" && params.is_empty() => {
+                Some((name.trim_matches('|').to_string(), value.to_string()))
+            }
+            _ => None,
+        },
+        // Some solvers start the model with `model`, as in SMT-LIB 2.0.
+        Sexp::Atom(_) => None,
+    });
+    Some(values.collect())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Sexp {
+    Atom(String),
+    List(Vec<Sexp>),
+}
+
+impl Sexp {
+    // The first s-expression in `text`.
+    fn parse(text: &str) -> Option<Sexp> {
+        let mut stack: Vec<Vec<Sexp>> = vec![];
+        let mut chars = text.char_indices().peekable();
+        while let Some((start, c)) = chars.next() {
+            let sexp = match c {
+                '(' => {
+                    stack.push(vec![]);
+                    continue;
+                }
+                ')' => Sexp::List(stack.pop()?),
+                ';' => {
+                    while chars.next_if(|(_, c)| *c != '\n').is_some() {}
+                    continue;
+                }
+                c if c.is_whitespace() => continue,
+                // Quoted symbols and strings can hold anything up to their end.
+                '|' | '"' => {
+                    let (end, _) = chars.find(|(_, end)| *end == c)?;
+                    Sexp::Atom(text[start..=end].to_string())
+                }
+                _ => {
+                    let mut end = start + c.len_utf8();
+                    while let Some((i, c)) =
+                        chars.next_if(|(_, c)| !c.is_whitespace() && !"();".contains(*c))
+                    {
+                        end = i + c.len_utf8();
+                    }
+                    Sexp::Atom(text[start..end].to_string())
+                }
+            };
+            match stack.last_mut() {
+                Some(list) => list.push(sexp),
+                None => return Some(sexp),
+            }
+        }
+        None
+    }
+}
+
+impl Display for Sexp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Sexp::Atom(atom) => write!(f, "{atom}"),
+            Sexp::List(items) => {
+                write!(f, "(")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+                    write!(f, "{item}")?;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+/// Remembers on disk what `solver` answered to each query, which repeated checks of
+/// the same code ask again. Models come back as SMT-LIB2 values, and running out of
+/// time is remembered too, for as long as the solver is configured the same.
+#[derive(Debug, Clone)]
+pub struct Cached<S> {
+    pub solver: S,
+    /// A directory with a file for each query, named after the hash of the solver
+    /// and the script.
+    pub dir: PathBuf,
+}
+
+impl<S: Solver + Debug> Solver for Cached<S> {
+    fn check(&self, query: &Query) -> Outcome {
+        let key = format!("; {:?}\n{}", self.solver, query.smtlib2());
+        let path = self.dir.join(format!("{:016x}", fnv1a(&key)));
+        // The key is kept to tell apart queries with the same hash.
+        let cached = std::fs::read_to_string(&path).ok().and_then(|cached| {
+            let answer = cached.strip_prefix(&key)?.strip_prefix(ANSWER)?;
+            parse_answer(answer)
+        });
+        if let Some(outcome) = cached {
+            return outcome;
+        }
+        let outcome = self.solver.check(query);
+        // Failing to cache only costs time later.
+        let _ = std::fs::write(&path, key + ANSWER + &answer(&outcome));
+        outcome
+    }
+}
+
+// Between the key and the answer in a cached query.
+const ANSWER: &str = "; answer\n";
+
+fn answer(outcome: &Outcome) -> String {
+    match outcome {
+        Outcome::Sat(model) => {
+            let values: Vec<_> = model
+                .values()
+                .into_iter()
+                .map(|(name, value)| format!("(|{name}| {value})"))
+                .collect();
+            format!("sat\n({})", values.join("\n"))
+        }
+        Outcome::Unsat => "unsat".to_string(),
+        Outcome::Unknown(reason) => format!("unknown\n{reason}"),
+    }
+}
+
+fn parse_answer(answer: &str) -> Option<Outcome> {
+    let (kind, rest) = answer.split_once('\n').unwrap_or((answer, ""));
+    match kind {
+        "sat" => {
+            let Sexp::List(items) = Sexp::parse(rest)? else {
+                return None;
+            };
+            let values = items.iter().map(|item| match item {
+                Sexp::List(pair) => match pair.as_slice() {
+                    [Sexp::Atom(name), value] => {
+                        Some((name.trim_matches('|').to_string(), value.to_string()))
+                    }
+                    _ => None,
+                },
+                Sexp::Atom(_) => None,
+            });
+            Some(Outcome::Sat(Model::SmtLib2(values.collect::<Option<_>>()?)))
+        }
+        "unsat" => Some(Outcome::Unsat),
+        "unknown" => Some(Outcome::Unknown(rest.to_string())),
+        _ => None,
+    }
+}
+
+// A hash that stays the same across runs and builds, unlike the ones of `std`.
+fn fnv1a(text: &str) -> u64 {
+    text.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+    })
+}
+
+fn millis(duration: Duration) -> u32 {
+    duration.as_millis().try_into().unwrap_or(u32::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_output() {
+        let output = "sat\n(\n  (define-fun |_1| () (_ BitVec 32) #x0000000a)\n  \
+                      (define-fun b () Bool true)\n)\n";
+        let Outcome::Sat(Model::SmtLib2(values)) = parse_output(output) else {
+            panic!("not sat: {output}");
+        };
+        assert_eq!(
+            values,
+            vec![
+                ("_1".to_string(), "#x0000000a".to_string()),
+                ("b".to_string(), "true".to_string())
+            ]
+        );
+        assert!(matches!(
+            parse_output("unsat\n(error \"no model\")\n"),
+            Outcome::Unsat
+        ));
+        assert!(matches!(parse_output("unknown\n"), Outcome::Unknown(_)));
+    }
+
+    #[test]
+    fn test_into_z3() {
+        let x = BV::new_const("x", 8);
+        let query = Query::new([x.bvugt(BV::from_u64(5, 8))]);
+        let model = |value: &str| Model::SmtLib2(vec![("x".to_string(), value.to_string())]);
+        let z3_model = model("#x0a").into_z3(&query).unwrap();
+        assert_eq!(eval(&z3_model, &x), 10);
+        // Values that don't make the assertions hold, or that z3 can't read.
+        assert!(model("#x03").into_z3(&query).is_none());
+        assert!(model("(bad").into_z3(&query).is_none());
+    }
+
+    // Answers from a cached answer, and counts how often it is asked.
+    struct Counting {
+        answer: &'static str,
+        calls: Cell<usize>,
+    }
+
+    // Only the answer tells them apart, like a configuration.
+    impl Debug for Counting {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "Counting({:?})", self.answer)
+        }
+    }
+
+    impl Solver for Counting {
+        fn check(&self, _: &Query) -> Outcome {
+            self.calls.set(self.calls.get() + 1);
+            parse_answer(self.answer).unwrap()
+        }
+    }
+
+    #[test]
+    fn test_cached() {
+        let dir = std::env::temp_dir().join(format!("cached_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let x = BV::new_const("x", 8);
+        let query = Query::new([x.bvugt(BV::from_u64(5, 8))]);
+        // The same query to each, which only answers it once.
+        for answer in ["sat\n((|x| #x06))", "unsat", "unknown\ntimeout"] {
+            let cached = Cached {
+                solver: Counting {
+                    answer,
+                    calls: Cell::new(0),
+                },
+                dir: dir.clone(),
+            };
+            let first = format!("{:?}", cached.check(&query));
+            assert_eq!(format!("{:?}", cached.check(&query)), first);
+            assert_eq!(cached.solver.calls.get(), 1, "{answer}");
+        }
+        // A model of z3 comes back as its values.
+        let cached = Cached {
+            solver: Z3Solver::default(),
+            dir: dir.clone(),
+        };
+        let Outcome::Sat(first @ Model::Z3(_)) = cached.check(&query) else {
+            panic!("x > 5 holds for some x");
+        };
+        let Outcome::Sat(second @ Model::SmtLib2(_)) = cached.check(&query) else {
+            panic!("not cached");
+        };
+        let (first, second) = (first.into_z3(&query), second.into_z3(&query));
+        assert_eq!(eval(&first.unwrap(), &x), eval(&second.unwrap(), &x));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_run() {
+        // More than fits into a pipe, which `cat` only writes back while it is read.
+        let input = "x".repeat(1 << 20);
+        let timeout = Some(Duration::from_secs(60));
+        assert_eq!(run("cat", &[], &input, timeout).unwrap(), input);
+        // A solver that doesn't read its input still times out.
+        let start = Instant::now();
+        let sleep = run(
+            "sleep",
+            &["60".to_string()],
+            &input,
+            Some(Duration::from_millis(100)),
+        );
+        assert_eq!(sleep, Err("timed out".to_string()));
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_parse_nested_values() {
+        let model = "(model (define-fun m () (Array (_ BitVec 64) (_ BitVec 8)) \
+                     (store ((as const (Array (_ BitVec 64) (_ BitVec 8))) #x00) #x0000000000000001 #x2a)))";
+        assert_eq!(
+            parse_model(model),
+            Some(vec![(
+                "m".to_string(),
+                "(store ((as const (Array (_ BitVec 64) (_ BitVec 8))) #x00) \
+                 #x0000000000000001 #x2a)"
+                    .to_string()
+            )])
+        );
+    }
+}
@@ -0,0 +1,266 @@
+// Symbolic execution of a whole function, or of the part of it between two points.
+// Paths are merged where they meet, with `ite` picking the state of the path that
+// was taken. Loops are unrolled: blocks are visited in reverse postorder, once for
+// each number of back edges taken on the way there, up to a bound.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use my_cfg::{BasicBlock, Cfg, Idx, Terminator, reverse_postorder};
+use z3::ast::Bool;
+
+use crate::{Effect, Inputs, Z3CfgState, and, or};
+
+/// How far `z3_of_function` follows loops.
+#[derive(Debug, Clone, Copy)]
+pub struct SymbolicConfig {
+    /// How many back edges a path may take, over all loops together. Paths that
+    /// would take more are dropped.
+    pub unroll: usize,
+}
+
+impl Default for SymbolicConfig {
+    fn default() -> Self {
+        Self { unroll: 8 }
+    }
+}
+
+/// A `Terminator::If` that symbolic execution reached.
+#[derive(Debug, Clone)]
+pub struct Branch {
+    pub bb: Idx<BasicBlock>,
+    /// How many back edges were taken to get here.
+    pub back_edges: usize,
+    /// The inputs that get here.
+    pub reached: Bool,
+    /// The condition, going to `then` if it holds.
+    pub cond: Bool,
+}
+
+/// What calling a function does, as far as the unrolling goes.
+#[derive(Debug, Clone)]
+pub struct Summary {
+    /// The effect for the inputs in `returns`, meaningless for the others.
+    pub effect: Effect,
+    /// The inputs for which the function returns within the unrolling bound.
+    pub returns: Bool,
+    /// The verification conditions of the branches: whether `reached` together with
+    /// `cond`, or its negation, can hold tells whether a side can be taken.
+    pub branches: Vec<Branch>,
+}
+
+// The paths that get to a point, merged.
+pub(crate) struct Paths<'a> {
+    pub(crate) cond: Bool,
+    pub(crate) state: Z3CfgState<'a>,
+}
+
+impl<'a> Paths<'a> {
+    // The paths are disjoint, so either condition picks its state.
+    fn merge(self, other: Paths<'a>) -> Paths<'a> {
+        Paths {
+            state: Z3CfgState::ite(&other.cond, &other.state, &self.state),
+            cond: or(&self.cond, &other.cond),
+        }
+    }
+
+    fn merge_into(self, paths: Option<Paths<'a>>) -> Paths<'a> {
+        match paths {
+            Some(paths) => paths.merge(self),
+            None => self,
+        }
+    }
+}
+
+// Where the paths through part of a function went.
+pub(crate) struct Segment<'a> {
+    pub(crate) returned: Option<Paths<'a>>,
+    // By the block they stopped at.
+    pub(crate) stopped: HashMap<Idx<BasicBlock>, Paths<'a>>,
+    pub(crate) branches: Vec<Branch>,
+}
+
+impl Segment<'_> {
+    // The inputs for which the paths got to a return or a stop within the bound.
+    pub(crate) fn done(&self) -> Bool {
+        self.returned
+            .iter()
+            .chain(self.stopped.values())
+            .fold(Bool::from_bool(false), |done, paths| or(&done, &paths.cond))
+    }
+}
+
+/// Runs the whole function on `inputs`, unrolling loops as far as `config` says.
+pub fn z3_of_function(cfg: &Cfg, inputs: &Inputs, config: SymbolicConfig) -> Summary {
+    let state = Z3CfgState::new(cfg, inputs);
+    let segment = execute(state.clone(), Idx::from_usize(0), &HashSet::new(), config);
+    let returned = segment.returned.unwrap_or(Paths {
+        cond: Bool::from_bool(false),
+        state,
+    });
+    Summary {
+        effect: returned.state.effect(),
+        returns: returned.cond,
+        branches: segment.branches,
+    }
+}
+
+// Runs from `start`, in `state`, until returning or getting to one of `stops`.
+// `start` itself is run even if it is a stop.
+pub(crate) fn execute<'a>(
+    state: Z3CfgState<'a>,
+    start: Idx<BasicBlock>,
+    stops: &HashSet<Idx<BasicBlock>>,
+    config: SymbolicConfig,
+) -> Segment<'a> {
+    let cfg = state.cfg;
+    let rpo = reverse_postorder(cfg, start);
+    let rpo_index: HashMap<_, _> = rpo.iter().enumerate().map(|(i, idx)| (*idx, i)).collect();
+    let mut segment = Segment {
+        returned: None,
+        stopped: HashMap::new(),
+        branches: vec![],
+    };
+    let start = Paths {
+        cond: Bool::from_bool(true),
+        state,
+    };
+    // Keyed by the number of back edges taken and the position in reverse postorder,
+    // so that all paths to a block are merged before it is visited.
+    let mut pending = BTreeMap::from([((0, 0), start)]);
+    while let Some(((back_edges, index), paths)) = pending.pop_first() {
+        let bb = rpo[index];
+        let mut state = paths.state;
+        for stmt in &cfg.bb[bb].stmts {
+            state.z3_of_stmt(stmt);
+        }
+        let successors = match cfg.bb[bb].terminator() {
+            Terminator::Return => {
+                let paths = Paths {
+                    cond: paths.cond,
+                    state,
+                };
+                segment.returned = Some(paths.merge_into(segment.returned.take()));
+                continue;
+            }
+            Terminator::Goto { bb } => vec![(*bb, paths.cond)],
+            Terminator::If { cond, then, else_ } => {
+                let cond = state.z3_of_condition(cond);
+                segment.branches.push(Branch {
+                    bb,
+                    back_edges,
+                    reached: paths.cond.clone(),
+                    cond: cond.clone(),
+                });
+                vec![
+                    (*then, and(&paths.cond, &cond)),
+                    (*else_, and(&paths.cond, &cond.not())),
+                ]
+            }
+        };
+        for (succ, cond) in successors {
+            let paths = Paths {
+                cond,
+                state: state.clone(),
+            };
+            if stops.contains(&succ) {
+                let stopped = segment.stopped.remove(&succ);
+                segment.stopped.insert(succ, paths.merge_into(stopped));
+                continue;
+            }
+            let succ_index = rpo_index[&succ];
+            let key = (back_edges + usize::from(succ_index <= index), succ_index);
+            if key.0 > config.unroll {
+                continue;
+            }
+            let merged = paths.merge_into(pending.remove(&key));
+            pending.insert(key, merged);
+        }
+    }
+    segment
+}
+
+#[cfg(test)]
+mod tests {
+    use my_cfg::test_util::*;
+    use my_cfg::{Binop, CType, Value};
+    use z3::ast::BV;
+
+    use super::*;
+    use crate::{MemoryConfig, Outcome, Query, Solver, Z3Solver};
+
+    fn proves(claim: &Bool) -> bool {
+        matches!(
+            Z3Solver::default().check(&Query::new([claim.not()])),
+            Outcome::Unsat
+        )
+    }
+
+    #[test]
+    fn test_diamond() {
+        // bb0: if _1 < 0 { goto bb1 } else { goto bb2 }
+        // bb1: _0 = 0 - _1; goto bb3
+        // bb2: _0 = _1; goto bb3
+        // bb3: return
+        let mut cfg = with_locals([CType::Int(4), CType::Int(4)]);
+        cfg.arg_count = 1;
+        cfg.bb.alloc(block(
+            vec![],
+            branch(binop(local(1), Binop::Lt, Value::Literal(0)), 1, 2),
+        ));
+        cfg.bb.alloc(block(
+            vec![assign(0, binop(Value::Literal(0), Binop::Sub, local(1)))],
+            goto(3),
+        ));
+        cfg.bb.alloc(block(vec![assign(0, local(1))], goto(3)));
+        cfg.bb.alloc(block(vec![], Terminator::Return));
+
+        let inputs = Inputs::fresh(&cfg, &MemoryConfig::default());
+        let summary = z3_of_function(&cfg, &inputs, SymbolicConfig::default());
+        let x = &inputs.args[0];
+        let abs = x.bvslt(BV::from_i64(0, 32)).ite(&x.bvneg(), x);
+        assert!(proves(&summary.returns));
+        assert!(proves(&summary.effect.return_value.unwrap().eq(&abs)));
+        let [branch] = &summary.branches[..] else {
+            panic!("{:?}", summary.branches);
+        };
+        assert_eq!((branch.bb.to_usize(), branch.back_edges), (0, 0));
+        assert!(proves(&branch.reached));
+    }
+
+    #[test]
+    fn test_unrolling() {
+        // bb0: _0 = 0; _2 = 0; goto bb1
+        // bb1: if _2 < _1 { goto bb2 } else { goto bb3 }
+        // bb2: _0 = _0 + 3; _2 = _2 + 1; goto bb1
+        // bb3: return
+        let mut cfg = with_locals([CType::Int(4), CType::Int(4), CType::Int(4)]);
+        cfg.arg_count = 1;
+        let zero = |i| assign(i, Value::Literal(0));
+        let inc = |i, by| assign(i, binop(local(i), Binop::Add, Value::Literal(by)));
+        cfg.bb.alloc(block(vec![zero(0), zero(2)], goto(1)));
+        cfg.bb.alloc(block(
+            vec![],
+            branch(binop(local(2), Binop::Lt, local(1)), 2, 3),
+        ));
+        cfg.bb.alloc(block(vec![inc(0, 3), inc(2, 1)], goto(1)));
+        cfg.bb.alloc(block(vec![], Terminator::Return));
+
+        let inputs = Inputs::fresh(&cfg, &MemoryConfig::default());
+        let summary = z3_of_function(&cfg, &inputs, SymbolicConfig { unroll: 5 });
+        let x = &inputs.args[0];
+        // Each iteration takes the back edge once.
+        assert!(proves(&summary.returns.eq(x.bvsle(BV::from_i64(5, 32)))));
+        let times_three = x.bvmul(BV::from_i64(3, 32));
+        let expected = x
+            .bvslt(BV::from_i64(0, 32))
+            .ite(&BV::from_i64(0, 32), &times_three);
+        let returned = summary.effect.return_value.unwrap();
+        assert!(proves(&summary.returns.implies(returned.eq(&expected))));
+        let back_edges: Vec<_> = summary
+            .branches
+            .iter()
+            .map(|branch| (branch.bb.to_usize(), branch.back_edges))
+            .collect();
+        assert_eq!(back_edges, (0..=5).map(|n| (1, n)).collect::<Vec<_>>());
+    }
+}
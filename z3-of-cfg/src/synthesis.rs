@@ -0,0 +1,442 @@
+// Synthesis of smaller values equal to a given one. Values over the same locals are
+// enumerated from the smallest. Those that give other results than the original on
+// sample inputs are dropped, as are those that give the same results as a value
+// enumerated before them, which would only lead to larger copies of it. The first
+// one left is checked by z3, and if it isn't equal after all, the inputs z3 tells
+// them apart with are added to the samples and the search starts over.
+
+use std::collections::HashMap;
+
+use my_cfg::ranges::value_ty;
+use my_cfg::{Binop, CType, Cfg, Idx, Local, Place, Stmt, Value};
+
+use crate::{Inputs, MemoryConfig, Outcome, Query, Solver, bits, z3_of_stmts};
+
+/// What `synthesize` builds values from.
+#[derive(Debug, Clone)]
+pub struct Grammar {
+    /// The most operations and operands a value can have, counting every one.
+    pub max_size: usize,
+    pub binops: Vec<Binop>,
+    /// Whether to build `c ? a : b`, with a condition `c` that is a comparison or a
+    /// `bool` local.
+    pub selects: bool,
+    /// Literals to use, besides those of the value and the powers of two that those
+    /// between 1 and 30 shift by.
+    pub constants: Vec<i32>,
+}
+
+impl Default for Grammar {
+    fn default() -> Self {
+        Grammar {
+            max_size: 5,
+            binops: vec![
+                Binop::Add,
+                Binop::Sub,
+                Binop::Mul,
+                Binop::Div,
+                Binop::Rem,
+                Binop::Shl,
+                Binop::Shr,
+                Binop::Sar,
+                Binop::Xor,
+                Binop::Lt,
+                Binop::Le,
+                Binop::Eq,
+                Binop::Ne,
+            ],
+            selects: true,
+            constants: vec![0, 1, -1],
+        }
+    }
+}
+
+const SAMPLES: usize = 64;
+// Bounds on the work for one value, past which it is left as it is.
+const MAX_CANDIDATES: usize = 100_000;
+const MAX_PROOFS: usize = 8;
+
+struct Candidate {
+    value: Value,
+    is_literal: bool,
+}
+
+/// The smallest value over the locals of `value` that z3 proves equal to it, if one
+/// is smaller than `value` and within `grammar`. Only values on integer locals are
+/// searched, and what is found is never a literal, so that it can replace `value`
+/// as an operand too.
+pub fn synthesize(
+    value: &Value,
+    cfg: &Cfg,
+    grammar: &Grammar,
+    solver: &dyn Solver,
+) -> Option<Value> {
+    let mut locals = vec![];
+    let mut literals = grammar.constants.clone();
+    if !leaves(value, cfg, &mut locals, &mut literals) || locals.is_empty() {
+        return None;
+    }
+    let shifts = literals.iter().filter(|i| (1..31).contains(*i));
+    let powers: Vec<i32> = shifts.map(|i| 1 << i).collect();
+    literals.extend(powers);
+    literals.sort();
+    literals.dedup();
+    let mut samples = samples(&locals, cfg);
+    for _ in 0..MAX_PROOFS {
+        let candidate = search(value, cfg, grammar, &locals, &literals, &samples)?;
+        match check(value, &candidate, cfg, solver) {
+            Outcome::Unsat => return Some(candidate),
+            Outcome::Sat(model) => {
+                let mut sample = vec![0; cfg.locals.iter().count()];
+                for idx in &locals {
+                    let bits = bits(&cfg.locals[*idx].ty, &cfg.layout);
+                    let name = format!("_{}", idx.to_usize());
+                    sample[idx.to_usize()] = model.bits(&name, bits).unwrap_or(0);
+                }
+                samples.push(sample);
+            }
+            Outcome::Unknown(_) => return None,
+        }
+    }
+    None
+}
+
+// The first value of the enumeration that gives the same results as `value` on
+// `samples`.
+fn search(
+    value: &Value,
+    cfg: &Cfg,
+    grammar: &Grammar,
+    locals: &[Idx<Local>],
+    literals: &[i32],
+    samples: &[Vec<u128>],
+) -> Option<Value> {
+    let results = |value: &Value| -> Vec<u128> {
+        samples
+            .iter()
+            .map(|sample| eval(value, cfg, sample))
+            .collect()
+    };
+    let ty = value_ty(value, cfg);
+    let target = results(value);
+
+    // The results of the values kept so far, with their types.
+    let mut seen: HashMap<Vec<u128>, Vec<CType>> = HashMap::new();
+    // The values kept so far, by size.
+    let mut banks: Vec<Vec<Candidate>> = vec![vec![]];
+    let mut candidates = 0;
+    for size in 1..size_of(value).min(grammar.max_size + 1) {
+        let mut found = vec![];
+        if size == 1 {
+            found.extend(locals.iter().map(|idx| Candidate {
+                value: Value::Place(Place::Local(*idx)),
+                is_literal: false,
+            }));
+            found.extend(literals.iter().map(|i| Candidate {
+                value: Value::Literal(*i),
+                is_literal: true,
+            }));
+        }
+        for binop in &grammar.binops {
+            for l_size in 1..size.saturating_sub(1) {
+                let r_size = size - 1 - l_size;
+                for l in &banks[l_size] {
+                    for r in &banks[r_size] {
+                        if l.is_literal && r.is_literal {
+                            continue;
+                        }
+                        found.push(Candidate {
+                            value: Value::Binop(
+                                Box::new(l.value.clone()),
+                                *binop,
+                                Box::new(r.value.clone()),
+                            ),
+                            is_literal: false,
+                        });
+                    }
+                }
+            }
+        }
+        if grammar.selects {
+            for cond_size in 1..size.saturating_sub(2) {
+                for then_size in 1..size - 1 - cond_size {
+                    let else_size = size - 1 - cond_size - then_size;
+                    let conds = banks[cond_size]
+                        .iter()
+                        .filter(|cond| value_ty(&cond.value, cfg) == CType::Bool);
+                    for cond in conds {
+                        for then in &banks[then_size] {
+                            for else_ in &banks[else_size] {
+                                found.push(Candidate {
+                                    value: Value::Select(
+                                        Box::new(cond.value.clone()),
+                                        Box::new(then.value.clone()),
+                                        Box::new(else_.value.clone()),
+                                    ),
+                                    is_literal: false,
+                                });
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        let mut bank = vec![];
+        for candidate in found {
+            if candidate.is_literal {
+                bank.push(candidate);
+                continue;
+            }
+            candidates += 1;
+            if candidates > MAX_CANDIDATES {
+                return None;
+            }
+            let candidate_ty = value_ty(&candidate.value, cfg);
+            let candidate_results = results(&candidate.value);
+            let types = seen.entry(candidate_results.clone()).or_default();
+            if types.contains(&candidate_ty) {
+                continue;
+            }
+            types.push(candidate_ty.clone());
+            if candidate_ty == ty && candidate_results == target {
+                return Some(candidate.value);
+            }
+            bank.push(candidate);
+        }
+        banks.push(bank);
+    }
+    None
+}
+
+fn size_of(value: &Value) -> usize {
+    match value {
+        Value::Binop(l, _, r) => 1 + size_of(l) + size_of(r),
+        Value::Select(cond, then, else_) => 1 + size_of(cond) + size_of(then) + size_of(else_),
+        _ => 1,
+    }
+}
+
+// Collects the locals and literals of `value`. Whether it only reads integer locals.
+fn leaves(value: &Value, cfg: &Cfg, locals: &mut Vec<Idx<Local>>, literals: &mut Vec<i32>) -> bool {
+    match value {
+        Value::Literal(i) => {
+            literals.push(*i);
+            true
+        }
+        Value::Place(Place::Local(idx)) => {
+            if !locals.contains(idx) {
+                locals.push(*idx);
+            }
+            matches!(
+                cfg.locals[*idx].ty,
+                CType::Int(_) | CType::UInt(_) | CType::Bool
+            )
+        }
+        Value::Place(_) => false,
+        Value::Binop(l, _, r) => {
+            leaves(l, cfg, locals, literals) && leaves(r, cfg, locals, literals)
+        }
+        Value::Select(cond, then, else_) => {
+            leaves(cond, cfg, locals, literals)
+                && leaves(then, cfg, locals, literals)
+                && leaves(else_, cfg, locals, literals)
+        }
+    }
+}
+
+// Values of the locals, by index: the ones where operations change behavior, first
+// for all locals at once and then picked for each, and pseudo-random ones after.
+fn samples(locals: &[Idx<Local>], cfg: &Cfg) -> Vec<Vec<u128>> {
+    const EDGES: [i128; 12] = [0, 1, -1, 2, 3, 7, 8, -8, 31, 100, i128::MIN, i128::MAX];
+    let mut state: u64 = 0x9e37_79b9_7f4a_7c15;
+    let mut random = move || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    };
+    (0..SAMPLES)
+        .map(|i| {
+            let mut sample = vec![0; cfg.locals.iter().count()];
+            for idx in locals {
+                let ty = &cfg.locals[*idx].ty;
+                let bits = bits(ty, &cfg.layout);
+                let edge = match i {
+                    _ if i < EDGES.len() => Some(EDGES[i]),
+                    _ if i < SAMPLES * 3 / 4 => Some(EDGES[random() as usize % EDGES.len()]),
+                    _ => None,
+                };
+                let value = match edge {
+                    // The extremes of the type, as if it were signed.
+                    Some(i128::MIN) => 1 << (bits - 1),
+                    Some(i128::MAX) => (1 << (bits - 1)) - 1,
+                    Some(edge) => edge as u128,
+                    None if random() % 3 == 0 => u128::from(random() % 16),
+                    None => (u128::from(random()) << 64) | u128::from(random()),
+                };
+                sample[idx.to_usize()] = match ty {
+                    CType::Bool => value & 1,
+                    _ => mask(value, bits),
+                };
+            }
+            sample
+        })
+        .collect()
+}
+
+// Whether `candidate` can differ from `value`, both being assigned to a local of the
+// type of `value`.
+fn check(value: &Value, candidate: &Value, cfg: &Cfg, solver: &dyn Solver) -> Outcome {
+    let mut cfg = cfg.clone();
+    let result = cfg.locals.alloc(Local {
+        name: None,
+        ty: value_ty(value, &cfg),
+    });
+    let inputs = Inputs::fresh(&cfg, &MemoryConfig::default());
+    let run = |value: &Value| {
+        let stmt = Stmt::Assign {
+            place: Place::Local(result),
+            value: value.clone(),
+            origin: None,
+        };
+        z3_of_stmts(&cfg, &[stmt], &inputs)
+            .locals
+            .swap_remove(result.to_usize())
+    };
+    solver.check(&Query::new([run(value).eq(run(candidate)).not()]))
+}
+
+fn mask(value: u128, bits: u32) -> u128 {
+    if bits >= 128 {
+        value
+    } else {
+        value & ((1 << bits) - 1)
+    }
+}
+
+// The bits of `value` on `sample`, which holds the bits of every local.
+fn eval(value: &Value, cfg: &Cfg, sample: &[u128]) -> u128 {
+    let local = |idx: Idx<Local>| Some(sample[idx.to_usize()] as i128);
+    let result = my_cfg::eval::value(value, cfg, &local).expect("only integers are sampled");
+    mask(result as u128, bits(&value_ty(value, cfg), &cfg.layout))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+    use crate::{Model, Z3Solver};
+    use my_cfg::test_util::*;
+
+    // Takes whatever matches on the samples, to test the search without z3.
+    struct Trusting;
+
+    impl Solver for Trusting {
+        fn check(&self, _: &Query) -> Outcome {
+            Outcome::Unsat
+        }
+    }
+
+    // Tells the first candidate apart with `_2 = 15` and takes the next.
+    struct Refuting(Cell<bool>);
+
+    impl Solver for Refuting {
+        fn check(&self, _: &Query) -> Outcome {
+            if self.0.replace(true) {
+                return Outcome::Unsat;
+            }
+            Outcome::Sat(Model::SmtLib2(vec![(
+                "_2".to_string(),
+                "#x0000000f".to_string(),
+            )]))
+        }
+    }
+
+    #[test]
+    fn test_synthesize() {
+        let mut cfg = with_locals([CType::Int(4), CType::UInt(4), CType::Int(4)]);
+        cfg.arg_count = 2;
+        let grammar = Grammar::default();
+        // `x - ((x >> 3) << 3)` clears all but the low 3 bits.
+        let low_bits = binop(
+            local(1),
+            Binop::Sub,
+            binop(
+                binop(local(1), Binop::Shr, Value::Literal(3)),
+                Binop::Shl,
+                Value::Literal(3),
+            ),
+        );
+        assert_eq!(
+            synthesize(&low_bits, &cfg, &grammar, &Trusting),
+            Some(binop(local(1), Binop::Rem, Value::Literal(8)))
+        );
+        // `(x << 1) + (x << 2)` is `x * 6`, with a literal that has to be given.
+        let times_six = binop(
+            binop(local(2), Binop::Shl, Value::Literal(1)),
+            Binop::Add,
+            binop(local(2), Binop::Shl, Value::Literal(2)),
+        );
+        assert_eq!(synthesize(&times_six, &cfg, &grammar, &Trusting), None);
+        let with_six = Grammar {
+            constants: vec![6],
+            ..grammar.clone()
+        };
+        assert_eq!(
+            synthesize(&times_six, &cfg, &with_six, &Trusting),
+            Some(binop(local(2), Binop::Mul, Value::Literal(6)))
+        );
+        // `x - x` is 0, and literals aren't answers.
+        let zero = binop(local(2), Binop::Sub, local(2));
+        assert_eq!(synthesize(&zero, &cfg, &grammar, &Trusting), None);
+        // `x / 1 < x + 0` is `x < x`, the first thing that is always false.
+        let never = binop(
+            binop(local(2), Binop::Div, Value::Literal(1)),
+            Binop::Lt,
+            binop(local(2), Binop::Add, Value::Literal(0)),
+        );
+        assert_eq!(
+            synthesize(&never, &cfg, &grammar, &Trusting),
+            Some(binop(local(2), Binop::Lt, local(2)))
+        );
+
+        // Only 15 tells `x >> 128 == 15`, where the shift is by 0, from `x < x`.
+        let fifteen = binop(
+            binop(local(2), Binop::Shr, Value::Literal(128)),
+            Binop::Eq,
+            Value::Literal(15),
+        );
+        assert_eq!(
+            synthesize(&fifteen, &cfg, &grammar, &Trusting),
+            Some(binop(local(2), Binop::Lt, local(2)))
+        );
+        assert_eq!(
+            synthesize(&fifteen, &cfg, &grammar, &Refuting(Cell::new(false))),
+            Some(binop(local(2), Binop::Eq, Value::Literal(15)))
+        );
+    }
+
+    #[test]
+    fn test_abs() {
+        let mut cfg = with_locals([CType::Int(4), CType::Int(4)]);
+        cfg.arg_count = 1;
+        // `(x ^ (x >> 31)) - (x >> 31)` flips the bits of negative values and adds one.
+        let sign = || binop(local(1), Binop::Sar, Value::Literal(31));
+        let abs = binop(binop(local(1), Binop::Xor, sign()), Binop::Sub, sign());
+        let grammar = Grammar {
+            max_size: 8,
+            binops: vec![Binop::Sub, Binop::Xor, Binop::Sar, Binop::Lt],
+            selects: true,
+            constants: vec![0],
+        };
+        assert_eq!(
+            synthesize(&abs, &cfg, &grammar, &Z3Solver::default()),
+            Some(Value::Select(
+                Box::new(binop(Value::Literal(0), Binop::Lt, local(1))),
+                Box::new(local(1)),
+                Box::new(binop(Value::Literal(0), Binop::Sub, local(1))),
+            ))
+        );
+    }
+}
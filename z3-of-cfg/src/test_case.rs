@@ -0,0 +1,199 @@
+// Decoding z3 models into concrete `TestCase`s: the arguments by their types, and
+// the bytes of memory that the code on the inputs accesses.
+
+use std::collections::BTreeSet;
+
+use my_cfg::{CType, Cfg, MemoryChunk, TestCase, TestValue};
+use z3::Model;
+use z3::ast::BV;
+
+use crate::{Inputs, Memory, Region, Z3CfgState};
+
+/// The inputs that `model` gives `inputs`, and what `returned`, a state in which
+/// `cfg` returns after being run on them, expects.
+pub(crate) fn test_case(
+    model: &Model,
+    cfg: &Cfg,
+    inputs: &Inputs,
+    returned: &Z3CfgState,
+) -> TestCase {
+    let value = |bv: &BV, tag: &BV, ty: &CType| test_value(model, bv, tag, ty);
+    let args = inputs
+        .args
+        .iter()
+        .zip(&inputs.arg_regions)
+        .zip(cfg.locals.iter().skip(1))
+        .map(|((arg, region), (_, local))| value(arg, &region.tag(), &local.ty))
+        .collect();
+    let (_, return_local) = cfg.locals.iter().next().unwrap();
+    let expected = (return_local.ty != CType::Void)
+        .then(|| value(&returned.locals[0], &returned.regions[0], &return_local.ty));
+
+    let address_bits = u32::from(cfg.layout.pointer_size) * 8;
+    let mut used = BTreeSet::new();
+    for access in inputs.memory.accesses() {
+        let tag = eval(model, &access.tag) as u64;
+        let addr = eval(model, &access.addr) as u64;
+        for i in 0..access.size_bytes {
+            used.insert((tag, wrap(addr.wrapping_add(i.into()), address_bits)));
+        }
+    }
+    let bytes = |memory: &Memory, (tag, addr): (u64, u64)| {
+        let byte = memory
+            .array(tag)
+            .select(&BV::from_u64(addr, address_bits))
+            .as_bv()
+            .unwrap();
+        ((tag, addr), eval(model, &byte) as u8)
+    };
+    let frame = Region::Frame.tag().as_u64();
+    TestCase {
+        args,
+        memory: MemoryChunk::chunks(used.iter().map(|at| bytes(&inputs.memory, *at))),
+        expected,
+        expected_memory: MemoryChunk::chunks(
+            used.iter()
+                .filter(|(tag, _)| Some(*tag) != frame)
+                .map(|at| bytes(&returned.memory, *at)),
+        ),
+    }
+}
+
+// The value of `bv` of type `ty`, in the region with the tag `tag` if it is a pointer.
+fn test_value(model: &Model, bv: &BV, tag: &BV, ty: &CType) -> TestValue {
+    let bits = eval(model, bv);
+    match ty {
+        CType::Int(bytes) => {
+            let unused = 128 - u32::from(*bytes) * 8;
+            TestValue::Int((bits << unused) as i128 >> unused)
+        }
+        CType::UInt(_) | CType::Bool => TestValue::Int(bits as i128),
+        CType::Ptr(_) => TestValue::Pointer {
+            region: eval(model, tag) as u64,
+            address: bits as u64,
+        },
+        CType::Float(_) => TestValue::Float(bits),
+        CType::Void => panic!("no test values of type {ty}"),
+    }
+}
+
+/// The bits of `bv` in `model`, which has values for all constants.
+pub(crate) fn eval(model: &Model, bv: &BV) -> u128 {
+    let size = bv.get_size();
+    let part = |high, low| {
+        let part = bv.extract(high, low);
+        model
+            .eval(&part, true)
+            .and_then(|bits| bits.as_u64())
+            .unwrap()
+    };
+    if size <= 64 {
+        part(size - 1, 0).into()
+    } else {
+        u128::from(part(size - 1, 64)) << 64 | u128::from(part(63, 0))
+    }
+}
+
+fn wrap(addr: u64, bits: u32) -> u64 {
+    if bits < 64 {
+        addr & ((1 << bits) - 1)
+    } else {
+        addr
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::process::{Command, Stdio};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use my_cfg::interpreter::replay;
+    use my_cfg::test_util::*;
+    use my_cfg::{Binop, Place, Stmt, Terminator, Value, emit_c, emit_c_harness};
+
+    use super::*;
+    use crate::{Equivalence, MemoryConfig, SymbolicConfig, Z3Solver, check_equivalence};
+
+    // *_1 = *_1 op _2; _0 = 0
+    fn update(op: Binop) -> Cfg {
+        let mut cfg = with_locals([
+            CType::Int(4),
+            CType::Ptr(Box::new(CType::Int(4))),
+            CType::Int(4),
+        ]);
+        cfg.arg_count = 2;
+        let deref = || Place::Deref(Box::new(place(1)));
+        let stmt = Stmt::Assign {
+            place: deref(),
+            value: binop(Value::Place(deref()), op, local(2)),
+            origin: None,
+        };
+        cfg.bb.alloc(block(
+            vec![stmt, assign(0, Value::Literal(0))],
+            Terminator::Return,
+        ));
+        cfg
+    }
+
+    // Whether the C harness for `test` passes on `cfg`.
+    fn passes(cfg: &Cfg, test: &TestCase) -> bool {
+        static BINARIES: AtomicUsize = AtomicUsize::new(0);
+        let source = emit_c(cfg, &cfg.loopify(), "f") + &emit_c_harness(cfg, "f", test);
+        let binary = std::env::temp_dir().join(format!(
+            "test_case_{}_{}",
+            std::process::id(),
+            BINARIES.fetch_add(1, Ordering::Relaxed)
+        ));
+        let mut cc = Command::new("cc")
+            .args(["-x", "c", "-", "-o"])
+            .arg(&binary)
+            .stdin(Stdio::piped())
+            .spawn()
+            .expect("cc runs");
+        cc.stdin
+            .take()
+            .unwrap()
+            .write_all(source.as_bytes())
+            .unwrap();
+        assert!(cc.wait().unwrap().success(), "{source}");
+        let status = Command::new(&binary).status().unwrap();
+        let _ = std::fs::remove_file(&binary);
+        status.success()
+    }
+
+    #[test]
+    fn test_counterexample() {
+        let (before, after) = (update(Binop::Add), update(Binop::Sub));
+        let config = SymbolicConfig::default();
+        let memory = MemoryConfig::default();
+        let solver = Z3Solver::default();
+        let Equivalence::Counterexample(counterexample) =
+            check_equivalence(&before, &after, config, &memory, &[], &solver)
+        else {
+            panic!("adding and subtracting are the same");
+        };
+        let test = counterexample.test_case.unwrap();
+        let [TestValue::Pointer { region: 0, address }, TestValue::Int(x)] = test.args[..] else {
+            panic!("{test:?}");
+        };
+        assert_eq!(test.expected, Some(TestValue::Int(0)));
+        // The four bytes that `_1` points to, before and after adding.
+        let [chunk] = &test.memory[..] else {
+            panic!("{test:?}");
+        };
+        assert_eq!((chunk.address, chunk.bytes.len()), (address, 4));
+        let old = i32::from_le_bytes(chunk.bytes[..].try_into().unwrap());
+        let [expected] = &test.expected_memory[..] else {
+            panic!("{test:?}");
+        };
+        let new = old.wrapping_add(x as i32).to_le_bytes();
+        assert_eq!((expected.address, &expected.bytes[..]), (address, &new[..]));
+        assert_eq!(TestCase::from_json(&test.to_json()).unwrap(), test);
+
+        assert!(passes(&before, &test));
+        assert!(!passes(&after, &test));
+        assert_eq!(replay(&before, &test, 10), Some(true));
+        assert_eq!(replay(&after, &test, 10), Some(false));
+    }
+}